@@ -2,12 +2,11 @@
 
 use pnet::datalink;
 use std::{
-    cmp::max,
     collections::{HashMap, hash_map::Entry},
     io::ErrorKind,
     net::{IpAddr, SocketAddr},
     sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    time::Instant,
 };
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt, split},
@@ -17,13 +16,14 @@ use tokio::{
 };
 use tokio_stream::StreamExt;
 use tokio_util::{codec::FramedRead, sync::CancellationToken};
-use tracing::{debug, debug_span, error, trace, warn};
+use tracing::{debug, error, trace, warn};
 
 use crate::{
     client::{Searcher, searcher::CouldNotFindError},
-    dbr::{Dbr, DbrBasicType, DbrCategory, DbrType, DbrValue},
-    messages::{self, Access, CAMessage, ClientMessage, Message, MonitorMask, RsrvIsUp},
-    utils::{new_reusable_udp_socket, wrapping_inplace_add},
+    dbr::{Dbr, DbrCategory, DbrType, DbrValue},
+    messages::{self, CAMessage, ClientMessage, Message, RsrvIsUp},
+    proto::client::{ChannelInfo, ClientCircuit, ClientEvent},
+    utils::new_reusable_udp_socket,
 };
 
 fn get_default_broadcast_ips() -> Vec<IpAddr> {
@@ -81,17 +81,7 @@ impl Circuit {
         // Exchange version messages
         Message::write_all_messages(&[messages::Version::default().into()], &mut tcp).await?;
         Self::do_read_check_version(&mut tcp).await?;
-        debug!("Done version exchange, sending identification messages");
-        // Send the identification messages
-        Message::write_all_messages(
-            &[
-                messages::ClientName { name: client_name }.into(),
-                messages::HostName { name: host_name }.into(),
-            ],
-            &mut tcp,
-        )
-        .await?;
-
+        debug!("Done version exchange");
         let (requests_tx, requests_rx) = mpsc::channel(8);
         // Now we have a connected circuit, ready for lifecycle!
         let cancel = CancellationToken::new();
@@ -104,15 +94,12 @@ impl Circuit {
                 address: inner_address,
                 requests_rx,
                 cancel: inner_cancel,
-                next_cid: 0,
-                channel_lookup: Default::default(),
-                channels: Default::default(),
+                // Identifying ourselves is the first thing the circuit sends
+                state: ClientCircuit::new(client_name, host_name, Instant::now()),
+                pending_open: Default::default(),
                 pending_reads: Default::default(),
-                last_echo_sent_at: Instant::now(),
-                last_received_message_at: Instant::now(),
                 pending_broadcasts: Default::default(),
                 broadcast_receivers: Default::default(),
-                broadcast_channels: Default::default(),
             }
             .circuit_lifecycle(tcp)
             .await;
@@ -193,80 +180,22 @@ impl Circuit {
     }
 }
 
-#[derive(Debug, Default, Copy, Clone)]
-enum ChannelState {
-    #[default]
-    Closed,
-    SentCreate,
-    Ready,
-}
-
-/// Summary of channel information
-#[derive(Debug, Clone, Copy)]
-struct ChannelInfo {
-    state: ChannelState,
-    native_type: DbrBasicType,
-    native_count: u32,
-    cid: u32,
-    permissions: Access,
-}
-
-#[derive(Debug, Default)]
-struct Channel {
-    name: String,
-    state: ChannelState,
-    native_type: Option<DbrBasicType>,
-    native_count: u32,
-    cid: u32,
-    sid: u32,
-    permissions: Access,
-    /// Watchers waiting for this channel to be open
-    pending_open: Vec<oneshot::Sender<Result<ChannelInfo, ClientError>>>,
-    next_ioid: u32,
-    broadcast_receivers: Vec<u32>,
-}
-
-impl Channel {
-    fn info(&self) -> ChannelInfo {
-        ChannelInfo {
-            state: self.state,
-            native_type: self.native_type.unwrap(),
-            native_count: self.native_count,
-            cid: self.cid,
-            permissions: self.permissions,
-        }
-    }
-}
-
 // Inner circuit state, used to hold async management data
 struct CircuitInternal {
     /// A copy of the address we are connected to
     address: SocketAddr,
-    /// When the last message was received. Used to calculate Echo timing.
-    last_received_message_at: Instant,
-    last_echo_sent_at: Instant,
-    // requests_tx: mpsc::Sender<CircuitRequest>,
     requests_rx: mpsc::Receiver<CircuitRequest>,
     cancel: CancellationToken,
-    next_cid: u32,
-    channels: HashMap<u32, Channel>,
-    channel_lookup: HashMap<String, u32>,
+    /// The protocol state of the circuit
+    state: ClientCircuit,
+    /// Watchers waiting for channels to be open
+    pending_open: HashMap<u32, Vec<oneshot::Sender<Result<ChannelInfo, ClientError>>>>,
     /// Watchers waiting for specific reads
-    pending_reads: HashMap<u32, (Instant, oneshot::Sender<Result<Dbr, ClientError>>)>,
+    pending_reads: HashMap<u32, oneshot::Sender<Result<Dbr, ClientError>>>,
     /// Broadcast subscriptions we have not had confirmed yet
-    pending_broadcasts: HashMap<
-        u32,
-        (
-            Instant,
-            (
-                usize,
-                DbrType,
-                oneshot::Sender<Result<broadcast::Receiver<Dbr>, ClientError>>,
-            ),
-        ),
-    >,
-    broadcast_receivers: HashMap<u32, (usize, DbrType, broadcast::Sender<Dbr>)>,
-    broadcast_channels: HashMap<u32, u32>,
+    pending_broadcasts:
+        HashMap<u32, oneshot::Sender<Result<broadcast::Receiver<Dbr>, ClientError>>>,
+    broadcast_receivers: HashMap<u32, broadcast::Sender<Dbr>>,
 }
 
 impl CircuitInternal {
@@ -275,100 +204,53 @@ impl CircuitInternal {
         let (tcp_rx, mut tcp_tx) = split(tcp);
         let mut framed = FramedRead::with_capacity(tcp_rx, ClientMessage::default(), 16384usize);
         loop {
-            let next_timing_stop = max(self.last_echo_sent_at, self.last_received_message_at)
-                + Duration::from_secs(15);
-            let messages_out = select! {
+            // Send anything that the last step queued up
+            if let Some(data) = self.state.poll_transmit_bytes()
+                && tcp_tx.write_all(&data).await.is_err()
+            {
+                error!("Failed to write messages to io stream, aborting");
+                break;
+            }
+            let next_timing_stop = self.state.poll_timeout();
+            select! {
                 _ = self.cancel.cancelled() => break,
                 incoming = framed.next() => match incoming {
-                    Some(message) => match message {
-                        Ok(message) => Some(self.handle_message(message)),
-                        Err(e) => {
-                            error!("Got error processing server message: {e}");
-                            continue;
+                    Some(Ok(message)) => {
+                        for event in self.state.handle_message(message, Instant::now()) {
+                            self.handle_event(event);
                         }
-                    },
+                    }
+                    Some(Err(e)) => {
+                        error!("Got error processing server message: {e}");
+                        continue;
+                    }
                     None => break,
                 },
                 request = self.requests_rx.recv() => match request {
                     None => break,
-                    Some(req) => Some(self.handle_request(req).await)
+                    Some(req) => self.handle_request(req),
                 },
                 _ = tokio::time::sleep_until(next_timing_stop.into()) => {
-                    if self.last_echo_sent_at < self.last_received_message_at {
-                        self.last_echo_sent_at = Instant::now();
-                        Some(vec![Message::Echo])
-                    } else {
-                        // We sent an echo already, this is the termination time
-                        error!("Received no reply from server, assuming connection dead");
-                        break
+                    if !self.state.handle_timeout(Instant::now()) {
+                        break;
                     }
                 },
             };
-
-            // Send any messages out
-            if let Some(messages) = messages_out {
-                for message in &messages {
-                    trace!("Sending {message:?}");
-                }
-                if Message::write_all_messages(&messages, &mut tcp_tx)
-                    .await
-                    .is_err()
-                {
-                    error!("Failed to write messages to io stream, aborting");
-                }
-            }
         }
         self.cancel.cancel();
         let _ = tcp_tx.shutdown().await;
     }
 
-    fn create_channel(&mut self, name: String) -> (&mut Channel, Vec<Message>) {
-        // We need to open a new channel
-        let cid = self.next_cid;
-        self.next_cid = self.next_cid.wrapping_add(1);
-        let channel = Channel {
-            cid,
-            state: ChannelState::SentCreate, // Or, about to, anyway
-            next_ioid: 4242,
-            ..Default::default()
-        };
-        let _span = debug_span!("create_channel", cid = cid).entered();
-        debug!("Creating channel '{name}' cid: {cid}");
-        self.channel_lookup.insert(name.clone(), cid);
-        self.channels.insert(cid, channel);
-        (
-            self.channels.get_mut(&cid).unwrap(),
-            vec![
-                messages::CreateChannel {
-                    client_id: cid,
-                    channel_name: name,
-                    ..Default::default()
-                }
-                .into(),
-            ],
-        )
-    }
-
-    async fn handle_request(&mut self, request: CircuitRequest) -> Vec<Message> {
+    fn handle_request(&mut self, request: CircuitRequest) {
         match request {
             CircuitRequest::GetChannel(name, sender) => {
-                if let Some(id) = self.channel_lookup.get(&name) {
-                    // We already have this channel.. let's check if it is open
-                    let channel = self.channels.get_mut(id).unwrap();
-                    match channel.state {
-                        ChannelState::Closed => panic!("We should never see a closed channel?"),
-                        ChannelState::SentCreate => channel.pending_open.push(sender),
-                        ChannelState::Ready => {
-                            // Already ready, just send it out
-                            let _ = sender.send(Ok(channel.info()));
-                        }
+                let cid = self.state.open_channel(&name);
+                match self.state.channel_info(cid) {
+                    // Already ready, just send it out
+                    Some(info) => {
+                        let _ = sender.send(Ok(info));
                     }
-                    Vec::new()
-                } else {
-                    let (channel, messages) = self.create_channel(name);
-                    channel.pending_open.push(sender);
-                    // Pass the channel create messages back
-                    messages
+                    None => self.pending_open.entry(cid).or_default().push(sender),
                 }
             }
             CircuitRequest::Read {
@@ -376,200 +258,71 @@ impl CircuitInternal {
                 length,
                 category,
                 reply,
-            } => {
-                let Some(channel) = self.channels.get_mut(&cid) else {
+            } => match self.state.read(cid, category, length) {
+                Some(ioid) => {
+                    self.pending_reads.insert(ioid, reply);
+                }
+                None => {
                     let _ = reply.send(Err(ClientError::ChannelClosed));
-                    return Vec::new();
-                };
-                let _span = debug_span!("handle_request", cid = cid).entered();
-                // Send the read request
-                let ioid = wrapping_inplace_add(&mut channel.next_ioid);
-                debug!(
-                    "Sending read request {ioid} for channel {cid} ({})",
-                    channel.name
-                );
-                self.pending_reads.insert(ioid, (Instant::now(), reply));
-                vec![
-                    messages::ReadNotify {
-                        data_type: DbrType {
-                            basic_type: channel.native_type.unwrap(),
-                            category,
-                        },
-                        data_count: length as u32,
-                        server_id: channel.sid,
-                        client_ioid: ioid,
-                    }
-                    .into(),
-                ]
-            }
+                }
+            },
             CircuitRequest::Subscribe {
                 channel: cid,
                 length,
                 dbr_type,
                 reply,
-            } => {
-                let Some(channel) = self.channels.get_mut(&cid) else {
+            } => match self.state.subscribe(cid, dbr_type, length) {
+                Some(subscription_id) => {
+                    self.pending_broadcasts.insert(subscription_id, reply);
+                }
+                None => {
                     let _ = reply.send(Err(ClientError::ChannelClosed));
-                    return Vec::new();
-                };
-                let _span = debug_span!("handle_request", cid = cid).entered();
-                let ioid = wrapping_inplace_add(&mut channel.next_ioid);
-                self.pending_broadcasts
-                    .insert(ioid, (Instant::now(), (length, dbr_type, reply)));
-                self.broadcast_channels.insert(ioid, channel.cid);
-                channel.broadcast_receivers.push(ioid);
-                vec![
-                    messages::EventAdd {
-                        data_type: dbr_type,
-                        data_count: length as u32,
-                        server_id: channel.sid,
-                        subscription_id: ioid,
-                        mask: MonitorMask::default(),
-                    }
-                    .into(),
-                ]
-            }
+                }
+            },
         }
     }
-    fn handle_message(&mut self, message: ClientMessage) -> Vec<Message> {
-        self.last_received_message_at = Instant::now();
-        trace!("Received message: {message:?}");
-        match message {
-            ClientMessage::AccessRights(msg) => {
-                let _span = debug_span!("handle_message", cid = &msg.client_id).entered();
-                let Some(channel) = self.channels.get_mut(&msg.client_id) else {
-                    debug!("Got message for closed/uncreated channel");
-                    return Vec::new();
-                };
-                debug!("Got AccessRights update: {}", msg.access_rights);
-                channel.permissions = msg.access_rights;
-                Vec::new()
-            }
-            ClientMessage::CreateChannelResponse(msg) => {
-                let _span = debug_span!("handle_message", cid = &msg.client_id).entered();
-                let Some(channel) = self.channels.get_mut(&msg.client_id) else {
-                    debug!("Got message for closed/uncreated channel: {msg:?}");
-                    return Vec::new();
-                };
-                channel.native_count = msg.data_count;
-                channel.native_type = Some(msg.data_type);
-                channel.state = ChannelState::Ready;
-                channel.sid = msg.server_id;
-                let info = channel.info();
 
-                for sender in channel.pending_open.drain(..) {
+    fn handle_event(&mut self, event: ClientEvent) {
+        match event {
+            ClientEvent::ChannelReady(info) => {
+                for sender in self.pending_open.remove(&info.cid).unwrap_or_default() {
                     let _ = sender.send(Ok(info));
                 }
-                Vec::new()
             }
-            ClientMessage::CreateChannelFailure(msg) => {
-                let Some(mut channel) = self.channels.remove(&msg.client_id) else {
-                    warn!(
-                        "Got channel failure message for a nonexistent channel {}",
-                        msg.client_id
-                    );
-                    return Vec::new();
-                };
-                for sender in channel.pending_open.drain(..) {
+            ClientEvent::ChannelFailed { cid } => {
+                for sender in self.pending_open.remove(&cid).unwrap_or_default() {
                     let _ = sender.send(Err(ClientError::ChannelCreateFailed));
                 }
-                Vec::new()
             }
-            ClientMessage::ReadNotifyResponse(msg) => {
-                let Some((_, reply_tx)) = self.pending_reads.remove(&msg.client_ioid) else {
-                    warn!("Got ReadNotifyResponse for apparently unknown read request?! {msg:?}");
-                    return Vec::new();
-                };
-                debug!("Processing message {msg:?}");
-                match Dbr::from_bytes(msg.data_type, msg.data_count as usize, &msg.data) {
-                    Ok(dbr) => {
-                        let _ = reply_tx.send(Ok(dbr));
-                    }
-                    Err(_) => {
-                        let _ = reply_tx.send(Err(ClientError::ServerSentInvalidMessage));
-                    }
-                };
-                Vec::new()
-            }
-            ClientMessage::Echo => Vec::new(), // Echo just bumps our last_received message counter
-            ClientMessage::Version(_msg) => {
-                warn!("Got unexpected VERSION message in normal circuit lifecycle.");
-                Vec::new()
-            }
-            ClientMessage::EventAddResponse(msg) => {
-                let Some(channel_id) = self.broadcast_channels.get(&msg.subscription_id) else {
-                    warn!(
-                        "Got subscription message without associated channel: {}",
-                        msg.subscription_id
-                    );
-                    return Vec::new();
-                };
-                let _span = debug_span!("handle_message", cid = channel_id).entered();
-                if msg.data.is_empty() {
-                    debug!(
-                        "Got empty EventAddResponse: Purging subscription {}",
-                        msg.subscription_id
-                    );
-                    // This is a special case: The server is requesting termination
-                    // of the subscription (possibly because we asked it to). Shut down
-                    // the channel monitors.
-                    if let Some(cid) = self.broadcast_channels.get(&msg.subscription_id) {
-                        self.channels
-                            .get_mut(cid)
-                            .unwrap()
-                            .broadcast_receivers
-                            .retain(|s| *s != msg.subscription_id);
-                    }
-                    self.broadcast_channels.remove(&msg.subscription_id);
-                    self.broadcast_receivers.remove(&msg.subscription_id);
-                    self.pending_broadcasts.remove(&msg.subscription_id);
-                    return Vec::new();
+            ClientEvent::ReadComplete { ioid, value } => {
+                if let Some(reply) = self.pending_reads.remove(&ioid) {
+                    let _ = reply.send(value.ok_or(ClientError::ServerSentInvalidMessage));
                 }
-                let Ok(dbr) = Dbr::from_bytes(msg.data_type, msg.data_count as usize, &msg.data)
-                else {
-                    error!("Got invalid subscription response from server: {msg:?}");
-                    return Vec::new();
-                };
-                debug!(
-                    "Got subscription {} response: {:?}",
-                    msg.subscription_id, dbr
-                );
-                // Check - this might be the first
-                if let Some((_, (length, dbrtype, reply))) =
-                    self.pending_broadcasts.remove(&msg.subscription_id)
-                {
-                    // This is the first EventAdd response, tell waiting clients that opening was successful
+            }
+            ClientEvent::SubscriptionUpdate {
+                subscription_id,
+                value,
+            } => {
+                // This might be the first, which tells waiting clients it worked
+                if let Some(reply) = self.pending_broadcasts.remove(&subscription_id) {
                     // TODO: Make this capacity configurable.
                     let (tx, rx) = broadcast::channel(32);
-                    self.broadcast_receivers
-                        .insert(msg.subscription_id, (length, dbrtype, tx));
-                    // Send the receiver to the waiting client
+                    self.broadcast_receivers.insert(subscription_id, tx);
                     let _ = reply.send(Ok(rx));
                 }
-                let transmitter = &self
-                    .broadcast_receivers
-                    .get(&msg.subscription_id)
-                    .expect("Should have just created this")
-                    .2;
-                match transmitter.send(dbr) {
-                    Ok(0) | Err(_) => {
-                        // We have no receivers left; cancel this subscription
-                        debug!("No more receivers for {}: Cancelling", msg.subscription_id);
-
-                        let sid = self.channels.get(channel_id).unwrap().sid;
-                        return vec![msg.cancel(sid).into()];
-                    }
-                    Ok(_) => (),
+                let Some(transmitter) = self.broadcast_receivers.get(&subscription_id) else {
+                    return;
                 };
-                Vec::new()
+                if let Ok(0) | Err(_) = transmitter.send(value) {
+                    // We have no receivers left; cancel this subscription
+                    debug!("No more receivers for {subscription_id}: Cancelling");
+                    self.state.cancel_subscription(subscription_id);
+                }
+            }
+            ClientEvent::SubscriptionEnded { subscription_id } => {
+                self.broadcast_receivers.remove(&subscription_id);
+                self.pending_broadcasts.remove(&subscription_id);
             }
-            msg => {
-                debug!("Got unhandled message from server: {msg:?}");
-                Vec::new()
-            } // ClientMessage::SearchResponse(msg) => todo!(),
-              // ClientMessage::ServerDisconnect(msg) => todo!(),
-              // ClientMessage::WriteNotifyResponse(msg) => todo!(),
-              // ClientMessage::ECAError(msg) => todo!(),
         }
     }
 }
@@ -1,7 +1,5 @@
-use num::{FromPrimitive, traits::WrappingAdd};
 use pnet::datalink;
 use std::{
-    cmp::min,
     collections::HashMap,
     fmt::Display,
     future,
@@ -16,9 +14,9 @@ use tokio::{
     sync::{broadcast, mpsc, oneshot},
 };
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, warn};
+use tracing::{debug, error};
 
-use crate::messages::{self, AsBytes, Message};
+use crate::proto::search::{SearchEvent, SearchState};
 
 fn get_default_broadcast_ips() -> Vec<IpAddr> {
     let interfaces = datalink::interfaces();
@@ -31,13 +29,6 @@ fn get_default_broadcast_ips() -> Vec<IpAddr> {
         .collect()
 }
 
-/// Increments a mutable reference in place, and returns the original value
-fn wrapping_add<T: WrappingAdd + FromPrimitive + Copy>(value: &mut T) -> T {
-    let id = *value;
-    *value = value.wrapping_add(&T::from_u8(1).unwrap());
-    id
-}
-
 pub struct SearcherBuilder {
     search_port: u16,
     stop_token: CancellationToken,
@@ -134,9 +125,9 @@ impl Searcher {
         let mut state = SearcherInternal {
             search_port: self.search_port,
             broadcast_addresses: self.broadcast_addresses.clone(),
+            state: SearchState::new(self.timeout),
+            reporters: HashMap::new(),
             stop_token: self.stop_token.clone(),
-            timeout: self.timeout,
-            ..Default::default()
         };
 
         tokio::spawn(async move {
@@ -151,21 +142,12 @@ impl Searcher {
                         state.handle_new_requests(&send_socket, requests).await
                     },
                     result = send_socket.recv_from(&mut buffer) => match result {
-                        Ok((size, sender)) => state.handle_response(&buffer[..size], sender).await,
+                        Ok((size, sender)) => state.handle_response(&buffer[..size], sender),
                         Err(e) => {
                             error!("Error waiting for search responses: {e}");
                         },
                     },
-                    _ = state.next_attempt() => if let Some(buf) = state.handle_retries_and_timeouts() {
-                        for ip in &state.broadcast_addresses {
-                            let target_addr = (*ip, state.search_port).into();
-                            debug!("Sending retry to: {target_addr}");
-                            send_socket
-                                .send_to::<SocketAddr>(&buf, target_addr)
-                                .await
-                                .expect("Socket sending failed");
-                        }
-                    },
+                    _ = state.next_attempt() => state.handle_retries_and_timeouts(&send_socket).await,
                 };
             }
         });
@@ -215,79 +197,36 @@ impl Display for CouldNotFindError {
 
 impl std::error::Error for CouldNotFindError {}
 
-#[derive(Debug)]
-struct SearchAttempt {
-    name: String,
-    // attempts: u8,
-    search_expires_at: Option<Instant>,
-    active_searches: Vec<u32>,
-    next_search_at: Instant,
-    /// How are results reported back to the requesters?
-    reporter: broadcast::Sender<Option<SocketAddr>>,
-}
-
-impl SearchAttempt {
-    /// Recalculate timings and return a new search message
-    fn new_search(&mut self, search_id: u32) -> messages::Search {
-        let backoff =
-            Duration::from_millis(32 * 2u64.pow(min(self.active_searches.len(), 11) as u32));
-        self.active_searches.push(search_id);
-        self.next_search_at = Instant::now() + backoff;
-        messages::Search {
-            search_id,
-            channel_name: self.name.clone(),
-            ..Default::default()
-        }
-    }
-}
-impl Default for SearchAttempt {
-    fn default() -> Self {
-        SearchAttempt {
-            name: String::new(),
-            search_expires_at: None,
-            active_searches: Vec::new(),
-            next_search_at: Instant::now(),
-            reporter: broadcast::Sender::new(1),
-        }
-    }
-}
-
 /// Handle searcher internal state, inside a single Async context
-#[derive(Default)]
 struct SearcherInternal {
     /// The port to send request broadcasts to
     search_port: u16,
     /// Interfaces to broadcast onto
     broadcast_addresses: Vec<IpAddr>,
-    /// Search IDs of outstanding requests to the PV name
-    in_flight: HashMap<u32, String>,
-    /// Data about all the PVs we are searching for
-    per_pv_info: HashMap<String, SearchAttempt>,
+    /// The protocol state of all searches
+    state: SearchState,
+    /// How results are reported back to the requesters of each PV
+    reporters: HashMap<String, broadcast::Sender<Option<SocketAddr>>>,
     stop_token: CancellationToken,
-    /// The next search ID to send
-    search_id: u32,
-    timeout: Option<Duration>,
 }
 impl SearcherInternal {
     /// Wait until it's time for the next tracked attempt
     fn next_attempt(&self) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> {
-        let next_wake = self
-            .per_pv_info
-            .values()
-            .flat_map(|v| [Some(v.next_search_at), v.search_expires_at])
-            .flatten()
-            .min();
-        match next_wake {
+        match self.state.poll_timeout() {
             None => Box::pin(future::pending()),
-            Some(instant) => {
-                if instant < Instant::now() {
-                    Box::pin(future::ready(()))
-                } else {
-                    Box::pin(tokio::time::sleep_until(tokio::time::Instant::from_std(
-                        instant,
-                    )))
-                }
-            }
+            Some(instant) => Box::pin(tokio::time::sleep_until(instant.into())),
+        }
+    }
+
+    /// Send a datagram to all of the broadcast addresses
+    async fn broadcast(&self, socket: &UdpSocket, buffer: &[u8]) {
+        for ip in &self.broadcast_addresses {
+            let target_addr = (*ip, self.search_port).into();
+            debug!("Sending to: {target_addr}");
+            socket
+                .send_to::<SocketAddr>(buffer, target_addr)
+                .await
+                .expect("Socket sending failed");
         }
     }
 
@@ -302,123 +241,43 @@ impl SearcherInternal {
         // We have received messages on the buffer
         debug_assert!(!requests.is_empty());
 
-        let mut messages = vec![Message::Version(messages::Version::default())];
+        let mut names = Vec::new();
         for (name, waiter_reply) in requests {
-            // Get or create an entry in our per-PV map to keep track of everything
-            let info = self
-                .per_pv_info
-                .entry(name.clone())
-                .or_insert_with(|| SearchAttempt {
-                    name: name.clone(),
-                    search_expires_at: self.timeout.map(|t| Instant::now() + t),
-                    ..Default::default()
-                });
             // Give the requester a place to wait for replies
-            let _ = waiter_reply.send(info.reporter.subscribe());
-            let search_id = wrapping_add(&mut self.search_id);
-            // Register this search attempt
-            self.in_flight.insert(search_id, name.clone());
-            // Build the search message for this
-            messages.push(Message::Search(info.new_search(search_id)));
-            debug!("Sending search for {name}");
+            let reporter = self
+                .reporters
+                .entry(name.clone())
+                .or_insert_with(|| broadcast::Sender::new(1));
+            let _ = waiter_reply.send(reporter.subscribe());
+            names.push(name);
         }
-
         // Build a single search packet for all of these
-        let buffer: Vec<_> = messages.into_iter().flat_map(|m| m.as_bytes()).collect();
-        // Send it to all of our broadcast IPs
-        for ip in &self.broadcast_addresses {
-            let target_addr = (*ip, self.search_port).into();
-            debug!("Sending to: {target_addr}");
-            socket
-                .send_to::<SocketAddr>(&buffer, target_addr)
-                .await
-                .expect("Socket sending failed");
-        }
+        let buffer = self.state.search(names, Instant::now());
+        self.broadcast(socket, &buffer).await;
     }
 
-    async fn handle_response(&mut self, response: &[u8], sender: SocketAddr) {
-        let Ok(messages) = Message::parse_many_client_messages(response) else {
-            warn!("Received unparseable search response");
-            return;
-        };
-        for message in messages {
-            let response = match message {
-                Message::SearchResponse(search_response) => search_response,
-                Message::Version(_) => continue,
-                m => {
-                    warn!("Received unexpected search response: {m:?}");
-                    continue;
-                }
-            };
-            // What was this a response to?
-            let Some(pv_name) = self.in_flight.remove(&response.search_id) else {
-                warn!("Received unrequested or duplicate search response");
-                continue;
+    fn report(&mut self, events: Vec<SearchEvent>) {
+        for event in events {
+            let (name, result) = match event {
+                SearchEvent::Found { name, server } => (name, Some(server)),
+                SearchEvent::TimedOut { name } => (name, None),
             };
-            // Now we know we have a response to an actual request - clear out any past
-            // requests for this and send the notification up to the caller
-            let info = self.per_pv_info.remove(&pv_name).unwrap();
-            // Get rid of any other in-flight searches for this
-            for search_id in info.active_searches {
-                self.in_flight.remove(&search_id);
+            if let Some(reporter) = self.reporters.remove(&name) {
+                let _ = reporter.send(result);
             }
-            let server_origin = (
-                response.server_ip.map(|i| i.into()).unwrap_or(sender.ip()),
-                response.port_number,
-            )
-                .into();
-            // Report this to all the listeners
-            debug!("Found server for {pv_name}: {server_origin:?}");
-            let _ = info.reporter.send(Some(server_origin));
         }
     }
 
-    fn handle_retries_and_timeouts(&mut self) -> Option<Vec<u8>> {
-        let now = Instant::now();
-
-        // discard any expired searches
-        self.per_pv_info.retain(|_, v| match v.search_expires_at {
-            None => true,
-            Some(time) => {
-                if time < now {
-                    // We are discarding this. Send the termination signal,
-                    let _ = v.reporter.send(None);
-                    // And then remove from the in-flight register
-                    for id in v.active_searches.iter() {
-                        let _ = self.in_flight.remove(id);
-                    }
-                    debug!(
-                        "Dropping search for {} as reached search timeout {:.2} ms ago",
-                        v.name,
-                        (now - time).as_secs_f32() * 1000.0
-                    );
-                    false
-                } else {
-                    true
-                }
-            }
-        });
+    fn handle_response(&mut self, response: &[u8], sender: SocketAddr) {
+        let events = self.state.handle_datagram(response, sender);
+        self.report(events);
+    }
 
-        let mut search_messages = self
-            .per_pv_info
-            .values_mut()
-            .filter(|s| s.next_search_at < now)
-            .map(|s| {
-                debug!("Sending retry search for: {}", s.name);
-                Message::Search(s.new_search(wrapping_add(&mut self.search_id)))
-            })
-            .peekable();
-
-        if search_messages.peek().is_some() {
-            Some(
-                vec![Message::Version(messages::Version::default())]
-                    .into_iter()
-                    .chain(search_messages)
-                    .flat_map(|m| m.as_bytes())
-                    .collect(),
-            )
-        } else {
-            None
+    async fn handle_retries_and_timeouts(&mut self, socket: &UdpSocket) {
+        let (events, retry) = self.state.handle_timeout(Instant::now());
+        self.report(events);
+        if let Some(buffer) = retry {
+            self.broadcast(socket, &buffer).await;
         }
     }
 }
@@ -430,8 +289,9 @@ mod test {
     use tokio::net::UdpSocket;
 
     use crate::{
-        client::{SearcherBuilder, searcher::wrapping_add},
+        client::SearcherBuilder,
         messages::{AsBytes, Message},
+        utils::wrapping_inplace_add as wrapping_add,
     };
 
     #[test]
@@ -18,8 +18,8 @@
 //! - [`DbrValue::Float`] ([`Vec<f32>`])
 //! - [`DbrValue::Double`] ([`Vec<f64>`])
 //! - [`DbrValue::Enum`] ([`u16`] by encoding) which is a special case - it reresents an
-//!   index of an array of `[[u8; 26]; 16]` string options, normally stored on
-//!   [`Dbr::Graphics`] but not yet implemented in this library.
+//!   index of an array of `[[u8; 26]; 16]` string options, which are carried in the
+//!   [`DisplayMetadata`] of [`Dbr::Graphics`] and [`Dbr::Control`].
 //! - [`DbrValue::String`] - natively in CA this is a `[u8; 40]`, but for interchange
 //!   here is represented by [`Vec<String>`], and is converted back and forth to
 //!   fixed-length as required for communication. There is minimal support for
@@ -38,32 +38,33 @@
 //!   to the data.
 //! - [`Dbr::Time`] - All of the information from [`Dbr::Status`], but with associated
 //!   timestamp information.
-//! - [`Dbr::Graphics`] - In CA, this controls information about the represented value
-//!   e.g. units, limits. **This is currently unimplemented**.
-//! - [`Dbr::Control`] - This can be used to fetch enum values, but further uses are
-//!   unclear. **This is currently unimplemented**.
+//! - [`Dbr::Graphics`] - Alarm status, along with information about how to display
+//!   the value e.g. units, limits, and enum strings, held in [`DisplayMetadata`].
+//! - [`Dbr::Control`] - As [`Dbr::Graphics`], with the addition of control limits.
 //!
 //! Both [`DbrCategory`] and [`DbrBasicType`] are combined in the [`DbrType`] struct,
 //! which provides interfaces to convert to/from the integer representation of types
-//! used by the CA protocol.
+//! used by the CA protocol. The one other type supported is [`DBR_CLASS_NAME`], which
+//! is used to ask for the record type of a PV, and is answered with a string.
 //!
 //! [DBR]:
 //!     https://docs.epics-controls.org/en/latest/internal/ca_protocol.html#payload-data-types
 //!
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{convert::TryFrom, fmt::Debug, num::NonZeroUsize, time::Duration};
 use nom::{
     Parser,
+    bytes::complete::take,
     multi::count,
-    number::complete::{be_f32, be_f64, be_i8, be_i16, be_i32, be_u16, be_u32},
+    number::complete::{be_f32, be_f64, be_i8, be_i16, be_i32, be_u8, be_u16, be_u32},
 };
 use num::{NumCast, cast::AsPrimitive, traits::ToBytes};
-use std::{
-    cmp,
-    convert::TryFrom,
-    fmt::Debug,
-    io::{self, Cursor},
-    num::NonZeroUsize,
-    time::{Duration, SystemTime, UNIX_EPOCH},
-};
+#[cfg(feature = "std")]
+use std::time::SystemTime;
 
 use crate::messages::ErrorCondition;
 
@@ -87,6 +88,15 @@ fn string_to_fixed_length_bytes(value: &str, max_length: usize) -> Vec<u8> {
     buffer
 }
 
+/// Decode a null-terminated string from a fixed-length byte array
+///
+/// If there is no null, then the whole array is used. Invalid UTF-8 is replaced,
+/// rather than failing, as this comes straight from the network.
+fn fixed_length_bytes_to_string(data: &[u8]) -> String {
+    let length = data.iter().position(|&c| c == 0x00).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..length]).into_owned()
+}
+
 /// Represent actual data transferred over CA
 #[derive(Clone, Debug, PartialEq)]
 pub enum DbrValue {
@@ -142,7 +152,14 @@ impl DbrValue {
             return Err(DbrParseError::SelfIsNotString);
         };
         Ok(match basic_type {
-            DbrBasicType::Enum => todo!(),
+            DbrBasicType::Enum => match val.as_slice() {
+                [s] => DbrValue::Enum(
+                    s.trim()
+                        .parse()
+                        .map_err(|_| DbrParseError::CannotParse(s.clone()))?,
+                ),
+                _ => return Err(DbrParseError::CannotParse(val.join(","))),
+            },
             DbrBasicType::String => self.clone(),
             DbrBasicType::Char => DbrValue::Char(
                 val.iter()
@@ -258,66 +275,75 @@ impl DbrValue {
                     String::from_utf8(val.iter().map(|c| *c as u8).collect())
                         .map_err(|_| ErrorCondition::NoConvert)?,
                 ]),
+                // Without the labels all we can give is the index
+                DbrValue::Enum(val) => DbrValue::String(vec![val.to_string()]),
                 _ => return Err(ErrorCondition::UnavailInServ),
             },
             DbrBasicType::Enum => match self {
                 DbrValue::Enum(_val) => self.clone(),
-                _ => return Err(ErrorCondition::NoConvert),
+                DbrValue::String(_) => self
+                    .parse_into(DbrBasicType::Enum)
+                    .map_err(|_| ErrorCondition::NoConvert)?,
+                _ => match self.convert_to(DbrBasicType::Double)? {
+                    DbrValue::Double(val) if val.len() == 1 => {
+                        DbrValue::Enum(NumCast::from(val[0]).ok_or(ErrorCondition::NoConvert)?)
+                    }
+                    _ => return Err(ErrorCondition::NoConvert),
+                },
             },
         })
     }
 
     /// Encode the value contents of a DBR into a byte vector
     ///
-    /// If max_elems is `None`, then all elements available will be returned.
+    /// If `elements` is `None`, then all elements currently in the value are
+    /// encoded. Otherwise exactly that many are: the value is truncated, or padded
+    /// with zeros if it is shorter. This matches the CA semantics of a request
+    /// count of zero meaning "the current size".
     ///
     /// Returns the number of elements along with the bytes
-    pub fn to_bytes(&self, max_elems: Option<NonZeroUsize>) -> (usize, Vec<u8>) {
-        let elements = if let Some(max_elem) = max_elems {
-            cmp::min(max_elem.into(), self.get_count())
-        } else {
-            self.get_count()
-        };
+    pub fn to_bytes(&self, elements: Option<NonZeroUsize>) -> (usize, Vec<u8>) {
+        let elements = elements.map_or(self.get_count(), NonZeroUsize::get);
 
-        (
-            elements,
-            match self {
-                DbrValue::Enum(val) => val.to_be_bytes().to_vec(),
-                DbrValue::String(val) => val
-                    .iter()
-                    .flat_map(|v| {
-                        let mut buf = string_to_fixed_length_bytes(v, 39);
-                        buf.resize(40, 0u8);
-                        buf
-                    })
-                    .collect(),
-                DbrValue::Char(val) => val
-                    .iter()
-                    .take(elements)
-                    .flat_map(|v| v.to_be_bytes())
-                    .collect(),
-                DbrValue::Int(val) => val
-                    .iter()
-                    .take(elements)
-                    .flat_map(|v| v.to_be_bytes())
-                    .collect(),
-                DbrValue::Long(val) => val
-                    .iter()
-                    .take(elements)
-                    .flat_map(|v| v.to_be_bytes())
-                    .collect(),
-                DbrValue::Float(val) => val
-                    .iter()
-                    .take(elements)
-                    .flat_map(|v| v.to_be_bytes())
-                    .collect(),
-                DbrValue::Double(val) => val
-                    .iter()
-                    .take(elements)
-                    .flat_map(|v| v.to_be_bytes())
-                    .collect(),
-            },
-        )
+        let mut data: Vec<u8> = match self {
+            DbrValue::Enum(val) => val.to_be_bytes().to_vec(),
+            DbrValue::String(val) => val
+                .iter()
+                .take(elements)
+                .flat_map(|v| {
+                    let mut buf = string_to_fixed_length_bytes(v, 39);
+                    buf.resize(40, 0u8);
+                    buf
+                })
+                .collect(),
+            DbrValue::Char(val) => val
+                .iter()
+                .take(elements)
+                .flat_map(|v| v.to_be_bytes())
+                .collect(),
+            DbrValue::Int(val) => val
+                .iter()
+                .take(elements)
+                .flat_map(|v| v.to_be_bytes())
+                .collect(),
+            DbrValue::Long(val) => val
+                .iter()
+                .take(elements)
+                .flat_map(|v| v.to_be_bytes())
+                .collect(),
+            DbrValue::Float(val) => val
+                .iter()
+                .take(elements)
+                .flat_map(|v| v.to_be_bytes())
+                .collect(),
+            DbrValue::Double(val) => val
+                .iter()
+                .take(elements)
+                .flat_map(|v| v.to_be_bytes())
+                .collect(),
+        };
+        data.resize(elements * self.get_type().element_size(), 0u8);
+        (elements, data)
     }
 
     pub fn decode_value(
@@ -326,21 +352,12 @@ impl DbrValue {
         data: &[u8],
     ) -> Result<DbrValue, nom::Err<nom::error::Error<&[u8]>>> {
         match data_type {
-            DbrBasicType::Enum => {
-                assert!(
-                    item_count == 1,
-                    "Multiple item count makes no sense for enum"
-                );
-                Ok(DbrValue::Enum(be_u16.parse(data)?.1))
-            }
+            // Enums are only ever a single value, whatever count was asked for
+            DbrBasicType::Enum => Ok(DbrValue::Enum(be_u16.parse(data)?.1)),
             DbrBasicType::String => Ok(DbrValue::String(
                 data.chunks(40)
-                    .map(|d| {
-                        let strlen = d.iter().position(|&c| c == 0x00).unwrap();
-                        str::from_utf8(&d[0..strlen]).unwrap()
-                    })
+                    .map(fixed_length_bytes_to_string)
                     .take(item_count)
-                    .map(|s| s.to_string())
                     .collect(),
             )),
             DbrBasicType::Char => Ok(DbrValue::Char(count(be_i8, item_count).parse(data)?.1)),
@@ -418,9 +435,28 @@ impl TryFrom<u16> for DbrBasicType {
     }
 }
 
+impl DbrBasicType {
+    /// The number of bytes used to send a single element of this type
+    pub fn element_size(&self) -> usize {
+        match self {
+            DbrBasicType::String => 40,
+            DbrBasicType::Int => 2,
+            DbrBasicType::Float => 4,
+            DbrBasicType::Enum => 2,
+            DbrBasicType::Char => 1,
+            DbrBasicType::Long => 4,
+            DbrBasicType::Double => 8,
+        }
+    }
+}
+
 /// Marks a type as being convertible to a DBRValue representation
 pub trait IntoDbrBasicType {
     fn get_dbr_basic_type() -> DbrBasicType;
+    /// The state names, for types that are represented as an enum
+    fn enum_labels() -> Vec<String> {
+        Vec::new()
+    }
 }
 
 macro_rules! impl_into_dbr_basic_type {
@@ -442,6 +478,113 @@ impl_into_dbr_basic_type!(f32, Float);
 impl_into_dbr_basic_type!(f64, Double);
 impl_into_dbr_basic_type!(String, String);
 
+/// A Rust enum that can be represented as a CA [`DbrValue::Enum`]
+///
+/// Each state is sent as its index into [`EpicsEnum::LABELS`], and clients that ask
+/// for the value as a string get the label. CA can only name the first 16 states.
+/// Most enums should use [`epics_enum!`](crate::epics_enum) rather than implementing
+/// this by hand.
+pub trait EpicsEnum: Sized {
+    /// The name of each state, in order of index
+    const LABELS: &'static [&'static str];
+    /// The index of this state
+    fn to_index(&self) -> u16;
+    /// The state with a given index, if there is one
+    fn from_index(index: u16) -> Option<Self>;
+
+    /// The state names as sent in [`DisplayMetadata::enum_strings`]
+    fn labels() -> Vec<String> {
+        Self::LABELS.iter().map(|label| label.to_string()).collect()
+    }
+}
+
+impl<E: EpicsEnum> IntoDbrBasicType for E {
+    fn get_dbr_basic_type() -> DbrBasicType {
+        DbrBasicType::Enum
+    }
+    fn enum_labels() -> Vec<String> {
+        E::labels()
+    }
+}
+
+/// Enums are always a single value, so only the first is used
+impl<E: EpicsEnum> From<Vec<E>> for DbrValue {
+    fn from(value: Vec<E>) -> Self {
+        DbrValue::Enum(value.first().map_or(0, E::to_index))
+    }
+}
+
+impl<E: EpicsEnum> TryFrom<&DbrValue> for Vec<E> {
+    type Error = ErrorCondition;
+    fn try_from(value: &DbrValue) -> Result<Self, Self::Error> {
+        match value.convert_to(DbrBasicType::Enum)? {
+            DbrValue::Enum(index) => {
+                Ok(vec![E::from_index(index).ok_or(ErrorCondition::NoConvert)?])
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Declare an enum that can be used as a CA [`DbrValue::Enum`]
+///
+/// This implements [`EpicsEnum`], with the variant names as the state labels, and
+/// [`Default`] as the first variant. Variants cannot carry data or discriminants.
+///
+/// ```
+/// epicars::epics_enum! {
+///     #[derive(Clone, Copy, Debug, PartialEq)]
+///     pub enum Mode {
+///         Idle,
+///         Acquire,
+///         Fault,
+///     }
+/// }
+/// # use epicars::dbr::EpicsEnum;
+/// assert_eq!(Mode::Acquire.to_index(), 1);
+/// assert_eq!(Mode::from_index(2), Some(Mode::Fault));
+/// assert_eq!(Mode::LABELS, ["Idle", "Acquire", "Fault"]);
+/// ```
+#[macro_export]
+macro_rules! epics_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $first:ident $(, $rest:ident)* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $first,
+            $($rest,)*
+        }
+
+        impl $crate::dbr::EpicsEnum for $name {
+            const LABELS: &'static [&'static str] =
+                &[stringify!($first), $(stringify!($rest),)*];
+            fn to_index(&self) -> u16 {
+                match self {
+                    $name::$first => 0,
+                    $($name::$rest => $name::$rest as u16,)*
+                }
+            }
+            fn from_index(index: u16) -> Option<Self> {
+                match index {
+                    0 => Some($name::$first),
+                    $(i if i == $name::$rest as u16 => Some($name::$rest),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                $name::$first
+            }
+        }
+    };
+}
+
 /// Mapping of DBR categories
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum DbrCategory {
@@ -450,6 +593,8 @@ pub enum DbrCategory {
     Time = 2,
     Graphics = 3,
     Control = 4,
+    /// Not a real category, but used to represent [`DBR_CLASS_NAME`]
+    ClassName = 5,
 }
 impl TryFrom<u16> for DbrCategory {
     type Error = ();
@@ -477,9 +622,18 @@ pub const DBR_BASIC_STRING: DbrType = DbrType {
     category: DbrCategory::Basic,
 };
 
+/// Request for the record type of a PV, which is returned as a string
+pub const DBR_CLASS_NAME: DbrType = DbrType {
+    basic_type: DbrBasicType::String,
+    category: DbrCategory::ClassName,
+};
+
 impl TryFrom<u16> for DbrType {
     type Error = ();
     fn try_from(value: u16) -> Result<Self, Self::Error> {
+        if value == 38 {
+            return Ok(DBR_CLASS_NAME);
+        }
         Ok(Self {
             basic_type: (value % 7).try_into()?,
             category: (value / 7).try_into()?,
@@ -489,6 +643,9 @@ impl TryFrom<u16> for DbrType {
 
 impl From<DbrType> for u16 {
     fn from(value: DbrType) -> Self {
+        if value.category == DbrCategory::ClassName {
+            return 38;
+        }
         value.category as u16 * 7 + value.basic_type as u16
     }
 }
@@ -510,67 +667,416 @@ impl DbrType {
             (DbrCategory::Time, DbrBasicType::Enum) => 2,
             (DbrCategory::Time, DbrBasicType::Char) => 3,
             (DbrCategory::Time, DbrBasicType::Double) => 4,
-            (DbrCategory::Graphics, DbrBasicType::Float) => 2,
-            (DbrCategory::Graphics, DbrBasicType::Char) => 1,
-            (DbrCategory::Control, DbrBasicType::Char) => 1,
+            // Graphics and Control types have padding within their metadata, which
+            // is handled by DisplayMetadata
             _ => 0,
         }
     }
 }
 
 /// Represent alarm status of the record
-#[derive(Copy, Clone, Debug, Default)]
+///
+/// These are kept as the raw values sent over CA, see [`AlarmStatus`] and
+/// [`AlarmSeverity`] for their meanings.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub struct Status {
     pub status: i16,
     pub severity: i16,
 }
 
+impl Status {
+    pub fn new(status: AlarmStatus, severity: AlarmSeverity) -> Status {
+        Status {
+            status: status as i16,
+            severity: severity as i16,
+        }
+    }
+    /// The alarm condition, if it is one that we know about
+    pub fn alarm_status(&self) -> Option<AlarmStatus> {
+        AlarmStatus::try_from(self.status).ok()
+    }
+    /// How serious the alarm is, if it is a valid severity
+    pub fn alarm_severity(&self) -> Option<AlarmSeverity> {
+        AlarmSeverity::try_from(self.severity).ok()
+    }
+}
+
+/// How serious an alarm is, in increasing order
+#[repr(i16)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AlarmSeverity {
+    #[default]
+    NoAlarm = 0,
+    Minor = 1,
+    Major = 2,
+    Invalid = 3,
+}
+
+impl TryFrom<i16> for AlarmSeverity {
+    type Error = ();
+    fn try_from(value: i16) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => AlarmSeverity::NoAlarm,
+            1 => AlarmSeverity::Minor,
+            2 => AlarmSeverity::Major,
+            3 => AlarmSeverity::Invalid,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// The condition that caused an alarm, as numbered by epics-base
+#[repr(i16)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AlarmStatus {
+    #[default]
+    NoAlarm = 0,
+    Read = 1,
+    Write = 2,
+    HiHi = 3,
+    High = 4,
+    LoLo = 5,
+    Low = 6,
+    State = 7,
+    Cos = 8,
+    Comm = 9,
+    Timeout = 10,
+    HwLimit = 11,
+    Calc = 12,
+    Scan = 13,
+    Link = 14,
+    Soft = 15,
+    BadSub = 16,
+    Udf = 17,
+    Disable = 18,
+    Simm = 19,
+    ReadAccess = 20,
+    WriteAccess = 21,
+}
+
+impl TryFrom<i16> for AlarmStatus {
+    type Error = ();
+    fn try_from(value: i16) -> Result<Self, Self::Error> {
+        use AlarmStatus::*;
+        const ALL: [AlarmStatus; 22] = [
+            NoAlarm,
+            Read,
+            Write,
+            HiHi,
+            High,
+            LoLo,
+            Low,
+            State,
+            Cos,
+            Comm,
+            Timeout,
+            HwLimit,
+            Calc,
+            Scan,
+            Link,
+            Soft,
+            BadSub,
+            Udf,
+            Disable,
+            Simm,
+            ReadAccess,
+            WriteAccess,
+        ];
+        usize::try_from(value)
+            .ok()
+            .and_then(|i| ALL.get(i).copied())
+            .ok_or(())
+    }
+}
+
+/// Information about how to display and control a value, sent with GR and CTRL types
+///
+/// Which of these are sent depends on the type of the value. Numeric types carry the
+/// units and limits, which are encoded as the value type, and floating point types
+/// also carry the precision. Enums only carry their strings, and strings carry
+/// nothing at all. Control limits are only sent for [`DbrCategory::Control`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DisplayMetadata {
+    /// Engineering units, at most 7 characters
+    pub units: String,
+    /// Number of decimal places to show
+    pub precision: i16,
+    pub upper_display_limit: f64,
+    pub lower_display_limit: f64,
+    pub upper_alarm_limit: f64,
+    pub upper_warning_limit: f64,
+    pub lower_warning_limit: f64,
+    pub lower_alarm_limit: f64,
+    pub upper_control_limit: f64,
+    pub lower_control_limit: f64,
+    /// Names of each enum state, at most 16 of 25 characters each
+    pub enum_strings: Vec<String>,
+}
+
+impl DisplayMetadata {
+    /// The limits in the order they are sent, for a given category
+    fn limits(&self, category: DbrCategory) -> Vec<f64> {
+        let mut limits = vec![
+            self.upper_display_limit,
+            self.lower_display_limit,
+            self.upper_alarm_limit,
+            self.upper_warning_limit,
+            self.lower_warning_limit,
+            self.lower_alarm_limit,
+        ];
+        if category == DbrCategory::Control {
+            limits.extend([self.upper_control_limit, self.lower_control_limit]);
+        }
+        limits
+    }
+
+    /// Write the metadata as it is sent between the status and the value
+    fn write_be(&self, buffer: &mut Vec<u8>, data_type: DbrType) {
+        let write_units = |buffer: &mut Vec<u8>| {
+            let mut units = string_to_fixed_length_bytes(&self.units, 8);
+            units.resize(8, 0u8);
+            buffer.extend_from_slice(&units)
+        };
+        match data_type.basic_type {
+            DbrBasicType::String => (),
+            DbrBasicType::Enum => {
+                let count = self.enum_strings.len().min(16);
+                buffer.extend_from_slice(&(count as i16).to_be_bytes());
+                for index in 0..16 {
+                    let name = self.enum_strings.get(index).map_or("", String::as_str);
+                    let mut name_bytes = string_to_fixed_length_bytes(name, 26);
+                    name_bytes.resize(26, 0u8);
+                    buffer.extend_from_slice(&name_bytes);
+                }
+            }
+            DbrBasicType::Float | DbrBasicType::Double => {
+                buffer.extend_from_slice(&self.precision.to_be_bytes());
+                buffer.extend_from_slice(&[0u8; 2]);
+                write_units(buffer);
+                for limit in self.limits(data_type.category) {
+                    match data_type.basic_type {
+                        DbrBasicType::Float => {
+                            buffer.extend_from_slice(&(limit as f32).to_be_bytes())
+                        }
+                        _ => buffer.extend_from_slice(&limit.to_be_bytes()),
+                    }
+                }
+            }
+            DbrBasicType::Char => {
+                write_units(buffer);
+                for limit in self.limits(data_type.category) {
+                    buffer.extend_from_slice(&(limit as u8).to_be_bytes());
+                }
+                buffer.extend_from_slice(&[0u8]);
+            }
+            DbrBasicType::Int => {
+                write_units(buffer);
+                for limit in self.limits(data_type.category) {
+                    buffer.extend_from_slice(&(limit as i16).to_be_bytes());
+                }
+            }
+            DbrBasicType::Long => {
+                write_units(buffer);
+                for limit in self.limits(data_type.category) {
+                    buffer.extend_from_slice(&(limit as i32).to_be_bytes());
+                }
+            }
+        }
+    }
+
+    /// Read the metadata that is sent between the status and the value
+    fn parse(data: &[u8], data_type: DbrType) -> nom::IResult<&[u8], DisplayMetadata> {
+        let limit_count = if data_type.category == DbrCategory::Control {
+            8
+        } else {
+            6
+        };
+        let parse_units = |data| take(8usize).map(fixed_length_bytes_to_string).parse(data);
+        let (data, precision, units, limits) = match data_type.basic_type {
+            DbrBasicType::String => return Ok((data, DisplayMetadata::default())),
+            DbrBasicType::Enum => {
+                let (data, string_count) = be_i16(data)?;
+                let (data, names) = count(take(26usize), 16).parse(data)?;
+                let enum_strings = names
+                    .into_iter()
+                    .take(string_count.clamp(0, 16) as usize)
+                    .map(fixed_length_bytes_to_string)
+                    .collect();
+                return Ok((
+                    data,
+                    DisplayMetadata {
+                        enum_strings,
+                        ..Default::default()
+                    },
+                ));
+            }
+            DbrBasicType::Float => {
+                let (data, (precision, _, units)) = (be_i16, be_i16, parse_units).parse(data)?;
+                let (data, limits) =
+                    count(be_f32.map(<f64 as From<_>>::from), limit_count).parse(data)?;
+                (data, precision, units, limits)
+            }
+            DbrBasicType::Double => {
+                let (data, (precision, _, units)) = (be_i16, be_i16, parse_units).parse(data)?;
+                let (data, limits) = count(be_f64, limit_count).parse(data)?;
+                (data, precision, units, limits)
+            }
+            DbrBasicType::Char => {
+                let (data, units) = parse_units(data)?;
+                let (data, limits) =
+                    count(be_u8.map(<f64 as From<_>>::from), limit_count).parse(data)?;
+                let (data, _) = be_u8(data)?;
+                (data, 0, units, limits)
+            }
+            DbrBasicType::Int => {
+                let (data, units) = parse_units(data)?;
+                let (data, limits) =
+                    count(be_i16.map(<f64 as From<_>>::from), limit_count).parse(data)?;
+                (data, 0, units, limits)
+            }
+            DbrBasicType::Long => {
+                let (data, units) = parse_units(data)?;
+                let (data, limits) =
+                    count(be_i32.map(<f64 as From<_>>::from), limit_count).parse(data)?;
+                (data, 0, units, limits)
+            }
+        };
+        let limit = |index: usize| limits.get(index).copied().unwrap_or_default();
+        Ok((
+            data,
+            DisplayMetadata {
+                units,
+                precision,
+                upper_display_limit: limit(0),
+                lower_display_limit: limit(1),
+                upper_alarm_limit: limit(2),
+                upper_warning_limit: limit(3),
+                lower_warning_limit: limit(4),
+                lower_alarm_limit: limit(5),
+                upper_control_limit: limit(6),
+                lower_control_limit: limit(7),
+                enum_strings: Vec::new(),
+            },
+        ))
+    }
+}
+
+/// Seconds from the UNIX epoch to the EPICS epoch, 1990-01-01 00:00:00 UTC
+const EPICS_EPOCH_OFFSET: u64 = 631152000;
+
+/// A timestamp as carried in [`Dbr::Time`]
+///
+/// CA counts time from the EPICS epoch of 1990-01-01 UTC, rather than the UNIX
+/// epoch. With the `std` feature, this converts to and from `SystemTime`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EpicsTime {
+    /// Whole seconds since the EPICS epoch
+    pub seconds: u32,
+    /// Nanoseconds within the second
+    pub nanoseconds: u32,
+}
+
+impl EpicsTime {
+    /// Convert from a time since the UNIX epoch
+    ///
+    /// Times before the EPICS epoch are clamped to it.
+    pub fn from_unix_time(since_unix_epoch: Duration) -> Self {
+        let Some(seconds) = since_unix_epoch.as_secs().checked_sub(EPICS_EPOCH_OFFSET) else {
+            return EpicsTime::default();
+        };
+        EpicsTime {
+            seconds: seconds.min(u32::MAX as u64) as u32,
+            nanoseconds: since_unix_epoch.subsec_nanos(),
+        }
+    }
+
+    /// The time since the UNIX epoch
+    pub fn unix_time(&self) -> Duration {
+        Duration::new(self.seconds as u64 + EPICS_EPOCH_OFFSET, self.nanoseconds)
+    }
+
+    /// The current time
+    #[cfg(feature = "std")]
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<SystemTime> for EpicsTime {
+    fn from(value: SystemTime) -> Self {
+        EpicsTime::from_unix_time(
+            value
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default(),
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<EpicsTime> for SystemTime {
+    fn from(value: EpicsTime) -> Self {
+        SystemTime::UNIX_EPOCH + value.unix_time()
+    }
+}
+
+/// The timestamp to give values that do not have one
+#[cfg(feature = "std")]
+fn current_time() -> EpicsTime {
+    EpicsTime::now()
+}
+
+/// Without a clock, values that do not have a timestamp get the EPICS epoch
+#[cfg(not(feature = "std"))]
+fn current_time() -> EpicsTime {
+    EpicsTime::default()
+}
+
 /// Structured unit of exchange for records in the CA protocol
 #[derive(Clone, Debug)]
 pub enum Dbr {
     /// Value only, with no metadata
     Basic(DbrValue),
     /// Alarm status metadata alongside the record value
-    Status {
+    Status { status: Status, value: DbrValue },
+    /// Timestamp, alarm status, and value
+    Time {
         status: Status,
+        timestamp: EpicsTime,
         value: DbrValue,
     },
-    /// Timestamp, alarm status, and value
-    Time {
+    /// Alarm status and display information, and value
+    Graphics {
+        status: Status,
+        display: DisplayMetadata,
+        value: DbrValue,
+    },
+    /// Alarm status and display information including control limits, and value
+    Control {
         status: Status,
-        timestamp: SystemTime,
+        display: DisplayMetadata,
         value: DbrValue,
     },
-    Graphics,
-    Control,
 }
 
 impl Dbr {
     pub fn take_value(self) -> DbrValue {
         match self {
             Dbr::Basic(value) => value,
-            Dbr::Status { status: _, value } => value,
-            Dbr::Time {
-                status: _,
-                timestamp: _,
-                value,
-            } => value,
-            Dbr::Graphics => todo!(),
-            Dbr::Control => todo!(),
+            Dbr::Status { value, .. } => value,
+            Dbr::Time { value, .. } => value,
+            Dbr::Graphics { value, .. } => value,
+            Dbr::Control { value, .. } => value,
         }
     }
     /// Retrieve the [`DbrValue`] contained by this DBR
     pub fn value(&self) -> &DbrValue {
         match self {
             Dbr::Basic(value) => value,
-            Dbr::Status { status: _, value } => value,
-            Dbr::Time {
-                status: _,
-                timestamp: _,
-                value,
-            } => value,
-            Dbr::Graphics => todo!(),
-            Dbr::Control => todo!(),
+            Dbr::Status { value, .. } => value,
+            Dbr::Time { value, .. } => value,
+            Dbr::Graphics { value, .. } => value,
+            Dbr::Control { value, .. } => value,
         }
     }
     /// If a DBR type encoding alarm status, fetch that
@@ -579,30 +1085,36 @@ impl Dbr {
             Dbr::Basic(_) => None,
             Dbr::Status { status, .. } => Some(*status),
             Dbr::Time { status, .. } => Some(*status),
-            Dbr::Graphics => todo!(),
-            Dbr::Control => todo!(),
+            Dbr::Graphics { status, .. } => Some(*status),
+            Dbr::Control { status, .. } => Some(*status),
         }
     }
-    pub fn data_type(&self) -> DbrType {
+    /// If a DBR type with a timestamp, fetch that
+    pub fn timestamp(&self) -> Option<EpicsTime> {
         match self {
-            Dbr::Basic(value) => DbrType {
-                basic_type: value.get_type(),
-                category: DbrCategory::Basic,
-            },
-            Dbr::Status { status: _, value } => DbrType {
-                basic_type: value.get_type(),
-                category: DbrCategory::Status,
-            },
-            Dbr::Time {
-                status: _,
-                timestamp: _,
-                value,
-            } => DbrType {
-                basic_type: value.get_type(),
-                category: DbrCategory::Time,
-            },
-            Dbr::Graphics => todo!(),
-            Dbr::Control => todo!(),
+            Dbr::Time { timestamp, .. } => Some(*timestamp),
+            _ => None,
+        }
+    }
+    /// If a DBR type with display information, fetch that
+    pub fn display(&self) -> Option<&DisplayMetadata> {
+        match self {
+            Dbr::Graphics { display, .. } => Some(display),
+            Dbr::Control { display, .. } => Some(display),
+            _ => None,
+        }
+    }
+    pub fn data_type(&self) -> DbrType {
+        let category = match self {
+            Dbr::Basic(_) => DbrCategory::Basic,
+            Dbr::Status { .. } => DbrCategory::Status,
+            Dbr::Time { .. } => DbrCategory::Time,
+            Dbr::Graphics { .. } => DbrCategory::Graphics,
+            Dbr::Control { .. } => DbrCategory::Control,
+        };
+        DbrType {
+            basic_type: self.value().get_type(),
+            category,
         }
     }
 
@@ -611,40 +1123,41 @@ impl Dbr {
         data_count: usize,
         data: &[u8],
     ) -> Result<Dbr, nom::Err<nom::error::Error<&[u8]>>> {
-        if data_type.category == DbrCategory::Control {
-            todo!("Don't understand CTRL structure usage well enough to parse yet");
-        }
-        if data_type.category == DbrCategory::Graphics {
-            todo!("Don't understand GR structure usage well enough to parse yet");
-        }
-
-        let (data, status) = if data_type.category != DbrCategory::Basic {
-            let (d, (status, severity)) = (be_i16, be_i16).parse(data)?;
-            (d, Some(Status { status, severity }))
-        } else {
-            (data, None)
+        let (data, status) = match data_type.category {
+            DbrCategory::Basic | DbrCategory::ClassName => (data, None),
+            _ => {
+                let (d, (status, severity)) = (be_i16, be_i16).parse(data)?;
+                (d, Some(Status { status, severity }))
+            }
         };
 
         let (data, timestamp) = if data_type.category == DbrCategory::Time {
-            let (input, (time_s, time_ns)) = (be_i32, be_u32).parse(data)?;
+            let (input, (seconds, nanoseconds)) = (be_u32, be_u32).parse(data)?;
             (
                 input,
-                Some(
-                    UNIX_EPOCH
-                        .checked_add(Duration::new(time_s as u64 + 631152000u64, time_ns))
-                        .unwrap(),
-                ),
+                Some(EpicsTime {
+                    seconds,
+                    nanoseconds,
+                }),
             )
         } else {
             (data, None)
         };
 
+        let (data, display) = match data_type.category {
+            DbrCategory::Graphics | DbrCategory::Control => {
+                let (data, display) = DisplayMetadata::parse(data, data_type)?;
+                (data, Some(display))
+            }
+            _ => (data, None),
+        };
+
         // Offset the read buffer to account for metadata padding
         let data = &data[data_type.get_metadata_padding()..];
         let value = DbrValue::decode_value(data_type.basic_type, data_count, data)?;
 
         Ok(match data_type.category {
-            DbrCategory::Basic => Dbr::Basic(value),
+            DbrCategory::Basic | DbrCategory::ClassName => Dbr::Basic(value),
             DbrCategory::Status => Dbr::Status {
                 status: status.unwrap(),
                 value,
@@ -654,98 +1167,137 @@ impl Dbr {
                 timestamp: timestamp.unwrap(),
                 value,
             },
-            DbrCategory::Graphics => todo!(),
-            DbrCategory::Control => todo!(),
+            DbrCategory::Graphics => Dbr::Graphics {
+                status: status.unwrap(),
+                display: display.unwrap(),
+                value,
+            },
+            DbrCategory::Control => Dbr::Control {
+                status: status.unwrap(),
+                display: display.unwrap(),
+                value,
+            },
         })
     }
 
     pub fn to_bytes(&self, max_elems: Option<NonZeroUsize>) -> (usize, Vec<u8>) {
-        let mut buffer = Cursor::new(Vec::new());
-        let real_count = self.write_be(&mut buffer, max_elems).unwrap();
-        (real_count, buffer.into_inner())
+        let mut buffer = Vec::new();
+        let real_count = self.write_be(&mut buffer, max_elems);
+        (real_count, buffer)
     }
 
-    /// Write a requested number of elements to a stream
+    /// Append a requested number of elements to a buffer
     ///
     /// Return the actual number of elements written
-    pub fn write_be<W: io::Write>(
-        &self,
-        writer: &mut W,
-        max_elems: Option<NonZeroUsize>,
-    ) -> io::Result<usize> {
+    pub fn write_be(&self, buffer: &mut Vec<u8>, max_elems: Option<NonZeroUsize>) -> usize {
         let (real_elems, data) = self.value().to_bytes(max_elems);
         // All except Basic write status/severity
         if let Some(status) = self.status() {
-            writer.write_all(&status.status.to_be_bytes())?;
-            writer.write_all(&status.severity.to_be_bytes())?;
+            buffer.extend_from_slice(&status.status.to_be_bytes());
+            buffer.extend_from_slice(&status.severity.to_be_bytes());
         }
         match self {
             Dbr::Time { timestamp, .. } => {
-                let unix_time = timestamp.duration_since(UNIX_EPOCH).unwrap();
-                let time_s = unix_time.as_secs() as i32 - 631152000i32;
-                let time_ns = unix_time.subsec_nanos();
-                writer.write_all(&time_s.to_be_bytes())?;
-                writer.write_all(&time_ns.to_be_bytes())?;
+                buffer.extend_from_slice(&timestamp.seconds.to_be_bytes());
+                buffer.extend_from_slice(&timestamp.nanoseconds.to_be_bytes());
+            }
+            Dbr::Graphics { display, .. } | Dbr::Control { display, .. } => {
+                display.write_be(buffer, self.data_type());
             }
-            Dbr::Graphics => todo!(),
-            Dbr::Control => todo!(),
             _ => (),
         }
 
-        writer.write_all(&vec![0u8; self.data_type().get_metadata_padding()])?;
-        writer.write_all(&data)?;
-        Ok(real_elems)
+        buffer.resize(buffer.len() + self.data_type().get_metadata_padding(), 0u8);
+        buffer.extend_from_slice(&data);
+        real_elems
+    }
+
+    /// Attach display information to this DBR, keeping its status and value
+    pub fn with_display(self, display: DisplayMetadata) -> Dbr {
+        let status = self.status().unwrap_or_default();
+        Dbr::Control {
+            status,
+            display,
+            value: self.take_value(),
+        }
+    }
+
+    /// Replace an enum index with the name of that state, keeping the metadata
+    ///
+    /// This is how CA gives enums to clients that ask for them as strings. An
+    /// index without a name is given as the number. Any other value is unchanged.
+    pub fn label_enum(self, labels: &[String]) -> Dbr {
+        let DbrValue::Enum(index) = self.value() else {
+            return self;
+        };
+        let label = labels
+            .get(*index as usize)
+            .cloned()
+            .unwrap_or_else(|| index.to_string());
+        let value = DbrValue::String(vec![label]);
+        match self {
+            Dbr::Basic(_) => Dbr::Basic(value),
+            Dbr::Status { status, .. } => Dbr::Status { status, value },
+            Dbr::Time {
+                status, timestamp, ..
+            } => Dbr::Time {
+                status,
+                timestamp,
+                value,
+            },
+            Dbr::Graphics {
+                status, display, ..
+            } => Dbr::Graphics {
+                status,
+                display,
+                value,
+            },
+            Dbr::Control {
+                status, display, ..
+            } => Dbr::Control {
+                status,
+                display,
+                value,
+            },
+        }
     }
 
+    /// Convert to a different type, keeping as much of the metadata as possible
+    ///
+    /// Metadata that this DBR does not have, such as the timestamp or display
+    /// information, is filled in with defaults (or the current time, if built with
+    /// the `std` feature). Enums converted to strings use the state names from the
+    /// display information, if this DBR has it. Converting
+    /// to [`DBR_CLASS_NAME`] gives the value as a string, so it should be called
+    /// on a DBR holding the record type rather than the record value.
     pub fn convert_to(&self, dbr_type: DbrType) -> Result<Dbr, ErrorCondition> {
-        let value = self.value().convert_to(dbr_type.basic_type)?;
-        // First handle category changes - we can do this for some but not all
-        Ok(match self {
-            Dbr::Basic(_) => match dbr_type.category {
-                DbrCategory::Basic => Dbr::Basic(value),
-                DbrCategory::Status => Dbr::Status {
-                    status: Status::default(),
-                    value,
-                },
-                DbrCategory::Time => Dbr::Time {
-                    status: Status::default(),
-                    timestamp: SystemTime::now(),
-                    value,
-                },
-                _ => return Err(ErrorCondition::NoConvert),
+        let value = match (self.value(), self.display()) {
+            (DbrValue::Enum(_), Some(display)) if dbr_type.basic_type == DbrBasicType::String => {
+                self.clone().label_enum(&display.enum_strings).take_value()
+            }
+            (value, _) => value.convert_to(dbr_type.basic_type)?,
+        };
+        let status = self.status().unwrap_or_default();
+        let display = || self.display().cloned().unwrap_or_default();
+        Ok(match dbr_type.category {
+            DbrCategory::Basic => Dbr::Basic(value),
+            DbrCategory::Status => Dbr::Status { status, value },
+            DbrCategory::Time => Dbr::Time {
+                status,
+                timestamp: self.timestamp().unwrap_or_else(current_time),
+                value,
             },
-            Dbr::Status { status, .. } => match dbr_type.category {
-                DbrCategory::Basic => Dbr::Basic(value),
-                DbrCategory::Status => Dbr::Status {
-                    status: *status,
-                    value,
-                },
-                DbrCategory::Time => Dbr::Time {
-                    status: *status,
-                    timestamp: SystemTime::now(),
-                    value,
-                },
-                _ => return Err(ErrorCondition::NoConvert),
+            DbrCategory::Graphics => Dbr::Graphics {
+                status,
+                display: display(),
+                value,
             },
-            Dbr::Time {
+            DbrCategory::Control => Dbr::Control {
                 status,
-                timestamp: ts,
-                value: _,
-            } => match dbr_type.category {
-                DbrCategory::Basic => Dbr::Basic(value),
-                DbrCategory::Status => Dbr::Status {
-                    status: *status,
-                    value,
-                },
-                DbrCategory::Time => Dbr::Time {
-                    status: *status,
-                    timestamp: *ts,
-                    value,
-                },
-                _ => return Err(ErrorCondition::NoConvert),
+                display: display(),
+                value,
             },
-            Dbr::Graphics => todo!(),
-            Dbr::Control => todo!(),
+            DbrCategory::ClassName => Dbr::Basic(value),
         })
     }
 }
@@ -783,6 +1335,7 @@ mod tests {
                 .flat_map(|v| v.to_be_bytes())
                 .collect::<Vec<u8>>()
         );
+        assert_eq!(v.to_bytes(NonZeroUsize::new(3)).0, 3);
         // Try converting this to an int with truncation
         let v = v.convert_to(DbrBasicType::Int).unwrap();
         assert_eq!(v.to_bytes(None).1, vec![0x01, 0xf4, 0x00, 0x0c]);
@@ -791,9 +1344,9 @@ mod tests {
             DbrValue::Float(vec![455.9f32])
                 .convert_to(DbrBasicType::Long)
                 .unwrap()
-                .to_bytes(NonZeroUsize::new(5))
+                .to_bytes(NonZeroUsize::new(2))
                 .1,
-            vec![0x00, 0x00, 0x01, 0xc7]
+            vec![0x00, 0x00, 0x01, 0xc7, 0x00, 0x00, 0x00, 0x00]
         );
     }
 
@@ -804,9 +1357,7 @@ mod tests {
         ];
         let dbr = Dbr::Time {
             status: Status::default(),
-            timestamp: SystemTime::UNIX_EPOCH
-                .checked_add(Duration::from_secs(1741731609))
-                .unwrap(),
+            timestamp: EpicsTime::from_unix_time(Duration::from_secs(1741731609)),
             value: vec![42i32].into(),
         };
 
@@ -819,6 +1370,43 @@ mod tests {
             .to_bytes(None);
         assert_eq!(out_data.len(), example_packet.len());
         assert_eq!(out_data, example_packet);
+        let parsed = Dbr::from_bytes(dbr.data_type(), 1, &out_data).unwrap();
+        assert_eq!(parsed.timestamp(), dbr.timestamp());
+    }
+
+    #[test]
+    fn test_display_metadata_roundtrip() {
+        let display = DisplayMetadata {
+            units: "mm".to_string(),
+            precision: 3,
+            upper_display_limit: 10.0,
+            lower_display_limit: -10.0,
+            upper_control_limit: 5.0,
+            ..Default::default()
+        };
+        let dbr = Dbr::Control {
+            status: Status::default(),
+            display: display.clone(),
+            value: vec![1.5f64].into(),
+        };
+        // Status, precision + pad, units, 8 limits, then the value
+        let (_, data) = dbr.to_bytes(None);
+        assert_eq!(data.len(), 4 + 4 + 8 + 8 * 8 + 8);
+        let parsed = Dbr::from_bytes(dbr.data_type(), 1, &data).unwrap();
+        assert_eq!(parsed.display(), Some(&display));
+        assert_eq!(parsed.value(), &DbrValue::Double(vec![1.5]));
+
+        let dbr = Dbr::Graphics {
+            status: Status::default(),
+            display: DisplayMetadata {
+                enum_strings: vec!["Off".to_string(), "On".to_string()],
+                ..Default::default()
+            },
+            value: DbrValue::Enum(1),
+        };
+        let (_, data) = dbr.to_bytes(None);
+        let parsed = Dbr::from_bytes(dbr.data_type(), 1, &data).unwrap();
+        assert_eq!(parsed.display().unwrap().enum_strings, vec!["Off", "On"]);
     }
 
     #[test]
@@ -1,4 +1,5 @@
 // #![warn(missing_docs)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
 //! Rust implementation of EPICS CA protocol and basic variable access layers.
 //!
@@ -27,6 +28,14 @@
 //!     as a natively mapped data type. The access objects can be cloned and passed
 //!     across thread boundaries, and retain access to the same data (internally stored
 //!     in an `Arc<Mutex<dbr::DbrValue>>`).
+//!   - [`providers::ProviderSet`] and [`providers::AliasProvider`]: Combine other
+//!     providers, so that several can be served together or under extra names.
+//! - The protocol state machines in [proto], which do no I/O of their own, so can be
+//!   driven by runtimes other than tokio. The [Client] and [Server] are built on them.
+//!
+//! Everything except [dbr] and [messages] needs the default `std` feature. Without it,
+//! those two modules build under `no_std` with only `alloc`, for encoding and
+//! decoding CA on targets without an operating system.
 //!
 //! ## Example Client
 //!
@@ -89,18 +98,32 @@
 //! ["DBR" types]:
 //!     https://docs.epics-controls.org/en/latest/internal/ca_protocol.html#payload-data-types
 
+extern crate alloc;
+
+#[cfg(feature = "std")]
 pub mod client;
+#[cfg(feature = "std")]
 pub use crate::client::Client;
 
 pub mod dbr;
 pub mod messages;
 
+#[cfg(feature = "std")]
 pub use crate::providers::Provider;
 
+#[cfg(feature = "std")]
 mod server;
+#[cfg(feature = "std")]
 pub use crate::server::Server;
+#[cfg(feature = "std")]
 pub use crate::server::ServerBuilder;
+#[cfg(feature = "std")]
+pub use crate::server::{ChannelInfo, CircuitInfo, ServerAddresses, ServerError, ServerHandle};
 
+#[cfg(feature = "std")]
+pub mod proto;
+#[cfg(feature = "std")]
 pub mod providers;
 
+#[cfg(feature = "std")]
 pub(crate) mod utils;
@@ -6,6 +6,9 @@
 //! through CA and it's utility function [`Message::read_server_message`], which
 //! directly parses a message from a TCP stream.
 //!
+//! Reading from and writing to tokio streams needs the `std` feature. Without it,
+//! messages are still parsed from byte slices and encoded into a `Vec<u8>`.
+//!
 //! Not implemented yet:
 //! - Deprecated `CA_PROTO_READ` and `CA_PROTO_READ_SYNC`.
 //! - Obsolete `CA_PROTO_BUILD`, `CA_PROTO_READ_BUILD`, `CA_PROTO_SIGNAL`, and
@@ -13,13 +16,20 @@
 //! - `REPEATER_CONFIRM`, `REPEATER_REGISTER`, as we don't interact with repeaters yet.
 //! - `CA_PROTO_NOT_FOUND` over unclear rules as to when it is sent.
 //!
-use std::{
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
     fmt::Display,
-    io::{self, Cursor},
     net::Ipv4Addr,
     num::NonZeroUsize,
     ops::{Shl, Shr},
 };
+#[cfg(feature = "std")]
+use std::io;
 
 use nom::{
     Err, IResult, Parser,
@@ -30,7 +40,9 @@ use nom::{
     number::complete::{be_f32, be_u16, be_u32},
 };
 use thiserror::Error;
+#[cfg(feature = "std")]
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "std")]
 use tokio_util::{
     bytes::{Buf, BytesMut},
     codec::Decoder,
@@ -42,9 +54,9 @@ const EPICS_VERSION: u16 = 13;
 
 /// A basic trait to tie message parsing/serialization to a struct.
 ///
-/// Also adds common interface for writing a message struct to a writer.
+/// Also adds common interface for appending the encoded message to a buffer.
 pub trait CAMessage: TryFrom<RawMessage> {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()>;
+    fn write(&self, buffer: &mut Vec<u8>);
     fn parse(input: &[u8]) -> IResult<&[u8], Self> {
         let (i, raw) = RawMessage::parse(input)?;
         let converted: Self = raw
@@ -74,8 +86,27 @@ pub struct RawMessage {
     payload: Vec<u8>,
 }
 
-pub struct RawMessageDecoder;
+#[cfg(feature = "std")]
+/// Splits a byte stream into [`RawMessage`]s, for use with a framed reader.
+#[derive(Default)]
+pub struct RawMessageDecoder {
+    max_payload_size: Option<usize>,
+}
+
+#[cfg(feature = "std")]
+impl RawMessageDecoder {
+    /// Create a decoder that rejects any message with a payload larger than `size`
+    ///
+    /// Messages that are too large cause an [`io::ErrorKind::InvalidData`] error
+    /// as soon as the header has been read, without buffering the payload.
+    pub fn with_max_payload_size(size: usize) -> Self {
+        RawMessageDecoder {
+            max_payload_size: Some(size),
+        }
+    }
+}
 
+#[cfg(feature = "std")]
 impl Decoder for RawMessageDecoder {
     type Item = RawMessage;
     type Error = std::io::Error;
@@ -88,7 +119,7 @@ impl Decoder for RawMessageDecoder {
             .parse_complete(&src[..4])
             .unwrap();
         let header_len = if payload_size == 0xFFFF {
-            32usize
+            24usize
         } else {
             16usize
         };
@@ -122,7 +153,7 @@ impl Decoder for RawMessageDecoder {
                 be_u32,
                 be_u32,
             )
-                .parse_complete(&src[4..32])
+                .parse_complete(&src[4..24])
                 .unwrap();
             MessageHeader {
                 command,
@@ -134,6 +165,17 @@ impl Decoder for RawMessageDecoder {
             }
         };
         // Now we have the full header, we know how long the payload is
+        if let Some(max_size) = self.max_payload_size
+            && header.payload_size as usize > max_size
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Message payload of {} bytes exceeds limit of {max_size}",
+                    header.payload_size
+                ),
+            ));
+        }
         let full_message_length = header_len + (header.payload_size as usize);
         src.reserve(full_message_length);
         if src.len() < full_message_length {
@@ -155,17 +197,26 @@ impl Decoder for RawMessageDecoder {
 }
 
 impl RawMessage {
+    #[cfg(feature = "std")]
     async fn read<T: AsyncRead + Unpin>(source: &mut T) -> Result<RawMessage, MessageError> {
         let mut data = vec![0u8; 16];
         source.read_exact(data.as_mut_slice()).await?;
-        let (input, (command, payload_size, field_1)) = (
+        let (_, (command, payload_size)) =
+            (be_u16::<&[u8], nom::error::Error<&[u8]>>, be_u16).parse(data.as_slice())?;
+
+        // Handle packets that could be large
+        if payload_size == 0xFFFF {
+            // Large headers have an extra 8 bytes
+            data.resize(24, 0);
+            source.read_exact(&mut data[16..]).await?;
+        }
+        let (input, (_, _, field_1)) = (
             be_u16::<&[u8], nom::error::Error<&[u8]>>,
             be_u16,
             be_u16::<&[u8], nom::error::Error<&[u8]>>,
         )
             .parse(data.as_slice())?;
 
-        // Handle packets that could be large
         if payload_size == 0xFFFF {
             let (_, (_, field_3, field_4, payload_size, field_2)) = (
                 take::<usize, &[u8], nom::error::Error<&[u8]>>(2usize),
@@ -207,6 +258,10 @@ impl RawMessage {
     fn payload_size(&self) -> usize {
         self.payload.len()
     }
+    /// The total number of bytes this message takes up on the wire
+    pub fn message_size(&self) -> usize {
+        MessageHeader::from(self).header_size() + self.payload.len().div_ceil(8) * 8
+    }
     fn expect_id(&self, id: u16) -> Result<(), MessageError> {
         if self.command == id {
             Ok(())
@@ -239,18 +294,14 @@ impl RawMessage {
 }
 
 impl CAMessage for RawMessage {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         let payload_size = self.payload.len().div_ceil(8) * 8;
         let header: MessageHeader = self.into();
-        header.write(writer)?;
+        header.write(buffer);
 
-        writer.write_all(&self.payload)?;
-        let extra_bytes = payload_size - self.payload.len();
-        if extra_bytes > 0 {
-            writer.write_all(&vec![0; extra_bytes])?;
-        }
-
-        Ok(())
+        buffer.extend_from_slice(&self.payload);
+        // Pad out to the size declared in the header
+        buffer.resize(buffer.len() + payload_size - self.payload.len(), 0);
     }
 }
 
@@ -270,7 +321,7 @@ impl MessageHeader {
         if self.payload_size < 0xFFFF && self.field_2_data_count <= 0xFFFF {
             16
         } else {
-            32
+            24
         }
     }
 
@@ -340,26 +391,25 @@ impl From<RawMessage> for MessageHeader {
 }
 
 impl CAMessage for MessageHeader {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         let payload_size = self.payload_size.div_ceil(8) * 8;
 
-        writer.write_all(&self.command.to_be_bytes())?;
+        buffer.extend_from_slice(&self.command.to_be_bytes());
         if payload_size < 0xFFFF && self.field_2_data_count <= 0xFFFF {
-            writer.write_all(&(payload_size as u16).to_be_bytes())?;
-            writer.write_all(&self.field_1_data_type.to_be_bytes())?;
-            writer.write_all(&(self.field_2_data_count as u16).to_be_bytes())?;
-            writer.write_all(&self.field_3_parameter_1.to_be_bytes())?;
-            writer.write_all(&self.field_4_parameter_2.to_be_bytes())?;
+            buffer.extend_from_slice(&(payload_size as u16).to_be_bytes());
+            buffer.extend_from_slice(&self.field_1_data_type.to_be_bytes());
+            buffer.extend_from_slice(&(self.field_2_data_count as u16).to_be_bytes());
+            buffer.extend_from_slice(&self.field_3_parameter_1.to_be_bytes());
+            buffer.extend_from_slice(&self.field_4_parameter_2.to_be_bytes());
         } else {
-            writer.write_all(&0xFFFFu32.to_be_bytes())?;
-            writer.write_all(&self.field_1_data_type.to_be_bytes())?;
-            writer.write_all(&[0x0000])?;
-            writer.write_all(&self.field_3_parameter_1.to_be_bytes())?;
-            writer.write_all(&self.field_4_parameter_2.to_be_bytes())?;
-            writer.write_all(&payload_size.to_be_bytes())?;
-            writer.write_all(&self.field_2_data_count.to_be_bytes())?;
+            buffer.extend_from_slice(&0xFFFFu16.to_be_bytes());
+            buffer.extend_from_slice(&self.field_1_data_type.to_be_bytes());
+            buffer.extend_from_slice(&0x0000u16.to_be_bytes());
+            buffer.extend_from_slice(&self.field_3_parameter_1.to_be_bytes());
+            buffer.extend_from_slice(&self.field_4_parameter_2.to_be_bytes());
+            buffer.extend_from_slice(&payload_size.to_be_bytes());
+            buffer.extend_from_slice(&self.field_2_data_count.to_be_bytes());
         }
-        Ok(())
     }
 }
 
@@ -383,6 +433,7 @@ pub enum Message {
     EventsOff,
     EventsOn,
     HostName(HostName),
+    NotFound(NotFound),
     ReadNotify(ReadNotify),
     ReadNotifyResponse(ReadNotifyResponse),
     RsrvIsUp(RsrvIsUp),
@@ -412,6 +463,7 @@ impl AsBytes for Message {
             Message::EventsOff => EventsOff.as_bytes(),
             Message::EventsOn => EventsOn.as_bytes(),
             Message::HostName(msg) => msg.as_bytes(),
+            Message::NotFound(msg) => msg.as_bytes(),
             Message::ReadNotify(msg) => msg.as_bytes(),
             Message::ReadNotifyResponse(msg) => msg.as_bytes(),
             Message::RsrvIsUp(msg) => msg.as_bytes(),
@@ -433,6 +485,7 @@ impl Message {
     /// sent to a server. This is because some response messages have the same command
     /// ID but different fields, so it is impossible to tell which is which purely from
     /// the contents of the message.
+    #[cfg(feature = "std")]
     pub async fn read_client_message<T: AsyncRead + Unpin>(
         source: &mut T,
     ) -> Result<Self, MessageError> {
@@ -446,6 +499,7 @@ impl Message {
             1 => Self::EventAddResponse(message.try_into()?),
             6 => Self::SearchResponse(message.try_into()?),
             11 => Self::ECAError(message.try_into()?),
+            14 => Self::NotFound(message.try_into()?),
             15 => Self::ReadNotifyResponse(message.try_into()?),
             18 => Self::CreateChannelResponse(message.try_into()?),
             19 => Self::WriteNotifyResponse(message.try_into()?),
@@ -472,6 +526,7 @@ impl Message {
     /// sent to a client. This is because some response messages have the same command
     /// ID but different fields, so it is impossible to tell which is which purely from
     /// the contents of the message.
+    #[cfg(feature = "std")]
     pub async fn read_server_message<T: AsyncRead + Unpin>(
         source: &mut T,
     ) -> Result<Self, MessageError> {
@@ -506,6 +561,7 @@ impl Message {
         result
     }
 
+    #[cfg(feature = "std")]
     pub async fn write_all_messages<T: AsyncWriteExt + Unpin>(
         messages: &[Message],
         to: &mut T,
@@ -541,6 +597,7 @@ impl_from_for!(
     EventCancel,
     EventAddResponse,
     HostName,
+    NotFound,
     ReadNotify,
     ReadNotifyResponse,
     RsrvIsUp,
@@ -563,6 +620,7 @@ pub enum ClientMessage {
     #[default]
     Echo,
     EventAddResponse(EventAddResponse),
+    NotFound(NotFound),
     ReadNotifyResponse(ReadNotifyResponse),
     SearchResponse(SearchResponse),
     ServerDisconnect(ServerDisconnect),
@@ -580,6 +638,7 @@ impl_from_for!(
     WriteNotifyResponse,
     AccessRights,
     CreateChannelFailure,
+    NotFound,
     ServerDisconnect
 );
 
@@ -590,6 +649,7 @@ impl ClientMessage {
     /// sent to a server. This is because some response messages have the same command
     /// ID but different fields, so it is impossible to tell which is which purely from
     /// the contents of the message.
+    #[cfg(feature = "std")]
     pub async fn read_message<T: AsyncRead + Unpin>(source: &mut T) -> Result<Self, MessageError> {
         let message = RawMessage::read(source).await?;
         message.try_into()
@@ -611,6 +671,7 @@ impl TryFrom<RawMessage> for ClientMessage {
             1 => Self::EventAddResponse(value.try_into()?),
             6 => Self::SearchResponse(value.try_into()?),
             11 => Self::ECAError(value.try_into()?),
+            14 => Self::NotFound(value.try_into()?),
             15 => Self::ReadNotifyResponse(value.try_into()?),
             18 => Self::CreateChannelResponse(value.try_into()?),
             19 => Self::WriteNotifyResponse(value.try_into()?),
@@ -623,12 +684,13 @@ impl TryFrom<RawMessage> for ClientMessage {
     }
 }
 
+#[cfg(feature = "std")]
 impl Decoder for ClientMessage {
     type Item = ClientMessage;
     type Error = MessageError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let Some(message) = RawMessageDecoder {}.decode(src).unwrap() else {
+        let Some(message) = RawMessageDecoder::default().decode(src).unwrap() else {
             return Ok(None);
         };
         Ok(Some(message.try_into()?))
@@ -712,10 +774,11 @@ impl Decoder for ClientMessage {
 /// Unified thiserror enum to represent failures from functions in this module.
 #[derive(Error, Debug)]
 pub enum MessageError {
+    #[cfg(feature = "std")]
     #[error("IO Error Occured: {0}")]
     IO(#[from] io::Error),
     #[error("An error occured parsing a message")]
-    ParsingError(#[from] nom::Err<nom::error::Error<Vec<u8>>>),
+    ParsingError(nom::Err<nom::error::Error<Vec<u8>>>),
     #[error("Unknown command ID: {0}")]
     UnknownCommandId(u16),
     #[error("Got a valid message but is not valid at this state: {0:?}")]
@@ -728,6 +791,12 @@ pub enum MessageError {
     ErrorResponse(ErrorCondition),
 }
 
+// Not #[from], as nom errors only implement Error with std
+impl From<nom::Err<nom::error::Error<Vec<u8>>>> for MessageError {
+    fn from(err: nom::Err<nom::error::Error<Vec<u8>>>) -> Self {
+        MessageError::ParsingError(err)
+    }
+}
 impl From<nom::Err<nom::error::Error<&[u8]>>> for MessageError {
     fn from(err: nom::Err<nom::error::Error<&[u8]>>) -> Self {
         MessageError::ParsingError(err.to_owned())
@@ -803,9 +872,9 @@ where
     T: CAMessage,
 {
     fn as_bytes(&self) -> Vec<u8> {
-        let mut buffer = Cursor::new(Vec::new());
-        self.write(&mut buffer).unwrap();
-        buffer.into_inner()
+        let mut buffer = Vec::new();
+        self.write(&mut buffer);
+        buffer
     }
 }
 
@@ -826,18 +895,17 @@ impl TryFrom<RawMessage> for RsrvIsUp {
 }
 
 impl CAMessage for RsrvIsUp {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
-        writer.write_all(&13_u16.to_be_bytes())?;
-        writer.write_all(&0_u16.to_be_bytes())?;
-        writer.write_all(&EPICS_VERSION.to_be_bytes())?;
-        writer.write_all(&self.server_port.to_be_bytes())?;
-        writer.write_all(&self.beacon_id.to_be_bytes())?;
+    fn write(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&13_u16.to_be_bytes());
+        buffer.extend_from_slice(&0_u16.to_be_bytes());
+        buffer.extend_from_slice(&EPICS_VERSION.to_be_bytes());
+        buffer.extend_from_slice(&self.server_port.to_be_bytes());
+        buffer.extend_from_slice(&self.beacon_id.to_be_bytes());
         if let Some(ip) = &self.server_ip {
-            writer.write_all(&ip.octets())?;
+            buffer.extend_from_slice(&ip.octets());
         } else {
-            writer.write_all(&0u32.to_be_bytes())?;
+            buffer.extend_from_slice(&0u32.to_be_bytes());
         }
-        Ok(())
     }
 }
 
@@ -877,14 +945,14 @@ impl TryFrom<RawMessage> for Version {
 }
 
 impl CAMessage for Version {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 0,
             field_1_data_type: self.priority,
             field_2_data_count: EPICS_VERSION as u32,
             ..Default::default()
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -925,6 +993,13 @@ impl Search {
             protocol_version: if is_udp { Some(EPICS_VERSION) } else { None },
         }
     }
+    /// Construct a reply saying that we do not have the searched-for channel
+    pub fn respond_not_found(&self) -> NotFound {
+        NotFound {
+            search_id: self.search_id,
+            protocol_version: self.protocol_version,
+        }
+    }
 }
 impl TryFrom<RawMessage> for Search {
     type Error = MessageError;
@@ -939,7 +1014,7 @@ impl TryFrom<RawMessage> for Search {
     }
 }
 impl CAMessage for Search {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 6,
             field_1_data_type: if self.should_reply { 10 } else { 5 },
@@ -948,7 +1023,7 @@ impl CAMessage for Search {
             field_4_parameter_2: self.search_id,
             payload: pad_string(&self.channel_name),
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -989,7 +1064,7 @@ impl TryFrom<RawMessage> for SearchResponse {
 }
 
 impl CAMessage for SearchResponse {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 0x06,
             field_1_data_type: self.port_number,
@@ -1004,7 +1079,43 @@ impl CAMessage for SearchResponse {
                 Some(v) => v.to_be_bytes().to_vec(),
             },
         }
-        .write(writer)
+        .write(buffer);
+    }
+}
+
+/// Message CA_PROTO_NOT_FOUND.
+///
+/// Response to a [`Search`] for a channel that the server does not have. Only
+/// sent if the search asked for a reply on failure. Sent over UDP or TCP.
+#[derive(Debug)]
+pub struct NotFound {
+    pub search_id: u32,
+    pub protocol_version: u16,
+}
+
+impl TryFrom<RawMessage> for NotFound {
+    type Error = MessageError;
+    fn try_from(value: RawMessage) -> Result<Self, Self::Error> {
+        value.expect_id(14)?;
+        Ok(NotFound {
+            protocol_version: value.field_2_data_count as u16,
+            search_id: value.field_3_parameter_1,
+        })
+    }
+}
+
+impl CAMessage for NotFound {
+    fn write(&self, buffer: &mut Vec<u8>) {
+        // This echoes back the fields of the original search request
+        RawMessage {
+            command: 14,
+            field_1_data_type: 10,
+            field_2_data_count: self.protocol_version as u32,
+            field_3_parameter_1: self.search_id,
+            field_4_parameter_2: self.search_id,
+            payload: Vec::new(),
+        }
+        .write(buffer);
     }
 }
 
@@ -1058,7 +1169,7 @@ impl TryFrom<RawMessage> for CreateChannel {
     }
 }
 impl CAMessage for CreateChannel {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 18,
             field_1_data_type: 0,
@@ -1067,7 +1178,7 @@ impl CAMessage for CreateChannel {
             field_4_parameter_2: self.protocol_version,
             payload: pad_string(&self.channel_name),
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1094,7 +1205,7 @@ impl TryFrom<RawMessage> for CreateChannelResponse {
 }
 
 impl CAMessage for CreateChannelResponse {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 18,
             field_1_data_type: self.data_type as u16,
@@ -1103,7 +1214,7 @@ impl CAMessage for CreateChannelResponse {
             field_4_parameter_2: self.server_id,
             ..Default::default()
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1125,13 +1236,13 @@ impl TryFrom<RawMessage> for CreateChannelFailure {
     }
 }
 impl CAMessage for CreateChannelFailure {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 26,
             field_3_parameter_1: self.client_id,
             ..Default::default()
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1145,8 +1256,19 @@ pub enum Access {
     ReadWrite = 3,
 }
 
+impl Access {
+    /// Whether this allows reading the value of a channel
+    pub fn can_read(&self) -> bool {
+        matches!(self, Access::Read | Access::ReadWrite)
+    }
+    /// Whether this allows writing to a channel
+    pub fn can_write(&self) -> bool {
+        matches!(self, Access::Write | Access::ReadWrite)
+    }
+}
+
 impl Display for Access {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_str(match self {
             Access::None => "None",
             Access::Read => "Read",
@@ -1194,14 +1316,14 @@ impl TryFrom<RawMessage> for AccessRights {
 }
 
 impl CAMessage for AccessRights {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 22,
             field_3_parameter_1: self.client_id,
             field_4_parameter_2: self.access_rights as u32,
             ..Default::default()
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1220,13 +1342,12 @@ impl TryFrom<RawMessage> for Echo {
 }
 
 impl CAMessage for Echo {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 23,
             ..Default::default()
         }
-        .write(writer)?;
-        Ok(())
+        .write(buffer);
     }
 }
 
@@ -1249,13 +1370,13 @@ impl TryFrom<RawMessage> for ClientName {
 }
 
 impl CAMessage for ClientName {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 20,
             payload: pad_string(&self.name),
             ..Default::default()
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1276,13 +1397,13 @@ impl TryFrom<RawMessage> for HostName {
     }
 }
 impl CAMessage for HostName {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 21,
             payload: pad_string(&self.name),
             ..Default::default()
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1303,13 +1424,13 @@ impl TryFrom<RawMessage> for ServerDisconnect {
     }
 }
 impl CAMessage for ServerDisconnect {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 27,
             field_3_parameter_1: self.client_id,
             ..Default::default()
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1329,12 +1450,12 @@ impl TryFrom<RawMessage> for EventsOn {
     }
 }
 impl CAMessage for EventsOn {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 9,
             ..Default::default()
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1354,12 +1475,12 @@ impl TryFrom<RawMessage> for EventsOff {
     }
 }
 impl CAMessage for EventsOff {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 8,
             ..Default::default()
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1383,14 +1504,14 @@ impl TryFrom<RawMessage> for ClearChannel {
     }
 }
 impl CAMessage for ClearChannel {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 12,
             field_3_parameter_1: self.server_id,
             field_4_parameter_2: self.client_id,
             ..Default::default()
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1454,7 +1575,7 @@ impl TryFrom<RawMessage> for EventAdd {
 }
 
 impl CAMessage for EventAdd {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         let mut payload = vec![0u8; 12];
         let mask: u16 = if self.mask.value { 1 } else { 0 }
             + if self.mask.log { 2 } else { 0 }
@@ -1470,7 +1591,7 @@ impl CAMessage for EventAdd {
             field_4_parameter_2: self.subscription_id,
             payload,
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1521,7 +1642,7 @@ impl TryFrom<RawMessage> for EventAddResponse {
 }
 
 impl CAMessage for EventAddResponse {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 1,
             field_1_data_type: self.data_type.into(),
@@ -1530,7 +1651,7 @@ impl CAMessage for EventAddResponse {
             field_4_parameter_2: self.subscription_id,
             payload: self.data.clone(),
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1579,7 +1700,7 @@ impl TryFrom<RawMessage> for EventCancel {
 }
 
 impl CAMessage for EventCancel {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 2,
             field_1_data_type: self.data_type.into(),
@@ -1588,7 +1709,7 @@ impl CAMessage for EventCancel {
             field_4_parameter_2: self.subscription_id,
             ..Default::default()
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 
@@ -1646,8 +1767,8 @@ impl From<&ReadNotify> for RawMessage {
 }
 
 impl CAMessage for ReadNotify {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
-        RawMessage::from(self).write(writer)
+    fn write(&self, buffer: &mut Vec<u8>) {
+        RawMessage::from(self).write(buffer);
     }
 }
 
@@ -1690,8 +1811,8 @@ impl TryFrom<RawMessage> for ReadNotifyResponse {
 }
 
 impl CAMessage for ReadNotifyResponse {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
-        RawMessage::from(self).write(writer)
+    fn write(&self, buffer: &mut Vec<u8>) {
+        RawMessage::from(self).write(buffer);
     }
 }
 
@@ -1736,8 +1857,8 @@ impl TryFrom<RawMessage> for Write {
 }
 
 impl CAMessage for Write {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
-        RawMessage::from(self).write(writer)
+    fn write(&self, buffer: &mut Vec<u8>) {
+        RawMessage::from(self).write(buffer);
     }
 }
 
@@ -1790,8 +1911,8 @@ impl TryFrom<RawMessage> for WriteNotify {
 }
 
 impl CAMessage for WriteNotify {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
-        RawMessage::from(self).write(writer)
+    fn write(&self, buffer: &mut Vec<u8>) {
+        RawMessage::from(self).write(buffer);
     }
 }
 
@@ -1831,8 +1952,8 @@ impl TryFrom<RawMessage> for WriteNotifyResponse {
 }
 
 impl CAMessage for WriteNotifyResponse {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
-        RawMessage::from(self).write(writer)
+    fn write(&self, buffer: &mut Vec<u8>) {
+        RawMessage::from(self).write(buffer);
     }
 }
 
@@ -1978,13 +2099,14 @@ impl ErrorCondition {
             Self::Nocast => ErrorSeverity::Warning,
         }
     }
-    fn eca_code(&self) -> u32 {
+    /// The status code used on the wire, combining condition and severity
+    pub fn eca_code(&self) -> u32 {
         let val = *self as u32;
         val.shl(3) + (self.get_severity() as u32)
     }
 }
-impl std::fmt::Display for ErrorCondition {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for ErrorCondition {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{}",
@@ -2198,7 +2320,7 @@ impl TryFrom<RawMessage> for ECAError {
 }
 
 impl CAMessage for ECAError {
-    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+    fn write(&self, buffer: &mut Vec<u8>) {
         RawMessage {
             command: 11,
             field_1_data_type: 0,
@@ -2207,13 +2329,12 @@ impl CAMessage for ECAError {
             field_4_parameter_2: self.condition.eca_code(),
             payload: self.original_request.as_bytes().to_vec(),
         }
-        .write(writer)
+        .write(buffer);
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::{Cursor, Seek};
 
     #[test]
     fn parse_beacon() {
@@ -2230,10 +2351,10 @@ mod tests {
         println!("Beacon: {beacon:?}");
 
         // Now try converting it back
-        let mut writer = Cursor::new(Vec::new());
-        beacon.write(&mut writer).unwrap();
-        assert_eq!(writer.stream_position().unwrap(), 16);
-        assert_eq!(writer.into_inner(), raw_beacon);
+        let mut buffer = Vec::new();
+        beacon.write(&mut buffer);
+        assert_eq!(buffer.len(), 16);
+        assert_eq!(buffer, raw_beacon);
     }
     #[test]
     fn parse_version() {
@@ -2273,4 +2394,23 @@ mod tests {
         ];
         parse_search_packet(&raw).unwrap();
     }
+
+    #[test]
+    fn roundtrip_large_message() {
+        let message = RawMessage {
+            command: 1,
+            field_1_data_type: 6,
+            field_2_data_count: 0x12345,
+            field_3_parameter_1: 1,
+            field_4_parameter_2: 2,
+            payload: vec![0x42; 0x10000],
+        };
+        let bytes = message.as_bytes();
+        assert_eq!(bytes.len(), 24 + 0x10000);
+        let (rest, parsed) = RawMessage::parse(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed.field_1_data_type, 6);
+        assert_eq!(parsed.field_2_data_count, 0x12345);
+        assert_eq!(parsed.payload, message.payload);
+    }
 }
@@ -0,0 +1,479 @@
+//! The client end of a circuit to a CA server
+//!
+//! [`ClientCircuit`] keeps track of the channels and requests on a single TCP
+//! connection, after the version exchange is complete. Requests are made by calling
+//! its methods, which queue messages to send, and the results come back as
+//! [`ClientEvent`]s as the server's replies are handled.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, debug_span, error, trace, warn};
+
+use crate::{
+    dbr::{Dbr, DbrBasicType, DbrCategory, DbrType},
+    messages::{self, Access, AsBytes, ClientMessage, Message, MessageError, MonitorMask},
+    proto::{Liveness, LivenessAction, MessageBuffer},
+    utils::wrapping_inplace_add,
+};
+
+/// How long the server can be quiet before we check that it is still there
+const ECHO_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ChannelState {
+    #[default]
+    Closed,
+    SentCreate,
+    Ready,
+}
+
+/// Summary of channel information
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelInfo {
+    pub state: ChannelState,
+    pub native_type: DbrBasicType,
+    pub native_count: u32,
+    pub cid: u32,
+    pub permissions: Access,
+}
+
+#[derive(Debug, Default)]
+struct Channel {
+    name: String,
+    state: ChannelState,
+    native_type: Option<DbrBasicType>,
+    native_count: u32,
+    cid: u32,
+    sid: u32,
+    permissions: Access,
+}
+
+impl Channel {
+    /// The channel information, once the server has told us about it
+    fn info(&self) -> Option<ChannelInfo> {
+        Some(ChannelInfo {
+            state: self.state,
+            native_type: self.native_type?,
+            native_count: self.native_count,
+            cid: self.cid,
+            permissions: self.permissions,
+        })
+    }
+}
+
+/// A subscription that we have asked the server for
+#[derive(Debug)]
+struct Subscription {
+    cid: u32,
+    data_type: DbrType,
+    data_count: u32,
+}
+
+/// Something that happened on a [`ClientCircuit`]
+#[derive(Debug)]
+pub enum ClientEvent {
+    /// The server has opened a channel we asked for
+    ChannelReady(ChannelInfo),
+    /// The server could not open a channel we asked for
+    ChannelFailed { cid: u32 },
+    /// A read has completed, with `None` if the server sent an invalid value
+    ReadComplete { ioid: u32, value: Option<Dbr> },
+    /// A new value for a subscription, including the first one
+    SubscriptionUpdate { subscription_id: u32, value: Dbr },
+    /// The server has ended a subscription, usually because we cancelled it
+    SubscriptionEnded { subscription_id: u32 },
+}
+
+/// Protocol state of a circuit from a client to a server
+pub struct ClientCircuit {
+    liveness: Liveness,
+    buffer: MessageBuffer,
+    outgoing: Vec<Message>,
+    next_cid: u32,
+    /// IDs for reads and subscriptions, shared by all channels so they are unique
+    next_ioid: u32,
+    channels: HashMap<u32, Channel>,
+    channel_lookup: HashMap<String, u32>,
+    /// Reads we are waiting for the server to answer
+    pending_reads: HashMap<u32, u32>,
+    subscriptions: HashMap<u32, Subscription>,
+}
+
+impl ClientCircuit {
+    /// Start tracking a circuit, once the version exchange is done
+    ///
+    /// The client identifies itself to the server with the first messages sent.
+    pub fn new(client_name: String, host_name: String, now: Instant) -> Self {
+        ClientCircuit {
+            liveness: Liveness::new(ECHO_TIMEOUT, now),
+            buffer: MessageBuffer::default(),
+            outgoing: vec![
+                messages::ClientName { name: client_name }.into(),
+                messages::HostName { name: host_name }.into(),
+            ],
+            next_cid: 0,
+            next_ioid: 4242,
+            channels: HashMap::new(),
+            channel_lookup: HashMap::new(),
+            pending_reads: HashMap::new(),
+            subscriptions: HashMap::new(),
+        }
+    }
+
+    /// Open a channel to a PV, returning its client ID
+    ///
+    /// If there is already a channel to this PV then its ID is returned, and no
+    /// new channel is opened. Once the server has answered, a
+    /// [`ClientEvent::ChannelReady`] or [`ClientEvent::ChannelFailed`] is returned.
+    pub fn open_channel(&mut self, name: &str) -> u32 {
+        if let Some(cid) = self.channel_lookup.get(name) {
+            return *cid;
+        }
+        let cid = wrapping_inplace_add(&mut self.next_cid);
+        let _span = debug_span!("create_channel", cid = cid).entered();
+        debug!("Creating channel '{name}' cid: {cid}");
+        self.channel_lookup.insert(name.to_string(), cid);
+        self.channels.insert(
+            cid,
+            Channel {
+                name: name.to_string(),
+                cid,
+                state: ChannelState::SentCreate,
+                ..Default::default()
+            },
+        );
+        self.outgoing.push(
+            messages::CreateChannel {
+                client_id: cid,
+                channel_name: name.to_string(),
+                ..Default::default()
+            }
+            .into(),
+        );
+        cid
+    }
+
+    /// Information about a channel, if the server has opened it
+    pub fn channel_info(&self, cid: u32) -> Option<ChannelInfo> {
+        self.channels.get(&cid).and_then(Channel::info)
+    }
+
+    /// Read the value of a channel in its native type, returning the request ID
+    ///
+    /// A `length` of zero reads the current number of elements. Returns `None` if
+    /// the channel is not open.
+    pub fn read(&mut self, cid: u32, category: DbrCategory, length: usize) -> Option<u32> {
+        let channel = self.channels.get_mut(&cid)?;
+        let native_type = channel.native_type?;
+        let ioid = wrapping_inplace_add(&mut self.next_ioid);
+        debug!(
+            "Sending read request {ioid} for channel {cid} ({})",
+            channel.name
+        );
+        self.pending_reads.insert(ioid, cid);
+        self.outgoing.push(
+            messages::ReadNotify {
+                data_type: DbrType {
+                    basic_type: native_type,
+                    category,
+                },
+                data_count: length as u32,
+                server_id: channel.sid,
+                client_ioid: ioid,
+            }
+            .into(),
+        );
+        Some(ioid)
+    }
+
+    /// Subscribe to updates of a channel, returning the subscription ID
+    ///
+    /// Returns `None` if the channel is not open.
+    pub fn subscribe(&mut self, cid: u32, data_type: DbrType, length: usize) -> Option<u32> {
+        let channel = self.channels.get_mut(&cid)?;
+        channel.native_type?;
+        let subscription_id = wrapping_inplace_add(&mut self.next_ioid);
+        self.subscriptions.insert(
+            subscription_id,
+            Subscription {
+                cid,
+                data_type,
+                data_count: length as u32,
+            },
+        );
+        self.outgoing.push(
+            messages::EventAdd {
+                data_type,
+                data_count: length as u32,
+                server_id: channel.sid,
+                subscription_id,
+                mask: MonitorMask::default(),
+            }
+            .into(),
+        );
+        Some(subscription_id)
+    }
+
+    /// Ask the server to stop sending updates for a subscription
+    ///
+    /// The server confirms this, which ends in [`ClientEvent::SubscriptionEnded`].
+    pub fn cancel_subscription(&mut self, subscription_id: u32) {
+        let Some(subscription) = self.subscriptions.get(&subscription_id) else {
+            return;
+        };
+        let Some(channel) = self.channels.get(&subscription.cid) else {
+            return;
+        };
+        self.outgoing.push(
+            messages::EventCancel {
+                data_type: subscription.data_type,
+                data_count: subscription.data_count,
+                server_id: channel.sid,
+                subscription_id,
+            }
+            .into(),
+        );
+    }
+
+    /// Take the messages that need to be sent to the server
+    pub fn poll_transmit(&mut self) -> Vec<Message> {
+        for message in &self.outgoing {
+            trace!("Sending {message:?}");
+        }
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Take the bytes that need to be sent to the server, if any
+    pub fn poll_transmit_bytes(&mut self) -> Option<Vec<u8>> {
+        let messages = self.poll_transmit();
+        (!messages.is_empty()).then(|| messages.iter().flat_map(|m| m.as_bytes()).collect())
+    }
+
+    /// When [`ClientCircuit::handle_timeout`] next needs to be called
+    pub fn poll_timeout(&self) -> Instant {
+        self.liveness.poll_timeout()
+    }
+
+    /// Check that the server is still there
+    ///
+    /// Returns `false` if the server has stopped answering, and the circuit
+    /// should be closed.
+    pub fn handle_timeout(&mut self, now: Instant) -> bool {
+        match self.liveness.handle_timeout(now) {
+            LivenessAction::Wait => true,
+            LivenessAction::SendEcho => {
+                self.outgoing.push(Message::Echo);
+                true
+            }
+            LivenessAction::Disconnect => {
+                error!("Received no reply from server, assuming connection dead");
+                false
+            }
+        }
+    }
+
+    /// Handle bytes received from the server
+    ///
+    /// An error means that the stream from the server can not be decoded any
+    /// further, and the circuit should be closed.
+    pub fn handle_bytes(
+        &mut self,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<Vec<ClientEvent>, MessageError> {
+        self.buffer.extend(data);
+        let mut events = Vec::new();
+        while let Some(raw) = self.buffer.next_message()? {
+            match ClientMessage::try_from(raw) {
+                Ok(message) => events.extend(self.handle_message(message, now)),
+                Err(e) => error!("Got error processing server message: {e}"),
+            }
+        }
+        Ok(events)
+    }
+
+    /// Handle a message received from the server
+    pub fn handle_message(&mut self, message: ClientMessage, now: Instant) -> Vec<ClientEvent> {
+        let answered = self
+            .liveness
+            .message_received(now, matches!(message, ClientMessage::Echo));
+        trace!("Received message: {message:?}");
+        match message {
+            ClientMessage::AccessRights(msg) => {
+                let _span = debug_span!("handle_message", cid = &msg.client_id).entered();
+                let Some(channel) = self.channels.get_mut(&msg.client_id) else {
+                    debug!("Got message for closed/uncreated channel");
+                    return Vec::new();
+                };
+                debug!("Got AccessRights update: {}", msg.access_rights);
+                channel.permissions = msg.access_rights;
+                Vec::new()
+            }
+            ClientMessage::CreateChannelResponse(msg) => {
+                let _span = debug_span!("handle_message", cid = &msg.client_id).entered();
+                let Some(channel) = self.channels.get_mut(&msg.client_id) else {
+                    debug!("Got message for closed/uncreated channel: {msg:?}");
+                    return Vec::new();
+                };
+                channel.native_count = msg.data_count;
+                channel.native_type = Some(msg.data_type);
+                channel.state = ChannelState::Ready;
+                channel.sid = msg.server_id;
+                channel
+                    .info()
+                    .map(ClientEvent::ChannelReady)
+                    .into_iter()
+                    .collect()
+            }
+            ClientMessage::CreateChannelFailure(msg) => {
+                let Some(channel) = self.channels.remove(&msg.client_id) else {
+                    warn!(
+                        "Got channel failure message for a nonexistent channel {}",
+                        msg.client_id
+                    );
+                    return Vec::new();
+                };
+                // Let a later request try again
+                self.channel_lookup.remove(&channel.name);
+                vec![ClientEvent::ChannelFailed { cid: msg.client_id }]
+            }
+            ClientMessage::ReadNotifyResponse(msg) => {
+                if self.pending_reads.remove(&msg.client_ioid).is_none() {
+                    warn!("Got ReadNotifyResponse for apparently unknown read request?! {msg:?}");
+                    return Vec::new();
+                };
+                debug!("Processing message {msg:?}");
+                let value = Dbr::from_bytes(msg.data_type, msg.data_count as usize, &msg.data).ok();
+                vec![ClientEvent::ReadComplete {
+                    ioid: msg.client_ioid,
+                    value,
+                }]
+            }
+            // Either the reply to our probe, or the server checking on us
+            ClientMessage::Echo => {
+                if !answered {
+                    self.outgoing.push(Message::Echo);
+                }
+                Vec::new()
+            }
+            ClientMessage::Version(_msg) => {
+                warn!("Got unexpected VERSION message in normal circuit lifecycle.");
+                Vec::new()
+            }
+            ClientMessage::EventAddResponse(msg) => {
+                let Some(subscription) = self.subscriptions.get(&msg.subscription_id) else {
+                    warn!(
+                        "Got subscription message without associated channel: {}",
+                        msg.subscription_id
+                    );
+                    return Vec::new();
+                };
+                let _span = debug_span!("handle_message", cid = subscription.cid).entered();
+                if msg.data.is_empty() {
+                    // This is a special case: The server is confirming termination
+                    // of the subscription (possibly because we asked it to).
+                    debug!(
+                        "Got empty EventAddResponse: Purging subscription {}",
+                        msg.subscription_id
+                    );
+                    self.subscriptions.remove(&msg.subscription_id);
+                    return vec![ClientEvent::SubscriptionEnded {
+                        subscription_id: msg.subscription_id,
+                    }];
+                }
+                let Ok(value) = Dbr::from_bytes(msg.data_type, msg.data_count as usize, &msg.data)
+                else {
+                    error!("Got invalid subscription response from server: {msg:?}");
+                    return Vec::new();
+                };
+                debug!(
+                    "Got subscription {} response: {:?}",
+                    msg.subscription_id, value
+                );
+                vec![ClientEvent::SubscriptionUpdate {
+                    subscription_id: msg.subscription_id,
+                    value,
+                }]
+            }
+            msg => {
+                debug!("Got unhandled message from server: {msg:?}");
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{ClientCircuit, ClientEvent};
+    use crate::{
+        dbr::{Dbr, DbrBasicType, DbrCategory, DbrValue},
+        messages::{AsBytes, CreateChannelResponse, Message, ReadNotify},
+    };
+
+    #[test]
+    fn test_channel_open_and_read() {
+        let now = Instant::now();
+        let mut circuit = ClientCircuit::new("user".to_string(), "host".to_string(), now);
+        let cid = circuit.open_channel("TEST");
+        assert_eq!(circuit.open_channel("TEST"), cid);
+        // Identification and the channel request
+        assert_eq!(circuit.poll_transmit().len(), 3);
+        assert!(circuit.read(cid, DbrCategory::Basic, 0).is_none());
+
+        let response = CreateChannelResponse {
+            data_type: DbrBasicType::Long,
+            data_count: 1,
+            client_id: cid,
+            server_id: 7,
+        };
+        let events = circuit
+            .handle_bytes(&Message::from(response).as_bytes(), now)
+            .unwrap();
+        assert!(matches!(&events[..], [ClientEvent::ChannelReady(info)] if info.cid == cid));
+
+        let ioid = circuit.read(cid, DbrCategory::Basic, 0).unwrap();
+        let [Message::ReadNotify(request)] = &circuit.poll_transmit()[..] else {
+            panic!("Expected a read request");
+        };
+        assert_eq!(request.server_id, 7);
+        let reply = ReadNotify::respond(
+            request,
+            1,
+            Dbr::Basic(DbrValue::Long(vec![42])).to_bytes(None).1,
+        );
+        let events = circuit
+            .handle_bytes(&Message::from(reply).as_bytes(), now)
+            .unwrap();
+        let [ClientEvent::ReadComplete { ioid: read, value }] = &events[..] else {
+            panic!("Expected the read to complete");
+        };
+        assert_eq!(*read, ioid);
+        assert_eq!(value.as_ref().unwrap().value(), &DbrValue::Long(vec![42]));
+    }
+
+    #[test]
+    fn test_echo_from_server_is_answered() {
+        let now = Instant::now();
+        let mut circuit = ClientCircuit::new("user".to_string(), "host".to_string(), now);
+        circuit.poll_transmit();
+        let echo = Message::Echo.as_bytes();
+        circuit.handle_bytes(&echo, now).unwrap();
+        assert!(matches!(&circuit.poll_transmit()[..], [Message::Echo]));
+        // The server's reply to that isn't answered again
+        circuit.handle_bytes(&echo, now).unwrap();
+        assert!(circuit.poll_transmit().is_empty());
+
+        // Nor is the reply to our own probe
+        let later = now + Duration::from_secs(60);
+        assert!(circuit.handle_timeout(later));
+        assert!(matches!(&circuit.poll_transmit()[..], [Message::Echo]));
+        circuit.handle_bytes(&echo, later).unwrap();
+        assert!(circuit.poll_transmit().is_empty());
+    }
+}
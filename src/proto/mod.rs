@@ -0,0 +1,259 @@
+//! Sans-IO state machines for the CA protocol
+//!
+//! The types in this module keep track of the state of a protocol conversation,
+//! but never touch a socket, spawn a task or read the clock. They are fed the
+//! messages (or raw bytes) that arrive, along with the current time, and hand back
+//! the messages to send and events for the caller to act on. Each also says when it
+//! next needs to be woken up, if nothing else happens before then.
+//!
+//! The tokio-based [Client](crate::Client), [Searcher](crate::client::Searcher) and
+//! [Server](crate::Server) are thin adapters over these. For the server, that means
+//! running the provider's async calls and waiting on subscription updates; what to
+//! ask the provider and what to send the client is decided here. The state machines can equally be driven from another executor, or stepped
+//! through directly in tests.
+//!
+//! They still need `std`: times are passed in as [`Instant`]s, they log through
+//! `tracing`, and [`MessageBuffer`] is built on the `tokio_util` codec, although
+//! nothing here needs a tokio runtime.
+//!
+//! - [`search::SearchState`] tracks name searches sent by a client, with retries.
+//! - [`search::SearchResponder`] answers the search datagrams a server receives.
+//! - [`client::ClientCircuit`] is the client end of a TCP circuit to a server.
+//! - [`server::ServerCircuit`] is the server end of a TCP circuit from a client.
+//! - [`Liveness`] decides when to probe a quiet connection, and when to give up.
+
+pub mod client;
+pub mod search;
+pub mod server;
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use tokio_util::{bytes::BytesMut, codec::Decoder};
+
+use crate::messages::{RawMessage, RawMessageDecoder};
+
+/// Splits a stream of bytes into [`RawMessage`]s
+///
+/// Bytes can be added in chunks of any size, as they arrive from the transport.
+#[derive(Default)]
+pub struct MessageBuffer {
+    buffer: BytesMut,
+    decoder: RawMessageDecoder,
+}
+
+impl MessageBuffer {
+    /// Create a buffer that rejects any message with a payload larger than `size`
+    pub fn with_max_payload_size(size: usize) -> Self {
+        MessageBuffer {
+            buffer: BytesMut::new(),
+            decoder: RawMessageDecoder::with_max_payload_size(size),
+        }
+    }
+
+    /// Add bytes received from the transport
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Take the next complete message out of the buffer, if there is one
+    ///
+    /// An error means that the stream cannot be decoded any further, and the
+    /// connection should be closed.
+    pub fn next_message(&mut self) -> io::Result<Option<RawMessage>> {
+        self.decoder.decode(&mut self.buffer)
+    }
+}
+
+/// How long after sending an Echo that one coming back is taken as the reply
+///
+/// Echoes outside of this are the peer's own probes, and need answering. This is
+/// the same time that libca waits for a reply to its own probes.
+const ECHO_REPLY_WINDOW: Duration = Duration::from_secs(5);
+
+/// What a connection should do about a quiet peer, from [`Liveness::handle_timeout`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LivenessAction {
+    /// Nothing to do yet
+    Wait,
+    /// The peer has been quiet for too long, so send it an Echo
+    SendEcho,
+    /// Nothing came back after the Echo, so the connection is dead
+    Disconnect,
+}
+
+/// Tracks whether the other end of a circuit is still there
+///
+/// Both ends of a CA circuit probe the other with an Echo if it has been quiet
+/// for a while. Any message from the peer shows that it is alive. Servers answer
+/// an Echo from a client, but clients take one from the server as proof of life
+/// and don't answer it, so only clients can give up on a peer that stays quiet;
+/// see [`Liveness::keep_probing`].
+#[derive(Clone, Debug)]
+pub struct Liveness {
+    timeout: Duration,
+    last_received: Instant,
+    echo_sent_at: Option<Instant>,
+    /// Until when an Echo from the peer is taken as the reply to ours, even if
+    /// other messages have since shown that the peer is alive
+    echo_reply_due: Option<Instant>,
+    /// When we last answered the peer's Echo, so that answers can't bounce
+    echo_answered_at: Option<Instant>,
+    disconnect: bool,
+}
+
+impl Liveness {
+    pub fn new(timeout: Duration, now: Instant) -> Self {
+        Liveness {
+            timeout,
+            last_received: now,
+            echo_sent_at: None,
+            echo_reply_due: None,
+            echo_answered_at: None,
+            disconnect: true,
+        }
+    }
+
+    /// Never give up on a quiet peer, but keep probing it every `timeout`
+    ///
+    /// This is for servers, which can't tell an idle client from a dead one,
+    /// and have to rely on the transport to notice when the client has gone.
+    pub fn keep_probing(mut self) -> Self {
+        self.disconnect = false;
+        self
+    }
+
+    /// Record that a message has arrived
+    ///
+    /// Any message shows that the peer is still there. Returns `true` if the
+    /// message is an Echo that doesn't need answering, either because it is the
+    /// reply to ours, or because we have only just answered one.
+    pub fn message_received(&mut self, now: Instant, is_echo: bool) -> bool {
+        self.last_received = now;
+        self.echo_sent_at = None;
+        if !is_echo {
+            return false;
+        }
+        if self.echo_reply_due.take().is_some_and(|due| now <= due)
+            || self
+                .echo_answered_at
+                .is_some_and(|answered| now < answered + ECHO_REPLY_WINDOW)
+        {
+            return true;
+        }
+        self.echo_answered_at = Some(now);
+        false
+    }
+
+    /// When [`Liveness::handle_timeout`] next needs to be called
+    pub fn poll_timeout(&self) -> Instant {
+        self.echo_sent_at.unwrap_or(self.last_received) + self.timeout
+    }
+
+    /// Check on the peer, if it has been quiet for long enough
+    pub fn handle_timeout(&mut self, now: Instant) -> LivenessAction {
+        if now < self.poll_timeout() {
+            LivenessAction::Wait
+        } else if self.echo_sent_at.is_some() && self.disconnect {
+            LivenessAction::Disconnect
+        } else {
+            self.echo_sent_at = Some(now);
+            self.echo_reply_due = Some(now + ECHO_REPLY_WINDOW.min(self.timeout));
+            LivenessAction::SendEcho
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Liveness, LivenessAction, MessageBuffer};
+    use crate::messages::{AsBytes, Message, Version};
+
+    #[test]
+    fn test_liveness_probes_then_disconnects() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut liveness = Liveness::new(second, start);
+        assert_eq!(liveness.handle_timeout(start), LivenessAction::Wait);
+        assert_eq!(
+            liveness.handle_timeout(start + second),
+            LivenessAction::SendEcho
+        );
+        // The reply to the probe resets everything
+        assert!(liveness.message_received(start + second, true));
+        assert_eq!(liveness.poll_timeout(), start + 2 * second);
+        assert_eq!(
+            liveness.handle_timeout(start + 2 * second),
+            LivenessAction::SendEcho
+        );
+        assert_eq!(
+            liveness.handle_timeout(start + 3 * second),
+            LivenessAction::Disconnect
+        );
+    }
+
+    #[test]
+    fn test_liveness_only_takes_echo_as_reply() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut liveness = Liveness::new(second, start);
+        assert_eq!(
+            liveness.handle_timeout(start + second),
+            LivenessAction::SendEcho
+        );
+        // Another message keeps the connection alive, but isn't the reply
+        assert!(!liveness.message_received(start + second, false));
+        assert_eq!(liveness.poll_timeout(), start + 2 * second);
+        // So the late reply is still recognised, and only once
+        assert!(liveness.message_received(start + second, true));
+        assert!(!liveness.message_received(start + 10 * second, true));
+    }
+
+    #[test]
+    fn test_liveness_keeps_probing_quiet_peers() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let timeout = 30 * second;
+        let mut liveness = Liveness::new(timeout, start).keep_probing();
+        assert_eq!(
+            liveness.handle_timeout(start + timeout),
+            LivenessAction::SendEcho
+        );
+        assert_eq!(
+            liveness.handle_timeout(start + 2 * timeout),
+            LivenessAction::SendEcho
+        );
+        // An Echo long after ours is the peer's own probe, so needs answering
+        assert!(!liveness.message_received(start + 2 * timeout + 10 * second, true));
+    }
+
+    #[test]
+    fn test_liveness_answers_unasked_echo_once() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut liveness = Liveness::new(30 * second, start);
+        assert!(!liveness.message_received(start, true));
+        // An Echo straight back is the answer to ours, so doesn't bounce forever
+        assert!(liveness.message_received(start + second, true));
+        assert!(!liveness.message_received(start + 10 * second, true));
+    }
+
+    #[test]
+    fn test_message_buffer_reassembles_messages() {
+        let bytes = Message::Version(Version::default()).as_bytes();
+        let mut buffer = MessageBuffer::default();
+        buffer.extend(&bytes[..5]);
+        assert!(buffer.next_message().unwrap().is_none());
+        buffer.extend(&bytes[5..]);
+        let message = buffer.next_message().unwrap().unwrap();
+        assert!(matches!(
+            Message::from_raw_client_message(message),
+            Ok(Message::Version(_))
+        ));
+        assert!(buffer.next_message().unwrap().is_none());
+    }
+}
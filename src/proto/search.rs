@@ -0,0 +1,347 @@
+//! Name searches, from both the client and the server side
+//!
+//! Searches are sent over UDP, usually broadcast, and so can be lost or arrive
+//! more than once. A client retries with an increasing backoff until it hears a
+//! reply or gives up, and a server ignores copies of a search that arrive on
+//! several interfaces at once.
+
+use std::{
+    cmp::min,
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+    messages::{self, AsBytes, Message, MessageError, Search, parse_search_packet},
+    utils::wrapping_inplace_add,
+};
+
+/// The result of a search, from [`SearchState`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SearchEvent {
+    /// A server replied that it has the PV
+    Found { name: String, server: SocketAddr },
+    /// Nobody replied before the search timeout
+    TimedOut { name: String },
+}
+
+#[derive(Debug)]
+struct SearchAttempt {
+    name: String,
+    search_expires_at: Option<Instant>,
+    active_searches: Vec<u32>,
+    next_search_at: Instant,
+}
+
+impl SearchAttempt {
+    /// Recalculate timings and return a new search message
+    fn new_search(&mut self, search_id: u32, now: Instant) -> messages::Search {
+        let backoff =
+            Duration::from_millis(32 * 2u64.pow(min(self.active_searches.len(), 11) as u32));
+        self.active_searches.push(search_id);
+        self.next_search_at = now + backoff;
+        messages::Search {
+            search_id,
+            channel_name: self.name.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Build a single search datagram out of several searches
+fn search_datagram(searches: impl IntoIterator<Item = Search>) -> Vec<u8> {
+    std::iter::once(Message::Version(messages::Version::default()))
+        .chain(searches.into_iter().map(Message::Search))
+        .flat_map(|m| m.as_bytes())
+        .collect()
+}
+
+/// Keeps track of the name searches that a client has sent
+///
+/// Each datagram returned should be sent to every search address.
+#[derive(Debug, Default)]
+pub struct SearchState {
+    /// How long to keep searching for a PV, if not forever
+    timeout: Option<Duration>,
+    /// Search IDs of outstanding requests to the PV name
+    in_flight: HashMap<u32, String>,
+    /// Data about all the PVs we are searching for
+    per_pv_info: HashMap<String, SearchAttempt>,
+    /// The next search ID to send
+    search_id: u32,
+}
+
+impl SearchState {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        SearchState {
+            timeout,
+            ..Default::default()
+        }
+    }
+
+    /// Start searching for some PVs, returning the datagram to send
+    ///
+    /// Searching again for a PV that is already being searched for sends another
+    /// search, but does not restart the timeout.
+    pub fn search(&mut self, names: impl IntoIterator<Item = String>, now: Instant) -> Vec<u8> {
+        let mut searches = Vec::new();
+        for name in names {
+            // Get or create an entry in our per-PV map to keep track of everything
+            let info = self
+                .per_pv_info
+                .entry(name.clone())
+                .or_insert_with(|| SearchAttempt {
+                    name: name.clone(),
+                    search_expires_at: self.timeout.map(|t| now + t),
+                    active_searches: Vec::new(),
+                    next_search_at: now,
+                });
+            let search_id = wrapping_inplace_add(&mut self.search_id);
+            // Register this search attempt
+            self.in_flight.insert(search_id, name.clone());
+            searches.push(info.new_search(search_id, now));
+            debug!("Sending search for {name}");
+        }
+        search_datagram(searches)
+    }
+
+    /// Handle a datagram received in reply to our searches
+    pub fn handle_datagram(&mut self, data: &[u8], sender: SocketAddr) -> Vec<SearchEvent> {
+        let Ok(messages) = Message::parse_many_client_messages(data) else {
+            warn!("Received unparseable search response");
+            return Vec::new();
+        };
+        let mut events = Vec::new();
+        for message in messages {
+            let response = match message {
+                Message::SearchResponse(search_response) => search_response,
+                Message::Version(_) => continue,
+                m => {
+                    warn!("Received unexpected search response: {m:?}");
+                    continue;
+                }
+            };
+            // What was this a response to?
+            let Some(pv_name) = self.in_flight.remove(&response.search_id) else {
+                warn!("Received unrequested or duplicate search response");
+                continue;
+            };
+            // Now we know we have a response to an actual request - clear out any past
+            // requests for this and report it
+            let info = self.per_pv_info.remove(&pv_name).unwrap();
+            for search_id in info.active_searches {
+                self.in_flight.remove(&search_id);
+            }
+            let server = (
+                response.server_ip.map(|i| i.into()).unwrap_or(sender.ip()),
+                response.port_number,
+            )
+                .into();
+            debug!("Found server for {pv_name}: {server:?}");
+            events.push(SearchEvent::Found {
+                name: pv_name,
+                server,
+            });
+        }
+        events
+    }
+
+    /// When [`SearchState::handle_timeout`] next needs to be called, if at all
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.per_pv_info
+            .values()
+            .flat_map(|v| [Some(v.next_search_at), v.search_expires_at])
+            .flatten()
+            .min()
+    }
+
+    /// Give up on expired searches, and retry the others that are due
+    ///
+    /// Returns the searches that were given up on, and a datagram to send if any
+    /// searches are being retried.
+    pub fn handle_timeout(&mut self, now: Instant) -> (Vec<SearchEvent>, Option<Vec<u8>>) {
+        let mut events = Vec::new();
+        self.per_pv_info.retain(|_, v| match v.search_expires_at {
+            Some(time) if time <= now => {
+                for id in v.active_searches.iter() {
+                    let _ = self.in_flight.remove(id);
+                }
+                debug!(
+                    "Dropping search for {} as reached search timeout {:.2} ms ago",
+                    v.name,
+                    (now - time).as_secs_f32() * 1000.0
+                );
+                events.push(SearchEvent::TimedOut {
+                    name: v.name.clone(),
+                });
+                false
+            }
+            _ => true,
+        });
+
+        let mut retries = Vec::new();
+        for attempt in self.per_pv_info.values_mut() {
+            if attempt.next_search_at <= now {
+                debug!("Sending retry search for: {}", attempt.name);
+                let search_id = wrapping_inplace_add(&mut self.search_id);
+                self.in_flight.insert(search_id, attempt.name.clone());
+                retries.push(attempt.new_search(search_id, now));
+            }
+        }
+        let datagram = (!retries.is_empty()).then(|| search_datagram(retries));
+        (events, datagram)
+    }
+}
+
+/// Answers the search datagrams received by a server
+///
+/// This does not know which PVs the server has, so looking them up is left to the
+/// caller, in between [`SearchResponder::handle_datagram`] and
+/// [`SearchResponder::reply`].
+#[derive(Debug)]
+pub struct SearchResponder {
+    server_ip: Option<Ipv4Addr>,
+    connection_port: u16,
+    /// Recent searches and their source ports, to reject copies of the same
+    /// datagram arriving on multiple network interfaces.
+    recent_searches: Vec<(Instant, u16, Vec<Search>)>,
+}
+
+impl SearchResponder {
+    /// Copies of a search that arrive within this long of each other are ignored
+    const REJECTION_WINDOW: Duration = Duration::from_micros(500);
+
+    /// Create a responder for a server that accepts circuits on `connection_port`
+    ///
+    /// If `server_ip` is given, then replies tell the client to connect there,
+    /// otherwise the client connects to the address that the reply came from.
+    pub fn new(server_ip: Option<Ipv4Addr>, connection_port: u16) -> Self {
+        SearchResponder {
+            server_ip,
+            connection_port,
+            recent_searches: Vec::new(),
+        }
+    }
+
+    /// Read the searches out of a datagram
+    ///
+    /// Returns nothing if this is a copy of a datagram that was just handled.
+    pub fn handle_datagram(
+        &mut self,
+        data: &[u8],
+        origin: SocketAddr,
+        now: Instant,
+    ) -> Result<Vec<Search>, MessageError> {
+        let searches = parse_search_packet(data)?;
+        // Drop all requests for identical PVs from the same source port
+        let mut is_duplicate = false;
+        self.recent_searches.retain(|(at, port, previous)| {
+            let keep = now.saturating_duration_since(*at) < Self::REJECTION_WINDOW;
+            if keep && *port == origin.port() && previous == &searches {
+                is_duplicate = true;
+            }
+            keep
+        });
+        if is_duplicate {
+            return Ok(Vec::new());
+        }
+        // This isn't a duplicate search, record it in case it comes in again
+        self.recent_searches
+            .push((now, origin.port(), searches.clone()));
+        Ok(searches)
+    }
+
+    /// Build the reply datagram, given whether each search was for one of our PVs
+    ///
+    /// Returns `None` if there is nothing to reply with.
+    pub fn reply(&self, answers: impl IntoIterator<Item = (Search, bool)>) -> Option<Vec<u8>> {
+        let replies: Vec<Message> = answers
+            .into_iter()
+            .filter_map(|(search, found)| {
+                if found {
+                    Some(
+                        search
+                            .respond(self.server_ip, self.connection_port, true)
+                            .into(),
+                    )
+                } else if search.should_reply {
+                    Some(search.respond_not_found().into())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        if replies.is_empty() {
+            return None;
+        }
+        let mut datagram = messages::Version::default().as_bytes();
+        for reply in &replies {
+            datagram.extend(reply.as_bytes());
+        }
+        Some(datagram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use super::{SearchEvent, SearchResponder, SearchState};
+
+    #[test]
+    fn test_search_is_answered_and_retried() {
+        let now = Instant::now();
+        let client: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let server: SocketAddr = "127.0.0.1:5064".parse().unwrap();
+        let mut state = SearchState::new(Some(Duration::from_secs(1)));
+        let mut responder = SearchResponder::new(None, 6464);
+
+        let datagram = state.search(["TEST".to_string(), "OTHER".to_string()], now);
+        let searches = responder.handle_datagram(&datagram, client, now).unwrap();
+        assert_eq!(searches.len(), 2);
+        // The same datagram arriving on another interface is ignored
+        assert!(
+            responder
+                .handle_datagram(&datagram, client, now)
+                .unwrap()
+                .is_empty()
+        );
+
+        // Only answer the search for TEST
+        let answers = searches.into_iter().map(|s| {
+            let found = s.channel_name == "TEST";
+            (s, found)
+        });
+        let reply = responder.reply(answers).unwrap();
+        assert_eq!(
+            state.handle_datagram(&reply, server),
+            vec![SearchEvent::Found {
+                name: "TEST".to_string(),
+                server: "127.0.0.1:6464".parse().unwrap()
+            }]
+        );
+
+        // OTHER is retried until the timeout, then given up on
+        let (events, retry) = state.handle_timeout(state.poll_timeout().unwrap());
+        assert!(events.is_empty());
+        let retry = retry.unwrap();
+        let searches = responder
+            .handle_datagram(&retry, client, now + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(searches[0].channel_name, "OTHER");
+        let (events, _) = state.handle_timeout(now + Duration::from_secs(1));
+        assert_eq!(
+            events,
+            vec![SearchEvent::TimedOut {
+                name: "OTHER".to_string()
+            }]
+        );
+        assert_eq!(state.poll_timeout(), None);
+    }
+}
@@ -0,0 +1,1312 @@
+//! The server end of a circuit from a CA client
+//!
+//! [`ServerCircuit`] keeps track of the channels and subscriptions that a client has
+//! open on a single TCP connection, and answers the client's messages. Anything that
+//! needs more from the provider than it can say straight away is handed back as a
+//! [`ServerEvent::Request`], and the answer passed back in to
+//! [`ServerCircuit::complete`]. Only one request is made at a time, and no more
+//! messages should be handled until it is answered, so that the client's requests
+//! are still answered in order.
+//!
+//! Subscription updates are kept apart from the other replies, so that a client
+//! that is slow to read doesn't hold up everything else. New values are pulled out
+//! of the provider with [`ServerCircuit::collect_monitor_updates`], coalesced into
+//! the newest value until there is room to send them, and then taken with
+//! [`ServerCircuit::next_pending_update`].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{SocketAddr, SocketAddrV4},
+    num::NonZeroUsize,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::broadcast;
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{
+    dbr::{Dbr, DbrBasicType, DbrCategory, DbrType, DisplayMetadata},
+    messages::{
+        self, Access, AccessRights, CreateChannel, CreateChannelResponse, ECAError, ErrorCondition,
+        EventAdd, EventAddResponse, Message, MessageError, MonitorMask, RawMessage, ReadNotify,
+        ReadNotifyResponse, Search,
+    },
+    proto::{Liveness, LivenessAction},
+    providers::{ChannelMetadata, ClientInfo, MISSED_REMOVALS, Provider, WriteCompletion},
+};
+
+/// How many searches a single circuit can be forwarding to other servers at once.
+///
+/// Searches arriving when this many are in progress are answered as if the name
+/// server did not know of the PV.
+pub const MAX_FORWARDED_SEARCHES: usize = 32;
+
+/// A snapshot of a connected client, and the channels it has open
+#[derive(Clone, Debug)]
+pub struct CircuitInfo {
+    /// Identifies this circuit for as long as the server is running
+    pub id: u64,
+    /// Where the client is connecting from
+    pub address: SocketAddr,
+    /// The host name that the client reported, if it has
+    pub host_name: Option<String>,
+    /// The user name that the client reported, if it has
+    pub user_name: Option<String>,
+    pub channels: Vec<ChannelInfo>,
+}
+
+impl CircuitInfo {
+    /// The number of channels with an active subscription
+    pub fn subscriptions(&self) -> usize {
+        self.channels.iter().filter(|c| c.subscribed).count()
+    }
+}
+
+/// A snapshot of a single open channel on a circuit
+#[derive(Clone, Debug)]
+pub struct ChannelInfo {
+    /// The ID that the server assigned to this channel on the circuit
+    pub server_id: u32,
+    /// The name of the PV that this channel is connected to
+    pub name: String,
+    /// Does the client have a subscription to this channel
+    pub subscribed: bool,
+}
+
+/// Settings for a [`ServerCircuit`]
+#[derive(Clone, Debug)]
+pub struct CircuitSettings {
+    /// How long the client can be quiet before we probe it with an Echo
+    pub inactivity_timeout: Duration,
+    /// The largest message payload that will be sent, if limited
+    pub max_array_bytes: Option<usize>,
+    /// Whether PVs searched for over TCP that we don't have are looked for on other
+    /// servers, as a name server does
+    pub forward_searches: bool,
+}
+
+/// Something the provider needs to be asked, from [`ServerEvent::Request`]
+#[derive(Debug)]
+pub enum ProviderRequest {
+    /// Does the provider have a PV, for a search made over TCP
+    Search(Search),
+    /// Describe the PV that a channel is being opened to
+    ///
+    /// The answer includes the PV's [`Provider::pv_generation`], read before it is
+    /// described, so that a PV replaced in the meantime is not mistaken for it.
+    CreateChannel(CreateChannel),
+    /// Read the PV that a channel is open to
+    Read { request: ReadNotify, name: String },
+    /// Read the initial value of a PV, and start monitoring it
+    Subscribe { request: EventAdd, name: String },
+    /// Write to a PV, for a client that doesn't want to hear back
+    Write {
+        request: messages::Write,
+        name: String,
+        value: Dbr,
+        client: ClientInfo,
+    },
+    /// Write to a PV, for a client waiting to hear once it is done
+    WriteNotify {
+        request: messages::WriteNotify,
+        name: String,
+        value: Dbr,
+        client: ClientInfo,
+    },
+    /// Which of these channels are open to PVs that the provider no longer has
+    CheckProvided(Vec<(u32, String)>),
+}
+
+/// Everything the provider gave us to start a subscription
+pub struct NewSubscription {
+    pub initial: Dbr,
+    /// State names to send enum values as, if the client asked for strings
+    pub enum_strings: Vec<String>,
+    pub receiver: broadcast::Receiver<Dbr>,
+}
+
+/// The provider's answer to a [`ProviderRequest`], along with what was asked
+pub enum Completion {
+    Search(Search, bool),
+    CreateChannel(
+        CreateChannel,
+        Option<u64>,
+        Result<ChannelMetadata, ErrorCondition>,
+    ),
+    Read(ReadNotify, Result<Dbr, ErrorCondition>),
+    Subscribe(EventAdd, Result<NewSubscription, ErrorCondition>),
+    Write(messages::Write, Result<WriteCompletion, ErrorCondition>),
+    WriteNotify(
+        messages::WriteNotify,
+        Result<WriteCompletion, ErrorCondition>,
+    ),
+    /// Channels to PVs that the provider no longer has
+    Unprovided(Vec<u32>),
+}
+
+/// Something for the caller of a [`ServerCircuit`] to do
+pub enum ServerEvent {
+    /// Ask the provider, and pass the answer to [`ServerCircuit::complete`]
+    Request(Box<ProviderRequest>),
+    /// Look for a PV on other servers, and pass where it was found to
+    /// [`ServerCircuit::search_forwarded`]
+    ForwardSearch(Search),
+    /// The provider is still finishing a write. Once it has, pass the result to
+    /// [`ServerCircuit::write_finished`].
+    WriteInProgress(messages::WriteNotify, WriteCompletion),
+}
+
+#[derive(Debug)]
+struct Channel {
+    name: String,
+    /// The largest element count that clients may ask for on this channel
+    max_count: usize,
+    /// Which PV of this name the channel was opened to, if the provider reuses names
+    generation: Option<u64>,
+    client_id: u32,
+    server_id: u32,
+    subscription: Option<PVSubscription>,
+}
+
+#[derive(Debug)]
+struct PVSubscription {
+    data_type: DbrType,
+    data_count: usize,
+    subscription_id: u32,
+    mask: MonitorMask,
+    /// Display information for GR/CTRL subscriptions, read when subscribing
+    display: Option<DisplayMetadata>,
+    /// State names to send enum values as, for subscriptions asking for strings
+    enum_strings: Vec<String>,
+    receiver: broadcast::Receiver<Dbr>,
+    /// The newest value that has not yet been sent to the client
+    pending: Option<Dbr>,
+    /// How many updates were replaced before they could be sent
+    dropped_updates: u64,
+    /// How many dropped updates we have already warned about
+    reported_dropped_updates: u64,
+}
+
+impl PVSubscription {
+    /// Drain all new values from the provider, keeping only the newest one
+    ///
+    /// This is how epics-base handles a full event queue: the newest value replaces
+    /// the last one queued, so that a slow client always gets the most recent value
+    /// once it catches up, without the queue growing unbounded.
+    fn collect_updates(&mut self) {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(dbr) => {
+                    if self.pending.replace(dbr).is_some() {
+                        self.dropped_updates += 1;
+                    }
+                }
+                Err(broadcast::error::TryRecvError::Lagged(missed)) => {
+                    self.dropped_updates += missed;
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Refuse element counts larger than the channel was created with
+///
+/// Counts larger than the current size are padded out with zeros, so without this
+/// a client could ask for an arbitrarily large allocation.
+fn check_data_count(channel: &Channel, data_count: u32) -> Result<(), ErrorCondition> {
+    if data_count as usize > channel.max_count {
+        warn!(
+            "Refusing a count of {data_count} on {}, which has at most {} elements",
+            channel.name, channel.max_count
+        );
+        return Err(ErrorCondition::BadCount);
+    }
+    Ok(())
+}
+
+/// Protocol state of a circuit from a client to this server
+///
+/// The provider is asked directly for anything it can answer synchronously, such as
+/// access rights, and is told as channels and subscriptions come and go. Channels
+/// still open when this is dropped are released.
+pub struct ServerCircuit<L: Provider> {
+    id: u64,
+    settings: CircuitSettings,
+    /// Whether the client is still there, and when to probe it
+    liveness: Liveness,
+    outgoing: Vec<Message>,
+    /// The protocol version the client reported, once it has
+    client_version: Option<u16>,
+    client_host_name: Option<String>,
+    client_user_name: Option<String>,
+    client_events_on: bool,
+    library: L,
+    /// Whether a request is waiting on the provider
+    waiting: bool,
+    channels: HashMap<u32, Channel>,
+    /// Channels with a subscription update waiting, in the order they arrived
+    pending_updates: VecDeque<u32>,
+    next_channel_id: u32,
+    /// The TCP port that the client connected to us on
+    server_port: u16,
+    /// Where the client is connecting from
+    client_address: SocketAddr,
+    /// How many searches are being looked for on other servers
+    forwarded_searches: usize,
+}
+
+impl<L: Provider> Drop for ServerCircuit<L> {
+    fn drop(&mut self) {
+        for channel in self.channels.values() {
+            self.release_channel(channel);
+        }
+    }
+}
+
+impl<L: Provider> ServerCircuit<L> {
+    /// Start tracking a newly connected client
+    ///
+    /// Our Version is the first message sent, and the client must answer with its
+    /// own before anything else.
+    pub fn new(
+        id: u64,
+        library: L,
+        settings: CircuitSettings,
+        server_port: u16,
+        client_address: SocketAddr,
+        now: Instant,
+    ) -> Self {
+        ServerCircuit {
+            id,
+            // Clients don't have to answer our Echo, so we never give up on one
+            liveness: Liveness::new(settings.inactivity_timeout, now).keep_probing(),
+            settings,
+            outgoing: vec![Message::Version(messages::Version::default())],
+            client_version: None,
+            client_host_name: None,
+            client_user_name: None,
+            client_events_on: true,
+            library,
+            waiting: false,
+            channels: HashMap::new(),
+            pending_updates: VecDeque::new(),
+            next_channel_id: 0,
+            server_port,
+            client_address,
+            forwarded_searches: 0,
+        }
+    }
+
+    /// Take the messages that need to be sent to the client
+    ///
+    /// Subscription updates are not included, see
+    /// [`ServerCircuit::next_pending_update`].
+    pub fn poll_transmit(&mut self) -> Vec<Message> {
+        for message in &self.outgoing {
+            trace!("{}: Sending {message:?}", self.id);
+        }
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// When [`ServerCircuit::handle_timeout`] next needs to be called
+    pub fn poll_timeout(&self) -> Instant {
+        self.liveness.poll_timeout()
+    }
+
+    /// Probe the client with an Echo, if it has been quiet
+    ///
+    /// Clients don't have to answer, so dead connections are left for the transport
+    /// to notice, e.g. with TCP keepalive.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        match self.liveness.handle_timeout(now) {
+            LivenessAction::Wait => (),
+            LivenessAction::SendEcho => {
+                debug!(
+                    "{}: No messages from client in {:?}, sending Echo",
+                    self.id, self.settings.inactivity_timeout
+                );
+                self.outgoing.push(Message::Echo);
+            }
+            // We keep probing, so are never told to give up
+            LivenessAction::Disconnect => unreachable!("Server circuits keep probing"),
+        }
+    }
+
+    /// Is a request waiting on the provider?
+    ///
+    /// No more messages should be handled until it has been answered.
+    pub fn waiting_on_provider(&self) -> bool {
+        self.waiting
+    }
+
+    /// Handle a message received from the client, before it has been parsed
+    ///
+    /// Messages that can't be parsed are logged and skipped over.
+    pub fn handle_raw_message(
+        &mut self,
+        message: RawMessage,
+        now: Instant,
+    ) -> Result<Vec<ServerEvent>, MessageError> {
+        let id = self.id;
+        let message = match Message::from_raw_server_message(message) {
+            Ok(message) => message,
+            Err(MessageError::IO(io)) => return Err(MessageError::IO(io)),
+            Err(MessageError::UnknownCommandId(command_id)) => {
+                error!("{id}: Error: Receieved unknown command id: {command_id}");
+                return Ok(Vec::new());
+            }
+            Err(MessageError::ParsingError(msg)) => {
+                error!("{id}: Error: Incoming message parse error: {msg}");
+                return Ok(Vec::new());
+            }
+            Err(MessageError::UnexpectedMessage(msg)) => {
+                error!(
+                    "{id}: Error: Got message from client that is invalid to receive on a server: {msg:?}"
+                );
+                return Ok(Vec::new());
+            }
+            Err(MessageError::IncorrectCommandId(msg, expect)) => {
+                error!("{id}: Error: Decoded command {msg} as {expect}");
+                return Ok(Vec::new());
+            }
+            Err(MessageError::InvalidField(message)) => {
+                error!("{id}: Got invalid message field: {message}");
+                return Ok(Vec::new());
+            }
+            Err(MessageError::ErrorResponse(message)) => {
+                error!("{id}: Got reading server messages generated error response: {message}");
+                return Ok(Vec::new());
+            }
+        };
+        self.handle_message(message, now)
+    }
+
+    /// Handle a message received from the client
+    ///
+    /// An error means that the client did not start with a Version, and the circuit
+    /// should be closed.
+    pub fn handle_message(
+        &mut self,
+        message: Message,
+        now: Instant,
+    ) -> Result<Vec<ServerEvent>, MessageError> {
+        assert!(
+            !self.waiting,
+            "Messages are not handled while waiting on the provider"
+        );
+        let id = self.id;
+        if self
+            .liveness
+            .message_received(now, matches!(message, Message::Echo))
+        {
+            // This is the reply to our probe, so doesn't need answering
+            return Ok(Vec::new());
+        }
+        // The version is the bare minimum we need to establish a valid circuit
+        if self.client_version.is_none() {
+            let Message::Version(version) = message else {
+                return Err(MessageError::UnexpectedMessage(message));
+            };
+            debug!("{id}: Got client version: {}", version.protocol_version);
+            self.client_version = Some(version.protocol_version);
+            return Ok(Vec::new());
+        }
+        let messages = match message {
+            Message::Echo => vec![Message::Echo],
+            Message::Search(search) => {
+                debug!("{id}: Got search for {}", search.channel_name);
+                return Ok(self.request(ProviderRequest::Search(search)));
+            }
+            Message::EventAdd(msg) => {
+                debug!("{id}: {}: Got {:?}", msg.server_id, msg);
+                match self.check_subscription(&msg) {
+                    Ok(name) => {
+                        return Ok(self.request(ProviderRequest::Subscribe { request: msg, name }));
+                    }
+                    Err(e) => self.subscription_failed(msg, e),
+                }
+            }
+            Message::EventCancel(msg) => {
+                debug!("{id}:{}: Got {:?}", msg.server_id, msg);
+                let client = self.client_info();
+                let Some(channel) = self.channels.get_mut(&msg.server_id) else {
+                    let id = msg.subscription_id;
+                    self.outgoing.push(Message::ECAError(ECAError::new(
+                        ErrorCondition::BadChId,
+                        id,
+                        Message::EventCancel(msg),
+                    )));
+                    return Ok(Vec::new());
+                };
+                if channel
+                    .subscription
+                    .take_if(|s| s.subscription_id == msg.subscription_id)
+                    .is_none()
+                {
+                    let id = msg.subscription_id;
+                    self.outgoing.push(Message::ECAError(ECAError::new(
+                        ErrorCondition::BadMonId,
+                        id,
+                        Message::EventCancel(msg),
+                    )));
+                    return Ok(Vec::new());
+                }
+                self.library.subscription_removed(&channel.name, &client);
+                // Confirm the cancellation with an empty update, as rsrv does
+                vec![Message::EventAddResponse(EventAddResponse {
+                    data_type: msg.data_type,
+                    data_count: msg.data_count,
+                    subscription_id: msg.subscription_id,
+                    status_code: ErrorCondition::Normal,
+                    data: Vec::new(),
+                })]
+            }
+            Message::ClientName(name) if self.client_user_name.is_none() => {
+                info!("{id}: Got client username: {}", name.name);
+                self.client_user_name = Some(name.name);
+                Vec::default()
+            }
+            Message::EventsOff => {
+                // The client is struggling to keep up. Until it turns events back on,
+                // we only keep the latest value for each subscription.
+                debug!("{id}: Client requested events off");
+                self.client_events_on = false;
+                Vec::default()
+            }
+            Message::EventsOn => {
+                debug!("{id}: Client requested events on");
+                self.client_events_on = true;
+                Vec::default()
+            }
+            Message::HostName(name) if self.client_host_name.is_none() => {
+                info!("{id}: Got client hostname: {}", name.name);
+                self.client_host_name = Some(name.name);
+                Vec::default()
+            }
+            Message::CreateChannel(message) => {
+                info!(
+                    "{id}: Got request to create channel to: {}",
+                    message.channel_name
+                );
+                return Ok(self.request(ProviderRequest::CreateChannel(message)));
+            }
+            Message::ClearChannel(message) => {
+                info!("{id}:{}: Request to clear channel", message.server_id);
+                if let Some(channel) = self.channels.remove(&message.server_id) {
+                    self.release_channel(&channel);
+                }
+                Vec::default()
+            }
+            Message::ReadNotify(msg) => {
+                info!("{id}:{}: ReadNotify request: {:?}", msg.server_id, msg);
+                match self.check_read(&msg) {
+                    Ok(name) => {
+                        return Ok(self.request(ProviderRequest::Read { request: msg, name }));
+                    }
+                    Err(e) => vec![read_failed(msg, e)],
+                }
+            }
+            Message::Write(msg) => {
+                debug!("{id}:{}: Write request: {:?}", msg.server_id, msg);
+                match self.check_write(msg.server_id, msg.data_type, msg.data_count, &msg.data) {
+                    Ok((name, value)) => {
+                        let client = self.client_info();
+                        return Ok(self.request(ProviderRequest::Write {
+                            request: msg,
+                            name,
+                            value,
+                            client,
+                        }));
+                    }
+                    Err(e) => write_failed(msg, e),
+                }
+            }
+            Message::WriteNotify(msg) => {
+                debug!("{id}:{}: WriteNotify request: {:?}", msg.server_id, msg);
+                match self.check_write(msg.server_id, msg.data_type, msg.data_count, &msg.data) {
+                    Ok((name, value)) => {
+                        let client = self.client_info();
+                        return Ok(self.request(ProviderRequest::WriteNotify {
+                            request: msg,
+                            name,
+                            value,
+                            client,
+                        }));
+                    }
+                    Err(e) => vec![Message::WriteNotifyResponse(msg.respond(e.eca_code()))],
+                }
+            }
+            msg => {
+                error!("{id}: Error: Unexpected message: {msg:?}");
+                Vec::new()
+            }
+        };
+        self.outgoing.extend(messages);
+        Ok(Vec::new())
+    }
+
+    /// Answer a request, now that the provider has
+    pub fn complete(&mut self, completion: Completion) -> Vec<ServerEvent> {
+        self.waiting = false;
+        let messages = match completion {
+            Completion::Search(search, found) => return self.answer_search(search, found),
+            Completion::CreateChannel(message, generation, metadata) => {
+                self.create_channel(message, generation, metadata)
+            }
+            Completion::Read(msg, result) => {
+                match result.and_then(|dbr| self.read_response(&msg, dbr)) {
+                    Ok(response) => {
+                        debug!("Sending response: {response:?}");
+                        vec![Message::ReadNotifyResponse(response)]
+                    }
+                    Err(e) => vec![read_failed(msg, e)],
+                }
+            }
+            Completion::Subscribe(msg, result) => {
+                match result.and_then(|subscription| self.add_subscription(&msg, subscription)) {
+                    Ok(Some(response)) => vec![self.limit_update_size(response).into()],
+                    // The channel was closed while we were waiting
+                    Ok(None) => Vec::new(),
+                    Err(e) => self.subscription_failed(msg, e),
+                }
+            }
+            Completion::Write(msg, Err(e)) => write_failed(msg, e),
+            // Nobody is waiting to hear when the write has finished
+            Completion::Write(_, Ok(_)) => Vec::new(),
+            Completion::WriteNotify(msg, Err(e)) => {
+                vec![Message::WriteNotifyResponse(msg.respond(e.eca_code()))]
+            }
+            Completion::WriteNotify(msg, Ok(mut finished)) => {
+                // Most writes are finished as soon as the value is stored
+                let mut cx = Context::from_waker(Waker::noop());
+                let Poll::Ready(result) = finished.as_mut().poll(&mut cx) else {
+                    return vec![ServerEvent::WriteInProgress(msg, finished)];
+                };
+                let status = result.err().unwrap_or(ErrorCondition::Normal);
+                vec![Message::WriteNotifyResponse(msg.respond(status.eca_code()))]
+            }
+            Completion::Unprovided(server_ids) => {
+                for server_id in server_ids {
+                    if self.channels.contains_key(&server_id) {
+                        self.disconnect_channel(server_id);
+                    }
+                }
+                Vec::new()
+            }
+        };
+        self.outgoing.extend(messages);
+        Vec::new()
+    }
+
+    /// Answer a WriteNotify that the provider has finished
+    pub fn write_finished(
+        &mut self,
+        request: messages::WriteNotify,
+        result: Result<(), ErrorCondition>,
+    ) {
+        let status = result.err().unwrap_or(ErrorCondition::Normal);
+        self.outgoing.push(Message::WriteNotifyResponse(
+            request.respond(status.eca_code()),
+        ));
+    }
+
+    /// Answer a search that was forwarded to other servers
+    ///
+    /// `server` is where the PV was found, if anywhere.
+    pub fn search_forwarded(&mut self, search: Search, server: Option<SocketAddrV4>) {
+        self.forwarded_searches -= 1;
+        match server {
+            Some(server) => {
+                debug!(
+                    "{}: Forwarding {} to {server}",
+                    self.id, search.channel_name
+                );
+                self.outgoing.push(
+                    search
+                        .respond(Some(*server.ip()), server.port(), true)
+                        .into(),
+                );
+            }
+            None if search.should_reply => self.outgoing.push(search.respond_not_found().into()),
+            None => (),
+        }
+    }
+
+    /// Handle a name sent by [`Provider::watch_removed`]
+    ///
+    /// Every channel open to the PV is disconnected, unless it was opened to a PV
+    /// that has since replaced it.
+    pub fn pv_removed(&mut self, pv_name: &str) -> Vec<ServerEvent> {
+        if pv_name == MISSED_REMOVALS {
+            return self.removals_missed();
+        }
+        let current = self.library.pv_generation(pv_name);
+        let server_ids: Vec<u32> = self
+            .channels
+            .values()
+            .filter(|c| c.name == pv_name && (c.generation.is_none() || c.generation != current))
+            .map(|c| c.server_id)
+            .collect();
+        for server_id in server_ids {
+            self.disconnect_channel(server_id);
+        }
+        Vec::new()
+    }
+
+    /// Look for channels to PVs that the provider no longer provides
+    ///
+    /// This is for when some removals were missed. Channels to PVs that have been
+    /// replaced by another of the same name are disconnected straight away, if the
+    /// provider tells them apart, and the provider is asked about the rest.
+    pub fn removals_missed(&mut self) -> Vec<ServerEvent> {
+        let replaced: Vec<u32> = self
+            .channels
+            .values()
+            .filter(|c| {
+                c.generation.is_some() && self.library.pv_generation(&c.name) != c.generation
+            })
+            .map(|c| c.server_id)
+            .collect();
+        for server_id in replaced {
+            self.disconnect_channel(server_id);
+        }
+        let channels: Vec<(u32, String)> = self
+            .channels
+            .values()
+            .map(|c| (c.server_id, c.name.clone()))
+            .collect();
+        if channels.is_empty() {
+            return Vec::new();
+        }
+        self.request(ProviderRequest::CheckProvided(channels))
+    }
+
+    /// Forget about a channel, and tell the client that it has gone away
+    pub fn disconnect_channel(&mut self, server_id: u32) {
+        let Some(channel) = self.channels.remove(&server_id) else {
+            warn!(
+                "{}: Asked to disconnect unknown channel {server_id}",
+                self.id
+            );
+            return;
+        };
+        info!(
+            "{}:{server_id}: Disconnecting channel to {}",
+            self.id, channel.name
+        );
+        self.release_channel(&channel);
+        self.outgoing
+            .push(Message::ServerDisconnect(messages::ServerDisconnect {
+                client_id: channel.client_id,
+            }));
+    }
+
+    /// Disconnect every channel, e.g. when the server is shutting down
+    ///
+    /// This lets the client promptly look for the PVs elsewhere.
+    pub fn disconnect_all(&mut self) {
+        let server_ids: Vec<u32> = self.channels.keys().copied().collect();
+        for server_id in server_ids {
+            self.disconnect_channel(server_id);
+        }
+    }
+
+    /// Describe the current state of this circuit
+    pub fn info(&self) -> CircuitInfo {
+        let mut channels: Vec<_> = self
+            .channels
+            .values()
+            .map(|c| ChannelInfo {
+                server_id: c.server_id,
+                name: c.name.clone(),
+                subscribed: c.subscription.is_some(),
+            })
+            .collect();
+        channels.sort_by_key(|c| c.server_id);
+        CircuitInfo {
+            id: self.id,
+            address: self.client_address,
+            host_name: self.client_host_name.clone(),
+            user_name: self.client_user_name.clone(),
+            channels,
+        }
+    }
+
+    /// Pull any new values out of the subscriptions, after a provider has sent some
+    ///
+    /// Triggers name the PV that changed, but providers that wrap others may not
+    /// know it by the same name that we do, so every subscription is checked.
+    /// Channels that now have an update waiting join the back of the queue to be
+    /// sent, so that a PV that updates quickly can't starve the others.
+    pub fn collect_monitor_updates(&mut self) {
+        for channel in self.channels.values_mut() {
+            if let Some(subscription) = channel.subscription.as_mut() {
+                let was_pending = subscription.pending.is_some();
+                subscription.collect_updates();
+                if !was_pending && subscription.pending.is_some() {
+                    self.pending_updates.push_back(channel.server_id);
+                }
+            }
+        }
+    }
+
+    /// Are there subscription updates to send, and does the client want them?
+    pub fn has_pending_updates(&self) -> bool {
+        self.client_events_on && !self.pending_updates.is_empty()
+    }
+
+    /// Take the next waiting subscription update, ready to send to the client
+    ///
+    /// If updates were coalesced since the last one sent, the client is first sent
+    /// a warning, in the same way that epics-base tells clients its server has
+    /// fallen behind. Channels that have since closed are skipped over.
+    pub fn next_pending_update(&mut self) -> Vec<Message> {
+        let (channel, subscription, dbr) = loop {
+            let Some(server_id) = self.pending_updates.pop_front() else {
+                return Vec::new();
+            };
+            let Some(channel) = self.channels.get_mut(&server_id) else {
+                continue;
+            };
+            let Some(subscription) = channel.subscription.as_mut() else {
+                continue;
+            };
+            if let Some(dbr) = subscription.pending.take() {
+                break (server_id, subscription, dbr);
+            }
+        };
+        let mut messages = Vec::new();
+        if subscription.dropped_updates > subscription.reported_dropped_updates {
+            warn!(
+                "{}:{channel}: Client is not keeping up, {} updates to subscription {} coalesced",
+                self.id,
+                subscription.dropped_updates - subscription.reported_dropped_updates,
+                subscription.subscription_id,
+            );
+            subscription.reported_dropped_updates = subscription.dropped_updates;
+            let request = EventAdd {
+                data_type: subscription.data_type,
+                data_count: subscription.data_count as u32,
+                server_id: channel,
+                subscription_id: subscription.subscription_id,
+                mask: subscription.mask,
+            };
+            messages.push(Message::ECAError(ECAError::new(
+                ErrorCondition::ServBehind,
+                subscription.subscription_id,
+                Message::EventAdd(request),
+            )));
+        }
+        let dbr = if subscription.data_type.basic_type == DbrBasicType::String {
+            dbr.label_enum(&subscription.enum_strings)
+        } else {
+            dbr
+        };
+        let dbr = match &subscription.display {
+            Some(display) if dbr.display().is_none() => dbr.with_display(display.clone()),
+            _ => dbr,
+        };
+        let (item_count, data) = match dbr.convert_to(subscription.data_type) {
+            Ok(dbr) => dbr.to_bytes(NonZeroUsize::new(subscription.data_count)),
+            Err(e) => {
+                error!(
+                    "{}:{channel}: Could not convert update {dbr:?} to {:?}: {e}",
+                    self.id, subscription.data_type
+                );
+                return messages;
+            }
+        };
+        let response = EventAddResponse {
+            data_type: subscription.data_type,
+            data_count: item_count as u32,
+            subscription_id: subscription.subscription_id,
+            status_code: ErrorCondition::Normal,
+            data,
+        };
+        messages.push(Message::EventAddResponse(self.limit_update_size(response)));
+        messages
+    }
+
+    /// Who the provider is told is on the other end of this circuit
+    fn client_info(&self) -> ClientInfo {
+        ClientInfo {
+            user: self.client_user_name.clone(),
+            host: self.client_host_name.clone(),
+            address: self.client_address,
+        }
+    }
+
+    /// What the provider allows the client on this circuit to do with a PV
+    fn access_to(&self, name: &str) -> Access {
+        self.library.get_access_right(
+            name,
+            self.client_user_name.as_deref(),
+            self.client_host_name.as_deref(),
+        )
+    }
+
+    /// Tell the provider that a channel, and any subscription on it, has gone
+    fn release_channel(&self, channel: &Channel) {
+        let client = self.client_info();
+        if channel.subscription.is_some() {
+            self.library.subscription_removed(&channel.name, &client);
+        }
+        self.library.channel_cleared(&channel.name, &client);
+    }
+
+    /// Ask the provider for something, holding further messages until it answers
+    fn request(&mut self, request: ProviderRequest) -> Vec<ServerEvent> {
+        assert!(
+            !self.waiting,
+            "Only one request is made of the provider at a time"
+        );
+        self.waiting = true;
+        vec![ServerEvent::Request(Box::new(request))]
+    }
+
+    /// Check that a response payload is within the configured array size limit
+    fn check_payload_size(&self, size: usize) -> Result<(), ErrorCondition> {
+        match self.settings.max_array_bytes {
+            Some(max_size) if size > max_size => {
+                warn!(
+                    "{}: Refusing to send {size} bytes, larger than the limit of {max_size}",
+                    self.id
+                );
+                Err(ErrorCondition::TooLarge)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace an oversized subscription update with an error status and no data
+    fn limit_update_size(&self, response: EventAddResponse) -> EventAddResponse {
+        match self.check_payload_size(response.data.len()) {
+            Ok(()) => response,
+            Err(status_code) => EventAddResponse {
+                data_count: 0,
+                status_code,
+                data: Vec::new(),
+                ..response
+            },
+        }
+    }
+
+    /// Answer a search made over TCP, as sent by clients using us as a name server
+    fn answer_search(&mut self, search: Search, found: bool) -> Vec<ServerEvent> {
+        if found {
+            // The client connects to the address this circuit is on. Like rsrv, we
+            // include our protocol version, even though this is over TCP.
+            self.outgoing
+                .push(search.respond(None, self.server_port, true).into());
+            return Vec::new();
+        }
+        if self.settings.forward_searches {
+            if self.forwarded_searches < MAX_FORWARDED_SEARCHES {
+                // Look for another server that has this, without holding up the circuit
+                self.forwarded_searches += 1;
+                return vec![ServerEvent::ForwardSearch(search)];
+            }
+            warn!(
+                "{}: Too many searches being forwarded, not looking for {}",
+                self.id, search.channel_name
+            );
+        }
+        if search.should_reply {
+            self.outgoing.push(search.respond_not_found().into());
+        }
+        Vec::new()
+    }
+
+    /// Check that a read can be made, returning the name of the PV to read
+    fn check_read(&self, request: &ReadNotify) -> Result<String, ErrorCondition> {
+        let channel = self
+            .channels
+            .get(&request.server_id)
+            .ok_or(ErrorCondition::BadChId)?;
+        if !self.access_to(&channel.name).can_read() {
+            return Err(ErrorCondition::NoRdAccess);
+        }
+        // A count above the current size is padded with zeros, so check it first
+        check_data_count(channel, request.data_count)?;
+        self.check_payload_size(
+            request.data_count as usize * request.data_type.basic_type.element_size(),
+        )?;
+        Ok(channel.name.clone())
+    }
+
+    fn read_response(
+        &self,
+        request: &ReadNotify,
+        pv: Dbr,
+    ) -> Result<ReadNotifyResponse, ErrorCondition> {
+        // Read the data into a Vec<u8>. A count of zero gives the current size.
+        let (data_count, data) = pv
+            .convert_to(request.data_type)?
+            .to_bytes(NonZeroUsize::new(request.data_count as usize));
+        self.check_payload_size(data.len())?;
+        Ok(request.respond(data_count, data))
+    }
+
+    /// Check that a subscription can be made, returning the name of the PV to watch
+    fn check_subscription(&self, msg: &EventAdd) -> Result<String, ErrorCondition> {
+        let channel = self
+            .channels
+            .get(&msg.server_id)
+            .ok_or(ErrorCondition::BadChId)?;
+        check_data_count(channel, msg.data_count)?;
+        // The record type never changes, so there is nothing to monitor
+        if msg.data_type.category == DbrCategory::ClassName {
+            return Err(ErrorCondition::BadType);
+        }
+        if !self.access_to(&channel.name).can_read() {
+            return Err(ErrorCondition::NoRdAccess);
+        }
+        // Every update is padded out to a non-zero count, so check it will fit
+        self.check_payload_size(msg.data_count as usize * msg.data_type.basic_type.element_size())?;
+        Ok(channel.name.clone())
+    }
+
+    /// Start a subscription on a channel, returning the initial value
+    ///
+    /// Returns nothing if the channel has gone away since the subscription was asked for.
+    fn add_subscription(
+        &mut self,
+        msg: &EventAdd,
+        subscription: NewSubscription,
+    ) -> Result<Option<EventAddResponse>, ErrorCondition> {
+        let response = msg.respond(&subscription.initial)?;
+        let client = self.client_info();
+        let Some(channel) = self.channels.get_mut(&msg.server_id) else {
+            return Ok(None);
+        };
+        // A new subscription on the same channel replaces the old one
+        if channel.subscription.is_some() {
+            self.library.subscription_removed(&channel.name, &client);
+        }
+        self.library.subscription_added(&channel.name, &client);
+        channel.subscription = Some(PVSubscription {
+            data_type: msg.data_type,
+            data_count: msg.data_count as usize,
+            mask: msg.mask,
+            subscription_id: msg.subscription_id,
+            display: subscription.initial.display().cloned(),
+            enum_strings: subscription.enum_strings,
+            receiver: subscription.receiver,
+            pending: None,
+            dropped_updates: 0,
+            reported_dropped_updates: 0,
+        });
+        Ok(Some(response))
+    }
+
+    fn subscription_failed(&self, msg: EventAdd, error: ErrorCondition) -> Vec<Message> {
+        warn!(
+            "{}:{}: Could not subscribe: {error}",
+            self.id, msg.server_id
+        );
+        let id = msg.subscription_id;
+        vec![Message::ECAError(ECAError::new(
+            error,
+            id,
+            Message::EventAdd(msg),
+        ))]
+    }
+
+    /// Check that a write can be made, returning the PV name and value to write
+    fn check_write(
+        &self,
+        server_id: u32,
+        data_type: DbrType,
+        data_count: u32,
+        data: &[u8],
+    ) -> Result<(String, Dbr), ErrorCondition> {
+        // Like rsrv, we only accept plain values for writes
+        if data_type.category != DbrCategory::Basic {
+            return Err(ErrorCondition::BadType);
+        }
+        let channel = self
+            .channels
+            .get(&server_id)
+            .ok_or(ErrorCondition::BadChId)?;
+        // Clients should not try to write without access, but don't rely on it
+        if !self.access_to(&channel.name).can_write() {
+            return Err(ErrorCondition::NoWtAccess);
+        }
+
+        // Failing to decode means there was less data than the count claimed
+        let dbr = Dbr::from_bytes(data_type, data_count as usize, data)
+            .map_err(|_| ErrorCondition::BadCount)?;
+        debug!("Got write request: {dbr:?}");
+        Ok((channel.name.clone(), dbr))
+    }
+
+    fn create_channel(
+        &mut self,
+        message: CreateChannel,
+        generation: Option<u64>,
+        metadata: Result<ChannelMetadata, ErrorCondition>,
+    ) -> Vec<Message> {
+        let Ok(metadata) = metadata else {
+            warn!(
+                "Got a request for channel to '{}', which we do not appear to have.",
+                message.channel_name
+            );
+            return vec![Message::CreateChannelFailure(message.respond_failure())];
+        };
+        let access_rights = AccessRights {
+            client_id: message.client_id,
+            access_rights: self.access_to(&message.channel_name),
+        };
+        let id = self.next_channel_id;
+        self.next_channel_id += 1;
+        let createchan = CreateChannelResponse {
+            data_count: metadata.max_count as u32,
+            data_type: metadata.native_type,
+            client_id: message.client_id,
+            server_id: id,
+        };
+        info!(
+            "{}:{}: Opening {:?} channel to {}",
+            self.id, id, access_rights.access_rights, message.channel_name
+        );
+        self.library
+            .channel_created(&message.channel_name, &self.client_info());
+        self.channels.insert(
+            id,
+            Channel {
+                name: message.channel_name,
+                max_count: metadata.max_count,
+                generation,
+                server_id: id,
+                client_id: message.client_id,
+                subscription: None,
+            },
+        );
+        // We have this channel, send the initial
+        vec![
+            Message::AccessRights(access_rights),
+            Message::CreateChannelResponse(createchan),
+        ]
+    }
+}
+
+/// The error sent to a client for a failed read
+fn read_failed(msg: ReadNotify, error: ErrorCondition) -> Message {
+    let err = ECAError::new(error, msg.client_ioid, Message::ReadNotify(msg));
+    error!("Returning error: {err:?}");
+    Message::ECAError(err)
+}
+
+/// The error sent to a client for a failed write, that didn't ask for a reply
+fn write_failed(msg: messages::Write, error: ErrorCondition) -> Vec<Message> {
+    let id = msg.client_ioid;
+    vec![Message::ECAError(ECAError::new(
+        error,
+        id,
+        Message::Write(msg),
+    ))]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::{Duration, Instant},
+    };
+
+    use tokio::sync::broadcast;
+
+    use super::{
+        CircuitSettings, Completion, NewSubscription, ProviderRequest, ServerCircuit, ServerEvent,
+    };
+    use crate::{
+        dbr::{Dbr, DbrBasicType, DbrCategory, DbrType, DbrValue},
+        messages::{CreateChannel, ErrorCondition, EventAdd, Message, ReadNotify, Version},
+        providers::{ChannelMetadata, IntercomProvider},
+    };
+
+    const LONG: DbrType = DbrType {
+        basic_type: DbrBasicType::Long,
+        category: DbrCategory::Basic,
+    };
+
+    fn connect(now: Instant) -> ServerCircuit<IntercomProvider> {
+        let mut provider = IntercomProvider::new();
+        provider.add_pv("TEST", 0i32).unwrap();
+        let settings = CircuitSettings {
+            inactivity_timeout: Duration::from_secs(60),
+            max_array_bytes: None,
+            forward_searches: false,
+        };
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, 5065));
+        let mut circuit = ServerCircuit::new(0, provider, settings, 5064, address, now);
+        assert!(matches!(
+            &circuit.poll_transmit()[..],
+            [Message::Version(_)]
+        ));
+        circuit
+            .handle_message(Message::Version(Version::default()), now)
+            .unwrap();
+        circuit
+    }
+
+    /// Open a channel to TEST, answering the provider request ourselves
+    fn open_channel(circuit: &mut ServerCircuit<IntercomProvider>, now: Instant) -> u32 {
+        let request = CreateChannel {
+            client_id: 3,
+            channel_name: "TEST".to_string(),
+            ..Default::default()
+        };
+        let events = circuit
+            .handle_message(Message::CreateChannel(request), now)
+            .unwrap();
+        let Ok([ServerEvent::Request(request)]) = <[_; 1]>::try_from(events) else {
+            panic!("Expected a provider request");
+        };
+        let ProviderRequest::CreateChannel(request) = *request else {
+            panic!("Expected a channel request");
+        };
+        assert!(circuit.waiting_on_provider());
+        let metadata = Ok(ChannelMetadata::new(DbrBasicType::Long, 1));
+        assert!(
+            circuit
+                .complete(Completion::CreateChannel(request, None, metadata))
+                .is_empty()
+        );
+        assert!(!circuit.waiting_on_provider());
+        let [
+            Message::AccessRights(_),
+            Message::CreateChannelResponse(response),
+        ] = &circuit.poll_transmit()[..]
+        else {
+            panic!("Expected the channel to open");
+        };
+        assert_eq!(response.client_id, 3);
+        response.server_id
+    }
+
+    #[test]
+    fn test_version_must_come_first() {
+        let now = Instant::now();
+        let mut circuit = ServerCircuit::new(
+            0,
+            IntercomProvider::new(),
+            CircuitSettings {
+                inactivity_timeout: Duration::from_secs(60),
+                max_array_bytes: None,
+                forward_searches: false,
+            },
+            5064,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 5065)),
+            now,
+        );
+        let request = Message::CreateChannel(CreateChannel::default());
+        assert!(circuit.handle_message(request, now).is_err());
+    }
+
+    #[test]
+    fn test_channel_open_and_read() {
+        let now = Instant::now();
+        let mut circuit = connect(now);
+        let server_id = open_channel(&mut circuit, now);
+        assert_eq!(circuit.info().channels.len(), 1);
+
+        let read = ReadNotify {
+            data_type: LONG,
+            data_count: 1,
+            server_id,
+            client_ioid: 9,
+        };
+        let events = circuit
+            .handle_message(Message::ReadNotify(read), now)
+            .unwrap();
+        let Ok([ServerEvent::Request(request)]) = <[_; 1]>::try_from(events) else {
+            panic!("Expected a provider request");
+        };
+        let ProviderRequest::Read {
+            request: read,
+            name,
+        } = *request
+        else {
+            panic!("Expected a read request");
+        };
+        assert_eq!(name, "TEST");
+        let value = Dbr::Basic(DbrValue::Long(vec![42]));
+        circuit.complete(Completion::Read(read, Ok(value)));
+        let [Message::ReadNotifyResponse(response)] = &circuit.poll_transmit()[..] else {
+            panic!("Expected a read response");
+        };
+        assert_eq!(response.client_ioid, 9);
+
+        circuit.disconnect_channel(server_id);
+        assert!(matches!(
+            &circuit.poll_transmit()[..],
+            [Message::ServerDisconnect(_)]
+        ));
+        assert!(circuit.info().channels.is_empty());
+    }
+
+    #[test]
+    fn test_slow_client_updates_coalesce() {
+        let now = Instant::now();
+        let mut circuit = connect(now);
+        let server_id = open_channel(&mut circuit, now);
+
+        let subscribe = EventAdd {
+            data_type: LONG,
+            data_count: 1,
+            server_id,
+            subscription_id: 5,
+            mask: Default::default(),
+        };
+        let events = circuit
+            .handle_message(Message::EventAdd(subscribe), now)
+            .unwrap();
+        let Ok([ServerEvent::Request(request)]) = <[_; 1]>::try_from(events) else {
+            panic!("Expected a provider request");
+        };
+        let ProviderRequest::Subscribe {
+            request: subscribe, ..
+        } = *request
+        else {
+            panic!("Expected a subscription request");
+        };
+        let (sender, receiver) = broadcast::channel(8);
+        let subscription = NewSubscription {
+            initial: Dbr::Basic(DbrValue::Long(vec![0])),
+            enum_strings: Vec::new(),
+            receiver,
+        };
+        circuit.complete(Completion::Subscribe(subscribe, Ok(subscription)));
+        assert!(matches!(
+            &circuit.poll_transmit()[..],
+            [Message::EventAddResponse(_)]
+        ));
+        assert!(circuit.info().channels[0].subscribed);
+
+        for value in 1..=3 {
+            sender
+                .send(Dbr::Basic(DbrValue::Long(vec![value])))
+                .unwrap();
+        }
+        circuit.collect_monitor_updates();
+        assert!(circuit.has_pending_updates());
+        let [
+            Message::ECAError(warning),
+            Message::EventAddResponse(update),
+        ] = &circuit.next_pending_update()[..]
+        else {
+            panic!("Expected a warning and the newest value");
+        };
+        assert!(matches!(warning.condition, ErrorCondition::ServBehind));
+        assert_eq!(update.data, 3i32.to_be_bytes());
+        assert!(!circuit.has_pending_updates());
+
+        circuit.handle_message(Message::EventsOff, now).unwrap();
+        sender.send(Dbr::Basic(DbrValue::Long(vec![4]))).unwrap();
+        circuit.collect_monitor_updates();
+        assert!(!circuit.has_pending_updates());
+    }
+}
@@ -0,0 +1,879 @@
+//! Combine several [Provider] implementations into one
+//!
+//! A [Server](crate::Server) only takes a single provider. [ProviderSet] lets several
+//! providers, possibly of different types, be served together, either by asking each
+//! in turn or by routing PV names with a given prefix to a specific provider.
+//! [AliasProvider] exposes PVs of another provider under additional names.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{self},
+    },
+    task::JoinHandle,
+};
+use tracing::warn;
+
+use crate::{
+    dbr::{Dbr, DbrType},
+    messages::{self, ErrorCondition, MonitorMask},
+    providers::{
+        BoxFuture, ChannelMetadata, ClientInfo, MISSED_REMOVALS, Provider, WriteCompletion,
+    },
+};
+
+/// Object-safe version of [Provider], so that different providers can be stored together
+trait DynProvider: Send + Sync {
+    fn provides(&self, pv_name: &str) -> bool;
+    fn read_value(
+        &self,
+        pv_name: &str,
+        requested_type: Option<DbrType>,
+    ) -> Result<Dbr, ErrorCondition>;
+    fn channel_metadata(&self, pv_name: &str) -> Result<ChannelMetadata, ErrorCondition>;
+    fn get_access_right(
+        &self,
+        pv_name: &str,
+        client_user_name: Option<&str>,
+        client_host_name: Option<&str>,
+    ) -> messages::Access;
+    fn write_value(&mut self, pv_name: &str, value: Dbr) -> Result<(), ErrorCondition>;
+    fn monitor_value(
+        &mut self,
+        pv_name: &str,
+        data_type: DbrType,
+        data_count: usize,
+        mask: MonitorMask,
+        trigger: mpsc::Sender<String>,
+    ) -> Result<broadcast::Receiver<Dbr>, ErrorCondition>;
+    fn watch_removed(&self) -> Option<broadcast::Receiver<String>>;
+    fn pv_generation(&self, pv_name: &str) -> Option<u64>;
+    fn channel_created(&self, pv_name: &str, client: &ClientInfo);
+    fn channel_cleared(&self, pv_name: &str, client: &ClientInfo);
+    fn subscription_added(&self, pv_name: &str, client: &ClientInfo);
+    fn subscription_removed(&self, pv_name: &str, client: &ClientInfo);
+    fn provides_async<'a>(&'a self, pv_name: &'a str) -> BoxFuture<'a, bool>;
+    fn read_value_async<'a>(
+        &'a self,
+        pv_name: &'a str,
+        requested_type: Option<DbrType>,
+    ) -> BoxFuture<'a, Result<Dbr, ErrorCondition>>;
+    fn channel_metadata_async<'a>(
+        &'a self,
+        pv_name: &'a str,
+    ) -> BoxFuture<'a, Result<ChannelMetadata, ErrorCondition>>;
+    fn write_value_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        value: Dbr,
+    ) -> BoxFuture<'a, Result<(), ErrorCondition>>;
+    fn write_value_from_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        value: Dbr,
+        client: &'a ClientInfo,
+    ) -> BoxFuture<'a, Result<WriteCompletion, ErrorCondition>>;
+    fn monitor_value_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        data_type: DbrType,
+        data_count: usize,
+        mask: MonitorMask,
+        trigger: mpsc::Sender<String>,
+    ) -> BoxFuture<'a, Result<broadcast::Receiver<Dbr>, ErrorCondition>>;
+    fn clone_box(&self) -> Box<dyn DynProvider>;
+}
+
+impl<L: Provider> DynProvider for L {
+    fn provides(&self, pv_name: &str) -> bool {
+        Provider::provides(self, pv_name)
+    }
+    fn read_value(
+        &self,
+        pv_name: &str,
+        requested_type: Option<DbrType>,
+    ) -> Result<Dbr, ErrorCondition> {
+        Provider::read_value(self, pv_name, requested_type)
+    }
+    fn channel_metadata(&self, pv_name: &str) -> Result<ChannelMetadata, ErrorCondition> {
+        Provider::channel_metadata(self, pv_name)
+    }
+    fn get_access_right(
+        &self,
+        pv_name: &str,
+        client_user_name: Option<&str>,
+        client_host_name: Option<&str>,
+    ) -> messages::Access {
+        Provider::get_access_right(self, pv_name, client_user_name, client_host_name)
+    }
+    fn write_value(&mut self, pv_name: &str, value: Dbr) -> Result<(), ErrorCondition> {
+        Provider::write_value(self, pv_name, value)
+    }
+    fn monitor_value(
+        &mut self,
+        pv_name: &str,
+        data_type: DbrType,
+        data_count: usize,
+        mask: MonitorMask,
+        trigger: mpsc::Sender<String>,
+    ) -> Result<broadcast::Receiver<Dbr>, ErrorCondition> {
+        Provider::monitor_value(self, pv_name, data_type, data_count, mask, trigger)
+    }
+    fn watch_removed(&self) -> Option<broadcast::Receiver<String>> {
+        Provider::watch_removed(self)
+    }
+    fn pv_generation(&self, pv_name: &str) -> Option<u64> {
+        Provider::pv_generation(self, pv_name)
+    }
+    fn channel_created(&self, pv_name: &str, client: &ClientInfo) {
+        Provider::channel_created(self, pv_name, client)
+    }
+    fn channel_cleared(&self, pv_name: &str, client: &ClientInfo) {
+        Provider::channel_cleared(self, pv_name, client)
+    }
+    fn subscription_added(&self, pv_name: &str, client: &ClientInfo) {
+        Provider::subscription_added(self, pv_name, client)
+    }
+    fn subscription_removed(&self, pv_name: &str, client: &ClientInfo) {
+        Provider::subscription_removed(self, pv_name, client)
+    }
+    fn provides_async<'a>(&'a self, pv_name: &'a str) -> BoxFuture<'a, bool> {
+        Provider::provides_async(self, pv_name)
+    }
+    fn read_value_async<'a>(
+        &'a self,
+        pv_name: &'a str,
+        requested_type: Option<DbrType>,
+    ) -> BoxFuture<'a, Result<Dbr, ErrorCondition>> {
+        Provider::read_value_async(self, pv_name, requested_type)
+    }
+    fn channel_metadata_async<'a>(
+        &'a self,
+        pv_name: &'a str,
+    ) -> BoxFuture<'a, Result<ChannelMetadata, ErrorCondition>> {
+        Provider::channel_metadata_async(self, pv_name)
+    }
+    fn write_value_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        value: Dbr,
+    ) -> BoxFuture<'a, Result<(), ErrorCondition>> {
+        Provider::write_value_async(self, pv_name, value)
+    }
+    fn write_value_from_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        value: Dbr,
+        client: &'a ClientInfo,
+    ) -> BoxFuture<'a, Result<WriteCompletion, ErrorCondition>> {
+        Provider::write_value_from_async(self, pv_name, value, client)
+    }
+    fn monitor_value_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        data_type: DbrType,
+        data_count: usize,
+        mask: MonitorMask,
+        trigger: mpsc::Sender<String>,
+    ) -> BoxFuture<'a, Result<broadcast::Receiver<Dbr>, ErrorCondition>> {
+        Provider::monitor_value_async(self, pv_name, data_type, data_count, mask, trigger)
+    }
+    fn clone_box(&self) -> Box<dyn DynProvider> {
+        Box::new(self.clone())
+    }
+}
+
+/// Removal notifications relayed from inner providers
+///
+/// The relay tasks are stopped when this is dropped, along with the last clone of
+/// the provider that started them.
+struct RemovedRelay {
+    sender: broadcast::Sender<String>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for RemovedRelay {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Pass on removal notifications from an inner provider, renaming them on the way
+///
+/// Each removed name can map to any number of names on the outer provider. If any
+/// are missed, [MISSED_REMOVALS] is sent so that the server checks every PV.
+fn relay_removed<F>(
+    sender: &broadcast::Sender<String>,
+    mut receiver: broadcast::Receiver<String>,
+    rename: F,
+) -> JoinHandle<()>
+where
+    F: Fn(String) -> Vec<String> + Send + 'static,
+{
+    let sender = sender.clone();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(name) if name == MISSED_REMOVALS => {
+                    let _ = sender.send(name);
+                }
+                Ok(name) => {
+                    for name in rename(name) {
+                        let _ = sender.send(name);
+                    }
+                }
+                Err(RecvError::Lagged(count)) => {
+                    warn!("Missed {count} PV removal notifications from inner provider");
+                    let _ = sender.send(MISSED_REMOVALS.to_string());
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// Can removal notifications be relayed from here?
+///
+/// Relaying needs a task, so can only be done from inside a tokio runtime. The
+/// server always asks from one, but other callers might not.
+fn can_relay_removed() -> bool {
+    let available = tokio::runtime::Handle::try_current().is_ok();
+    if !available {
+        warn!("Not watching for removed PVs, as there is no tokio runtime to relay them");
+    }
+    available
+}
+
+struct Entry {
+    /// If set, only PVs starting with this are routed here, with the prefix removed
+    prefix: Option<String>,
+    provider: Box<dyn DynProvider>,
+}
+
+impl Clone for Entry {
+    fn clone(&self) -> Self {
+        Entry {
+            prefix: self.prefix.clone(),
+            provider: self.provider.clone_box(),
+        }
+    }
+}
+
+/// Serve PVs from several providers, of any type
+///
+/// Providers are asked in the order they were added, and the first that provides a
+/// PV handles it. Providers added with a prefix only see PV names starting with that
+/// prefix, so a provider for one device can be mounted under that device's name:
+///
+/// ```
+/// use epicars::providers::{IntercomProvider, ProviderSet};
+///
+/// let mut detector = IntercomProvider::new();
+/// detector.add_pv("ACQUIRE", 0i32).unwrap();
+/// let mut general = IntercomProvider::new();
+/// general.add_pv("BL04I-STATUS", 1i32).unwrap();
+///
+/// // Serves both BL04I-EA-DET-01:ACQUIRE and BL04I-STATUS
+/// let provider = ProviderSet::new()
+///     .with_prefix("BL04I-EA-DET-01:", detector)
+///     .with(general);
+/// ```
+///
+/// Providers that only implement [Provider::provides_async] can't be asked by the
+/// synchronous methods, such as [Provider::get_access_right] and the channel and
+/// subscription notifications. Those are routed to wherever the PV was last found
+/// by an asynchronous method, which the server always calls first.
+#[derive(Clone, Default)]
+pub struct ProviderSet {
+    entries: Vec<Entry>,
+    removed: Arc<OnceLock<Option<RemovedRelay>>>,
+    /// PVs that were only found by asking asynchronously, and the entry serving them
+    found_async: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl ProviderSet {
+    pub fn new() -> ProviderSet {
+        ProviderSet::default()
+    }
+
+    /// Add a provider, asked for PVs after all those that were added before it
+    pub fn with<L: Provider>(mut self, provider: L) -> ProviderSet {
+        self.entries.push(Entry {
+            prefix: None,
+            provider: Box::new(provider),
+        });
+        self
+    }
+
+    /// Add a provider that serves all PVs starting with a prefix
+    ///
+    /// The prefix is removed before PV names are passed to the provider.
+    pub fn with_prefix<L: Provider>(mut self, prefix: &str, provider: L) -> ProviderSet {
+        self.entries.push(Entry {
+            prefix: Some(prefix.to_string()),
+            provider: Box::new(provider),
+        });
+        self
+    }
+
+    /// Entries that could handle a PV, in order, with the name each knows it by
+    fn candidates<'n>(&self, pv_name: &'n str) -> impl Iterator<Item = (usize, &'n str)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(move |(index, entry)| match &entry.prefix {
+                Some(prefix) => Some((index, pv_name.strip_prefix(prefix.as_str())?)),
+                None => Some((index, pv_name)),
+            })
+    }
+
+    /// Find the entry that handles a PV, and the name it knows the PV by
+    fn route<'n>(&self, pv_name: &'n str) -> Option<(usize, &'n str)> {
+        self.candidates(pv_name)
+            .find(|(index, name)| self.entries[*index].provider.provides(name))
+            .or_else(|| {
+                let found = *self.found_async.lock().unwrap().get(pv_name)?;
+                self.candidates(pv_name).find(|(index, _)| *index == found)
+            })
+    }
+
+    /// Asynchronous version of [ProviderSet::route]
+    ///
+    /// This remembers PVs that only an asynchronous lookup could find, so that
+    /// [ProviderSet::route] can find them again afterwards.
+    async fn route_async<'n>(&self, pv_name: &'n str) -> Option<(usize, &'n str)> {
+        for (index, name) in self.candidates(pv_name) {
+            let provider = &self.entries[index].provider;
+            if provider.provides_async(name).await {
+                if !provider.provides(name) {
+                    self.found_async
+                        .lock()
+                        .unwrap()
+                        .insert(pv_name.to_string(), index);
+                }
+                return Some((index, name));
+            }
+        }
+        self.found_async.lock().unwrap().remove(pv_name);
+        None
+    }
+}
+
+impl Provider for ProviderSet {
+    fn provides(&self, pv_name: &str) -> bool {
+        self.route(pv_name).is_some()
+    }
+
+    fn read_value(
+        &self,
+        pv_name: &str,
+        requested_type: Option<DbrType>,
+    ) -> Result<Dbr, ErrorCondition> {
+        let (index, name) = self.route(pv_name).ok_or(ErrorCondition::UnavailInServ)?;
+        self.entries[index]
+            .provider
+            .read_value(name, requested_type)
+    }
+
+    fn channel_metadata(&self, pv_name: &str) -> Result<ChannelMetadata, ErrorCondition> {
+        let (index, name) = self.route(pv_name).ok_or(ErrorCondition::UnavailInServ)?;
+        self.entries[index].provider.channel_metadata(name)
+    }
+
+    fn get_access_right(
+        &self,
+        pv_name: &str,
+        client_user_name: Option<&str>,
+        client_host_name: Option<&str>,
+    ) -> messages::Access {
+        match self.route(pv_name) {
+            Some((index, name)) => self.entries[index].provider.get_access_right(
+                name,
+                client_user_name,
+                client_host_name,
+            ),
+            None => messages::Access::None,
+        }
+    }
+
+    fn write_value(&mut self, pv_name: &str, value: Dbr) -> Result<(), ErrorCondition> {
+        let (index, name) = self.route(pv_name).ok_or(ErrorCondition::UnavailInServ)?;
+        self.entries[index].provider.write_value(name, value)
+    }
+
+    fn pv_generation(&self, pv_name: &str) -> Option<u64> {
+        let (index, name) = self.route(pv_name)?;
+        self.entries[index].provider.pv_generation(name)
+    }
+
+    fn monitor_value(
+        &mut self,
+        pv_name: &str,
+        data_type: DbrType,
+        data_count: usize,
+        mask: MonitorMask,
+        trigger: mpsc::Sender<String>,
+    ) -> Result<broadcast::Receiver<Dbr>, ErrorCondition> {
+        let (index, name) = self.route(pv_name).ok_or(ErrorCondition::UnavailInServ)?;
+        self.entries[index]
+            .provider
+            .monitor_value(name, data_type, data_count, mask, trigger)
+    }
+
+    fn watch_removed(&self) -> Option<broadcast::Receiver<String>> {
+        if !can_relay_removed() {
+            return None;
+        }
+        // The first caller sets up relays from all of the inner providers
+        self.removed
+            .get_or_init(|| {
+                let receivers: Vec<_> = self
+                    .entries
+                    .iter()
+                    .filter_map(|e| Some((e.prefix.clone(), e.provider.watch_removed()?)))
+                    .collect();
+                if receivers.is_empty() {
+                    return None;
+                }
+                let (sender, _) = broadcast::channel(32);
+                let tasks = receivers
+                    .into_iter()
+                    .map(|(prefix, receiver)| {
+                        let prefix = prefix.unwrap_or_default();
+                        relay_removed(&sender, receiver, move |name| {
+                            vec![format!("{prefix}{name}")]
+                        })
+                    })
+                    .collect();
+                Some(RemovedRelay { sender, tasks })
+            })
+            .as_ref()
+            .map(|relay| relay.sender.subscribe())
+    }
+
+    fn channel_created(&self, pv_name: &str, client: &ClientInfo) {
+        if let Some((index, name)) = self.route(pv_name) {
+            self.entries[index].provider.channel_created(name, client);
+        }
+    }
+
+    fn channel_cleared(&self, pv_name: &str, client: &ClientInfo) {
+        if let Some((index, name)) = self.route(pv_name) {
+            self.entries[index].provider.channel_cleared(name, client);
+        }
+    }
+
+    fn subscription_added(&self, pv_name: &str, client: &ClientInfo) {
+        if let Some((index, name)) = self.route(pv_name) {
+            self.entries[index]
+                .provider
+                .subscription_added(name, client);
+        }
+    }
+
+    fn subscription_removed(&self, pv_name: &str, client: &ClientInfo) {
+        if let Some((index, name)) = self.route(pv_name) {
+            self.entries[index]
+                .provider
+                .subscription_removed(name, client);
+        }
+    }
+
+    fn provides_async<'a>(&'a self, pv_name: &'a str) -> BoxFuture<'a, bool> {
+        Box::pin(async move { self.route_async(pv_name).await.is_some() })
+    }
+
+    fn read_value_async<'a>(
+        &'a self,
+        pv_name: &'a str,
+        requested_type: Option<DbrType>,
+    ) -> BoxFuture<'a, Result<Dbr, ErrorCondition>> {
+        Box::pin(async move {
+            let (index, name) = self
+                .route_async(pv_name)
+                .await
+                .ok_or(ErrorCondition::UnavailInServ)?;
+            self.entries[index]
+                .provider
+                .read_value_async(name, requested_type)
+                .await
+        })
+    }
+
+    fn channel_metadata_async<'a>(
+        &'a self,
+        pv_name: &'a str,
+    ) -> BoxFuture<'a, Result<ChannelMetadata, ErrorCondition>> {
+        Box::pin(async move {
+            let (index, name) = self
+                .route_async(pv_name)
+                .await
+                .ok_or(ErrorCondition::UnavailInServ)?;
+            self.entries[index]
+                .provider
+                .channel_metadata_async(name)
+                .await
+        })
+    }
+
+    fn write_value_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        value: Dbr,
+    ) -> BoxFuture<'a, Result<(), ErrorCondition>> {
+        Box::pin(async move {
+            let (index, name) = self
+                .route_async(pv_name)
+                .await
+                .ok_or(ErrorCondition::UnavailInServ)?;
+            self.entries[index]
+                .provider
+                .write_value_async(name, value)
+                .await
+        })
+    }
+
+    fn write_value_from_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        value: Dbr,
+        client: &'a ClientInfo,
+    ) -> BoxFuture<'a, Result<WriteCompletion, ErrorCondition>> {
+        Box::pin(async move {
+            let (index, name) = self
+                .route_async(pv_name)
+                .await
+                .ok_or(ErrorCondition::UnavailInServ)?;
+            self.entries[index]
+                .provider
+                .write_value_from_async(name, value, client)
+                .await
+        })
+    }
+
+    fn monitor_value_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        data_type: DbrType,
+        data_count: usize,
+        mask: MonitorMask,
+        trigger: mpsc::Sender<String>,
+    ) -> BoxFuture<'a, Result<broadcast::Receiver<Dbr>, ErrorCondition>> {
+        Box::pin(async move {
+            let (index, name) = self
+                .route_async(pv_name)
+                .await
+                .ok_or(ErrorCondition::UnavailInServ)?;
+            self.entries[index]
+                .provider
+                .monitor_value_async(name, data_type, data_count, mask, trigger)
+                .await
+        })
+    }
+}
+
+/// Expose PVs of another provider under additional names
+///
+/// The original names continue to work, and every alias behaves exactly as the PV
+/// that it refers to.
+///
+/// ```
+/// use epicars::providers::{AliasProvider, IntercomProvider};
+///
+/// let mut intercom = IntercomProvider::new();
+/// intercom.add_pv("BL04I-EA-DET-01:TEMPERATURE", 20.0f64).unwrap();
+/// let provider = AliasProvider::new(intercom).alias("DET-TEMP", "BL04I-EA-DET-01:TEMPERATURE");
+/// ```
+#[derive(Clone, Default)]
+pub struct AliasProvider<L: Provider> {
+    inner: L,
+    aliases: HashMap<String, String>,
+    removed: Arc<OnceLock<Option<RemovedRelay>>>,
+}
+
+impl<L: Provider> AliasProvider<L> {
+    pub fn new(inner: L) -> AliasProvider<L> {
+        AliasProvider {
+            inner,
+            aliases: HashMap::new(),
+            removed: Arc::default(),
+        }
+    }
+
+    /// Make the PV `target` also available as `alias`
+    pub fn alias(mut self, alias: &str, target: &str) -> AliasProvider<L> {
+        self.aliases.insert(alias.to_string(), target.to_string());
+        self
+    }
+}
+
+/// The name that the inner provider of an [AliasProvider] knows a PV by
+fn resolve<'a>(aliases: &'a HashMap<String, String>, pv_name: &'a str) -> &'a str {
+    aliases.get(pv_name).map_or(pv_name, String::as_str)
+}
+
+impl<L: Provider> Provider for AliasProvider<L> {
+    fn provides(&self, pv_name: &str) -> bool {
+        self.inner.provides(resolve(&self.aliases, pv_name))
+    }
+
+    fn read_value(
+        &self,
+        pv_name: &str,
+        requested_type: Option<DbrType>,
+    ) -> Result<Dbr, ErrorCondition> {
+        self.inner
+            .read_value(resolve(&self.aliases, pv_name), requested_type)
+    }
+
+    fn channel_metadata(&self, pv_name: &str) -> Result<ChannelMetadata, ErrorCondition> {
+        self.inner.channel_metadata(resolve(&self.aliases, pv_name))
+    }
+
+    fn get_access_right(
+        &self,
+        pv_name: &str,
+        client_user_name: Option<&str>,
+        client_host_name: Option<&str>,
+    ) -> messages::Access {
+        self.inner.get_access_right(
+            resolve(&self.aliases, pv_name),
+            client_user_name,
+            client_host_name,
+        )
+    }
+
+    fn write_value(&mut self, pv_name: &str, value: Dbr) -> Result<(), ErrorCondition> {
+        self.inner
+            .write_value(resolve(&self.aliases, pv_name), value)
+    }
+
+    fn pv_generation(&self, pv_name: &str) -> Option<u64> {
+        self.inner.pv_generation(resolve(&self.aliases, pv_name))
+    }
+
+    fn monitor_value(
+        &mut self,
+        pv_name: &str,
+        data_type: DbrType,
+        data_count: usize,
+        mask: MonitorMask,
+        trigger: mpsc::Sender<String>,
+    ) -> Result<broadcast::Receiver<Dbr>, ErrorCondition> {
+        self.inner.monitor_value(
+            resolve(&self.aliases, pv_name),
+            data_type,
+            data_count,
+            mask,
+            trigger,
+        )
+    }
+
+    fn watch_removed(&self) -> Option<broadcast::Receiver<String>> {
+        if !can_relay_removed() {
+            return None;
+        }
+        self.removed
+            .get_or_init(|| {
+                let receiver = self.inner.watch_removed()?;
+                let (sender, _) = broadcast::channel(32);
+                let aliases = self.aliases.clone();
+                let task = relay_removed(&sender, receiver, move |name| {
+                    let mut names: Vec<String> = aliases
+                        .iter()
+                        .filter(|(_, target)| **target == name)
+                        .map(|(alias, _)| alias.clone())
+                        .collect();
+                    names.push(name);
+                    names
+                });
+                Some(RemovedRelay {
+                    sender,
+                    tasks: vec![task],
+                })
+            })
+            .as_ref()
+            .map(|relay| relay.sender.subscribe())
+    }
+
+    fn channel_created(&self, pv_name: &str, client: &ClientInfo) {
+        self.inner
+            .channel_created(resolve(&self.aliases, pv_name), client)
+    }
+
+    fn channel_cleared(&self, pv_name: &str, client: &ClientInfo) {
+        self.inner
+            .channel_cleared(resolve(&self.aliases, pv_name), client)
+    }
+
+    fn subscription_added(&self, pv_name: &str, client: &ClientInfo) {
+        self.inner
+            .subscription_added(resolve(&self.aliases, pv_name), client)
+    }
+
+    fn subscription_removed(&self, pv_name: &str, client: &ClientInfo) {
+        self.inner
+            .subscription_removed(resolve(&self.aliases, pv_name), client)
+    }
+
+    fn provides_async<'a>(&'a self, pv_name: &'a str) -> BoxFuture<'a, bool> {
+        self.inner.provides_async(resolve(&self.aliases, pv_name))
+    }
+
+    fn read_value_async<'a>(
+        &'a self,
+        pv_name: &'a str,
+        requested_type: Option<DbrType>,
+    ) -> BoxFuture<'a, Result<Dbr, ErrorCondition>> {
+        self.inner
+            .read_value_async(resolve(&self.aliases, pv_name), requested_type)
+    }
+
+    fn channel_metadata_async<'a>(
+        &'a self,
+        pv_name: &'a str,
+    ) -> BoxFuture<'a, Result<ChannelMetadata, ErrorCondition>> {
+        self.inner
+            .channel_metadata_async(resolve(&self.aliases, pv_name))
+    }
+
+    fn write_value_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        value: Dbr,
+    ) -> BoxFuture<'a, Result<(), ErrorCondition>> {
+        self.inner
+            .write_value_async(resolve(&self.aliases, pv_name), value)
+    }
+
+    fn write_value_from_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        value: Dbr,
+        client: &'a ClientInfo,
+    ) -> BoxFuture<'a, Result<WriteCompletion, ErrorCondition>> {
+        self.inner
+            .write_value_from_async(resolve(&self.aliases, pv_name), value, client)
+    }
+
+    fn monitor_value_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        data_type: DbrType,
+        data_count: usize,
+        mask: MonitorMask,
+        trigger: mpsc::Sender<String>,
+    ) -> BoxFuture<'a, Result<broadcast::Receiver<Dbr>, ErrorCondition>> {
+        self.inner.monitor_value_async(
+            resolve(&self.aliases, pv_name),
+            data_type,
+            data_count,
+            mask,
+            trigger,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::broadcast::error::RecvError;
+
+    use crate::{
+        dbr::{Dbr, DbrType, DbrValue},
+        messages::MonitorMask,
+        providers::{AliasProvider, IntercomProvider, Provider, ProviderSet},
+    };
+
+    #[test]
+    fn test_set_routes_by_order_and_prefix() {
+        let mut first = IntercomProvider::new();
+        first.add_pv("A", 1i32).unwrap();
+        first.add_pv("DEV:B", 2i32).unwrap();
+        let mut second = IntercomProvider::new();
+        second.add_pv("A", 3i32).unwrap();
+        second.add_pv("C", 4i32).unwrap();
+        let mut device = IntercomProvider::new();
+        device.add_pv("B", 5i32).unwrap();
+
+        let provider = ProviderSet::new()
+            .with_prefix("DEV:", device)
+            .with(first)
+            .with(second);
+        let read = |name| provider.read_value(name, None).unwrap().value().clone();
+        assert_eq!(read("A"), DbrValue::Long(vec![1]));
+        assert_eq!(read("C"), DbrValue::Long(vec![4]));
+        assert_eq!(read("DEV:B"), DbrValue::Long(vec![5]));
+        assert!(!provider.provides("B"));
+        assert!(!provider.provides("DEV:C"));
+    }
+
+    #[test]
+    fn test_alias_exposes_pv_under_another_name() {
+        let mut intercom = IntercomProvider::new();
+        let value = intercom.add_pv("ORIGINAL", 1i32).unwrap();
+        let mut provider = AliasProvider::new(intercom)
+            .alias("ALIAS", "ORIGINAL")
+            .alias("BROKEN", "MISSING");
+        assert!(provider.provides("ORIGINAL"));
+        assert!(provider.provides("ALIAS"));
+        assert!(!provider.provides("BROKEN"));
+
+        let write = Dbr::Basic(DbrValue::Long(vec![7]));
+        provider.write_value("ALIAS", write).unwrap();
+        assert_eq!(value.load(), 7);
+    }
+
+    #[test]
+    fn test_monitors_work_without_a_runtime() {
+        let mut intercom = IntercomProvider::new();
+        let mut value = intercom.add_pv("VALUE", 1i32).unwrap();
+        let mut provider = AliasProvider::new(ProviderSet::new().with_prefix("DEV:", intercom))
+            .alias("ALIAS", "DEV:VALUE");
+        assert!(provider.watch_removed().is_none());
+
+        let (trigger, mut triggers) = tokio::sync::mpsc::channel(4);
+        let mut receiver = provider
+            .monitor_value(
+                "ALIAS",
+                DbrType::try_from(5).unwrap(),
+                1,
+                MonitorMask::default(),
+                trigger,
+            )
+            .unwrap();
+        value.store(&2);
+        assert!(triggers.try_recv().is_ok());
+        assert_eq!(
+            receiver.try_recv().unwrap().value(),
+            &DbrValue::Long(vec![2])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_removal_relays_stop_with_the_provider() {
+        let mut intercom = IntercomProvider::new();
+        intercom.add_pv("VALUE", 1i32).unwrap();
+        let provider = AliasProvider::new(ProviderSet::new().with_prefix("DEV:", intercom.clone()))
+            .alias("ALIAS", "DEV:VALUE");
+        let mut removed = provider.watch_removed().unwrap();
+
+        intercom.remove_pv("VALUE");
+        let mut names = vec![removed.recv().await.unwrap(), removed.recv().await.unwrap()];
+        names.sort();
+        assert_eq!(names, vec!["ALIAS", "DEV:VALUE"]);
+
+        // Once the provider has gone, so has everything relaying to it
+        drop(provider);
+        let closed = tokio::time::timeout(Duration::from_secs(1), removed.recv()).await;
+        assert!(matches!(closed, Ok(Err(RecvError::Closed))));
+    }
+}
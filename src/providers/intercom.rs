@@ -1,22 +1,186 @@
 use std::{
     collections::HashMap,
     marker::PhantomData,
-    sync::{Arc, Mutex},
-    time::SystemTime,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
 use tokio::sync::{
     broadcast::{self},
     mpsc::{self, error::TrySendError},
+    watch,
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::{
     Provider,
-    dbr::{Dbr, DbrBasicType, DbrType, DbrValue, IntoDbrBasicType, Status},
-    messages::{self, ErrorCondition, MonitorMask},
+    dbr::{
+        AlarmSeverity, AlarmStatus, Dbr, DbrBasicType, DbrType, DbrValue, DisplayMetadata,
+        EpicsTime, IntoDbrBasicType, Status,
+    },
+    messages::{Access, ErrorCondition, MonitorMask},
+    providers::{BoxFuture, ChannelMetadata, ClientInfo, WriteCompletion},
 };
 
+/// Where the latest change to the value of a PV came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeSource {
+    /// Stored through one of the PV's intercoms
+    Local,
+    /// Written by a client. This is `None` for writes that came through
+    /// [Provider::write_value] rather than from the server, which does not say
+    /// who the client was.
+    Client(Option<ClientInfo>),
+}
+
+/// Alarm limits for a numeric PV, as on an `ai` record
+///
+/// Each limit only raises an alarm if its severity is set. Once a limit is in
+/// alarm, the value has to come back past it by more than the hysteresis before
+/// the alarm clears.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AlarmLimits {
+    /// HIHI
+    pub hihi: f64,
+    /// HHSV, the severity when at or above HIHI
+    pub hihi_severity: AlarmSeverity,
+    /// HIGH
+    pub high: f64,
+    /// HSV, the severity when at or above HIGH
+    pub high_severity: AlarmSeverity,
+    /// LOW
+    pub low: f64,
+    /// LSV, the severity when at or below LOW
+    pub low_severity: AlarmSeverity,
+    /// LOLO
+    pub lolo: f64,
+    /// LLSV, the severity when at or below LOLO
+    pub lolo_severity: AlarmSeverity,
+    /// HYST
+    pub hysteresis: f64,
+}
+
+impl AlarmLimits {
+    /// Work out the alarm for a value, given the limit that was last in alarm
+    ///
+    /// This follows the order that epics-base checks the limits in.
+    fn check(&self, value: f64, last_alarm: AlarmStatus) -> Status {
+        let hyst = self.hysteresis;
+        let checks = [
+            (
+                AlarmStatus::HiHi,
+                self.hihi_severity,
+                value >= self.hihi,
+                value >= self.hihi - hyst,
+            ),
+            (
+                AlarmStatus::LoLo,
+                self.lolo_severity,
+                value <= self.lolo,
+                value <= self.lolo + hyst,
+            ),
+            (
+                AlarmStatus::High,
+                self.high_severity,
+                value >= self.high,
+                value >= self.high - hyst,
+            ),
+            (
+                AlarmStatus::Low,
+                self.low_severity,
+                value <= self.low,
+                value <= self.low + hyst,
+            ),
+        ];
+        for (status, severity, beyond, within_hysteresis) in checks {
+            if severity != AlarmSeverity::NoAlarm
+                && (beyond || (last_alarm == status && within_hysteresis))
+            {
+                return Status::new(status, severity);
+            }
+        }
+        Status::default()
+    }
+
+    /// Fill in the alarm limits sent with GR and CTRL types
+    ///
+    /// Limits that cannot alarm are sent as NaN, as epics-base does.
+    fn describe(&self, display: &mut DisplayMetadata) {
+        let limit = |value: f64, severity: AlarmSeverity| {
+            if severity == AlarmSeverity::NoAlarm {
+                f64::NAN
+            } else {
+                value
+            }
+        };
+        display.upper_alarm_limit = limit(self.hihi, self.hihi_severity);
+        display.upper_warning_limit = limit(self.high, self.high_severity);
+        display.lower_warning_limit = limit(self.low, self.low_severity);
+        display.lower_alarm_limit = limit(self.lolo, self.lolo_severity);
+    }
+}
+
+/// Read a value as a number that can be checked against alarm limits
+///
+/// Only single numbers are checked, as with epics-base, where only scalar
+/// records have alarm limits.
+fn scalar_value(value: &DbrValue) -> Option<f64> {
+    match value {
+        DbrValue::Char(v) if v.len() == 1 => Some(v[0].into()),
+        DbrValue::Int(v) if v.len() == 1 => Some(v[0].into()),
+        DbrValue::Long(v) if v.len() == 1 => Some(v[0].into()),
+        DbrValue::Float(v) if v.len() == 1 => Some(v[0].into()),
+        DbrValue::Double(v) if v.len() == 1 => Some(v[0]),
+        _ => None,
+    }
+}
+
+/// Checks a value written by a client, before it is stored
+type Validator = Arc<dyn Fn(&DbrValue) -> Result<(), ErrorCondition> + Send + Sync>;
+
+/// Called with the new value after a successful write by a client
+#[derive(Clone)]
+enum PutCallback {
+    Sync(Arc<dyn Fn(&DbrValue) + Send + Sync>),
+    Async(Arc<dyn Fn(DbrValue) -> BoxFuture<'static, ()> + Send + Sync>),
+}
+
+/// Application code that gets involved in client writes to a PV
+#[derive(Clone, Default)]
+struct PutHooks {
+    validators: Vec<Validator>,
+    callbacks: Vec<PutCallback>,
+}
+
+type AccessFn = dyn Fn(Option<&str>, Option<&str>) -> Access + Send + Sync;
+
+/// Decides what a client may do with a PV, from its user and host names
+#[derive(Clone)]
+struct AccessRule(Arc<AccessFn>);
+
+impl Default for AccessRule {
+    fn default() -> Self {
+        AccessRule(Arc::new(|_, _| Access::ReadWrite))
+    }
+}
+
+impl std::fmt::Debug for AccessRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AccessRule")
+    }
+}
+
+impl std::fmt::Debug for PutHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PutHooks")
+            .field("validators", &self.validators.len())
+            .field("callbacks", &self.callbacks.len())
+            .finish()
+    }
+}
+
 #[derive(Clone, Debug)]
 struct PV {
     name: String,
@@ -29,8 +193,24 @@ struct PV {
     /// The optional type to serialize as. This is useful for forcing an
     /// otherwise String DbrValue to be DbrValue::Char when sending off.
     force_dbr_type: Option<DbrBasicType>,
+    /// Names of the states, if this is an enum. Clients can write these.
+    enum_strings: Vec<String>,
     /// The last time this value was written
-    timestamp: SystemTime,
+    timestamp: EpicsTime,
+    /// Limits to check the value against whenever it is stored
+    alarm_limits: AlarmLimits,
+    /// The alarm from checking the last stored value against the limits
+    limit_alarm: Status,
+    /// The alarm given by the owner of the PV along with the last value
+    raised_alarm: Status,
+    /// Tells this PV apart from others that have had the same name
+    generation: u64,
+    /// Validators and callbacks for writes from clients
+    put_hooks: PutHooks,
+    /// What clients are allowed to do with the PV
+    access: AccessRule,
+    /// Tells the intercoms that the value has changed, and who changed it
+    changes: watch::Sender<ChangeSource>,
     /// Channel to send updates to EPIC clients
     sender: broadcast::Sender<Dbr>,
     /// Trigger channel, to notify the server there is a new broadcast available
@@ -38,6 +218,44 @@ struct PV {
 }
 
 impl PV {
+    /// Create a PV holding a single value
+    fn single<T>(name: &str, initial_value: T) -> PV
+    where
+        T: IntoDbrBasicType,
+        DbrValue: From<Vec<T>>,
+    {
+        PV {
+            name: name.to_owned(),
+            value: Arc::new(Mutex::new(DbrValue::from(vec![initial_value]))),
+            enum_strings: T::enum_labels(),
+            ..Default::default()
+        }
+    }
+
+    /// Create a PV holding an array
+    fn vec<T>(name: &str, initial_value: Vec<T>, minimum_length: Option<usize>) -> PV
+    where
+        DbrValue: From<Vec<T>>,
+    {
+        PV {
+            name: name.to_owned(),
+            value: Arc::new(Mutex::new(DbrValue::from(initial_value))),
+            minimum_length,
+            ..Default::default()
+        }
+    }
+
+    /// Create a PV holding a string, sent to clients as an array of chars
+    fn string(name: &str, initial_value: &str, minimum_u8_len: Option<usize>) -> PV {
+        PV {
+            name: name.to_owned(),
+            minimum_length: minimum_u8_len,
+            force_dbr_type: Some(DbrBasicType::Char),
+            value: Arc::new(Mutex::new(DbrValue::String(vec![initial_value.to_owned()]))),
+            ..Default::default()
+        }
+    }
+
     pub fn load(&self) -> DbrValue {
         let value = self.value.lock().unwrap();
         value.clone()
@@ -63,28 +281,116 @@ impl PV {
             let _ = value.resize(size);
         }
         Dbr::Time {
-            status: Status::default(),
+            status: self.status(),
             timestamp: self.timestamp,
             value,
         }
     }
-    /// Store a value from the CA protocol to the PV
+    /// Describe the PV as it is sent to CA clients
+    ///
+    /// The count includes any padding to the minimum length.
+    fn metadata(&self) -> ChannelMetadata {
+        let value = self.value.lock().unwrap();
+        let native_type = self.force_dbr_type.unwrap_or(value.get_type());
+        let count = match (native_type, &*value) {
+            // A string forced to a char array is sent as its bytes
+            (DbrBasicType::Char, DbrValue::String(strings)) => {
+                strings.first().map_or(0, |s| s.len())
+            }
+            _ => value.get_count(),
+        };
+        let mut metadata =
+            ChannelMetadata::new(native_type, count.max(self.minimum_length.unwrap_or(0)));
+        self.alarm_limits.describe(&mut metadata.display);
+        metadata.display.enum_strings = self.enum_strings.clone();
+        metadata
+    }
+    /// The alarm state of the PV, which is the more severe of the raised and
+    /// limit alarms
+    fn status(&self) -> Status {
+        if self.limit_alarm.severity > self.raised_alarm.severity {
+            self.limit_alarm
+        } else {
+            self.raised_alarm
+        }
+    }
+    /// Recalculate the limit alarm from the current value
+    fn update_alarm(&mut self) {
+        let value = scalar_value(&self.value.lock().unwrap());
+        self.limit_alarm = match value {
+            Some(value) => self
+                .alarm_limits
+                .check(value, self.limit_alarm.alarm_status().unwrap_or_default()),
+            None => Status::default(),
+        };
+    }
+    /// Change the alarm limits, and re-check the current value against them
+    fn set_alarm_limits(&mut self, limits: AlarmLimits) {
+        self.alarm_limits = limits;
+        let previous = self.status();
+        self.update_alarm();
+        if self.status() != previous {
+            self.publish();
+        }
+    }
+    /// Raise an alarm without changing the value, until the next store
+    fn set_alarm(&mut self, alarm: Status) {
+        self.raised_alarm = alarm;
+        self.publish();
+    }
+    /// Convert a value from the CA protocol to the type stored in the PV
     ///
     /// In this case, there are special behaviour like e.g. parsing
-    /// numbers out of string data type
-    fn store_from_ca(&mut self, value: &DbrValue) -> Result<(), ErrorCondition> {
+    /// numbers out of string data type, and looking up enum states by name
+    fn convert_from_ca(&self, value: &DbrValue) -> Result<DbrValue, ErrorCondition> {
         let native_type = self.value.lock().unwrap().get_type();
-        let value = if value.get_type() == DbrBasicType::String {
-            value
+        let converted = match value {
+            DbrValue::String(strings) if native_type == DbrBasicType::Enum => {
+                match self
+                    .enum_strings
+                    .iter()
+                    .position(|s| Some(s) == strings.first())
+                {
+                    Some(index) => DbrValue::Enum(index as u16),
+                    None => value.convert_to(native_type)?,
+                }
+            }
+            DbrValue::String(_) => value
                 .parse_into(native_type)
-                .map_err(|_| ErrorCondition::NoConvert)?
-        } else {
-            value.clone()
+                .map_err(|_| ErrorCondition::NoConvert)?,
+            _ => value.convert_to(native_type)?,
         };
-        self.store(&value)
+        // Like an mbbo record, refuse states that don't exist
+        match converted {
+            DbrValue::Enum(index)
+                if !self.enum_strings.is_empty() && index as usize >= self.enum_strings.len() =>
+            {
+                Err(ErrorCondition::NoConvert)
+            }
+            _ => Ok(converted),
+        }
     }
 
     pub fn store(&mut self, value: &DbrValue) -> Result<(), ErrorCondition> {
+        self.store_with(
+            value,
+            EpicsTime::now(),
+            Status::default(),
+            ChangeSource::Local,
+        )
+    }
+
+    /// Store a value with a known timestamp and alarm
+    ///
+    /// The alarm limits are still checked, and raise an alarm if it would be
+    /// more severe than the one given.
+    pub fn store_with(
+        &mut self,
+        value: &DbrValue,
+        timestamp: EpicsTime,
+        alarm: Status,
+        source: ChangeSource,
+    ) -> Result<(), ErrorCondition> {
         // Now update the shared value
         {
             let stored_value = &mut *self.value.lock().unwrap();
@@ -97,8 +403,25 @@ impl PV {
             }
             // Ensure lock is dropped
         }
-        self.timestamp = SystemTime::now();
-        // Now send off the new value to any listeners
+        self.timestamp = timestamp;
+        self.raised_alarm = alarm;
+        self.update_alarm();
+        self.publish();
+        self.changes.send_replace(source);
+        Ok(())
+    }
+
+    /// Cut the PV off from the server, once it has been removed from the provider
+    ///
+    /// Its intercoms can still load and store, but nothing else sees the values.
+    /// Dropping the sender for changes wakes anything waiting on them.
+    fn detach(&mut self) {
+        self.triggers.clear();
+        self.changes = watch::Sender::new(ChangeSource::Local);
+    }
+
+    /// Send the current value off to any listeners
+    fn publish(&mut self) {
         let _ = self.sender.send(self.load_for_ca());
         // Send the "please look at" triggers, filtering out any that are dead
         self.triggers = self
@@ -110,7 +433,6 @@ impl PV {
                 Err(TrySendError::Closed(_)) => None,
             })
             .collect();
-        Ok(())
     }
 }
 
@@ -121,13 +443,171 @@ impl Default for PV {
             value: Arc::new(Mutex::new(DbrValue::Int(vec![0]))),
             minimum_length: None,
             force_dbr_type: None,
-            timestamp: SystemTime::now(),
+            enum_strings: Vec::new(),
+            timestamp: EpicsTime::now(),
+            alarm_limits: AlarmLimits::default(),
+            limit_alarm: Status::default(),
+            raised_alarm: Status::default(),
+            generation: 0,
+            put_hooks: PutHooks::default(),
+            access: AccessRule::default(),
+            changes: watch::Sender::new(ChangeSource::Local),
             sender: broadcast::Sender::new(16),
             triggers: Vec::new(),
         }
     }
 }
 
+/// Define the methods shared by all of the intercom handles
+///
+/// Each handle converts between its own value type and the PV's [DbrValue] with
+/// `from_dbr` and `to_dbr`, and everything else is in terms of those. `$value` is
+/// the type handed out, and `$borrowed` the type taken in.
+macro_rules! impl_intercom_methods {
+    ($value:ty, $borrowed:ty) => {
+        pub fn load(&self) -> $value {
+            Self::from_dbr(&self.pv.lock().unwrap().load())
+        }
+
+        /// Wait for the value to change, returning the new value and who changed it
+        ///
+        /// This wakes for stores through any intercom to the PV, including this one,
+        /// so check the source to pick out writes from clients. If the value changes
+        /// several times before this is called again, only the latest is seen.
+        /// Returns `None` once the PV has been removed from the provider.
+        pub async fn changed(&mut self) -> Option<($value, ChangeSource)> {
+            self.changes.changed().await.ok()?;
+            // Stores announce the change while holding the PV lock, so reading both
+            // under it means the value and source are always from the same store
+            let pv = self.pv.lock().unwrap();
+            let source = self.changes.borrow_and_update().clone();
+            Some((Self::from_dbr(&pv.load()), source))
+        }
+
+        /// Watch where each change to the value comes from
+        ///
+        /// This is a new receiver, so only sees changes made after it was created.
+        pub fn watch(&self) -> watch::Receiver<ChangeSource> {
+            let mut changes = self.changes.clone();
+            changes.mark_unchanged();
+            changes
+        }
+
+        pub fn store(&mut self, value: &$borrowed) {
+            self.pv
+                .lock()
+                .unwrap()
+                .store(&Self::to_dbr(value))
+                .expect("Provider logic should ensure this never fails");
+        }
+
+        /// Store a value with its own timestamp and alarm, e.g. from hardware
+        pub fn store_with(
+            &mut self,
+            value: &$borrowed,
+            timestamp: impl Into<EpicsTime>,
+            status: AlarmStatus,
+            severity: AlarmSeverity,
+        ) {
+            self.pv
+                .lock()
+                .unwrap()
+                .store_with(
+                    &Self::to_dbr(value),
+                    timestamp.into(),
+                    Status::new(status, severity),
+                    ChangeSource::Local,
+                )
+                .expect("Provider logic should ensure this never fails");
+        }
+
+        /// Raise an alarm without changing the value
+        ///
+        /// The alarm lasts until the next store.
+        pub fn set_alarm(&mut self, status: AlarmStatus, severity: AlarmSeverity) {
+            self.pv
+                .lock()
+                .unwrap()
+                .set_alarm(Status::new(status, severity));
+        }
+
+        /// Set what every client is allowed to do with this PV
+        ///
+        /// For example, [`Access::Read`] makes a read-only PV. This does not limit
+        /// stores through the intercoms. Clients that already have a channel open
+        /// are not told of the change, but it is still enforced.
+        pub fn set_access(&mut self, access: Access) {
+            self.set_access_rule(move |_, _| access);
+        }
+
+        /// Decide what each client is allowed to do with this PV
+        ///
+        /// The rule is called with the user and host name that the client gave,
+        /// when it opens a channel and on every request. Writes from
+        /// [`Provider::write_value`] have neither.
+        pub fn set_access_rule<F>(&mut self, rule: F)
+        where
+            F: Fn(Option<&str>, Option<&str>) -> Access + Send + Sync + 'static,
+        {
+            self.pv.lock().unwrap().access = AccessRule(Arc::new(rule));
+        }
+
+        /// Check values written by clients, before they are stored
+        ///
+        /// If any validator returns an error, the write is rejected and the client
+        /// is sent that error.
+        pub fn add_validator<F>(&mut self, validator: F)
+        where
+            F: Fn(&$borrowed) -> Result<(), ErrorCondition> + Send + Sync + 'static,
+        {
+            self.pv
+                .lock()
+                .unwrap()
+                .put_hooks
+                .validators
+                .push(Arc::new(move |value| validator(&Self::from_dbr(value))));
+        }
+
+        /// Call a function with each value successfully written by a client
+        ///
+        /// This is not called for values stored through the intercom.
+        pub fn on_put<F>(&mut self, callback: F)
+        where
+            F: Fn(&$borrowed) + Send + Sync + 'static,
+        {
+            self.pv
+                .lock()
+                .unwrap()
+                .put_hooks
+                .callbacks
+                .push(PutCallback::Sync(Arc::new(move |value| {
+                    callback(&Self::from_dbr(value))
+                })));
+        }
+
+        /// Run an async task with each value successfully written by a client
+        ///
+        /// The client's write does not complete until the task has finished, so
+        /// that e.g. `caput -c` waits for a move to finish. The task runs on its own,
+        /// so it can take longer than the server's request timeout without holding up
+        /// the client's other requests.
+        pub fn on_put_async<F, Fut>(&mut self, callback: F)
+        where
+            F: Fn($value) -> Fut + Send + Sync + 'static,
+            Fut: Future<Output = ()> + Send + 'static,
+        {
+            self.pv
+                .lock()
+                .unwrap()
+                .put_hooks
+                .callbacks
+                .push(PutCallback::Async(Arc::new(move |value| {
+                    Box::pin(callback(Self::from_dbr(&value)))
+                })));
+        }
+    };
+}
+
 /// Typed interface to reading single values to/from a PV
 #[derive(Clone)]
 pub struct Intercom<T>
@@ -135,6 +615,7 @@ where
     T: IntoDbrBasicType,
 {
     pv: Arc<Mutex<PV>>,
+    changes: watch::Receiver<ChangeSource>,
     _marker: PhantomData<T>,
 }
 
@@ -145,16 +626,17 @@ where
     DbrValue: From<Vec<T>>,
 {
     fn new(pv: Arc<Mutex<PV>>) -> Self {
+        let changes = pv.lock().unwrap().changes.subscribe();
         Self {
             pv,
+            changes,
             _marker: PhantomData,
         }
     }
 
-    pub fn load(&self) -> T {
-        let value = self.pv.lock().unwrap().load();
-        // Convert the DbrValue into T
-        let ex: Vec<T> = match (&value).try_into() {
+    /// Convert a value of the PV into T
+    fn from_dbr(value: &DbrValue) -> T {
+        let ex: Vec<T> = match value.try_into() {
             Ok(v) => v,
             _ => panic!("Provider logic should ensure this conversion never fails!"),
         };
@@ -162,12 +644,17 @@ where
         ex.first().unwrap_or(&T::default()).clone()
     }
 
-    pub fn store(&mut self, value: &T) {
-        self.pv
-            .lock()
-            .unwrap()
-            .store(&(vec![value.clone()]).into())
-            .expect("Provider logic should ensure this never fails");
+    fn to_dbr(value: &T) -> DbrValue {
+        vec![value.clone()].into()
+    }
+
+    impl_intercom_methods!(T, T);
+
+    /// Set the limits that raise alarms for this PV
+    ///
+    /// The current value is checked against the new limits straight away.
+    pub fn set_alarm_limits(&mut self, limits: AlarmLimits) {
+        self.pv.lock().unwrap().set_alarm_limits(limits);
     }
 }
 
@@ -177,6 +664,7 @@ where
     T: IntoDbrBasicType,
 {
     pv: Arc<Mutex<PV>>,
+    changes: watch::Receiver<ChangeSource>,
     _marker: PhantomData<T>,
 }
 
@@ -187,40 +675,44 @@ where
     DbrValue: From<Vec<T>>,
 {
     fn new(pv: Arc<Mutex<PV>>) -> Self {
+        let changes = pv.lock().unwrap().changes.subscribe();
         Self {
             pv,
+            changes,
             _marker: PhantomData,
         }
     }
 
-    pub fn load(&self) -> Vec<T> {
-        match (&self.pv.lock().unwrap().load()).try_into() {
+    /// Convert a value of the PV into a Vec<T>
+    fn from_dbr(value: &DbrValue) -> Vec<T> {
+        match value.try_into() {
             Ok(v) => v,
             _ => panic!("Provider logic should ensure this conversion never fails!"),
         }
     }
 
-    pub fn store(&mut self, value: &[T]) {
-        self.pv
-            .lock()
-            .unwrap()
-            .store(&value.to_vec().into())
-            .expect("Provider logic should ensure this never fails");
+    fn to_dbr(value: &[T]) -> DbrValue {
+        value.to_vec().into()
     }
+
+    impl_intercom_methods!(Vec<T>, [T]);
 }
 
 #[derive(Debug)]
 pub struct StringIntercom {
     pv: Arc<Mutex<PV>>,
+    changes: watch::Receiver<ChangeSource>,
 }
 
 impl StringIntercom {
     fn new(pv: Arc<Mutex<PV>>) -> Self {
         assert!(pv.lock().unwrap().value.lock().unwrap().get_type() == DbrBasicType::String);
-        Self { pv }
+        let changes = pv.lock().unwrap().changes.subscribe();
+        Self { pv, changes }
     }
-    pub fn load(&self) -> String {
-        let DbrValue::String(value) = self.pv.lock().unwrap().load() else {
+    /// Convert a value of the PV into a String
+    fn from_dbr(value: &DbrValue) -> String {
+        let DbrValue::String(value) = value else {
             panic!("StringIntercom PV is not of string type!");
         };
         match value.as_slice() {
@@ -229,40 +721,131 @@ impl StringIntercom {
             _ => panic!("Got multi-value string DbrValue in StringIntercom!"),
         }
     }
-    pub fn store(&mut self, value: &str) {
-        self.pv
-            .lock()
-            .unwrap()
-            .store(&vec![value.to_owned()].into())
-            .expect("Provider logic should ensure this never fails");
+
+    fn to_dbr(value: &str) -> DbrValue {
+        vec![value.to_owned()].into()
     }
+
+    impl_intercom_methods!(String, str);
 }
 
 #[derive(Debug)]
 pub struct PVAlreadyExists;
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct IntercomProvider {
     pvs: Arc<Mutex<HashMap<String, Arc<Mutex<PV>>>>>,
+    /// Tells the server which PVs have been removed, so it can disconnect them
+    removed: broadcast::Sender<String>,
+    /// The generation to give the next PV that is added
+    next_generation: Arc<AtomicU64>,
+}
+
+impl Default for IntercomProvider {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IntercomProvider {
     pub fn new() -> IntercomProvider {
         IntercomProvider {
             pvs: Arc::new(Mutex::new(HashMap::new())),
+            removed: broadcast::Sender::new(64),
+            next_generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Stop providing a PV, returning whether it existed
+    ///
+    /// Clients with channels open to the PV are disconnected. Its intercoms keep
+    /// working, but are no longer connected to anything, and waiting for changes
+    /// on them returns `None`.
+    pub fn remove_pv(&mut self, name: &str) -> bool {
+        let Some(pv) = self.pvs.lock().unwrap().remove(name) else {
+            return false;
+        };
+        pv.lock().unwrap().detach();
+        let _ = self.removed.send(name.to_owned());
+        true
+    }
+
+    /// Replace a PV with a new one, which can be of a different type
+    ///
+    /// Clients are disconnected from the old PV, as for
+    /// [`remove_pv`](Self::remove_pv), and can then reconnect to the new one. The
+    /// new PV is added as by [`add_pv`](Self::add_pv), but the old one can be of
+    /// any kind.
+    pub fn replace_pv<T>(&mut self, name: &str, initial_value: T) -> Intercom<T>
+    where
+        T: IntoDbrBasicType + Clone + Default,
+        for<'a> Vec<T>: TryFrom<&'a DbrValue>,
+        DbrValue: From<Vec<T>>,
+    {
+        let pv = Arc::new(Mutex::new(PV::single(name, initial_value)));
+        self.swap_pv(pv.clone());
+        Intercom::<T>::new(pv)
+    }
+
+    /// Replace a PV with a new array PV, as for [`replace_pv`](Self::replace_pv)
+    pub fn replace_vec_pv<T>(
+        &mut self,
+        name: &str,
+        initial_value: Vec<T>,
+        minimum_length: Option<usize>,
+    ) -> VecIntercom<T>
+    where
+        T: IntoDbrBasicType + Clone + Default,
+        for<'a> Vec<T>: TryFrom<&'a DbrValue>,
+        DbrValue: From<Vec<T>>,
+    {
+        let pv = Arc::new(Mutex::new(PV::vec(name, initial_value, minimum_length)));
+        self.swap_pv(pv.clone());
+        VecIntercom::<T>::new(pv)
+    }
+
+    /// Replace a PV with a new string PV, as for [`replace_pv`](Self::replace_pv)
+    pub fn replace_string_pv(
+        &mut self,
+        name: &str,
+        initial_value: &str,
+        minimum_u8_len: Option<usize>,
+    ) -> StringIntercom {
+        let pv = Arc::new(Mutex::new(PV::string(name, initial_value, minimum_u8_len)));
+        self.swap_pv(pv.clone());
+        StringIntercom::new(pv)
+    }
+
+    /// Add a PV, detaching any old one of the same name
+    fn swap_pv(&mut self, pv: Arc<Mutex<PV>>) {
+        let name = {
+            let mut pv = pv.lock().unwrap();
+            pv.generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+            pv.name.clone()
+        };
+        let old = self.pvs.lock().unwrap().insert(name.clone(), pv);
+        if let Some(old) = old {
+            old.lock().unwrap().detach();
+            let _ = self.removed.send(name);
         }
     }
 
     fn register_pv(&mut self, pv: Arc<Mutex<PV>>) -> Result<(), PVAlreadyExists> {
-        let name = &pv.lock().unwrap().name;
+        let name = pv.lock().unwrap().name.clone();
         let mut pvmap = self.pvs.lock().unwrap();
-        if pvmap.contains_key(name) {
+        if pvmap.contains_key(&name) {
             return Err(PVAlreadyExists);
         }
-        let _ = pvmap.insert(name.to_owned(), pv.clone());
+        pv.lock().unwrap().generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        let _ = pvmap.insert(name, pv);
         Ok(())
     }
 
+    /// Add a PV holding a single value
+    ///
+    /// An [`EpicsEnum`](crate::dbr::EpicsEnum), such as one declared with
+    /// [`epics_enum!`](crate::epics_enum), becomes an ENUM PV with its labels as the
+    /// state names. Clients can write either the index or the name of a state.
     pub fn add_pv<T>(
         &mut self,
         name: &str,
@@ -273,11 +856,7 @@ impl IntercomProvider {
         for<'a> Vec<T>: TryFrom<&'a DbrValue>,
         DbrValue: From<Vec<T>>,
     {
-        let pv = Arc::new(Mutex::new(PV {
-            name: name.to_owned(),
-            value: Arc::new(Mutex::new(DbrValue::from(vec![initial_value.clone()]))),
-            ..Default::default()
-        }));
+        let pv = Arc::new(Mutex::new(PV::single(name, initial_value)));
         self.register_pv(pv.clone())?;
         Ok(Intercom::<T>::new(pv))
     }
@@ -293,13 +872,7 @@ impl IntercomProvider {
         for<'a> Vec<T>: TryFrom<&'a DbrValue>,
         DbrValue: From<Vec<T>>,
     {
-        // let pv = self.create_pv(name, DbrValue::from(initial_value.clone()), minimum_length)?;
-        let pv = Arc::new(Mutex::new(PV {
-            name: name.to_owned(),
-            value: Arc::new(Mutex::new(DbrValue::from(initial_value.clone()))),
-            minimum_length,
-            ..Default::default()
-        }));
+        let pv = Arc::new(Mutex::new(PV::vec(name, initial_value, minimum_length)));
         self.register_pv(pv.clone())?;
         Ok(VecIntercom::<T>::new(pv))
     }
@@ -310,16 +883,97 @@ impl IntercomProvider {
         initial_value: &str,
         minimum_u8_len: Option<usize>,
     ) -> Result<StringIntercom, PVAlreadyExists> {
-        let pv = Arc::new(Mutex::new(PV {
-            name: name.to_owned(),
-            minimum_length: minimum_u8_len,
-            force_dbr_type: Some(DbrBasicType::Char),
-            value: Arc::new(Mutex::new(DbrValue::String(vec![initial_value.to_owned()]))),
-            ..Default::default()
-        }));
+        let pv = Arc::new(Mutex::new(PV::string(name, initial_value, minimum_u8_len)));
         self.register_pv(pv.clone())?;
         Ok(StringIntercom::new(pv))
     }
+
+    /// Validate and store a write from a client, then run the sync callbacks
+    ///
+    /// The PV is not kept locked while validators and callbacks run, so they
+    /// are free to use its intercoms. Returns the async callbacks still to run.
+    fn write_from_client(
+        &self,
+        pv_name: &str,
+        value: Dbr,
+        client: Option<&ClientInfo>,
+    ) -> Result<Vec<BoxFuture<'static, ()>>, ErrorCondition> {
+        let pv = self
+            .pvs
+            .lock()
+            .unwrap()
+            .get(pv_name)
+            .ok_or(ErrorCondition::UnavailInServ)?
+            .clone();
+        info!("Provider: Processing write: {value:?}");
+        let (value, validators) = {
+            let pv = pv.lock().unwrap();
+            let (user, host) =
+                client.map_or((None, None), |c| (c.user.as_deref(), c.host.as_deref()));
+            if !(pv.access.0)(user, host).can_write() {
+                error!("    Error: Client has no write access to {pv_name}");
+                return Err(ErrorCondition::NoWtAccess);
+            }
+            (
+                pv.convert_from_ca(value.value()),
+                pv.put_hooks.validators.clone(),
+            )
+        };
+        let value = value
+            .and_then(|value| {
+                validators
+                    .iter()
+                    .try_for_each(|validate| validate(&value))?;
+                Ok(value)
+            })
+            .inspect_err(|e| error!("    Error: {e:?}"))?;
+        let callbacks = {
+            let mut pv = pv.lock().unwrap();
+            pv.store_with(
+                &value,
+                EpicsTime::now(),
+                Status::default(),
+                ChangeSource::Client(client.cloned()),
+            )?;
+            pv.put_hooks.callbacks.clone()
+        };
+        Ok(callbacks
+            .iter()
+            .filter_map(|callback| match callback {
+                PutCallback::Sync(callback) => {
+                    callback(&value);
+                    None
+                }
+                PutCallback::Async(callback) => Some(callback(value.clone())),
+            })
+            .collect())
+    }
+}
+
+/// Wait for the async callbacks from a client write, before it is complete
+async fn run_put_callbacks(
+    tasks: Result<Vec<BoxFuture<'static, ()>>, ErrorCondition>,
+) -> Result<(), ErrorCondition> {
+    for task in tasks? {
+        task.await;
+    }
+    Ok(())
+}
+
+/// Run the async callbacks from a client write on their own task
+///
+/// They run whether or not the returned completion is waited on.
+fn spawn_put_callbacks(tasks: Vec<BoxFuture<'static, ()>>) -> WriteCompletion {
+    if tasks.is_empty() {
+        return Box::pin(std::future::ready(Ok(())));
+    }
+    let callbacks = tokio::spawn(run_put_callbacks(Ok(tasks)));
+    Box::pin(async move {
+        callbacks.await.unwrap_or_else(|e| {
+            error!("    Error: Put callback failed: {e}");
+            Err(ErrorCondition::PutFail)
+        })
+    })
 }
 
 impl Provider for IntercomProvider {
@@ -343,29 +997,70 @@ impl Provider for IntercomProvider {
         Ok(pv.load_for_ca())
     }
 
+    fn channel_metadata(&self, pv_name: &str) -> Result<ChannelMetadata, ErrorCondition> {
+        let pvmap = self.pvs.lock().unwrap();
+        let pv = pvmap
+            .get(pv_name)
+            .ok_or(ErrorCondition::UnavailInServ)?
+            .lock()
+            .unwrap();
+        Ok(pv.metadata())
+    }
+
     fn get_access_right(
         &self,
-        _pv_name: &str,
-        _client_user_name: Option<&str>,
-        _client_host_name: Option<&str>,
-    ) -> messages::Access {
-        messages::Access::ReadWrite
+        pv_name: &str,
+        client_user_name: Option<&str>,
+        client_host_name: Option<&str>,
+    ) -> Access {
+        let pvmap = self.pvs.lock().unwrap();
+        match pvmap.get(pv_name) {
+            Some(pv) => (pv.lock().unwrap().access.0)(client_user_name, client_host_name),
+            None => Access::None,
+        }
     }
 
+    /// Any async callbacks are spawned onto the current tokio runtime, rather
+    /// than waited on. Outside of a runtime they are skipped.
     fn write_value(&mut self, pv_name: &str, value: Dbr) -> Result<(), ErrorCondition> {
-        let mut pvmap = self.pvs.lock().unwrap();
-        let mut pv = pvmap
-            .get_mut(pv_name)
-            .ok_or(ErrorCondition::UnavailInServ)?
-            .lock()
-            .unwrap();
-        info!("Provider: Processing write: {value:?}");
-        if let Err(e) = pv.store_from_ca(value.value()) {
-            error!("    Error: {e:?}");
-            Err(e)
-        } else {
-            Ok(())
+        let tasks = self.write_from_client(pv_name, value, None)?;
+        if tasks.is_empty() {
+            return Ok(());
+        }
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => tasks.into_iter().for_each(|task| drop(runtime.spawn(task))),
+            Err(_) => warn!("No tokio runtime to run put callbacks for {pv_name} on"),
         }
+        Ok(())
+    }
+
+    fn write_value_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        value: Dbr,
+    ) -> BoxFuture<'a, Result<(), ErrorCondition>> {
+        Box::pin(run_put_callbacks(
+            self.write_from_client(pv_name, value, None),
+        ))
+    }
+
+    fn write_value_from_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        value: Dbr,
+        client: &'a ClientInfo,
+    ) -> BoxFuture<'a, Result<WriteCompletion, ErrorCondition>> {
+        let tasks = self.write_from_client(pv_name, value, Some(client));
+        Box::pin(std::future::ready(tasks.map(spawn_put_callbacks)))
+    }
+
+    fn watch_removed(&self) -> Option<broadcast::Receiver<String>> {
+        Some(self.removed.subscribe())
+    }
+
+    fn pv_generation(&self, pv_name: &str) -> Option<u64> {
+        let pvmap = self.pvs.lock().unwrap();
+        Some(pvmap.get(pv_name)?.lock().unwrap().generation)
     }
 
     fn monitor_value(
@@ -392,8 +1087,16 @@ mod tests {
     use std::sync::{Arc, Mutex};
 
     use crate::{
-        dbr::DbrBasicType,
-        providers::intercom::{PV, StringIntercom},
+        Provider,
+        dbr::{
+            AlarmSeverity, AlarmStatus, Dbr, DbrBasicType, DbrCategory, DbrType, DbrValue,
+            EpicsTime,
+        },
+        messages::{Access, ErrorCondition},
+        providers::{
+            ClientInfo, IntercomProvider,
+            intercom::{AlarmLimits, ChangeSource, PV, StringIntercom},
+        },
     };
 
     #[test]
@@ -411,4 +1114,333 @@ mod tests {
             DbrBasicType::Char
         );
     }
+
+    #[test]
+    fn test_alarm_limits_with_hysteresis() {
+        let mut provider = IntercomProvider::new();
+        let mut value = provider.add_pv("TEMP", 5.0f64).unwrap();
+        value.set_alarm_limits(AlarmLimits {
+            hihi: 20.0,
+            hihi_severity: AlarmSeverity::Major,
+            high: 10.0,
+            high_severity: AlarmSeverity::Minor,
+            hysteresis: 1.0,
+            ..Default::default()
+        });
+        let alarm = |value: f64, intercom: &mut super::Intercom<f64>| {
+            intercom.store(&value);
+            let status = provider.read_value("TEMP", None).unwrap().status().unwrap();
+            (status.alarm_status(), status.alarm_severity())
+        };
+        let high = (Some(AlarmStatus::High), Some(AlarmSeverity::Minor));
+        let none = (Some(AlarmStatus::NoAlarm), Some(AlarmSeverity::NoAlarm));
+        assert_eq!(alarm(5.0, &mut value), none);
+        assert_eq!(alarm(10.0, &mut value), high);
+        // Still in alarm until the value drops below HIGH - HYST
+        assert_eq!(alarm(9.5, &mut value), high);
+        assert_eq!(alarm(8.9, &mut value), none);
+        assert_eq!(
+            alarm(25.0, &mut value),
+            (Some(AlarmStatus::HiHi), Some(AlarmSeverity::Major))
+        );
+
+        let display = provider.channel_metadata("TEMP").unwrap().display;
+        assert_eq!(display.upper_alarm_limit, 20.0);
+        assert_eq!(display.upper_warning_limit, 10.0);
+        assert!(display.lower_alarm_limit.is_nan());
+    }
+
+    #[test]
+    fn test_store_with_and_set_alarm() {
+        let mut provider = IntercomProvider::new();
+        let mut value = provider.add_pv("TEMP", 5.0f64).unwrap();
+        value.set_alarm_limits(AlarmLimits {
+            high: 10.0,
+            high_severity: AlarmSeverity::Minor,
+            ..Default::default()
+        });
+        let alarm = |provider: &IntercomProvider| {
+            let status = provider.read_value("TEMP", None).unwrap().status().unwrap();
+            (status.alarm_status(), status.alarm_severity())
+        };
+
+        // A raised alarm wins if it is more severe, and the timestamp is kept
+        let timestamp = EpicsTime {
+            seconds: 1000,
+            nanoseconds: 5,
+        };
+        value.store_with(&15.0, timestamp, AlarmStatus::Comm, AlarmSeverity::Invalid);
+        let dbr = provider.read_value("TEMP", None).unwrap();
+        assert_eq!(dbr.timestamp(), Some(timestamp));
+        assert_eq!(
+            alarm(&provider),
+            (Some(AlarmStatus::Comm), Some(AlarmSeverity::Invalid))
+        );
+        // Clearing the raised alarm leaves the one from the limits
+        value.set_alarm(AlarmStatus::NoAlarm, AlarmSeverity::NoAlarm);
+        assert_eq!(
+            alarm(&provider),
+            (Some(AlarmStatus::High), Some(AlarmSeverity::Minor))
+        );
+        // A raised alarm only lasts until the next store
+        value.set_alarm(AlarmStatus::Comm, AlarmSeverity::Major);
+        value.store(&5.0);
+        assert_eq!(
+            alarm(&provider),
+            (Some(AlarmStatus::NoAlarm), Some(AlarmSeverity::NoAlarm))
+        );
+
+        // The other kinds of intercom can do the same
+        let mut name = provider.add_string_pv("NAME", "one", None).unwrap();
+        name.store_with("two", timestamp, AlarmStatus::Comm, AlarmSeverity::Minor);
+        let dbr = provider.read_value("NAME", None).unwrap();
+        assert_eq!(dbr.timestamp(), Some(timestamp));
+        assert_eq!(name.load(), "two");
+    }
+
+    #[tokio::test]
+    async fn test_validators_and_put_callbacks() {
+        let mut provider = IntercomProvider::new();
+        let mut setpoint = provider.add_pv("SETPOINT", 0.0f64).unwrap();
+        setpoint.add_validator(|value| {
+            if (0.0..=10.0).contains(value) {
+                Ok(())
+            } else {
+                Err(ErrorCondition::PutFail)
+            }
+        });
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sync_seen = seen.clone();
+        setpoint.on_put(move |value| sync_seen.lock().unwrap().push(*value));
+        let (moved, mut moves) = tokio::sync::mpsc::unbounded_channel();
+        setpoint.on_put_async(move |value| {
+            let moved = moved.clone();
+            async move { moved.send(value).unwrap() }
+        });
+
+        let put = |value: f64| Dbr::Basic(DbrValue::Double(vec![value]));
+        assert!(matches!(
+            provider.write_value_async("SETPOINT", put(12.0)).await,
+            Err(ErrorCondition::PutFail)
+        ));
+        assert_eq!(setpoint.load(), 0.0);
+        // Strings are parsed before being validated
+        provider
+            .write_value_async("SETPOINT", Dbr::Basic(DbrValue::String(vec!["4.5".into()])))
+            .await
+            .unwrap();
+        assert_eq!(setpoint.load(), 4.5);
+        assert_eq!(*seen.lock().unwrap(), vec![4.5]);
+        assert_eq!(moves.try_recv(), Ok(4.5));
+        // Stores through the intercom are not client puts
+        setpoint.store(&20.0);
+        assert!(moves.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_sync_write_outside_runtime() {
+        let mut provider = IntercomProvider::new();
+        let mut setpoint = provider.add_pv("SETPOINT", 0i32).unwrap();
+        setpoint.on_put_async(|_| async {});
+        provider
+            .write_value("SETPOINT", Dbr::Basic(DbrValue::Long(vec![3])))
+            .unwrap();
+        assert_eq!(setpoint.load(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_changed_tells_client_writes_apart() {
+        let mut provider = IntercomProvider::new();
+        let mut setpoint = provider.add_pv("SETPOINT", 0i32).unwrap();
+        let mut watcher = setpoint.clone();
+
+        setpoint.store(&1);
+        assert_eq!(watcher.changed().await, Some((1, ChangeSource::Local)));
+
+        let client = ClientInfo {
+            user: Some("operator".to_string()),
+            host: Some("console".to_string()),
+            address: "127.0.0.1:40000".parse().unwrap(),
+        };
+        provider
+            .write_value_from_async("SETPOINT", Dbr::Basic(DbrValue::Long(vec![5])), &client)
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(
+            watcher.changed().await,
+            Some((5, ChangeSource::Client(Some(client.clone()))))
+        );
+
+        // Only the latest change is seen, with the value and source that went together
+        provider
+            .write_value_from_async("SETPOINT", Dbr::Basic(DbrValue::Long(vec![7])), &client)
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        setpoint.store(&8);
+        assert_eq!(watcher.changed().await, Some((8, ChangeSource::Local)));
+    }
+
+    #[tokio::test]
+    async fn test_access_rules() {
+        let mut provider = IntercomProvider::new();
+        let mut readback = provider.add_pv("READBACK", 1i32).unwrap();
+        readback.set_access(Access::Read);
+        let mut setpoint = provider.add_pv("SETPOINT", 2i32).unwrap();
+        setpoint.set_access_rule(|user, _host| match user {
+            Some("operator") => Access::ReadWrite,
+            _ => Access::Read,
+        });
+
+        assert!(matches!(
+            provider.get_access_right("READBACK", Some("operator"), None),
+            Access::Read
+        ));
+        assert!(matches!(
+            provider.get_access_right("SETPOINT", Some("operator"), None),
+            Access::ReadWrite
+        ));
+        assert!(matches!(
+            provider.get_access_right("MISSING", Some("operator"), None),
+            Access::None
+        ));
+
+        // Writes are refused even if the client ignores the access rights
+        let write = || Dbr::Basic(DbrValue::Long(vec![5]));
+        assert!(matches!(
+            provider.write_value("READBACK", write()),
+            Err(ErrorCondition::NoWtAccess)
+        ));
+        let mut client = ClientInfo {
+            user: Some("visitor".to_string()),
+            host: Some("console".to_string()),
+            address: "127.0.0.1:40000".parse().unwrap(),
+        };
+        assert!(matches!(
+            provider
+                .write_value_from_async("SETPOINT", write(), &client)
+                .await,
+            Err(ErrorCondition::NoWtAccess)
+        ));
+        assert_eq!(setpoint.load(), 2);
+        client.user = Some("operator".to_string());
+        provider
+            .write_value_from_async("SETPOINT", write(), &client)
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(setpoint.load(), 5);
+
+        // The owner of the PV can still store to it
+        readback.store(&3);
+        assert_eq!(readback.load(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_remove_and_replace_pvs() {
+        let mut provider = IntercomProvider::new();
+        let mut removed = provider.watch_removed().unwrap();
+        let mut gain = provider.add_pv("GAIN", 1i32).unwrap();
+        let mut threshold = provider.add_pv("THRESHOLD", 10i32).unwrap();
+
+        assert!(provider.remove_pv("GAIN"));
+        assert!(!provider.remove_pv("GAIN"));
+        assert!(!provider.provides("GAIN"));
+        assert_eq!(removed.recv().await.unwrap(), "GAIN");
+        // The old intercom still works, but nothing else sees it
+        gain.store(&2);
+        assert_eq!(gain.load(), 2);
+        assert_eq!(gain.changed().await, None);
+        assert!(matches!(
+            provider.write_value("GAIN", Dbr::Basic(DbrValue::Long(vec![3]))),
+            Err(ErrorCondition::UnavailInServ)
+        ));
+
+        let mut new_threshold = provider.replace_pv("THRESHOLD", 0.5f64);
+        assert_eq!(removed.recv().await.unwrap(), "THRESHOLD");
+        assert_eq!(threshold.changed().await, None);
+        assert_eq!(
+            provider.channel_metadata("THRESHOLD").unwrap().native_type,
+            DbrBasicType::Double
+        );
+        provider
+            .write_value("THRESHOLD", Dbr::Basic(DbrValue::Double(vec![0.75])))
+            .unwrap();
+        assert_eq!(new_threshold.load(), 0.75);
+        assert_eq!(threshold.load(), 10);
+        new_threshold.store(&0.25);
+        assert_eq!(
+            new_threshold.changed().await,
+            Some((0.25, ChangeSource::Local))
+        );
+
+        // Each PV of a name is told apart, and can be replaced by any kind of PV
+        let generation = provider.pv_generation("THRESHOLD");
+        assert!(generation.is_some());
+        let levels = provider.replace_vec_pv("THRESHOLD", vec![1i32, 2, 3], None);
+        assert_eq!(removed.recv().await.unwrap(), "THRESHOLD");
+        assert_eq!(new_threshold.changed().await, None);
+        assert_ne!(provider.pv_generation("THRESHOLD"), generation);
+        assert_eq!(levels.load(), vec![1, 2, 3]);
+        let label = provider.replace_string_pv("THRESHOLD", "high", None);
+        assert_eq!(removed.recv().await.unwrap(), "THRESHOLD");
+        assert_eq!(label.load(), "high");
+        assert_eq!(provider.pv_generation("GAIN"), None);
+    }
+
+    crate::epics_enum! {
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        enum Mode {
+            Idle,
+            Acquire,
+            Fault,
+        }
+    }
+
+    #[test]
+    fn test_enum_pv() {
+        let mut provider = IntercomProvider::new();
+        let mode = provider.add_pv("TEST:MODE", Mode::Acquire).unwrap();
+        let metadata = provider.channel_metadata("TEST:MODE").unwrap();
+        assert_eq!(metadata.native_type, DbrBasicType::Enum);
+        assert_eq!(metadata.record_type, "mbbi");
+        assert_eq!(metadata.display.enum_strings, ["Idle", "Acquire", "Fault"]);
+
+        // Clients can write either the name or the index of a state
+        let by_name = Dbr::Basic(DbrValue::String(vec!["Fault".to_owned()]));
+        provider.write_value("TEST:MODE", by_name).unwrap();
+        assert_eq!(mode.load(), Mode::Fault);
+        let by_index = Dbr::Basic(DbrValue::Int(vec![0]));
+        provider.write_value("TEST:MODE", by_index).unwrap();
+        assert_eq!(mode.load(), Mode::Idle);
+        let by_index_string = Dbr::Basic(DbrValue::String(vec!["1".to_owned()]));
+        provider.write_value("TEST:MODE", by_index_string).unwrap();
+        assert_eq!(mode.load(), Mode::Acquire);
+
+        // States that don't exist are refused
+        for bad in [DbrValue::Enum(3), DbrValue::String(vec!["Busy".to_owned()])] {
+            assert!(provider.write_value("TEST:MODE", Dbr::Basic(bad)).is_err());
+        }
+        assert_eq!(mode.load(), Mode::Acquire);
+
+        // Asking for a string with the display information gives the name
+        let value = provider
+            .read_value("TEST:MODE", None)
+            .unwrap()
+            .with_display(metadata.display);
+        let as_string = value
+            .convert_to(DbrType {
+                basic_type: DbrBasicType::String,
+                category: DbrCategory::Graphics,
+            })
+            .unwrap();
+        assert_eq!(
+            as_string.value(),
+            &DbrValue::String(vec!["Acquire".to_owned()])
+        );
+    }
 }
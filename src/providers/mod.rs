@@ -1,21 +1,117 @@
 //! Interface between the CA Server and rust code
 
+pub mod compose;
 pub mod intercom;
+pub use compose::{AliasProvider, ProviderSet};
 pub use intercom::IntercomProvider;
 
+use std::{future::Future, net::SocketAddr, pin::Pin};
+
 use tokio::sync::{
     broadcast::{self},
     mpsc::{self},
 };
 
 use crate::{
-    dbr::{Dbr, DbrType},
+    dbr::{Dbr, DbrBasicType, DbrType, DbrValue, DisplayMetadata},
     messages::{self, ErrorCondition, MonitorMask},
 };
 
+/// A boxed future, as returned by the asynchronous [Provider] methods
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Finishes once everything started by a client's write is done
+///
+/// See [Provider::write_value_from_async].
+pub type WriteCompletion = BoxFuture<'static, Result<(), ErrorCondition>>;
+
+/// Sent instead of a PV name by [Provider::watch_removed] when removals were missed
+///
+/// The server then checks that every PV it has channels open to is still provided.
+pub const MISSED_REMOVALS: &str = "";
+
+/// Description of a PV, as reported to clients connecting to it
+///
+/// This is everything about a channel except for its current value, so that
+/// the server can answer connection and metadata requests without reading it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelMetadata {
+    /// The type that values are natively stored as
+    pub native_type: DbrBasicType,
+    /// The largest number of elements that a value can have
+    pub max_count: usize,
+    /// The EPICS record type, reported to clients asking for DBR_CLASS_NAME
+    pub record_type: String,
+    /// A free-form description of the PV
+    pub description: String,
+    /// Units, limits and enum strings, reported to clients asking for GR/CTRL types
+    pub display: DisplayMetadata,
+}
+
+impl ChannelMetadata {
+    /// Create metadata for a channel with no display information
+    ///
+    /// The record type is guessed from the type and count, in the same way
+    /// that a simple IOC would pick an input record for them.
+    pub fn new(native_type: DbrBasicType, max_count: usize) -> ChannelMetadata {
+        let record_type = match native_type {
+            _ if max_count > 1 => "waveform",
+            DbrBasicType::String => "stringin",
+            DbrBasicType::Enum => "mbbi",
+            DbrBasicType::Char | DbrBasicType::Int | DbrBasicType::Long => "longin",
+            DbrBasicType::Float | DbrBasicType::Double => "ai",
+        };
+        ChannelMetadata {
+            native_type,
+            max_count,
+            record_type: record_type.to_string(),
+            description: String::new(),
+            display: DisplayMetadata::default(),
+        }
+    }
+
+    /// Create metadata describing the type and size of an existing value
+    pub fn from_value(value: &DbrValue) -> ChannelMetadata {
+        ChannelMetadata::new(value.get_type(), value.get_count())
+    }
+
+    /// Create metadata from a value read from a PV, including its display information
+    pub fn from_dbr(dbr: &Dbr) -> ChannelMetadata {
+        let mut metadata = ChannelMetadata::from_value(dbr.value());
+        if let Some(display) = dbr.display() {
+            metadata.display = display.clone();
+        }
+        metadata
+    }
+}
+
+/// Identifies the client on the other end of a channel
+///
+/// The user and host names are whatever the client reported, if anything, so
+/// should not be relied on for security.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The user name the client reported
+    pub user: Option<String>,
+    /// The host name the client reported
+    pub host: Option<String>,
+    /// Where the client is connecting from
+    pub address: SocketAddr,
+}
+
 /// Provides PV values for a CAServer
+///
+/// The server only calls the asynchronous `*_async` methods, which by default call
+/// the synchronous versions. Providers that can answer immediately only need to
+/// implement the synchronous methods. Providers that wait on I/O, such as a serial
+/// device or a database, should instead implement the asynchronous methods, so that
+/// they don't block the server while waiting. The server gives up on requests that
+/// take too long, see [ServerBuilder::request_timeout](crate::ServerBuilder::request_timeout).
 pub trait Provider: Sync + Send + Clone + Default + 'static {
     /// Does this provider control the given PV name?
+    ///
+    /// Providers that can only find out asynchronously should return `false` here,
+    /// and implement [Provider::provides_async].
     fn provides(&self, pv_name: &str) -> bool;
 
     /// Fetch a single PV value.
@@ -25,14 +121,34 @@ pub trait Provider: Sync + Send + Clone + Default + 'static {
     /// and it will be automatically converted to the target type (if
     /// such a safe conversion exists).
     ///
-    /// The record that you return with no requested_type is used for
-    /// the native type and data count that is reported to new subscribers.
+    /// Unless [Provider::channel_metadata] is implemented, the record that you
+    /// return with no requested_type is used for the native type and data count
+    /// that is reported to new subscribers.
+    #[allow(unused_variables)]
     fn read_value(
         &self,
         pv_name: &str,
         requested_type: Option<DbrType>,
-    ) -> Result<Dbr, ErrorCondition>;
+    ) -> Result<Dbr, ErrorCondition> {
+        Err(ErrorCondition::UnavailInServ)
+    }
+
+    /// Describe a PV, without reading its value
+    ///
+    /// This is used to tell connecting clients the native type and element count,
+    /// and to answer requests for the record type, units, limits and enum strings.
+    /// The default reads the current value with [Provider::read_value] and reports
+    /// its type and count, along with any display information it carries.
+    fn channel_metadata(&self, pv_name: &str) -> Result<ChannelMetadata, ErrorCondition> {
+        Ok(ChannelMetadata::from_dbr(&self.read_value(pv_name, None)?))
+    }
 
+    /// What a client is allowed to do with a PV
+    ///
+    /// This is sent to clients when they open a channel, and the server checks it
+    /// again for every read and write, refusing anything it doesn't allow. The
+    /// default only allows reading, so providers that implement writes must
+    /// override this as well, or clients will get `ECA_NOWTACCESS`.
     #[allow(unused_variables)]
     fn get_access_right(
         &self,
@@ -47,6 +163,9 @@ pub trait Provider: Sync + Send + Clone + Default + 'static {
     ///
     /// There is no type information - data sent from caput appears to
     /// always be as a string?
+    ///
+    /// The server only calls this for PVs where [Provider::get_access_right]
+    /// allows writing, which the default does not.
     #[allow(unused_variables)]
     fn write_value(&mut self, pv_name: &str, value: Dbr) -> Result<(), ErrorCondition> {
         Err(ErrorCondition::NoWtAccess)
@@ -54,7 +173,10 @@ pub trait Provider: Sync + Send + Clone + Default + 'static {
 
     /// Request setting up a subscription to a PV
     ///
-    ///
+    /// New values should be sent on the returned receiver, and then the PV name
+    /// sent on `trigger` to wake the server. The server checks every subscription
+    /// on the circuit when woken, so providers that wrap others can pass `trigger`
+    /// on unchanged, even if the inner provider knows the PV by another name.
     #[allow(unused_variables)]
     fn monitor_value(
         &mut self,
@@ -66,4 +188,140 @@ pub trait Provider: Sync + Send + Clone + Default + 'static {
     ) -> Result<broadcast::Receiver<Dbr>, ErrorCondition> {
         Err(ErrorCondition::UnavailInServ)
     }
+
+    /// Watch for PVs that this provider stops providing
+    ///
+    /// The names of removed PVs should be sent on the returned channel. The server
+    /// then disconnects any channels that clients have open to them, so that the
+    /// clients can search for the PV again if it later reappears. Providers with a
+    /// fixed set of PVs can leave this as the default, which returns `None`.
+    ///
+    /// Providers that pass on removals from others should send [MISSED_REMOVALS]
+    /// if they fall behind, in the same way that the server treats a lagging
+    /// receiver.
+    fn watch_removed(&self) -> Option<broadcast::Receiver<String>> {
+        None
+    }
+
+    /// Tell apart the different PVs that have had the same name
+    ///
+    /// Providers that can replace a PV with another of the same name should return
+    /// a different number for each. The server remembers it for every channel, so
+    /// that it can tell whether a channel is still connected to the PV it was
+    /// opened to, even if it missed the name being sent by
+    /// [Provider::watch_removed]. The default returns `None`, meaning that names
+    /// are never reused.
+    #[allow(unused_variables)]
+    fn pv_generation(&self, pv_name: &str) -> Option<u64> {
+        None
+    }
+
+    /// Called when a client has opened a channel to a PV
+    ///
+    /// This and the other notification methods are called from the client's
+    /// circuit, so they should return quickly. Each is matched by a later call
+    /// to [Provider::channel_cleared], including when the client disconnects.
+    #[allow(unused_variables)]
+    fn channel_created(&self, pv_name: &str, client: &ClientInfo) {}
+
+    /// Called when a channel to a PV is closed, for whatever reason
+    #[allow(unused_variables)]
+    fn channel_cleared(&self, pv_name: &str, client: &ClientInfo) {}
+
+    /// Called when a client has subscribed to updates from a PV
+    ///
+    /// Along with [Provider::subscription_removed], this lets a provider track
+    /// whether anyone is watching a PV, for example to only poll hardware while
+    /// there are subscribers. Every subscription is removed before its channel
+    /// is cleared.
+    #[allow(unused_variables)]
+    fn subscription_added(&self, pv_name: &str, client: &ClientInfo) {}
+
+    /// Called when a subscription to a PV is cancelled, for whatever reason
+    #[allow(unused_variables)]
+    fn subscription_removed(&self, pv_name: &str, client: &ClientInfo) {}
+
+    /// Asynchronous version of [Provider::provides]
+    fn provides_async<'a>(&'a self, pv_name: &'a str) -> BoxFuture<'a, bool> {
+        Box::pin(std::future::ready(self.provides(pv_name)))
+    }
+
+    /// Asynchronous version of [Provider::read_value]
+    fn read_value_async<'a>(
+        &'a self,
+        pv_name: &'a str,
+        requested_type: Option<DbrType>,
+    ) -> BoxFuture<'a, Result<Dbr, ErrorCondition>> {
+        Box::pin(std::future::ready(self.read_value(pv_name, requested_type)))
+    }
+
+    /// Asynchronous version of [Provider::channel_metadata]
+    ///
+    /// If [Provider::channel_metadata] fails, the default describes the value read
+    /// with [Provider::read_value_async] instead, so that providers which only read
+    /// asynchronously don't have to implement either.
+    fn channel_metadata_async<'a>(
+        &'a self,
+        pv_name: &'a str,
+    ) -> BoxFuture<'a, Result<ChannelMetadata, ErrorCondition>> {
+        Box::pin(async move {
+            match self.channel_metadata(pv_name) {
+                Ok(metadata) => Ok(metadata),
+                // Providers that only read asynchronously can still be described
+                Err(_) => Ok(ChannelMetadata::from_dbr(
+                    &self.read_value_async(pv_name, None).await?,
+                )),
+            }
+        })
+    }
+
+    /// Asynchronous version of [Provider::write_value]
+    fn write_value_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        value: Dbr,
+    ) -> BoxFuture<'a, Result<(), ErrorCondition>> {
+        Box::pin(std::future::ready(self.write_value(pv_name, value)))
+    }
+
+    /// Write a value on behalf of a particular client
+    ///
+    /// This is what the server calls for writes from clients. By default the
+    /// client is ignored and [Provider::write_value_async] is called, so this only
+    /// needs implementing by providers that care who is writing, or that start
+    /// work which takes longer than a write should.
+    ///
+    /// The returned future is subject to the server's request timeout, and holds
+    /// up the client's other requests, so should finish once the value is
+    /// accepted. Anything that carries on after that, e.g. a motor move, belongs
+    /// in the [WriteCompletion]. The server waits for it before answering a
+    /// `WriteNotify`, but without a timeout and alongside other requests. It is
+    /// dropped for writes that don't ask for an answer, so work that must always
+    /// happen should be spawned rather than done in the completion itself.
+    #[allow(unused_variables)]
+    fn write_value_from_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        value: Dbr,
+        client: &'a ClientInfo,
+    ) -> BoxFuture<'a, Result<WriteCompletion, ErrorCondition>> {
+        Box::pin(async move {
+            self.write_value_async(pv_name, value).await?;
+            Ok(Box::pin(std::future::ready(Ok(()))) as WriteCompletion)
+        })
+    }
+
+    /// Asynchronous version of [Provider::monitor_value]
+    fn monitor_value_async<'a>(
+        &'a mut self,
+        pv_name: &'a str,
+        data_type: DbrType,
+        data_count: usize,
+        mask: MonitorMask,
+        trigger: mpsc::Sender<String>,
+    ) -> BoxFuture<'a, Result<broadcast::Receiver<Dbr>, ErrorCondition>> {
+        Box::pin(std::future::ready(
+            self.monitor_value(pv_name, data_type, data_count, mask, trigger),
+        ))
+    }
 }
@@ -1,67 +1,222 @@
 #![allow(dead_code)]
 
+mod diagnostics;
+pub use crate::proto::server::{ChannelInfo, CircuitInfo};
+
 use core::str;
 use pnet::datalink;
+use socket2::{SockRef, TcpKeepalive};
 use std::{
-    collections::HashMap,
-    io::{self, Cursor},
-    net::{IpAddr, Ipv4Addr},
-    num::NonZeroUsize,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
     time::{Duration, Instant},
 };
 use tokio::{
     io::AsyncWriteExt,
-    net::{TcpListener, TcpStream, UdpSocket},
+    net::{TcpListener, TcpStream, UdpSocket, tcp::OwnedWriteHalf},
     select,
-    sync::{broadcast, mpsc},
-    task::{JoinHandle, JoinSet},
+    sync::{broadcast, mpsc, watch},
+    task::{JoinError, JoinHandle, JoinSet},
 };
-use tokio_util::sync::CancellationToken;
+use tokio_stream::StreamExt;
+use tokio_util::{codec::FramedRead, sync::CancellationToken};
 use tracing::{debug, error, info, trace, warn};
 
 use crate::{
-    dbr::{DBR_BASIC_STRING, Dbr, DbrType},
-    messages::{
-        self, AccessRights, AsBytes, CAMessage, CreateChannel, CreateChannelResponse, ECAError,
-        ErrorCondition, EventAddResponse, Message, MessageError, MonitorMask, ReadNotify,
-        ReadNotifyResponse, Write, parse_search_packet,
+    client::{Searcher, SearcherBuilder},
+    dbr::{Dbr, DbrBasicType, DbrCategory, DbrType, DbrValue},
+    messages::{self, AsBytes, ErrorCondition, EventAdd, RawMessageDecoder, Search},
+    proto::{
+        search::SearchResponder,
+        server::{
+            CircuitSettings, Completion, MAX_FORWARDED_SEARCHES, NewSubscription, ProviderRequest,
+            ServerCircuit, ServerEvent,
+        },
+    },
+    providers::{ChannelMetadata, ClientInfo, Provider, ProviderSet, WriteCompletion},
+    server::diagnostics::{CircuitCommand, DiagnosticPVs, ServerStats},
+    utils::{
+        Env, new_reusable_udp_socket, parse_address_list, parse_env_bool, parse_ip_list,
+        process_env,
     },
-    providers::Provider,
-    utils::new_reusable_udp_socket,
 };
 
+/// How many messages can be waiting to be written to a client.
+///
+/// Once this fills, subscription updates are coalesced so that only the newest value
+/// of each subscription is kept until the client catches up.
+const WRITE_QUEUE_LENGTH: usize = 16;
+
+/// How many search datagrams can be waiting on the provider at once.
+///
+/// Datagrams arriving while this many are being looked up are dropped, and the
+/// clients that sent them will search again.
+const MAX_SEARCH_DATAGRAMS_IN_PROGRESS: usize = 64;
+
+/// The delay between the first two beacons sent on startup.
+///
+/// As in epics-base, this doubles with every beacon sent until it reaches the
+/// configured beacon period, so that clients notice a (re)started server quickly.
+const INITIAL_BEACON_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The smallest array size limit that can be configured, the same as in rsrv.
+const MIN_MAX_ARRAY_BYTES: usize = 16384;
+
+/// The array size limit used unless one is configured.
+///
+/// This is far larger than rsrv's default, so that big waveforms work out of the
+/// box, but still stops a client from asking the server to allocate gigabytes.
+const DEFAULT_MAX_ARRAY_BYTES: usize = 16 * 1024 * 1024;
+
+/// How often the diagnostic PVs are updated, if enabled.
+const DIAGNOSTICS_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Serve data to CA clients by managing the Circuit/Channel lifecycles and interfacing with [`Provider`].
 #[derive(Default)]
 pub struct Server<L: Provider> {
     /// Broadcast port to sent beacons
     beacon_port: u16,
+    /// Maximum interval between beacons, once the startup ramp has finished
+    beacon_period: Duration,
+    /// Explicit destinations to send beacons to
+    beacon_addresses: Vec<SocketAddr>,
+    /// Whether to send beacons to the broadcast address of every interface
+    auto_beacon_addresses: bool,
     /// Port to receive search queries on
     search_port: u16,
     /// Port to receive connections on, if specified
     connection_port: Option<u16>,
+    /// Interfaces to serve on. If empty, then serve on all interfaces.
+    interface_addresses: Vec<Ipv4Addr>,
+    /// Clients that we should never respond to
+    ignore_addresses: Vec<Ipv4Addr>,
+    /// Settings passed on to every circuit
+    circuit_options: CircuitOptions,
+    /// How to find PVs on other servers, if acting as a name server
+    name_server: Option<SearcherBuilder>,
+    /// Counters and client information, shared with every circuit
+    stats: Arc<ServerStats>,
+    /// PVs to publish the server statistics to, if enabled
+    diagnostics: Option<DiagnosticPVs>,
+    /// Where to tell the handle what addresses we are listening on
+    addresses: Option<watch::Sender<Option<ServerAddresses>>>,
     /// The beacon ID of the last beacon broadcast
     beacon_id: u32,
     next_circuit_id: u64,
     circuits: Vec<Circuit<L>>,
     shutdown: CancellationToken,
     library_provider: L,
-    tasks: JoinSet<Result<(), io::Error>>,
+    tasks: JoinSet<Result<(), ServerError>>,
+}
+
+/// The local addresses that a running server is using
+#[derive(Clone, Debug)]
+pub struct ServerAddresses {
+    /// Where clients can connect to, one per served interface
+    pub connection: Vec<SocketAddr>,
+    /// Where searches are received
+    pub search: Vec<SocketAddr>,
+    /// Where beacons are sent from
+    pub beacon: SocketAddr,
+}
+
+/// Problems that stopped a server, or that it encountered while running
+#[derive(thiserror::Error, Debug)]
+pub enum ServerError {
+    #[error("{0}")]
+    IO(#[from] io::Error),
+    #[error("A server task failed: {0}")]
+    TaskFailed(String),
+    #[error("Multiple errors: {0:?}")]
+    Multiple(Vec<ServerError>),
+}
+
+impl ServerError {
+    /// Combine the errors from several tasks into one result
+    fn from_many(mut errors: Vec<ServerError>) -> Result<(), ServerError> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(ServerError::Multiple(errors)),
+        }
+    }
+}
+
+impl From<JoinError> for ServerError {
+    fn from(value: JoinError) -> Self {
+        if !value.is_panic() {
+            return ServerError::TaskFailed(value.to_string());
+        }
+        let panic = value.into_panic();
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        ServerError::TaskFailed(message)
+    }
 }
 
 pub struct ServerHandle {
     cancel: CancellationToken,
-    handle: JoinHandle<Result<(), io::Error>>,
+    handle: JoinHandle<Result<(), ServerError>>,
+    addresses: watch::Receiver<Option<ServerAddresses>>,
+    stats: Arc<ServerStats>,
 }
 
 impl ServerHandle {
-    pub async fn join(&mut self) -> Result<(), io::Error> {
-        (&mut self.handle).await.unwrap()
+    pub async fn join(&mut self) -> Result<(), ServerError> {
+        (&mut self.handle).await?
     }
 
-    pub async fn stop(mut self) -> Result<(), io::Error> {
+    /// Shut down the server, and wait for it to finish
+    ///
+    /// Every client is told that its channels have been disconnected, so that
+    /// it can look for them elsewhere, and is then given until the shutdown
+    /// timeout to receive anything still waiting to be sent. Any errors that
+    /// the server encountered while running are returned.
+    pub async fn stop(mut self) -> Result<(), ServerError> {
         self.cancel.cancel();
         self.join().await
     }
+
+    /// Wait for the server to start listening, and get the addresses it is using
+    ///
+    /// This is how to find out the connection port, if it was picked
+    /// automatically. Returns `None` if the server failed to start.
+    pub async fn addresses(&self) -> Option<ServerAddresses> {
+        let mut addresses = self.addresses.clone();
+        addresses
+            .wait_for(|a| a.is_some())
+            .await
+            .ok()
+            .and_then(|a| a.clone())
+    }
+
+    /// A snapshot of all the currently connected clients
+    pub fn circuits(&self) -> Vec<CircuitInfo> {
+        self.stats.circuits()
+    }
+
+    /// Close the connection to a client, returning false if it is not connected
+    pub async fn disconnect_client(&self, circuit_id: u64) -> bool {
+        self.stats
+            .send_command(circuit_id, CircuitCommand::Disconnect)
+            .await
+    }
+
+    /// Disconnect a single channel that a client has open
+    ///
+    /// The client is told that the channel has gone away, and is free to search
+    /// for it and connect again. Returns false if the client is not connected.
+    pub async fn disconnect_channel(&self, circuit_id: u64, server_id: u32) -> bool {
+        self.stats
+            .send_command(circuit_id, CircuitCommand::DisconnectChannel(server_id))
+            .await
+    }
 }
 
 impl Drop for ServerHandle {
@@ -70,6 +225,16 @@ impl Drop for ServerHandle {
     }
 }
 
+/// Is a client address in a list of addresses that we should ignore
+fn is_ignored(ignore_addresses: &[Ipv4Addr], client: SocketAddr) -> bool {
+    match client.ip() {
+        IpAddr::V4(ip) => ignore_addresses.contains(&ip),
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .is_some_and(|ip| ignore_addresses.contains(&ip)),
+    }
+}
+
 fn get_broadcast_ips() -> Vec<Ipv4Addr> {
     datalink::interfaces()
         .into_iter()
@@ -82,176 +247,342 @@ fn get_broadcast_ips() -> Vec<Ipv4Addr> {
         .collect()
 }
 
+/// Find the broadcast address for the network that a local interface address is on
+fn get_interface_broadcast_ip(interface: Ipv4Addr) -> Option<Ipv4Addr> {
+    datalink::interfaces()
+        .into_iter()
+        .flat_map(|i| i.ips.into_iter())
+        .find(|i| i.ip() == interface)
+        .and_then(|i| match i.broadcast() {
+            IpAddr::V4(broadcast_ip) => Some(broadcast_ip),
+            _ => None,
+        })
+}
+
 /// Try to bind a specific port, or if not supplied the default, or a random port if that fails.
 async fn try_bind_ports(
+    interface: Ipv4Addr,
     request_port: Option<u16>,
+    default_port: u16,
 ) -> Result<tokio::net::TcpListener, std::io::Error> {
     match request_port {
         None => {
-            // Try binding TCP 5064
-            if let Ok(socket) = tokio::net::TcpListener::bind("0.0.0.0:5064").await {
+            // Try binding the same port as searches, as rsrv does
+            if let Ok(socket) = tokio::net::TcpListener::bind((interface, default_port)).await {
                 return Ok(socket);
             }
-            tokio::net::TcpListener::bind("0.0.0.0:0").await
+            tokio::net::TcpListener::bind((interface, 0)).await
         }
-        Some(port) => tokio::net::TcpListener::bind(format!("0.0.0.0:{port}")).await,
+        Some(port) => tokio::net::TcpListener::bind((interface, port)).await,
     }
 }
 
 impl<L: Provider> Server<L> {
-    async fn listen(mut self) -> Result<(), std::io::Error> {
-        // Create the TCP listener first so we know what port to advertise
-        let request_port = self.connection_port;
-        let connection_socket = try_bind_ports(request_port).await?;
-
-        // Whatever we ended up with, we want to advertise
-        let listen_port = connection_socket.local_addr().unwrap().port();
-
-        self.listen_for_searches(listen_port);
-        self.handle_tcp_connections(connection_socket);
-        self.broadcast_beacons(listen_port).await?;
-
-        // Join all tasks, and take the first io::Error as the error to return
-        let results: Vec<_> = self
-            .tasks
-            .join_all()
-            .await
-            .into_iter()
-            .filter_map(Result::err)
-            .collect();
-        if let Some(e) = results.into_iter().next() {
-            Err(e)
+    async fn listen(mut self) -> Result<(), ServerError> {
+        if let Some(searcher) = self.name_server.take() {
+            self.circuit_options.name_server = Some(Arc::new(searcher.start().await?));
+        }
+        if let Some(diagnostics) = self.diagnostics.take() {
+            self.publish_diagnostics(diagnostics);
+        }
+        let interfaces = if self.interface_addresses.is_empty() {
+            vec![Ipv4Addr::UNSPECIFIED]
         } else {
-            Ok(())
+            self.interface_addresses.clone()
+        };
+        // Create the TCP listeners first so we know what port to advertise. The first
+        // interface decides what this is, and the others must then use the same one.
+        let first_listener =
+            try_bind_ports(interfaces[0], self.connection_port, self.search_port).await?;
+        let listen_port = first_listener.local_addr()?.port();
+        let mut listeners = vec![first_listener];
+        for interface in &interfaces[1..] {
+            listeners.push(TcpListener::bind((*interface, listen_port)).await?);
+        }
+
+        let mut search_addresses = Vec::new();
+        for interface in &interfaces {
+            search_addresses.extend(self.listen_for_searches(*interface, listen_port)?);
+        }
+        let connection_addresses = listeners
+            .iter()
+            .map(|l| l.local_addr())
+            .collect::<Result<_, _>>()?;
+        self.handle_tcp_connections(listeners);
+        let beacon_address = self.broadcast_beacons(&interfaces, listen_port).await?;
+        if let Some(addresses) = self.addresses.take() {
+            addresses.send_replace(Some(ServerAddresses {
+                connection: connection_addresses,
+                search: search_addresses,
+                beacon: beacon_address,
+            }));
+        }
+
+        // Join all tasks, and collect every error that any of them hit
+        let mut errors = Vec::new();
+        while let Some(result) = self.tasks.join_next().await {
+            match result {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => errors.push(e),
+                Err(e) => errors.push(e.into()),
+            }
         }
+        ServerError::from_many(errors)
+    }
+
+    /// Regularly update the diagnostic PVs from the server statistics
+    fn publish_diagnostics(&mut self, mut diagnostics: DiagnosticPVs) {
+        let stats = self.stats.clone();
+        let cancel = self.shutdown.clone();
+        self.tasks.spawn(async move {
+            let mut interval = tokio::time::interval(DIAGNOSTICS_INTERVAL);
+            loop {
+                select! {
+                    _ = interval.tick() => diagnostics.update(&stats),
+                    _ = cancel.cancelled() => break,
+                }
+            }
+            Ok(())
+        });
     }
 
-    async fn broadcast_beacons(&mut self, connection_port: u16) -> io::Result<()> {
+    async fn broadcast_beacons(
+        &mut self,
+        interfaces: &[Ipv4Addr],
+        connection_port: u16,
+    ) -> io::Result<SocketAddr> {
         let beacon_port = self.beacon_port;
-        let broadcast = UdpSocket::bind("0.0.0.0:0").await?;
+        let beacon_period = self.beacon_period;
+        let beacon_addresses = self.beacon_addresses.clone();
+        let auto_beacon_addresses = self.auto_beacon_addresses;
+        // Beacons can only tell clients our address if there is a single one to give,
+        // otherwise clients use the address that the beacon came from.
+        let server_ip = match interfaces {
+            [ip] if !ip.is_unspecified() => Some(*ip),
+            _ => None,
+        };
+        let interfaces = interfaces.to_vec();
+        let stats = self.stats.clone();
+        let broadcast = UdpSocket::bind((server_ip.unwrap_or(Ipv4Addr::UNSPECIFIED), 0)).await?;
+        broadcast.set_broadcast(true)?;
+        let beacon_address = broadcast.local_addr()?;
         let cancel = self.shutdown.clone();
         self.tasks.spawn(async move {
-            broadcast.set_broadcast(true).unwrap();
             let mut message = messages::RsrvIsUp {
                 server_port: connection_port,
                 beacon_id: 0,
+                server_ip,
                 ..Default::default()
             };
+            let mut interval = INITIAL_BEACON_INTERVAL;
             loop {
-                let mut writer = Cursor::new(Vec::new());
-                message.write(&mut writer).unwrap();
-                let message_bytes = writer.into_inner();
-                let broadcast_ips = get_broadcast_ips();
-                for i in broadcast_ips.iter() {
-                    broadcast
-                        .send_to(message_bytes.as_slice(), (*i, beacon_port))
+                let message_bytes = message.as_bytes();
+                // Interfaces can come and go, so work out broadcast addresses each time
+                let mut destinations = beacon_addresses.clone();
+                if auto_beacon_addresses {
+                    let broadcast_ips = if interfaces == [Ipv4Addr::UNSPECIFIED] {
+                        get_broadcast_ips()
+                    } else {
+                        interfaces
+                            .iter()
+                            .filter_map(|ip| get_interface_broadcast_ip(*ip))
+                            .collect()
+                    };
+                    destinations.extend(
+                        broadcast_ips
+                            .into_iter()
+                            .map(|ip| SocketAddr::from((ip, beacon_port))),
+                    );
+                }
+                for destination in destinations.iter() {
+                    if let Err(e) = broadcast
+                        .send_to(message_bytes.as_slice(), destination)
                         .await
-                        .unwrap();
+                    {
+                        warn!("Failed to send beacon to {destination}: {e}");
+                    }
                 }
-                info!(
-                    "Broadcasting beacon {} to {} interfaces: {:?}",
+                stats.beacon_sent();
+                debug!(
+                    "Sent beacon {} to {} addresses: {:?}",
                     message.beacon_id,
-                    broadcast_ips.len(),
-                    broadcast_ips,
+                    destinations.len(),
+                    destinations,
                 );
                 message.beacon_id = message.beacon_id.wrapping_add(1);
                 select! {
-                    _ = tokio::time::sleep(Duration::from_secs(15)) => (),
+                    _ = tokio::time::sleep(interval) => (),
                     _ = cancel.cancelled() => break,
                 };
+                interval = (interval * 2).min(beacon_period);
             }
             Ok(())
         });
-        Ok(())
+        Ok(beacon_address)
     }
 
-    fn listen_for_searches(&mut self, connection_port: u16) {
+    /// Start answering searches that arrive on an interface
+    ///
+    /// A socket bound to a specific address does not receive broadcasts, so for
+    /// specific interfaces we also listen on the broadcast address, as rsrv does.
+    fn listen_for_searches(
+        &mut self,
+        interface: Ipv4Addr,
+        connection_port: u16,
+    ) -> io::Result<Vec<SocketAddr>> {
         let search_port = self.search_port;
+        let listener = new_reusable_udp_socket((interface, search_port))?;
+        let mut addresses = vec![listener.local_addr()?];
+        if interface.is_unspecified() {
+            self.answer_searches(listener, None, connection_port);
+            return Ok(addresses);
+        }
+        self.answer_searches(listener, Some(interface), connection_port);
+        if let Some(broadcast_ip) = get_interface_broadcast_ip(interface)
+            && broadcast_ip != interface
+        {
+            match new_reusable_udp_socket((broadcast_ip, search_port)) {
+                Ok(listener) => {
+                    addresses.push(listener.local_addr()?);
+                    self.answer_searches(listener, Some(interface), connection_port);
+                }
+                Err(e) => warn!(
+                    "Could not listen for broadcast searches on {broadcast_ip}:{search_port}: {e}"
+                ),
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// Respond to searches received on a UDP socket
+    ///
+    /// If `server_ip` is given, then replies tell the client to connect there,
+    /// otherwise the client connects to the address that the reply came from.
+    fn answer_searches(
+        &mut self,
+        listener: UdpSocket,
+        server_ip: Option<Ipv4Addr>,
+        connection_port: u16,
+    ) {
         let library_provider = self.library_provider.clone();
+        let request_timeout = self.circuit_options.request_timeout;
+        let ignore_addresses = self.ignore_addresses.clone();
         let cancel = self.shutdown.clone();
 
         self.tasks.spawn(async move {
             let mut buf: Vec<u8> = vec![0; 0xFFFF];
-            let listener = new_reusable_udp_socket(format!("0.0.0.0:{search_port}")).unwrap();
 
             info!(
                 "Listening for searches on {:?}",
                 listener.local_addr().unwrap()
             );
-            // Keep track of recent searches and source ports, to help reject duplicate
-            // messages coming in on multiple network ports.
-            let mut recent_searches: Vec<(Instant, u16, Vec<messages::Search>)> = Vec::new();
+            let mut responder = SearchResponder::new(server_ip, connection_port);
+            // Each datagram is looked up separately, so a slow one doesn't hold up others
+            let mut lookups = JoinSet::new();
 
             loop {
-                // Receieve a message, or cancel
-                let (size, origin) = select! {
+                // Receieve a message, send the answer to one, or cancel
+                let received = select! {
                     r = listener.recv_from(&mut buf) => r,
-                    _ = cancel.cancelled() => break,
-                }
-                .unwrap();
-                let msg_buf = &buf[..size];
-                if let Ok(searches) = parse_search_packet(msg_buf) {
-                    // Handle rejection window:
-                    // Drop all requests for identical PVs from the same source port
-                    let rejection_window_start = Instant::now()
-                        .checked_sub(Duration::from_micros(500))
-                        .unwrap();
-                    let mut is_duplicate = false;
-                    recent_searches.retain(|(i, port, msgs)| {
-                        let keep = i > &rejection_window_start;
-                        if *port == origin.port() && msgs == &searches && keep {
-                            is_duplicate = true;
-                        }
-                        keep
-                    });
-                    if is_duplicate {
-                        continue;
-                    }
-                    // This isn't a duplicate search, record it in case it comes in again
-                    recent_searches.push((Instant::now(), origin.port(), searches.to_vec()));
-
-                    let mut replies = Vec::new();
-                    {
-                        for search in searches {
-                            if library_provider.provides(&search.channel_name) {
-                                replies.push(search.respond(None, connection_port, true));
+                    Some(result) = lookups.join_next(), if !lookups.is_empty() => {
+                        let Ok((origin, answers)) = result else {
+                            continue;
+                        };
+                        if let Some(reply) = responder.reply(answers) {
+                            if let Err(e) = listener.send_to(&reply, origin).await {
+                                warn!("Failed to send search results to {origin}: {e}");
+                                continue;
                             }
+                            debug!("Sending search results to {origin}");
                         }
+                        continue;
+                    },
+                    _ = cancel.cancelled() => break,
+                };
+                // e.g. ICMP errors from earlier replies can show up here
+                let (size, origin) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("Error receiving search: {e}");
+                        continue;
                     }
-                    if !replies.is_empty() {
-                        let mut reply_buf = Cursor::new(Vec::new());
-                        messages::Version::default().write(&mut reply_buf).unwrap();
-                        for reply in &replies {
-                            reply.write(&mut reply_buf).unwrap();
-                        }
-                        listener
-                            .send_to(&reply_buf.into_inner(), origin)
-                            .await
-                            .unwrap();
-                        debug!("Sending {} search results", replies.len());
-                    }
-                } else {
+                };
+                if is_ignored(&ignore_addresses, origin) {
+                    trace!("Ignoring search from {origin}");
+                    continue;
+                }
+                let Ok(searches) = responder.handle_datagram(&buf[..size], origin, Instant::now())
+                else {
                     error!("Got unparseable search message from {origin}");
+                    continue;
+                };
+                if searches.is_empty() {
+                    continue;
+                }
+                if lookups.len() >= MAX_SEARCH_DATAGRAMS_IN_PROGRESS {
+                    warn!(
+                        "Too many searches waiting on the provider, dropping search from {origin}"
+                    );
+                    continue;
                 }
+                let library_provider = library_provider.clone();
+                lookups.spawn(async move {
+                    // Every search in the datagram is looked up at once
+                    let lookups = searches.iter().map(|search| {
+                        let provides = library_provider.provides_async(&search.channel_name);
+                        Box::pin(tokio::time::timeout(request_timeout, provides))
+                    });
+                    let found = join_all(lookups.collect()).await;
+                    let answers: Vec<_> = searches
+                        .into_iter()
+                        .zip(found.into_iter().map(|f| f.unwrap_or(false)))
+                        .collect();
+                    (origin, answers)
+                });
             }
             Ok(())
         });
     }
 
-    fn handle_tcp_connections(&mut self, listener: TcpListener) {
+    fn handle_tcp_connections(&mut self, listeners: Vec<TcpListener>) {
         let library = self.library_provider.clone();
+        let ignore_addresses = self.ignore_addresses.clone();
         let cancel_inner = self.shutdown.clone();
+        let options = self.circuit_options.clone();
+        let stats = self.stats.clone();
         self.tasks.spawn(async move {
             let mut id = 0;
             let mut tasks = JoinSet::new();
-            loop {
+            let mut errors = Vec::new();
+            // Accept from whichever listener has a connection waiting
+            let accept_any = || {
+                std::future::poll_fn(|cx| {
+                    for listener in &listeners {
+                        if let Poll::Ready(result) = listener.poll_accept(cx) {
+                            return Poll::Ready(result);
+                        }
+                    }
+                    Poll::Pending
+                })
+            };
+            for listener in &listeners {
                 info!(
                     "Waiting to accept TCP connections on {}",
                     listener.local_addr().unwrap()
                 );
+            }
+            loop {
                 let (connection, client) = match select! {
                     _ = cancel_inner.cancelled() => break,
-                    x = listener.accept() => x,
+                    x = accept_any() => x,
+                    // Clean up after circuits as they finish
+                    Some(result) = tasks.join_next(), if !tasks.is_empty() => {
+                        if let Err(e) = result {
+                            error!("Circuit failed: {e}");
+                            errors.push(e.into());
+                        }
+                        continue;
+                    }
                 } {
                     Ok(x) => x,
                     Err(e) => {
@@ -259,371 +590,595 @@ impl<L: Provider> Server<L> {
                         continue;
                     }
                 };
+                if is_ignored(&ignore_addresses, client) {
+                    debug!("Refusing connection from ignored client {client}");
+                    continue;
+                }
                 debug!("  Got new stream from {client}");
                 let circuit_library = library.clone();
                 let cancel = cancel_inner.clone();
+                let options = options.clone();
+                let stats = stats.clone();
                 tasks.spawn(async move {
-                    Circuit::start(id, connection, circuit_library, cancel).await;
+                    Circuit::start(id, connection, circuit_library, options, stats, cancel).await;
                 });
                 id += 1;
             }
-            tasks.join_all().await;
-            Ok(())
+            // Circuits shut themselves down, but we need to wait for them to do so
+            while let Some(result) = tasks.join_next().await {
+                if let Err(e) = result {
+                    error!("Circuit failed: {e}");
+                    errors.push(e.into());
+                }
+            }
+            ServerError::from_many(errors)
         });
     }
 }
 
+/// Settings that apply to every circuit that the server runs
+#[derive(Clone, Debug)]
+struct CircuitOptions {
+    /// How long a circuit can be silent before we probe it with an Echo, and the
+    /// TCP keepalive time
+    inactivity_timeout: Duration,
+    /// The largest message payload that will be sent or received, if limited
+    max_array_bytes: Option<usize>,
+    /// Used to find PVs on other servers, if acting as a name server
+    name_server: Option<Arc<Searcher>>,
+    /// How long to spend sending remaining data to the client when shutting down
+    shutdown_timeout: Duration,
+    /// How long to wait for the provider to answer a request
+    request_timeout: Duration,
+}
+
+impl Default for CircuitOptions {
+    fn default() -> Self {
+        CircuitOptions {
+            inactivity_timeout: Duration::from_secs(30),
+            max_array_bytes: Some(DEFAULT_MAX_ARRAY_BYTES),
+            name_server: None,
+            shutdown_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Runs the protocol state of a circuit over a TCP connection
+///
+/// Everything the client asks for is worked out by the [ServerCircuit]. This does
+/// the waiting that it asks for: on the provider, on forwarded searches and on
+/// writes that take a while to finish.
 struct Circuit<L: Provider> {
     id: u64,
-    last_message: Instant,
-    client_version: u16,
-    client_host_name: Option<String>,
-    client_user_name: Option<String>,
-    client_events_on: bool,
+    state: ServerCircuit<L>,
+    requester: Requester<L>,
+    /// Answers to WriteNotify requests, once the provider has finished them
+    unfinished_writes: JoinSet<(messages::WriteNotify, Result<(), ErrorCondition>)>,
+    /// Used to find PVs on other servers, if acting as a name server
+    name_server: Option<Arc<Searcher>>,
+    /// Where searches forwarded to other servers send their results
+    search_replies: mpsc::Sender<(Search, Option<SocketAddrV4>)>,
+    /// Where we report what this circuit is doing
+    stats: Arc<ServerStats>,
+}
+
+/// The part of a circuit that asks the provider for things
+///
+/// Providers can take a while to answer, so requests are made away from the rest
+/// of the circuit, each with its own copy of the requester.
+#[derive(Clone)]
+struct Requester<L: Provider> {
+    id: u64,
     library: L,
-    channels: HashMap<u32, Channel>,
-    next_channel_id: u32,
+    request_timeout: Duration,
+    /// Wakes the circuit when a subscription has a new value
     monitor_value_available: mpsc::Sender<String>,
 }
 
-#[derive(Debug)]
-struct Channel {
-    name: String,
-    client_id: u32,
-    server_id: u32,
-    subscription: Option<PVSubscription>,
-}
+impl<L: Provider> Requester<L> {
+    /// Get the provider's answer to a request from the circuit
+    async fn run(mut self, request: ProviderRequest) -> Completion {
+        match request {
+            ProviderRequest::Search(search) => {
+                let found = self.provides(&search.channel_name).await;
+                Completion::Search(search, found)
+            }
+            ProviderRequest::CreateChannel(message) => {
+                // If the PV is replaced while we wait, this won't match the new one
+                let generation = self.library.pv_generation(&message.channel_name);
+                let read = self.library.channel_metadata_async(&message.channel_name);
+                let metadata = provider_request(self.request_timeout, read).await;
+                Completion::CreateChannel(message, generation, metadata)
+            }
+            ProviderRequest::Read { request, name } => {
+                let result = self.read_dbr(&name, request.data_type).await;
+                Completion::Read(request, result)
+            }
+            ProviderRequest::Subscribe { request, name } => {
+                let result = self.subscribe(&name, &request).await;
+                Completion::Subscribe(request, result)
+            }
+            ProviderRequest::Write {
+                request,
+                name,
+                value,
+                client,
+            } => {
+                let result = self.write(&name, value, &client).await;
+                Completion::Write(request, result)
+            }
+            ProviderRequest::WriteNotify {
+                request,
+                name,
+                value,
+                client,
+            } => {
+                let result = self.write(&name, value, &client).await;
+                Completion::WriteNotify(request, result)
+            }
+            ProviderRequest::CheckProvided(channels) => {
+                let mut server_ids = Vec::new();
+                for (server_id, name) in channels {
+                    // If the provider is too slow to answer, assume that the PV is still there
+                    let provides = self.library.provides_async(&name);
+                    let provides = tokio::time::timeout(self.request_timeout, provides).await;
+                    if provides == Ok(false) {
+                        server_ids.push(server_id);
+                    }
+                }
+                Completion::Unprovided(server_ids)
+            }
+        }
+    }
 
-#[derive(Debug)]
-struct PVSubscription {
-    data_type: DbrType,
-    data_count: usize,
-    subscription_id: u32,
-    mask: MonitorMask,
-    receiver: broadcast::Receiver<Dbr>,
-}
+    /// Does the provider have a PV, assuming not if it is too slow to say
+    async fn provides(&self, name: &str) -> bool {
+        let provides = self.library.provides_async(name);
+        tokio::time::timeout(self.request_timeout, provides)
+            .await
+            .unwrap_or(false)
+    }
 
-impl<L: Provider> Circuit<L> {
-    async fn do_version_exchange(stream: &mut TcpStream) -> Result<u16, MessageError> {
-        // Send our Version
-        stream
-            .write_all(messages::Version::default().as_bytes().as_ref())
-            .await?;
-        // Immediately receive a Version message back from the client
-        Ok(match Message::read_server_message(stream).await? {
-            Message::Version(v) => v.protocol_version,
-            err => {
-                // This is an error, we cannot receive anything until we get this
-                return Err(MessageError::UnexpectedMessage(err));
+    async fn read_dbr(&self, name: &str, data_type: DbrType) -> Result<Dbr, ErrorCondition> {
+        // The class name is the record type, which does not need the value
+        if data_type.category == DbrCategory::ClassName {
+            let metadata = self.read_metadata(name).await?;
+            return Ok(Dbr::Basic(DbrValue::String(vec![metadata.record_type])));
+        }
+        let read = self.library.read_value_async(name, Some(data_type));
+        let dbr = match provider_request(self.request_timeout, read).await {
+            Err(ErrorCondition::Timeout) => {
+                warn!("{}: Timed out waiting for provider to read {name}", self.id);
+                return Err(ErrorCondition::Timeout);
+            }
+            Err(e) => {
+                warn!("{}: Provider could not read {name}: {e}", self.id);
+                return Err(ErrorCondition::GetFail);
+            }
+            Ok(dbr) => dbr,
+        };
+        // Enums asked for as strings are sent as the name of their state
+        let dbr = match dbr.value() {
+            DbrValue::Enum(_) if data_type.basic_type == DbrBasicType::String => {
+                let labels = self.read_metadata(name).await?.display.enum_strings;
+                dbr.label_enum(&labels)
+            }
+            _ => dbr,
+        };
+        // Fill in display information if the provider didn't send any with the value
+        match data_type.category {
+            DbrCategory::Graphics | DbrCategory::Control if dbr.display().is_none() => {
+                Ok(dbr.with_display(self.read_metadata(name).await?.display))
+            }
+            _ => Ok(dbr),
+        }
+    }
+
+    async fn read_metadata(&self, name: &str) -> Result<ChannelMetadata, ErrorCondition> {
+        let read = self.library.channel_metadata_async(name);
+        match provider_request(self.request_timeout, read).await {
+            Err(ErrorCondition::Timeout) => {
+                warn!(
+                    "{}: Timed out waiting for provider to describe {name}",
+                    self.id
+                );
+                Err(ErrorCondition::Timeout)
             }
+            Err(e) => {
+                warn!("{}: Provider could not describe {name}: {e}", self.id);
+                Err(ErrorCondition::GetFail)
+            }
+            Ok(metadata) => Ok(metadata),
+        }
+    }
+
+    /// Read the initial value of a new subscription, and start it
+    async fn subscribe(
+        &mut self,
+        name: &str,
+        msg: &EventAdd,
+    ) -> Result<NewSubscription, ErrorCondition> {
+        let initial = self.read_dbr(name, msg.data_type).await?;
+        let enum_strings = if msg.data_type.basic_type == DbrBasicType::String {
+            self.read_metadata(name).await?.display.enum_strings
+        } else {
+            Vec::new()
+        };
+        let monitor = self.library.monitor_value_async(
+            name,
+            msg.data_type,
+            msg.data_count as usize,
+            msg.mask,
+            self.monitor_value_available.clone(),
+        );
+        let receiver = provider_request(self.request_timeout, monitor).await?;
+        Ok(NewSubscription {
+            initial,
+            enum_strings,
+            receiver,
         })
     }
-    async fn start(id: u64, mut stream: TcpStream, library: L, cancel: CancellationToken) {
+
+    async fn write(
+        &mut self,
+        name: &str,
+        dbr: Dbr,
+        client: &ClientInfo,
+    ) -> Result<WriteCompletion, ErrorCondition> {
+        let write = self.library.write_value_from_async(name, dbr, client);
+        provider_request(self.request_timeout, write).await
+    }
+}
+
+/// A request that is waiting on the provider
+type PendingRequest = Pin<Box<dyn Future<Output = Completion> + Send>>;
+
+/// Wait for all of a set of futures, running them concurrently
+///
+/// The results are returned in the same order as the futures.
+async fn join_all<F: Future + Unpin>(futures: Vec<F>) -> Vec<F::Output> {
+    let mut futures: Vec<_> = futures.into_iter().map(|f| (f, None)).collect();
+    std::future::poll_fn(|cx| {
+        let mut finished = true;
+        for (future, output) in futures.iter_mut().filter(|(_, o)| o.is_none()) {
+            match Pin::new(future).poll(cx) {
+                Poll::Ready(result) => *output = Some(result),
+                Poll::Pending => finished = false,
+            }
+        }
+        if finished {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    futures
+        .into_iter()
+        .map(|(_, output)| output.expect("All futures have finished"))
+        .collect()
+}
+
+/// Wait for the provider to answer a request, giving up after a timeout
+async fn provider_request<T>(
+    timeout: Duration,
+    request: impl Future<Output = Result<T, ErrorCondition>>,
+) -> Result<T, ErrorCondition> {
+    tokio::time::timeout(timeout, request)
+        .await
+        .unwrap_or(Err(ErrorCondition::Timeout))
+}
+
+impl<L: Provider> Circuit<L> {
+    async fn start(
+        id: u64,
+        stream: TcpStream,
+        library: L,
+        options: CircuitOptions,
+        stats: Arc<ServerStats>,
+        cancel: CancellationToken,
+    ) {
         info!("{id}: Starting circuit with {:?}", stream.peer_addr());
-        let client_version = Circuit::<L>::do_version_exchange(&mut stream)
-            .await
-            .unwrap();
-        // Client version is the bare minimum we need to establish a valid circuit
-        debug!("{id}: Got client version: {client_version}");
+        let (server_port, client_address) = match (stream.local_addr(), stream.peer_addr()) {
+            (Ok(local), Ok(peer)) => (local.port(), peer),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("{id}: Could not get addresses of circuit: {e}");
+                return;
+            }
+        };
         let (monitor_value_available, mut monitor_updates) = mpsc::channel::<String>(32);
+        // The circuit never forwards more searches than this at once
+        let (search_replies, mut forwarded_search_replies) = mpsc::channel(MAX_FORWARDED_SEARCHES);
+        let mut removed_pvs = library.watch_removed();
+        let inactivity_timeout = options.inactivity_timeout;
+        // Clients don't answer our Echo, so leave noticing that one has gone to TCP
+        let keepalive = TcpKeepalive::new()
+            .with_time(inactivity_timeout)
+            .with_interval(inactivity_timeout);
+        if let Err(e) = SockRef::from(&stream).set_tcp_keepalive(&keepalive) {
+            warn!("{id}: Could not enable TCP keepalive, dead clients may linger: {e}");
+        }
+        let decoder = match options.max_array_bytes {
+            Some(size) => RawMessageDecoder::with_max_payload_size(size),
+            None => RawMessageDecoder::default(),
+        };
+        let settings = CircuitSettings {
+            inactivity_timeout,
+            max_array_bytes: options.max_array_bytes,
+            forward_searches: options.name_server.is_some(),
+        };
         let mut circuit = Circuit {
             id,
-            last_message: Instant::now(),
-            client_version,
-            client_host_name: None,
-            client_user_name: None,
-            client_events_on: true,
-            library,
-            channels: HashMap::new(),
-            next_channel_id: 0,
-            monitor_value_available,
+            requester: Requester {
+                id,
+                library: library.clone(),
+                request_timeout: options.request_timeout,
+                monitor_value_available,
+            },
+            state: ServerCircuit::new(
+                id,
+                library,
+                settings,
+                server_port,
+                client_address,
+                Instant::now(),
+            ),
+            unfinished_writes: JoinSet::new(),
+            name_server: options.name_server,
+            search_replies,
+            stats: stats.clone(),
         };
+        let (commands, mut command_rx) = mpsc::channel(8);
+        stats.register_circuit(circuit.state.info(), commands);
+
+        // Writing happens on a separate task, so that a client that is slow to read
+        // only ever backs up the write queue, and never stalls reading.
+        let (reader, writer) = stream.into_split();
+        let mut reader = FramedRead::new(reader, decoder);
+        let (write_queue, write_queue_rx) = mpsc::channel::<(usize, Vec<u8>)>(WRITE_QUEUE_LENGTH);
+        let mut writer = tokio::spawn(Self::write_to_client(
+            id,
+            writer,
+            write_queue_rx,
+            inactivity_timeout,
+            stats.clone(),
+        ));
+
+        // The request waiting on the provider, if there is one
+        let mut in_flight: Option<PendingRequest> = None;
 
         // Now, everything else is based on responding to events
         loop {
-            tokio::select! {
+            // Replies are always queued, even if the client is slow to read them
+            for msg in circuit.state.poll_transmit() {
+                if write_queue.send((1, msg.as_bytes())).await.is_err() {
+                    break;
+                }
+            }
+            if write_queue.is_closed() {
+                break;
+            }
+            let next_liveness_check = circuit.state.poll_timeout();
+            let events = tokio::select! {
                 _ = cancel.cancelled() => break,
-                pv_name = monitor_updates.recv() => match pv_name {
-                    Some(pv_name) => match circuit.handle_monitor_update(&pv_name).await {
-                        Ok(messages) => {
-                            for msg in messages {
-                                debug!("{id}: Writing subscription update: {msg:?}");
-                                stream.write_all(&msg.as_bytes()).await.unwrap()
-                            }
-                        },
-                        Err(msg) => {
-                            error!("{id} Error: Unexpected Error message {msg:?}");
+                _ = tokio::time::sleep_until(next_liveness_check.into()) => {
+                    circuit.state.handle_timeout(Instant::now());
+                    continue;
+                },
+                trigger = monitor_updates.recv() => {
+                    // We hold a sender, so this channel can never be closed
+                    trigger.expect("Circuit holds a monitor trigger sender");
+                    circuit.state.collect_monitor_updates();
+                    continue;
+                },
+                command = command_rx.recv() => match command {
+                    Some(CircuitCommand::DisconnectChannel(server_id)) => {
+                        circuit.state.disconnect_channel(server_id);
+                        Vec::new()
+                    }
+                    // The server registry holds a sender for as long as we run
+                    Some(CircuitCommand::Disconnect) | None => {
+                        info!("{id}: Disconnecting client by request");
+                        break;
+                    }
+                },
+                // Removals wait for any request in progress, in case they need the provider
+                removed = async { removed_pvs.as_mut().unwrap().recv().await }, if removed_pvs.is_some() && in_flight.is_none() => {
+                    match removed {
+                        Ok(pv_name) => circuit.state.pv_removed(&pv_name),
+                        // We missed some, so check every channel still has a PV
+                        Err(broadcast::error::RecvError::Lagged(_)) => circuit.state.removals_missed(),
+                        Err(broadcast::error::RecvError::Closed) => {
+                            removed_pvs = None;
                             continue;
-                        },
+                        }
+                    }
+                },
+                reply = forwarded_search_replies.recv() => {
+                    // We hold a sender, so this channel can never be closed
+                    let (search, server) = reply.expect("Circuit holds a search reply sender");
+                    circuit.state.search_forwarded(search, server);
+                    continue;
+                },
+                // Only send subscription updates when there is space in the write
+                // queue; until then, they are coalesced into the latest value.
+                permit = write_queue.reserve(), if circuit.state.has_pending_updates() => {
+                    let Ok(permit) = permit else {
+                        break;
+                    };
+                    // Any warning goes out in the same write as the update
+                    let mut data = Vec::new();
+                    let mut count = 0;
+                    for msg in circuit.state.next_pending_update() {
+                        trace!("{id}: Writing subscription update: {msg:?}");
+                        data.extend(msg.as_bytes());
+                        count += 1;
                     }
-                    None => {
-                        panic!("Update monitor got all endpoints closed");
+                    if count > 0 {
+                        permit.send((count, data));
+                    }
+                    continue;
+                },
+                completion = async { in_flight.as_mut().unwrap().await }, if in_flight.is_some() => {
+                    in_flight = None;
+                    circuit.state.complete(completion)
+                },
+                Some(finished) = circuit.unfinished_writes.join_next(), if !circuit.unfinished_writes.is_empty() => {
+                    match finished {
+                        Ok((request, result)) => circuit.state.write_finished(request, result),
+                        Err(e) => error!("{id}: Failed waiting for a write to finish: {e}"),
                     }
+                    continue;
                 },
-                message = Message::read_server_message(&mut stream) => {
+                // Requests are answered in order, so wait for the provider before reading more
+                message = reader.next(), if in_flight.is_none() => {
                     let message = match message {
-                        Ok(message) => message,
-                        Err(MessageError::IO(io)) => {
-                            if io.kind() != io::ErrorKind::UnexpectedEof {
-                                error!("{id}: IO Error reading server message: {io}");
-                            }
+                        None => break,
+                        Some(Err(io)) => {
+                            error!("{id}: IO Error reading server message: {io}");
                             break;
                         }
-                        Err(MessageError::UnknownCommandId(command_id)) => {
-                            error!("{id}: Error: Receieved unknown command id: {command_id}");
-                            continue;
-                        }
-                        Err(MessageError::ParsingError(msg)) => {
-                            error!("{id}: Error: Incoming message parse error: {msg}");
-                            continue;
-                        }
-                        Err(MessageError::UnexpectedMessage(msg)) => {
-                            error!("{id}: Error: Got message from client that is invalid to receive on a server: {msg:?}");
-                            continue;
-                        }
-                        Err(MessageError::IncorrectCommandId(msg, expect)) => {
-                            panic!("{id}: Fatal error: Got {msg} instead of {expect}")
-                        }
-                        Err(MessageError::InvalidField(message)) => {
-                            error!("{id}: Got invalid message field: {message}");
-                            continue;
-                        }
-                        Err(MessageError::ErrorResponse(message)) => {
-                            error!("{id}: Got reading server messages generated error response: {message}");
-                            continue;
-                        }
+                        Some(Ok(message)) => message,
                     };
-                    match circuit.handle_message(message).await {
-                        Ok(messages) => {
-                            for msg in messages {
-                                trace!("Writing response message: {msg:?}");
-                                stream.write_all(&msg.as_bytes()).await.unwrap()
-                            }
-                        }
-                        Err(MessageError::UnexpectedMessage(msg)) => {
-                            error!("{id}: Error: Unexpected message: {msg:?}");
-                            continue;
-                        }
-                        Err(msg) => {
-                            error!("{id} Error: Unexpected Error message {msg:?}");
-                            continue;
+                    stats.message_received(message.message_size());
+                    match circuit.state.handle_raw_message(message, Instant::now()) {
+                        Ok(events) => events,
+                        Err(e) => {
+                            warn!("{id}: Closing circuit, client did not start with its version: {e}");
+                            break;
                         }
-                    };
+                    }
                 }
+            };
+            if let Some(request) = circuit.handle_events(events) {
+                in_flight = Some(request);
             }
         }
 
-        // If out here, we are closing the channel
-        info!("{id}: Closing circuit");
-        let _ = stream.shutdown().await;
-    }
-
-    async fn handle_monitor_update(&mut self, pv_name: &str) -> Result<Vec<Message>, MessageError> {
-        let c = self
-            .channels
-            .values_mut()
-            .find(|v| v.name == pv_name)
-            .unwrap();
-        debug!(
-            "{}: {}: Got update notification for PV {}",
-            self.id, c.server_id, c.name
+        // If out here, we are closing the channel. Dropping the circuit releases all
+        // of the channels and subscriptions that it held.
+        info!(
+            "{id}: Closing circuit with {} open channels",
+            circuit.state.info().channels.len()
         );
-        let subscription = c.subscription.as_mut().unwrap();
-        let dbr = subscription.receiver.recv().await.unwrap();
-
-        info!("Circuit got update notification: {dbr:?}");
-        let (item_count, data) = dbr
-            .convert_to(subscription.data_type)
-            .unwrap()
-            .to_bytes(NonZeroUsize::new(subscription.data_count));
-        Ok(vec![Message::EventAddResponse(EventAddResponse {
-            data_type: subscription.data_type,
-            data_count: item_count as u32,
-            subscription_id: subscription.subscription_id,
-            status_code: ErrorCondition::Normal,
-            data,
-        })])
-    }
-
-    async fn handle_message(&mut self, message: Message) -> Result<Vec<Message>, MessageError> {
-        let id = self.id;
-        match message {
-            Message::Echo => Ok(vec![Message::Echo]),
-            Message::EventAdd(msg) => {
-                debug!("{id}: {}: Got {:?}", msg.server_id, msg);
-                let channel = &mut self.channels.get_mut(&msg.server_id).unwrap();
-
-                let receiver = self
-                    .library
-                    .monitor_value(
-                        &channel.name,
-                        msg.data_type,
-                        msg.data_count as usize,
-                        msg.mask,
-                        self.monitor_value_available.clone(),
-                    )
-                    .map_err(MessageError::ErrorResponse)?;
-
-                channel.subscription = Some(PVSubscription {
-                    data_type: msg.data_type,
-                    data_count: msg.data_count as usize,
-                    mask: msg.mask,
-                    subscription_id: msg.subscription_id,
-                    receiver,
-                });
-
-                // Send back an initial value\
-                let name = channel.name.clone();
-                let value = self.do_read_dbr(&name, msg.data_type);
-                Ok(vec![
-                    msg.respond(&value)
-                        .map_err(MessageError::ErrorResponse)?
-                        .into(),
-                ])
-            }
-            Message::EventCancel(_) => todo!(),
-            Message::ClientName(name) if self.client_user_name.is_none() => {
-                info!("{id}: Got client username: {}", name.name);
-                self.client_user_name = Some(name.name);
-                Ok(Vec::default())
-            }
-            Message::HostName(name) if self.client_host_name.is_none() => {
-                info!("{id}: Got client hostname: {}", name.name);
-                self.client_host_name = Some(name.name);
-                Ok(Vec::default())
-            }
-            Message::CreateChannel(message) => {
-                info!(
-                    "{id}: Got request to create channel to: {}",
-                    message.channel_name
-                );
-                let (messages, channel) = self.create_channel(message);
-                if let Ok(channel) = channel {
-                    self.channels.insert(channel.server_id, channel);
+        stats.remove_circuit(id);
+        // If the server is shutting down, tell the client that all of its channels
+        // have gone, so that it can promptly look for them elsewhere.
+        if cancel.is_cancelled() {
+            circuit.state.disconnect_all();
+        }
+        let remaining = circuit.state.poll_transmit();
+        drop(circuit);
+        // Let the client receive everything still queued, but only for so long
+        let drain = async {
+            for msg in remaining {
+                if write_queue.send((1, msg.as_bytes())).await.is_err() {
+                    break;
                 }
-                Ok(messages)
-            }
-            Message::ClearChannel(message) => {
-                info!("{id}:{}: Request to clear channel", message.server_id);
-                self.channels.remove(&message.server_id);
-                Ok(Vec::default())
-            }
-            Message::ReadNotify(msg) => {
-                info!("{id}:{}: ReadNotify request: {:?}", msg.server_id, msg);
-                match self.do_read(&msg) {
-                    Ok(r) => {
-                        debug!("Sending response: {r:?}");
-                        // self.stream.write_all(&r.as_bytes()).await?
-                        Ok(vec![Message::ReadNotifyResponse(r)])
-                    }
+            }
+            drop(write_queue);
+            let _ = (&mut writer).await;
+        };
+        if tokio::time::timeout(options.shutdown_timeout, drain)
+            .await
+            .is_err()
+        {
+            warn!(
+                "{id}: Could not send everything to client within {:?}",
+                options.shutdown_timeout
+            );
+            writer.abort();
+        }
+    }
 
-                    Err(e) => {
-                        // Send an error in response to the read
-                        let err = ECAError::new(e, msg.client_ioid, Message::ReadNotify(msg));
-                        error!("Returning error: {err:?}");
-                        // self.stream.write_all(&err.as_bytes()).await?
-                        Ok(vec![Message::ECAError(err)])
-                    }
+    /// Start the work that the circuit state asked for, and report what changed
+    ///
+    /// Returns the request to wait on the provider for, if there is one.
+    fn handle_events(&mut self, events: Vec<ServerEvent>) -> Option<PendingRequest> {
+        let mut pending = None;
+        for event in events {
+            match event {
+                ServerEvent::Request(request) => {
+                    pending =
+                        Some(Box::pin(self.requester.clone().run(*request)) as PendingRequest);
                 }
-            }
-            Message::Write(msg) => {
-                debug!("{id}:{}: Write request: {:?}", msg.server_id, msg);
-                if !self.do_write(&msg) {
-                    Ok(vec![Message::ECAError(ECAError::new(
-                        ErrorCondition::PutFail,
-                        msg.client_ioid,
-                        Message::Write(msg),
-                    ))])
-                } else {
-                    Ok(Vec::default())
+                ServerEvent::ForwardSearch(search) => self.forward_search(search),
+                ServerEvent::WriteInProgress(request, finished) => {
+                    self.unfinished_writes
+                        .spawn(async move { (request, finished.await) });
                 }
             }
-            msg => Err(MessageError::UnexpectedMessage(msg)),
         }
+        self.stats.update_circuit(self.state.info());
+        pending
     }
 
-    fn do_read_dbr(&self, name: &str, data_type: DbrType) -> Dbr {
-        self.library.read_value(name, Some(data_type)).unwrap()
+    /// Look for a PV on other servers, without holding up the circuit
+    fn forward_search(&mut self, search: Search) {
+        let Some(name_server) = self.name_server.clone() else {
+            self.state.search_forwarded(search, None);
+            return;
+        };
+        let replies = self.search_replies.clone();
+        tokio::spawn(async move {
+            let server = match name_server.search_for(&search.channel_name).await {
+                Ok(SocketAddr::V4(server)) => Some(server),
+                _ => None,
+            };
+            // If the circuit has closed in the meantime, nobody needs the answer
+            let _ = replies.send((search, server)).await;
+        });
     }
 
-    fn do_read(&self, request: &ReadNotify) -> Result<ReadNotifyResponse, ErrorCondition> {
-        let channel = &self.channels[&request.server_id];
-        let pv = self.do_read_dbr(&channel.name, request.data_type);
-
-        // Read the data into a Vec<u8>
-        let (data_count, data) = pv
-            .convert_to(request.data_type)?
-            .to_bytes(NonZeroUsize::new(request.data_count as usize));
-        Ok(request.respond(data_count, data))
+    /// Write queued messages to the client until the queue is closed
+    ///
+    /// Each entry holds the number of messages in the data, so that several can be
+    /// written at once but still counted separately.
+    ///
+    /// If the client doesn't read anything for `write_timeout`, then we assume that it
+    /// has gone away and stop writing. This closes the queue, which tells the circuit
+    /// to shut down.
+    async fn write_to_client(
+        id: u64,
+        mut writer: OwnedWriteHalf,
+        mut queue: mpsc::Receiver<(usize, Vec<u8>)>,
+        write_timeout: Duration,
+        stats: Arc<ServerStats>,
+    ) {
+        while let Some((count, data)) = queue.recv().await {
+            match tokio::time::timeout(write_timeout, writer.write_all(&data)).await {
+                Ok(Ok(())) => stats.messages_sent(count, data.len()),
+                Ok(Err(e)) => {
+                    error!("{id}: Failed to write to client: {e}");
+                    return;
+                }
+                Err(_) => {
+                    warn!("{id}: Client has not read anything in {write_timeout:?}, closing");
+                    return;
+                }
+            }
+        }
+        let _ = writer.shutdown().await;
     }
-
-    fn do_write(&mut self, request: &Write) -> bool {
-        assert!(request.data_type == DBR_BASIC_STRING);
-        let channel = self.channels.get(&request.server_id).unwrap();
-
-        let Ok(dbr) = Dbr::from_bytes(
-            request.data_type,
-            request.data_count as usize,
-            &request.data,
-        ) else {
-            return false;
-        };
-        debug!("Got write request: {dbr:?}");
-        self.library.write_value(&channel.name, dbr).is_ok()
-    }
-
-    fn create_channel(&mut self, message: CreateChannel) -> (Vec<Message>, Result<Channel, ()>) {
-        let Ok(pv) = self.library.read_value(&message.channel_name, None) else {
-            warn!(
-                "Got a request for channel to '{}', which we do not appear to have.",
-                message.channel_name
-            );
-            return (
-                vec![Message::CreateChannelFailure(message.respond_failure())],
-                Err(()),
-            );
-        };
-        let access_rights = AccessRights {
-            client_id: message.client_id,
-            access_rights: self.library.get_access_right(
-                &message.channel_name,
-                self.client_user_name.as_deref(),
-                self.client_host_name.as_deref(),
-            ),
-        };
-        let id = self.next_channel_id;
-        self.next_channel_id += 1;
-        let createchan = CreateChannelResponse {
-            data_count: pv.value().get_count() as u32,
-            data_type: pv.data_type().basic_type,
-            client_id: message.client_id,
-            server_id: id,
-        };
-        info!(
-            "{}:{}: Opening {:?} channel to {}",
-            self.id, id, access_rights.access_rights, message.channel_name
-        );
-        // We have this channel, send the initial
-        (
-            vec![
-                Message::AccessRights(access_rights),
-                Message::CreateChannelResponse(createchan),
-            ],
-            Ok(Channel {
-                name: message.channel_name,
-                server_id: id,
-                client_id: message.client_id,
-                subscription: None,
-            }),
-        )
-    }
-}
+}
 
 /// Construct a [Server] object by setting up multiple aspects before running
 pub struct ServerBuilder<L: Provider> {
     beacon_port: u16,
+    beacon_period: Duration,
+    beacon_addresses: Option<Vec<SocketAddr>>,
+    auto_beacon_addresses: Option<bool>,
     search_port: u16,
     connection_port: Option<u16>,
+    interface_addresses: Vec<Ipv4Addr>,
+    ignore_addresses: Vec<Ipv4Addr>,
+    inactivity_timeout: Duration,
+    max_array_bytes: Option<usize>,
+    name_server: Option<SearcherBuilder>,
+    diagnostics_prefix: Option<String>,
+    shutdown_timeout: Duration,
+    request_timeout: Duration,
     provider: L,
     cancellation_token: CancellationToken,
 }
@@ -632,16 +1187,109 @@ impl<L: Provider> ServerBuilder<L> {
     pub fn new(provider: L) -> ServerBuilder<L> {
         ServerBuilder {
             beacon_port: 5065,
+            beacon_period: Duration::from_secs(15),
+            beacon_addresses: None,
+            auto_beacon_addresses: None,
             search_port: 5064,
             connection_port: None,
+            interface_addresses: Vec::new(),
+            ignore_addresses: Vec::new(),
+            inactivity_timeout: Duration::from_secs(30),
+            max_array_bytes: Some(DEFAULT_MAX_ARRAY_BYTES),
+            name_server: None,
+            diagnostics_prefix: None,
+            shutdown_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(5),
             provider,
             cancellation_token: CancellationToken::new(),
         }
     }
+    /// Create a builder configured from the environment, in the same way as rsrv
+    ///
+    /// This reads the following variables, with the `EPICS_CA_*` equivalent used
+    /// where the server-specific variable is not set:
+    ///
+    /// - `EPICS_CAS_SERVER_PORT` (`EPICS_CA_SERVER_PORT`): The port used for
+    ///   searches, and the preferred port for connections.
+    /// - `EPICS_CAS_BEACON_PORT` (`EPICS_CA_REPEATER_PORT`)
+    /// - `EPICS_CAS_BEACON_PERIOD` (`EPICS_CA_BEACON_PERIOD`): In seconds.
+    /// - `EPICS_CAS_INTF_ADDR_LIST`: Interfaces to serve on.
+    /// - `EPICS_CAS_IGNORE_ADDR_LIST`: Clients to ignore.
+    /// - `EPICS_CA_MAX_ARRAY_BYTES`: The largest array payload to send or receive.
+    ///
+    /// The beacon address lists are always read from the environment on start,
+    /// unless set on the builder. Invalid values are ignored with a warning. Any
+    /// settings made on the builder afterwards take precedence.
+    pub fn from_env(provider: L) -> ServerBuilder<L> {
+        ServerBuilder::from_lookup(provider, |name| std::env::var(name).ok())
+    }
+    /// Create a builder configured from EPICS variables looked up by `lookup`
+    ///
+    /// This reads the same variables as [ServerBuilder::from_env], but from
+    /// anywhere, e.g. a configuration file.
+    pub fn from_lookup<F>(provider: L, lookup: F) -> ServerBuilder<L>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let env = Env(lookup);
+        let mut builder = ServerBuilder::new(provider);
+        if let Some(port) = env.parse_with_fallback("EPICS_CAS_SERVER_PORT", "EPICS_CA_SERVER_PORT")
+        {
+            builder.search_port = port;
+        }
+        if let Some(port) =
+            env.parse_with_fallback("EPICS_CAS_BEACON_PORT", "EPICS_CA_REPEATER_PORT")
+        {
+            builder.beacon_port = port;
+        }
+        if let Some(period) =
+            env.parse_with_fallback::<f64>("EPICS_CAS_BEACON_PERIOD", "EPICS_CA_BEACON_PERIOD")
+        {
+            match Duration::try_from_secs_f64(period) {
+                Ok(period) if !period.is_zero() => builder.beacon_period = period,
+                _ => warn!("Ignoring invalid beacon period of {period} seconds"),
+            }
+        }
+        if let Some(list) = env.get("EPICS_CAS_INTF_ADDR_LIST") {
+            builder.interface_addresses = parse_ip_list(&list);
+        }
+        if let Some(list) = env.get("EPICS_CAS_IGNORE_ADDR_LIST") {
+            builder.ignore_addresses = parse_ip_list(&list);
+        }
+        if let Some(size) = env.parse("EPICS_CA_MAX_ARRAY_BYTES") {
+            builder.max_array_bytes = Some(size);
+        }
+        builder
+    }
     pub fn beacon_port(mut self, port: u16) -> ServerBuilder<L> {
         self.beacon_port = port;
         self
     }
+    /// The longest interval between beacons.
+    ///
+    /// On startup, beacons are sent rapidly and then back off until they are sent
+    /// once per period. Defaults to 15 seconds.
+    pub fn beacon_period(mut self, period: Duration) -> ServerBuilder<L> {
+        self.beacon_period = period;
+        self
+    }
+    /// Explicit addresses to send beacons to, in addition to any automatic ones.
+    ///
+    /// These can be broadcast or unicast addresses. If not set, this is read from
+    /// `EPICS_CAS_BEACON_ADDR_LIST` (or `EPICS_CA_ADDR_LIST` if that is not set),
+    /// with entries without a port using the beacon port.
+    pub fn beacon_addresses(mut self, addresses: Vec<SocketAddr>) -> ServerBuilder<L> {
+        self.beacon_addresses = Some(addresses);
+        self
+    }
+    /// Whether to send beacons to the broadcast address of every network interface.
+    ///
+    /// If not set, this is read from `EPICS_CAS_AUTO_BEACON_ADDR_LIST` (or
+    /// `EPICS_CA_AUTO_ADDR_LIST` if that is not set), and defaults to `true`.
+    pub fn auto_beacon_addresses(mut self, enabled: bool) -> ServerBuilder<L> {
+        self.auto_beacon_addresses = Some(enabled);
+        self
+    }
     pub fn search_port(mut self, port: u16) -> ServerBuilder<L> {
         self.search_port = port;
         self
@@ -650,6 +1298,86 @@ impl<L: Provider> ServerBuilder<L> {
         self.connection_port = Some(port);
         self
     }
+    /// Only serve on the network interfaces with these addresses.
+    ///
+    /// Connections and searches are only accepted on these interfaces, search
+    /// replies direct clients to the interface that the search arrived on, and
+    /// automatic beacons are only broadcast on these networks. By default, the
+    /// server is available on all interfaces.
+    pub fn interface_addresses(mut self, addresses: Vec<Ipv4Addr>) -> ServerBuilder<L> {
+        self.interface_addresses = addresses;
+        self
+    }
+    /// Never respond to clients connecting from these addresses.
+    pub fn ignore_addresses(mut self, addresses: Vec<Ipv4Addr>) -> ServerBuilder<L> {
+        self.ignore_addresses = addresses;
+        self
+    }
+    /// The largest array payload, in bytes, that will be sent to or accepted from clients.
+    ///
+    /// Reads of larger values fail with [`ErrorCondition::TooLarge`], and clients that
+    /// send larger messages are disconnected. As with rsrv, this cannot be set lower
+    /// than 16384 bytes. The default limit is 16 MiB.
+    pub fn max_array_bytes(mut self, size: usize) -> ServerBuilder<L> {
+        self.max_array_bytes = Some(size);
+        self
+    }
+    /// How long a client can be silent before the server probes it.
+    ///
+    /// If nothing has been received on a circuit for this long, the server sends an
+    /// Echo, and keeps doing so for as long as the client stays quiet. Clients
+    /// don't have to answer, so this is also used for TCP keepalive, which is how
+    /// dead connections are found and closed, along with all of their channels and
+    /// subscriptions.
+    pub fn inactivity_timeout(mut self, timeout: Duration) -> ServerBuilder<L> {
+        self.inactivity_timeout = timeout;
+        self
+    }
+    /// Act as a name server, pointing clients to PVs that other servers provide.
+    ///
+    /// Clients that search over TCP (e.g. with `EPICS_CA_NAME_SERVERS`) for a PV
+    /// that we do not have are answered with the address of a server found with
+    /// this searcher, if any. This allows clients to reach servers that they
+    /// cannot search for directly, such as across routed networks.
+    pub fn name_server(mut self, searcher: SearcherBuilder) -> ServerBuilder<L> {
+        self.name_server = Some(searcher);
+        self
+    }
+    /// Publish PVs describing the server itself, with names starting with `prefix`.
+    ///
+    /// These are read-only, updated every second, and are:
+    ///
+    /// - `CA_CLNT_CNT`: The number of connected clients.
+    /// - `CA_CLIENTS`: A line for each client, with user, host, address and the
+    ///   number of channels and subscriptions it has open. This is a fixed size
+    ///   char array, so very long lists are cut short.
+    /// - `CA_CONN_CNT`: The number of open channels.
+    /// - `CA_SUBS_CNT`: The number of active subscriptions.
+    /// - `CA_BYTES_IN`, `CA_BYTES_OUT`: Bytes received from and sent to clients.
+    /// - `CA_MSGS_IN`, `CA_MSGS_OUT`: Messages received from and sent to clients.
+    /// - `BEACON_CNT`: The number of beacons sent.
+    /// - `UPTIME`: Seconds since the server started.
+    pub fn diagnostics(mut self, prefix: &str) -> ServerBuilder<L> {
+        self.diagnostics_prefix = Some(prefix.to_string());
+        self
+    }
+    /// How long to wait for clients to receive their last messages when stopping.
+    ///
+    /// Clients that have not received everything by then are disconnected anyway.
+    /// Defaults to 5 seconds.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> ServerBuilder<L> {
+        self.shutdown_timeout = timeout;
+        self
+    }
+    /// How long to wait for the provider to answer a single request.
+    ///
+    /// Reads and writes that take longer than this are answered with
+    /// [ErrorCondition::Timeout], and searches are treated as not found. Defaults
+    /// to 5 seconds.
+    pub fn request_timeout(mut self, timeout: Duration) -> ServerBuilder<L> {
+        self.request_timeout = timeout;
+        self
+    }
     pub fn cancellation_token(mut self, cancel: CancellationToken) -> ServerBuilder<L> {
         self.cancellation_token = cancel;
         self
@@ -657,11 +1385,58 @@ impl<L: Provider> ServerBuilder<L> {
 
     pub fn start(self) -> ServerHandle {
         let shutdown = self.cancellation_token.clone();
+        let beacon_addresses = self.beacon_addresses.unwrap_or_else(|| {
+            process_env()
+                .get_with_fallback("EPICS_CAS_BEACON_ADDR_LIST", "EPICS_CA_ADDR_LIST")
+                .map(|list| parse_address_list(&list, self.beacon_port))
+                .unwrap_or_default()
+        });
+        let auto_beacon_addresses = self
+            .auto_beacon_addresses
+            .or_else(|| {
+                process_env()
+                    .get_with_fallback("EPICS_CAS_AUTO_BEACON_ADDR_LIST", "EPICS_CA_AUTO_ADDR_LIST")
+                    .and_then(|v| parse_env_bool(&v))
+            })
+            .unwrap_or(true);
+        let max_array_bytes = self.max_array_bytes.map(|size| {
+            if size < MIN_MAX_ARRAY_BYTES {
+                warn!("Max array bytes of {size} is too small, using {MIN_MAX_ARRAY_BYTES}");
+            }
+            size.max(MIN_MAX_ARRAY_BYTES)
+        });
+        let diagnostics = self
+            .diagnostics_prefix
+            .map(|prefix| DiagnosticPVs::new(&prefix));
+        // The diagnostic PVs are served first, so that they can't be hidden
+        let library_provider = match &diagnostics {
+            Some(diagnostics) => ProviderSet::new().with(diagnostics.provider()),
+            None => ProviderSet::new(),
+        }
+        .with(self.provider);
+        let (addresses, addresses_rx) = watch::channel(None);
+        let stats = Arc::new(ServerStats::default());
         let server = Server {
             beacon_port: self.beacon_port,
+            beacon_period: self.beacon_period,
+            beacon_addresses,
+            auto_beacon_addresses,
             search_port: self.search_port,
             connection_port: self.connection_port,
-            library_provider: self.provider,
+            interface_addresses: self.interface_addresses,
+            ignore_addresses: self.ignore_addresses,
+            circuit_options: CircuitOptions {
+                inactivity_timeout: self.inactivity_timeout,
+                max_array_bytes,
+                name_server: None,
+                shutdown_timeout: self.shutdown_timeout,
+                request_timeout: self.request_timeout,
+            },
+            name_server: self.name_server,
+            diagnostics,
+            addresses: Some(addresses),
+            stats: stats.clone(),
+            library_provider,
             shutdown,
             ..Default::default()
         };
@@ -669,6 +1444,1834 @@ impl<L: Provider> ServerBuilder<L> {
         ServerHandle {
             cancel: self.cancellation_token,
             handle: tokio::spawn(async move { server.listen().await }),
+            addresses: addresses_rx,
+            stats,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        dbr::{Dbr, DbrBasicType, DbrCategory, DbrType, DbrValue},
+        messages::{self, AsBytes, ErrorCondition, Message, MonitorMask},
+        proto::server::MAX_FORWARDED_SEARCHES,
+        providers::{BoxFuture, ChannelMetadata, ClientInfo, IntercomProvider, Provider},
+        server::{
+            Circuit, CircuitInfo, CircuitOptions, ServerStats,
+            diagnostics::{CLIENT_LIST_LEN, DiagnosticPVs},
+        },
+    };
+
+    /// Start a single circuit on a local socket, returning the connected client end
+    async fn connect_circuit<L: Provider>(
+        provider: L,
+        options: CircuitOptions,
+    ) -> (TcpStream, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let circuit = tokio::spawn(async move {
+            let stats = Arc::new(ServerStats::default());
+            Circuit::start(
+                0,
+                stream,
+                provider,
+                options,
+                stats,
+                CancellationToken::new(),
+            )
+            .await
+        });
+        // Do the version exchange
+        assert!(matches!(
+            Message::read_client_message(&mut client).await.unwrap(),
+            Message::Version(_)
+        ));
+        client
+            .write_all(&messages::Version::default().as_bytes())
+            .await
+            .unwrap();
+        (client, circuit)
+    }
+
+    /// Open a channel on a connected circuit, returning the server ID
+    async fn create_channel(client: &mut TcpStream, name: &str) -> u32 {
+        let request = messages::CreateChannel {
+            client_id: 1,
+            channel_name: name.to_string(),
+            ..Default::default()
+        };
+        client.write_all(&request.as_bytes()).await.unwrap();
+        loop {
+            match Message::read_client_message(client).await.unwrap() {
+                Message::AccessRights(_) | Message::Echo => continue,
+                Message::CreateChannelResponse(response) => return response.server_id,
+                msg => panic!("Unexpected response to CreateChannel: {msg:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_is_probed_but_kept() {
+        let timeout = Duration::from_millis(100);
+        let mut provider = IntercomProvider::new();
+        provider.add_pv("VALUE", 1i32).unwrap();
+        let (mut client, circuit) = connect_circuit(
+            provider,
+            CircuitOptions {
+                inactivity_timeout: timeout,
+                ..Default::default()
+            },
+        )
+        .await;
+        let start = Instant::now();
+        // We should be sent an echo after the inactivity timeout, and then again,
+        // even though we never answer, like libca
+        for _ in 0..3 {
+            assert!(matches!(
+                Message::read_client_message(&mut client).await.unwrap(),
+                Message::Echo
+            ));
+        }
+        assert!(start.elapsed() >= 3 * timeout);
+        assert!(!circuit.is_finished());
+        // The circuit is still usable
+        create_channel(&mut client, "VALUE").await;
+        drop(client);
+        circuit.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_echo_reply_keeps_circuit_alive() {
+        let timeout = Duration::from_millis(100);
+        let (mut client, circuit) = connect_circuit(
+            IntercomProvider::new(),
+            CircuitOptions {
+                inactivity_timeout: timeout,
+                ..Default::default()
+            },
+        )
+        .await;
+        for _ in 0..3 {
+            assert!(matches!(
+                Message::read_client_message(&mut client).await.unwrap(),
+                Message::Echo
+            ));
+            client.write_all(&Message::Echo.as_bytes()).await.unwrap();
+        }
+        assert!(!circuit.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_gets_latest_value() {
+        let mut provider = IntercomProvider::new();
+        let mut value = provider
+            .add_vec_pv("WAVEFORM", vec![0.0f64; 20000], None)
+            .unwrap();
+        let (mut client, circuit) = connect_circuit(provider, CircuitOptions::default()).await;
+        let server_id = create_channel(&mut client, "WAVEFORM").await;
+        let data_type = DbrType {
+            basic_type: DbrBasicType::Double,
+            category: DbrCategory::Basic,
+        };
+        let subscribe = messages::EventAdd {
+            data_type,
+            data_count: 0,
+            server_id,
+            subscription_id: 7,
+            mask: MonitorMask::default(),
+        };
+        client.write_all(&subscribe.as_bytes()).await.unwrap();
+
+        // Flood updates without reading any, until the socket is congested
+        let updates = 500;
+        for i in 1..=updates {
+            value.store(&vec![i as f64; 20000]);
+            tokio::task::yield_now().await;
+        }
+        assert!(!circuit.is_finished());
+
+        // Now read everything - we should get fewer updates, ending with the newest,
+        // and be warned that the server had to drop some
+        let mut received = 0;
+        let mut warned = false;
+        loop {
+            let update = match Message::read_client_message(&mut client).await.unwrap() {
+                Message::EventAddResponse(update) => update,
+                Message::ECAError(error) => {
+                    assert!(matches!(error.condition, ErrorCondition::ServBehind));
+                    warned = true;
+                    continue;
+                }
+                msg => panic!("Expected only subscription updates, got {msg:?}"),
+            };
+            assert_eq!(update.subscription_id, 7);
+            received += 1;
+            if update.data[..8] == (updates as f64).to_be_bytes() {
+                break;
+            }
+        }
+        assert!(received < updates);
+        assert!(warned);
+    }
+
+    #[tokio::test]
+    async fn test_busy_subscription_does_not_starve_others() {
+        let mut provider = IntercomProvider::new();
+        let mut fast = provider
+            .add_vec_pv("FAST", vec![0.0f64; 20000], None)
+            .unwrap();
+        let mut slow = provider.add_pv("SLOW", 0i32).unwrap();
+        let (mut client, _circuit) = connect_circuit(provider, CircuitOptions::default()).await;
+        for (subscription_id, name) in [(1, "FAST"), (2, "SLOW")] {
+            let server_id = create_channel(&mut client, name).await;
+            let subscribe = messages::EventAdd {
+                data_type: DbrType {
+                    basic_type: DbrBasicType::Double,
+                    category: DbrCategory::Basic,
+                },
+                data_count: 0,
+                server_id,
+                subscription_id,
+                mask: MonitorMask::default(),
+            };
+            client.write_all(&subscribe.as_bytes()).await.unwrap();
+            assert!(matches!(
+                Message::read_client_message(&mut client).await.unwrap(),
+                Message::EventAddResponse(_)
+            ));
+        }
+
+        // Keep the fast PV busy the whole time that the slow one has an update
+        let updates = 200;
+        slow.store(&1);
+        for i in 1..=updates {
+            fast.store(&vec![i as f64; 20000]);
+            tokio::task::yield_now().await;
+        }
+
+        // The slow update must not have to wait for the fast one to stop
+        loop {
+            match Message::read_client_message(&mut client).await.unwrap() {
+                Message::EventAddResponse(update) if update.subscription_id == 2 => break,
+                Message::EventAddResponse(update) => {
+                    assert_ne!(update.data[..8], (updates as f64).to_be_bytes());
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    #[test]
+    fn test_builder_from_env() {
+        let vars = [
+            // The server-specific port is unset, so the general one is used
+            ("EPICS_CA_SERVER_PORT", "6064"),
+            ("EPICS_CAS_BEACON_PORT", "6065"),
+            ("EPICS_CA_REPEATER_PORT", "7065"),
+            // Malformed values are ignored, leaving the defaults
+            ("EPICS_CAS_BEACON_PERIOD", "-3"),
+            ("EPICS_CA_MAX_ARRAY_BYTES", "lots"),
+            ("EPICS_CAS_INTF_ADDR_LIST", "127.0.0.1:5064 nonsense:port"),
+            ("EPICS_CAS_IGNORE_ADDR_LIST", "10.0.0.1 10.0.0.2"),
+        ];
+        let vars = std::collections::HashMap::from(vars);
+        let builder = crate::ServerBuilder::from_lookup(IntercomProvider::new(), |name| {
+            vars.get(name).map(|v| v.to_string())
+        });
+        assert_eq!(builder.search_port, 6064);
+        assert_eq!(builder.beacon_port, 6065);
+        assert_eq!(builder.beacon_period, Duration::from_secs(15));
+        assert_eq!(
+            builder.max_array_bytes,
+            crate::ServerBuilder::new(IntercomProvider::new()).max_array_bytes
+        );
+        assert_eq!(builder.interface_addresses, [std::net::Ipv4Addr::LOCALHOST]);
+        assert_eq!(
+            builder.ignore_addresses,
+            [
+                std::net::Ipv4Addr::new(10, 0, 0, 1),
+                std::net::Ipv4Addr::new(10, 0, 0, 2)
+            ]
+        );
+
+        // Without any variables set, everything is the default
+        let builder = crate::ServerBuilder::from_lookup(IntercomProvider::new(), |_| None);
+        assert_eq!(builder.search_port, 5064);
+        assert_eq!(builder.beacon_port, 5065);
+    }
+
+    #[tokio::test]
+    async fn test_beacons_ramp_up_to_period() {
+        let receiver = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = crate::ServerBuilder::new(IntercomProvider::new())
+            .search_port(0)
+            .connection_port(0)
+            .beacon_addresses(vec![receiver.local_addr().unwrap()])
+            .auto_beacon_addresses(false)
+            .beacon_period(Duration::from_millis(200))
+            .start();
+
+        let mut buffer = [0u8; 64];
+        let mut arrivals = Vec::new();
+        for expected_id in 0..7 {
+            let size = receiver.recv(&mut buffer).await.unwrap();
+            arrivals.push(Instant::now());
+            let (_, raw) = messages::RawMessage::parse(&buffer[..size]).unwrap();
+            let beacon: messages::RsrvIsUp = raw.try_into().unwrap();
+            assert_eq!(beacon.beacon_id, expected_id);
+            assert_ne!(beacon.server_port, 0);
+        }
+        server.stop().await.unwrap();
+
+        let gaps: Vec<_> = arrivals.windows(2).map(|w| w[1] - w[0]).collect();
+        // Early beacons come quickly, later ones settle to the period
+        assert!(gaps[0] < Duration::from_millis(100));
+        assert!(gaps[gaps.len() - 1] >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_max_array_bytes_is_enforced() {
+        let mut provider = IntercomProvider::new();
+        provider
+            .add_vec_pv("WAVEFORM", vec![0.0f64; 20000], None)
+            .unwrap();
+        let options = CircuitOptions {
+            max_array_bytes: Some(16384),
+            ..Default::default()
+        };
+        let (mut client, circuit) = connect_circuit(provider, options).await;
+        let server_id = create_channel(&mut client, "WAVEFORM").await;
+        let data_type = DbrType {
+            basic_type: DbrBasicType::Double,
+            category: DbrCategory::Basic,
+        };
+
+        // Reading the whole array is too large...
+        let read = messages::ReadNotify {
+            data_type,
+            data_count: 0,
+            server_id,
+            client_ioid: 3,
+        };
+        client.write_all(&read.as_bytes()).await.unwrap();
+        let Message::ECAError(err) = Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected an error response to oversized read");
+        };
+        assert!(matches!(err.condition, messages::ErrorCondition::TooLarge));
+
+        // ...but a part of it is fine
+        let read = messages::ReadNotify {
+            data_count: 100,
+            ..read
+        };
+        client.write_all(&read.as_bytes()).await.unwrap();
+        assert!(matches!(
+            Message::read_client_message(&mut client).await.unwrap(),
+            Message::ReadNotifyResponse(_)
+        ));
+
+        // Sending an oversized message should get us disconnected
+        let write = messages::Write {
+            data_type,
+            data_count: 20000,
+            server_id,
+            client_ioid: 4,
+            data: vec![0u8; 20000 * 8],
+        };
+        let _ = client.write_all(&write.as_bytes()).await;
+        assert!(Message::read_client_message(&mut client).await.is_err());
+        circuit.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_huge_counts_are_refused() {
+        let mut provider = IntercomProvider::new();
+        provider
+            .add_vec_pv("WAVEFORM", vec![0.0f64; 10], None)
+            .unwrap();
+        let (mut client, circuit) = connect_circuit(provider, CircuitOptions::default()).await;
+        let server_id = create_channel(&mut client, "WAVEFORM").await;
+        let data_type = DbrType {
+            basic_type: DbrBasicType::Double,
+            category: DbrCategory::Basic,
+        };
+
+        // Asking to read more elements than the channel has is refused...
+        let read = messages::ReadNotify {
+            data_type,
+            data_count: u32::MAX,
+            server_id,
+            client_ioid: 3,
+        };
+        client.write_all(&read.as_bytes()).await.unwrap();
+        let Message::ECAError(err) = Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected an error response to a huge read");
+        };
+        assert!(matches!(err.condition, messages::ErrorCondition::BadCount));
+
+        // ...as is subscribing with such a count...
+        let subscribe = messages::EventAdd {
+            data_type,
+            data_count: u32::MAX,
+            server_id,
+            subscription_id: 7,
+            mask: MonitorMask::default(),
+        };
+        client.write_all(&subscribe.as_bytes()).await.unwrap();
+        let Message::ECAError(err) = Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected an error response to a huge subscription");
+        };
+        assert!(matches!(err.condition, messages::ErrorCondition::BadCount));
+
+        // ...but asking for exactly the channel size still works
+        let read = messages::ReadNotify {
+            data_count: 10,
+            ..read
+        };
+        client.write_all(&read.as_bytes()).await.unwrap();
+        let Message::ReadNotifyResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a read response");
+        };
+        assert_eq!(response.data_count, 10);
+        drop(client);
+        circuit.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_list_keeps_its_size() {
+        let stats = ServerStats::default();
+        let mut diagnostics = DiagnosticPVs::new("TEST:");
+        let (mut client, circuit) =
+            connect_circuit(diagnostics.provider(), CircuitOptions::default()).await;
+        let request = messages::CreateChannel {
+            client_id: 1,
+            channel_name: "TEST:CA_CLIENTS".to_string(),
+            ..Default::default()
+        };
+        client.write_all(&request.as_bytes()).await.unwrap();
+        let response = loop {
+            match Message::read_client_message(&mut client).await.unwrap() {
+                Message::AccessRights(_) => continue,
+                Message::CreateChannelResponse(response) => break response,
+                msg => panic!("Unexpected response to CreateChannel: {msg:?}"),
+            }
+        };
+        assert_eq!(response.data_count as usize, CLIENT_LIST_LEN);
+
+        // Many more clients connect than fit in the list
+        let (commands, _) = mpsc::channel(1);
+        for id in 0..200 {
+            stats.register_circuit(
+                CircuitInfo {
+                    id,
+                    address: "127.0.0.1:4000".parse().unwrap(),
+                    host_name: Some("somehost".to_string()),
+                    user_name: Some("someone".to_string()),
+                    channels: Vec::new(),
+                },
+                commands.clone(),
+            );
+        }
+        diagnostics.update(&stats);
+
+        // Reading the size the channel was created with still works
+        let read = messages::ReadNotify {
+            data_type: DbrType {
+                basic_type: DbrBasicType::Char,
+                category: DbrCategory::Basic,
+            },
+            data_count: response.data_count,
+            server_id: response.server_id,
+            client_ioid: 3,
+        };
+        client.write_all(&read.as_bytes()).await.unwrap();
+        let Message::ReadNotifyResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a read response");
+        };
+        assert_eq!(response.data_count as usize, CLIENT_LIST_LEN);
+        assert!(
+            response
+                .data
+                .starts_with(b"someone@somehost (127.0.0.1:4000)")
+        );
+        assert_eq!(response.data[CLIENT_LIST_LEN - 1], 0);
+        drop(client);
+        circuit.await.unwrap();
+    }
+
+    /// Send a search for a PV to a server, and return the response if there is one
+    async fn search_for(
+        socket: &tokio::net::UdpSocket,
+        server: std::net::SocketAddr,
+        name: &str,
+    ) -> Option<messages::SearchResponse> {
+        let mut request = messages::Version::default().as_bytes();
+        request.extend(
+            messages::Search {
+                search_id: 42,
+                channel_name: name.to_string(),
+                ..Default::default()
+            }
+            .as_bytes(),
+        );
+        socket.send_to(&request, server).await.unwrap();
+        let mut buffer = [0u8; 1024];
+        let size = tokio::time::timeout(Duration::from_millis(200), socket.recv(&mut buffer))
+            .await
+            .ok()?
+            .unwrap();
+        // Skip the version message at the start
+        let (_, raw) = messages::RawMessage::parse(&buffer[16..size]).unwrap();
+        Some(raw.try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_interface_binding_and_ignore_list() {
+        let loopback = std::net::Ipv4Addr::LOCALHOST;
+        let mut provider = IntercomProvider::new();
+        provider.add_pv("VALUE", 1i32).unwrap();
+        // Find a free port to search on
+        let search_port = tokio::net::UdpSocket::bind((loopback, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let search_address = (loopback, search_port).into();
+        let client = tokio::net::UdpSocket::bind((loopback, 0)).await.unwrap();
+
+        let server = crate::ServerBuilder::new(provider.clone())
+            .search_port(search_port)
+            .connection_port(0)
+            .interface_addresses(vec![loopback])
+            .auto_beacon_addresses(false)
+            .beacon_addresses(Vec::new())
+            .start();
+        // Searches should be answered with the interface address
+        let mut response = None;
+        for _ in 0..10 {
+            response = search_for(&client, search_address, "VALUE").await;
+            if response.is_some() {
+                break;
+            }
         }
+        let response = response.expect("No response to search");
+        assert_eq!(response.search_id, 42);
+        assert_eq!(response.server_ip, Some(loopback));
+        let mut stream = TcpStream::connect((loopback, response.port_number))
+            .await
+            .unwrap();
+        assert!(matches!(
+            Message::read_client_message(&mut stream).await.unwrap(),
+            Message::Version(_)
+        ));
+        stream
+            .write_all(&messages::Version::default().as_bytes())
+            .await
+            .unwrap();
+        drop(stream);
+        server.stop().await.unwrap();
+
+        // Ignored clients get no search replies, and no connections
+        let server = crate::ServerBuilder::new(provider)
+            .search_port(search_port)
+            .connection_port(response.port_number)
+            .interface_addresses(vec![loopback])
+            .ignore_addresses(vec![loopback])
+            .auto_beacon_addresses(false)
+            .beacon_addresses(Vec::new())
+            .start();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(search_for(&client, search_address, "VALUE").await.is_none());
+        let mut stream = TcpStream::connect((loopback, response.port_number))
+            .await
+            .unwrap();
+        assert!(Message::read_client_message(&mut stream).await.is_err());
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_search_over_tcp() {
+        let loopback = std::net::Ipv4Addr::LOCALHOST;
+        // Another server, that we can only find by asking our name server
+        let mut remote_provider = IntercomProvider::new();
+        remote_provider.add_pv("REMOTE", 1i32).unwrap();
+        let remote_search_port = tokio::net::UdpSocket::bind((loopback, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let remote = crate::ServerBuilder::new(remote_provider)
+            .search_port(remote_search_port)
+            .connection_port(0)
+            .interface_addresses(vec![loopback])
+            .auto_beacon_addresses(false)
+            .beacon_addresses(Vec::new())
+            .start();
+
+        let mut provider = IntercomProvider::new();
+        provider.add_pv("LOCAL", 1i32).unwrap();
+        let searcher = crate::client::SearcherBuilder::new()
+            .search_port(remote_search_port)
+            .broadcast_to(vec![loopback.into()])
+            .start()
+            .await
+            .unwrap();
+        let options = CircuitOptions {
+            name_server: Some(std::sync::Arc::new(searcher)),
+            ..Default::default()
+        };
+        let (mut client, _circuit) = connect_circuit(provider, options).await;
+        let search = |id: u32, name: &str| messages::Search {
+            search_id: id,
+            channel_name: name.to_string(),
+            should_reply: true,
+            ..Default::default()
+        };
+
+        // Our own PVs point back to this circuit
+        client
+            .write_all(&search(1, "LOCAL").as_bytes())
+            .await
+            .unwrap();
+        let Message::SearchResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a search response");
+        };
+        assert_eq!(response.search_id, 1);
+        assert_eq!(response.server_ip, None);
+        assert_eq!(response.port_number, client.peer_addr().unwrap().port());
+
+        // PVs on other servers are forwarded
+        client
+            .write_all(&search(2, "REMOTE").as_bytes())
+            .await
+            .unwrap();
+        let Message::SearchResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a search response");
+        };
+        assert_eq!(response.search_id, 2);
+        assert_eq!(response.server_ip, Some(loopback));
+        assert_ne!(response.port_number, client.peer_addr().unwrap().port());
+
+        // And PVs that nobody has are reported missing
+        client
+            .write_all(&search(3, "MISSING").as_bytes())
+            .await
+            .unwrap();
+        let Message::NotFound(response) = Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a not found response");
+        };
+        assert_eq!(response.search_id, 3);
+        remote.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_searches_are_limited() {
+        let loopback = std::net::Ipv4Addr::LOCALHOST;
+        // A name server that asks somewhere nobody will ever answer
+        let silent = tokio::net::UdpSocket::bind((loopback, 0)).await.unwrap();
+        let searcher = crate::client::SearcherBuilder::new()
+            .search_port(silent.local_addr().unwrap().port())
+            .broadcast_to(vec![loopback.into()])
+            .start()
+            .await
+            .unwrap();
+        let options = CircuitOptions {
+            name_server: Some(std::sync::Arc::new(searcher)),
+            ..Default::default()
+        };
+        let (mut client, _circuit) = connect_circuit(IntercomProvider::new(), options).await;
+
+        // Fill every forwarding slot, and then ask once more
+        for search_id in 0..=MAX_FORWARDED_SEARCHES as u32 {
+            let search = messages::Search {
+                search_id,
+                channel_name: format!("MISSING:{search_id}"),
+                should_reply: true,
+                ..Default::default()
+            };
+            client.write_all(&search.as_bytes()).await.unwrap();
+        }
+        // Only the extra search is answered straight away
+        let Message::NotFound(response) = Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a not found response");
+        };
+        assert_eq!(response.search_id, MAX_FORWARDED_SEARCHES as u32);
+    }
+
+    #[tokio::test]
+    async fn test_udp_search_asking_for_reply_gets_not_found() {
+        let loopback = std::net::Ipv4Addr::LOCALHOST;
+        let search_port = tokio::net::UdpSocket::bind((loopback, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = crate::ServerBuilder::new(IntercomProvider::new())
+            .search_port(search_port)
+            .connection_port(0)
+            .interface_addresses(vec![loopback])
+            .auto_beacon_addresses(false)
+            .beacon_addresses(Vec::new())
+            .start();
+        let client = tokio::net::UdpSocket::bind((loopback, 0)).await.unwrap();
+        let mut request = messages::Version::default().as_bytes();
+        request.extend(
+            messages::Search {
+                search_id: 42,
+                channel_name: "MISSING".to_string(),
+                should_reply: true,
+                ..Default::default()
+            }
+            .as_bytes(),
+        );
+
+        // Keep asking until the server is listening
+        let mut buffer = [0u8; 1024];
+        let mut size = None;
+        for _ in 0..10 {
+            client
+                .send_to(&request, (loopback, search_port))
+                .await
+                .unwrap();
+            if let Ok(received) =
+                tokio::time::timeout(Duration::from_millis(200), client.recv(&mut buffer)).await
+            {
+                size = Some(received.unwrap());
+                break;
+            }
+        }
+        let size = size.expect("No response to search");
+        // Skip the version message at the start
+        let (_, raw) = messages::RawMessage::parse(&buffer[16..size]).unwrap();
+        let response: messages::NotFound = raw.try_into().unwrap();
+        assert_eq!(response.search_id, 42);
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handle_lists_and_disconnects_clients() {
+        let mut provider = IntercomProvider::new();
+        provider.add_pv("VALUE", 1i32).unwrap();
+        let server = crate::ServerBuilder::new(provider)
+            .search_port(0)
+            .connection_port(0)
+            .interface_addresses(vec![std::net::Ipv4Addr::LOCALHOST])
+            .auto_beacon_addresses(false)
+            .beacon_addresses(Vec::new())
+            .start();
+        let addresses = server.addresses().await.unwrap();
+        assert_eq!(addresses.connection.len(), 1);
+        assert_ne!(addresses.connection[0].port(), 0);
+
+        let mut client = TcpStream::connect(addresses.connection[0]).await.unwrap();
+        assert!(matches!(
+            Message::read_client_message(&mut client).await.unwrap(),
+            Message::Version(_)
+        ));
+        client
+            .write_all(&messages::Version::default().as_bytes())
+            .await
+            .unwrap();
+        let server_id = create_channel(&mut client, "VALUE").await;
+
+        let circuits = server.circuits();
+        assert_eq!(circuits.len(), 1);
+        assert_eq!(circuits[0].address, client.local_addr().unwrap());
+        assert_eq!(circuits[0].channels.len(), 1);
+        assert_eq!(circuits[0].channels[0].name, "VALUE");
+        assert_eq!(circuits[0].channels[0].server_id, server_id);
+
+        // Dropping a channel tells the client about it
+        assert!(server.disconnect_channel(circuits[0].id, server_id).await);
+        let Message::ServerDisconnect(disconnect) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected the channel to be disconnected");
+        };
+        assert_eq!(disconnect.client_id, 1);
+        assert!(server.circuits()[0].channels.is_empty());
+
+        // And dropping the client closes the connection
+        assert!(server.disconnect_client(circuits[0].id).await);
+        assert!(Message::read_client_message(&mut client).await.is_err());
+        assert!(!server.disconnect_client(circuits[0].id).await);
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stop_disconnects_channels_and_drains() {
+        let mut provider = IntercomProvider::new();
+        let value = provider.add_pv("VALUE", 1i32).unwrap();
+        provider
+            .add_vec_pv("WAVEFORM", vec![1.0f64; 100_000], None)
+            .unwrap();
+        let server = crate::ServerBuilder::new(provider)
+            .search_port(0)
+            .connection_port(0)
+            .interface_addresses(vec![std::net::Ipv4Addr::LOCALHOST])
+            .auto_beacon_addresses(false)
+            .beacon_addresses(Vec::new())
+            .start();
+        let addresses = server.addresses().await.unwrap();
+        let mut client = TcpStream::connect(addresses.connection[0]).await.unwrap();
+        assert!(matches!(
+            Message::read_client_message(&mut client).await.unwrap(),
+            Message::Version(_)
+        ));
+        client
+            .write_all(&messages::Version::default().as_bytes())
+            .await
+            .unwrap();
+        let server_id = create_channel(&mut client, "VALUE").await;
+        let waveform_id = create_channel(&mut client, "WAVEFORM").await;
+
+        // Ask for more than the socket can hold, so that replies back up in the
+        // server, then a write so that we can tell when they have all been queued
+        let reads = 8;
+        for ioid in 0..reads {
+            let read = messages::ReadNotify {
+                data_type: DbrType {
+                    basic_type: DbrBasicType::Double,
+                    category: DbrCategory::Basic,
+                },
+                data_count: 0,
+                server_id: waveform_id,
+                client_ioid: ioid,
+            };
+            client.write_all(&read.as_bytes()).await.unwrap();
+        }
+        let mut data = b"42".to_vec();
+        data.resize(40, 0);
+        let write = messages::WriteNotify {
+            data_type: crate::dbr::DBR_BASIC_STRING,
+            data_count: 1,
+            server_id,
+            client_ioid: 7,
+            data,
+        };
+        client.write_all(&write.as_bytes()).await.unwrap();
+        while value.load() != 42 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Stop the server before reading any of the replies
+        let stopping = tokio::spawn(server.stop());
+        for ioid in 0..reads {
+            let Message::ReadNotifyResponse(response) =
+                Message::read_client_message(&mut client).await.unwrap()
+            else {
+                panic!("Expected a ReadNotify response");
+            };
+            assert_eq!(response.client_ioid, ioid);
+            assert_eq!(response.data.len(), 800_000);
+        }
+        let Message::WriteNotifyResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a WriteNotify response");
+        };
+        assert_eq!(response.client_ioid, 7);
+        assert_eq!(
+            response.status_code,
+            messages::ErrorCondition::Normal.eca_code()
+        );
+        for _ in 0..2 {
+            let Message::ServerDisconnect(disconnect) =
+                Message::read_client_message(&mut client).await.unwrap()
+            else {
+                panic!("Expected the channels to be disconnected");
+            };
+            assert_eq!(disconnect.client_id, 1);
+        }
+        assert!(Message::read_client_message(&mut client).await.is_err());
+        stopping.await.unwrap().unwrap();
+    }
+
+    /// A provider that records when channels and subscriptions come and go
+    #[derive(Clone, Default)]
+    struct WatchingProvider {
+        inner: IntercomProvider,
+        events: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl WatchingProvider {
+        fn record(&self, event: &str, pv_name: &str, client: &ClientInfo) {
+            assert_eq!(client.user.as_deref(), Some("operator"));
+            let mut events = self.events.lock().unwrap();
+            events.push(format!("{event} {pv_name}"));
+        }
+    }
+
+    impl Provider for WatchingProvider {
+        fn provides(&self, pv_name: &str) -> bool {
+            self.inner.provides(pv_name)
+        }
+        fn read_value(
+            &self,
+            pv_name: &str,
+            requested_type: Option<DbrType>,
+        ) -> Result<Dbr, ErrorCondition> {
+            self.inner.read_value(pv_name, requested_type)
+        }
+        fn monitor_value(
+            &mut self,
+            pv_name: &str,
+            data_type: DbrType,
+            data_count: usize,
+            mask: MonitorMask,
+            trigger: tokio::sync::mpsc::Sender<String>,
+        ) -> Result<tokio::sync::broadcast::Receiver<Dbr>, ErrorCondition> {
+            self.inner
+                .monitor_value(pv_name, data_type, data_count, mask, trigger)
+        }
+        fn channel_created(&self, pv_name: &str, client: &ClientInfo) {
+            self.record("created", pv_name, client);
+        }
+        fn channel_cleared(&self, pv_name: &str, client: &ClientInfo) {
+            self.record("cleared", pv_name, client);
+        }
+        fn subscription_added(&self, pv_name: &str, client: &ClientInfo) {
+            self.record("added", pv_name, client);
+        }
+        fn subscription_removed(&self, pv_name: &str, client: &ClientInfo) {
+            self.record("removed", pv_name, client);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_provider_hears_about_channels_and_subscriptions() {
+        let mut provider = WatchingProvider::default();
+        provider.inner.add_pv("A", 1i32).unwrap();
+        provider.inner.add_pv("B", 2i32).unwrap();
+        let events = provider.events.clone();
+        let (mut client, circuit) = connect_circuit(provider, CircuitOptions::default()).await;
+        let name = messages::ClientName {
+            name: "operator".to_string(),
+        };
+        client.write_all(&name.as_bytes()).await.unwrap();
+        let channel_a = create_channel(&mut client, "A").await;
+        let channel_b = create_channel(&mut client, "B").await;
+        let long = DbrType::try_from(5).unwrap();
+        let subscribe = |server_id, subscription_id| messages::EventAdd {
+            data_type: long,
+            data_count: 1,
+            server_id,
+            subscription_id,
+            mask: MonitorMask::default(),
+        };
+        // Each request is answered, so the circuit has handled it after reading that
+        for request in [
+            subscribe(channel_a, 1).as_bytes(),
+            messages::EventCancel {
+                data_type: long,
+                data_count: 1,
+                server_id: channel_a,
+                subscription_id: 1,
+            }
+            .as_bytes(),
+            subscribe(channel_b, 2).as_bytes(),
+        ] {
+            client.write_all(&request).await.unwrap();
+            Message::read_client_message(&mut client).await.unwrap();
+        }
+        let clear = messages::ClearChannel {
+            server_id: channel_a,
+            client_id: 1,
+        };
+        client.write_all(&clear.as_bytes()).await.unwrap();
+        // Disconnecting releases everything that is still open
+        drop(client);
+        circuit.await.unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "created A",
+                "created B",
+                "added A",
+                "removed A",
+                "added B",
+                "cleared A",
+                "removed B",
+                "cleared B"
+            ]
+        );
+    }
+
+    /// A provider that can have PVs taken away from it
+    #[derive(Clone)]
+    struct RemovingProvider {
+        inner: IntercomProvider,
+        removed: tokio::sync::broadcast::Sender<String>,
+    }
+
+    impl Default for RemovingProvider {
+        fn default() -> Self {
+            RemovingProvider {
+                inner: IntercomProvider::new(),
+                removed: tokio::sync::broadcast::channel(8).0,
+            }
+        }
+    }
+
+    impl Provider for RemovingProvider {
+        fn provides(&self, pv_name: &str) -> bool {
+            self.inner.provides(pv_name)
+        }
+
+        fn read_value(
+            &self,
+            pv_name: &str,
+            requested_type: Option<DbrType>,
+        ) -> Result<Dbr, ErrorCondition> {
+            self.inner.read_value(pv_name, requested_type)
+        }
+
+        fn watch_removed(&self) -> Option<tokio::sync::broadcast::Receiver<String>> {
+            Some(self.removed.subscribe())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_monitors_through_prefix_and_alias() {
+        let mut intercom = IntercomProvider::new();
+        let mut value = intercom.add_pv("VALUE", 1i32).unwrap();
+        let provider = crate::providers::AliasProvider::new(
+            crate::providers::ProviderSet::new().with_prefix("DEV:", intercom),
+        )
+        .alias("ALIAS", "DEV:VALUE");
+        let (mut client, circuit) = connect_circuit(provider, CircuitOptions::default()).await;
+        let long = DbrType::try_from(5).unwrap();
+        for (subscription_id, name) in [(1, "DEV:VALUE"), (2, "ALIAS")] {
+            let server_id = create_channel(&mut client, name).await;
+            let subscribe = messages::EventAdd {
+                data_type: long,
+                data_count: 1,
+                server_id,
+                subscription_id,
+                mask: MonitorMask::default(),
+            };
+            client.write_all(&subscribe.as_bytes()).await.unwrap();
+            assert!(matches!(
+                Message::read_client_message(&mut client).await.unwrap(),
+                Message::EventAddResponse(_)
+            ));
+        }
+
+        // Both names see the update, even though the provider knows neither
+        value.store(&2);
+        let mut updated = Vec::new();
+        for _ in 0..2 {
+            let Message::EventAddResponse(update) =
+                Message::read_client_message(&mut client).await.unwrap()
+            else {
+                panic!("Expected a subscription update");
+            };
+            assert_eq!(update.data[..4], 2i32.to_be_bytes());
+            updated.push(update.subscription_id);
+        }
+        updated.sort();
+        assert_eq!(updated, vec![1, 2]);
+        drop(client);
+        circuit.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_removed_pv_disconnects_channels() {
+        let mut provider = RemovingProvider::default();
+        provider.inner.add_pv("VALUE", 1i32).unwrap();
+        provider.inner.add_pv("OTHER", 2i32).unwrap();
+        let (mut client, circuit) =
+            connect_circuit(provider.clone(), CircuitOptions::default()).await;
+        let other_id = create_channel(&mut client, "OTHER").await;
+        let request = messages::CreateChannel {
+            client_id: 2,
+            channel_name: "VALUE".to_string(),
+            ..Default::default()
+        };
+        client.write_all(&request.as_bytes()).await.unwrap();
+        while !matches!(
+            Message::read_client_message(&mut client).await.unwrap(),
+            Message::CreateChannelResponse(_)
+        ) {}
+
+        provider.removed.send("VALUE".to_string()).unwrap();
+        let Message::ServerDisconnect(disconnect) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected the channel to be disconnected");
+        };
+        assert_eq!(disconnect.client_id, 2);
+
+        // The other channel is still usable
+        let read = messages::ReadNotify {
+            data_type: DbrType {
+                basic_type: DbrBasicType::Long,
+                category: DbrCategory::Basic,
+            },
+            data_count: 1,
+            server_id: other_id,
+            client_ioid: 3,
+        };
+        client.write_all(&read.as_bytes()).await.unwrap();
+        let Message::ReadNotifyResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected the other channel to be read");
+        };
+        assert_eq!(response.client_ioid, 3);
+        assert_eq!(response.data[..4], 2i32.to_be_bytes());
+        drop(client);
+        circuit.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_removals_missed_by_a_set_are_checked() {
+        let provider = RemovingProvider::default();
+        provider.inner.clone().add_pv("VALUE", 1i32).unwrap();
+        let set = crate::providers::ProviderSet::new().with(provider.clone());
+        let (mut client, circuit) = connect_circuit(set, CircuitOptions::default()).await;
+        create_channel(&mut client, "VALUE").await;
+
+        // The PV goes, but its notification is lost among many others before the
+        // set can pass them on
+        provider.inner.clone().remove_pv("VALUE");
+        for i in 0..20 {
+            provider.removed.send(format!("UNKNOWN{i}")).unwrap();
+        }
+        let Message::ServerDisconnect(disconnect) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected the channel to be disconnected");
+        };
+        assert_eq!(disconnect.client_id, 1);
+        drop(client);
+        circuit.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replaced_pv_disconnects_channels_after_missed_removals() {
+        let mut provider = IntercomProvider::new();
+        provider.add_pv("VALUE", 1i32).unwrap();
+        provider.add_pv("OTHER", 2i32).unwrap();
+        let (mut client, circuit) =
+            connect_circuit(provider.clone(), CircuitOptions::default()).await;
+        let other_id = create_channel(&mut client, "OTHER").await;
+        let request = messages::CreateChannel {
+            client_id: 2,
+            channel_name: "VALUE".to_string(),
+            ..Default::default()
+        };
+        client.write_all(&request.as_bytes()).await.unwrap();
+        while !matches!(
+            Message::read_client_message(&mut client).await.unwrap(),
+            Message::CreateChannelResponse(_)
+        ) {}
+
+        // Replace VALUE, then bury that under enough removals that the circuit
+        // misses it, so only the generation can tell that the channel is stale
+        provider.replace_pv("VALUE", 3.0f64);
+        for _ in 0..100 {
+            provider.replace_pv("SPARE", 0i32);
+        }
+        let Message::ServerDisconnect(disconnect) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected the channel to the replaced PV to be disconnected");
+        };
+        assert_eq!(disconnect.client_id, 2);
+
+        // The other channel is still usable
+        let read = messages::ReadNotify {
+            data_type: DbrType {
+                basic_type: DbrBasicType::Long,
+                category: DbrCategory::Basic,
+            },
+            data_count: 1,
+            server_id: other_id,
+            client_ioid: 3,
+        };
+        client.write_all(&read.as_bytes()).await.unwrap();
+        let Message::ReadNotifyResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected the other channel to be read");
+        };
+        assert_eq!(response.client_ioid, 3);
+        drop(client);
+        circuit.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bad_requests_get_error_responses() {
+        let mut provider = IntercomProvider::new();
+        provider.add_pv("VALUE", 1i32).unwrap();
+        let (mut client, circuit) = connect_circuit(provider, CircuitOptions::default()).await;
+        let server_id = create_channel(&mut client, "VALUE").await;
+        let mut expect_error = async |request: Message, condition: ErrorCondition| {
+            client.write_all(&request.as_bytes()).await.unwrap();
+            let Message::ECAError(error) = Message::read_client_message(&mut client).await.unwrap()
+            else {
+                panic!("Expected an error in response to {request:?}");
+            };
+            assert_eq!(error.condition as u32, condition as u32);
+        };
+
+        // Reading from a channel that does not exist
+        let read = messages::ReadNotify {
+            data_type: DbrType::try_from(5).unwrap(),
+            data_count: 1,
+            server_id: 99,
+            client_ioid: 1,
+        };
+        expect_error(read.into(), ErrorCondition::BadChId).await;
+        // Writing a type that is not a plain value
+        let write = messages::Write {
+            data_type: DbrType::try_from(33).unwrap(),
+            data_count: 1,
+            server_id,
+            client_ioid: 2,
+            data: vec![0; 40],
+        };
+        expect_error(write.into(), ErrorCondition::BadType).await;
+        // Writing less data than claimed
+        let write = messages::Write {
+            data_type: DbrType::try_from(5).unwrap(),
+            data_count: 4,
+            server_id,
+            client_ioid: 3,
+            data: vec![0; 8],
+        };
+        expect_error(write.into(), ErrorCondition::BadCount).await;
+        // Cancelling a subscription that was never made
+        let cancel = messages::EventCancel {
+            data_type: DbrType::try_from(5).unwrap(),
+            data_count: 1,
+            server_id,
+            subscription_id: 4,
+        };
+        expect_error(cancel.into(), ErrorCondition::BadMonId).await;
+
+        // But a subscription can be made and cancelled
+        let subscribe = messages::EventAdd {
+            data_type: DbrType::try_from(5).unwrap(),
+            data_count: 1,
+            server_id,
+            subscription_id: 4,
+            mask: MonitorMask::default(),
+        };
+        client.write_all(&subscribe.as_bytes()).await.unwrap();
+        assert!(matches!(
+            Message::read_client_message(&mut client).await.unwrap(),
+            Message::EventAddResponse(_)
+        ));
+        let cancel = messages::EventCancel {
+            data_type: DbrType::try_from(5).unwrap(),
+            data_count: 1,
+            server_id,
+            subscription_id: 4,
+        };
+        client.write_all(&cancel.as_bytes()).await.unwrap();
+        let Message::EventAddResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected the cancellation to be confirmed");
+        };
+        assert_eq!(response.subscription_id, 4);
+        assert!(response.data.is_empty());
+
+        drop(client);
+        circuit.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_version_exchange_closes_circuit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        drop(client);
+        Circuit::start(
+            0,
+            stream,
+            IntercomProvider::new(),
+            CircuitOptions::default(),
+            Arc::new(ServerStats::default()),
+            CancellationToken::new(),
+        )
+        .await;
+    }
+
+    /// A provider that describes its PV separately from the value
+    #[derive(Clone, Default)]
+    struct MetadataProvider;
+
+    impl Provider for MetadataProvider {
+        fn provides(&self, pv_name: &str) -> bool {
+            pv_name == "MOTOR"
+        }
+        fn read_value(
+            &self,
+            _pv_name: &str,
+            _requested_type: Option<DbrType>,
+        ) -> Result<Dbr, ErrorCondition> {
+            Ok(Dbr::Basic(vec![2.5f64].into()))
+        }
+        fn channel_metadata(&self, _pv_name: &str) -> Result<ChannelMetadata, ErrorCondition> {
+            let mut metadata = ChannelMetadata::new(DbrBasicType::Double, 1);
+            metadata.record_type = "motor".to_string();
+            metadata.display.units = "mm".to_string();
+            metadata.display.precision = 2;
+            Ok(metadata)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metadata_requests() {
+        let (mut client, circuit) =
+            connect_circuit(MetadataProvider, CircuitOptions::default()).await;
+        let server_id = create_channel(&mut client, "MOTOR").await;
+        let mut read = async |data_type: DbrType| {
+            let request = messages::ReadNotify {
+                data_type,
+                data_count: 1,
+                server_id,
+                client_ioid: 1,
+            };
+            client.write_all(&request.as_bytes()).await.unwrap();
+            let Message::ReadNotifyResponse(response) =
+                Message::read_client_message(&mut client).await.unwrap()
+            else {
+                panic!("Expected a read response");
+            };
+            Dbr::from_bytes(data_type, 1, &response.data).unwrap()
+        };
+
+        let class_name = read(DbrType::try_from(38).unwrap()).await;
+        assert_eq!(
+            class_name.value(),
+            &DbrValue::String(vec!["motor".to_string()])
+        );
+        let graphics = read(DbrType::try_from(27).unwrap()).await;
+        assert_eq!(graphics.display().unwrap().units, "mm");
+        assert_eq!(graphics.display().unwrap().precision, 2);
+        assert_eq!(graphics.value(), &DbrValue::Double(vec![2.5]));
+
+        drop(client);
+        circuit.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_array_sizes() {
+        let mut provider = IntercomProvider::new();
+        let mut waveform = provider
+            .add_vec_pv("WAVEFORM", vec![1i32, 2, 3], None)
+            .unwrap();
+        let (mut client, circuit) = connect_circuit(provider, CircuitOptions::default()).await;
+        let server_id = create_channel(&mut client, "WAVEFORM").await;
+        let long = DbrType::try_from(5).unwrap();
+        let mut read = async |data_count| {
+            let request = messages::ReadNotify {
+                data_type: long,
+                data_count,
+                server_id,
+                client_ioid: 1,
+            };
+            client.write_all(&request.as_bytes()).await.unwrap();
+            let Message::ReadNotifyResponse(response) =
+                Message::read_client_message(&mut client).await.unwrap()
+            else {
+                panic!("Expected a read response");
+            };
+            // Payloads are padded to a multiple of 8 bytes on the wire
+            let size = response.data_count as usize * 4;
+            (response.data_count, response.data[..size].to_vec())
+        };
+
+        // Zero means the current size, and anything larger is zero-padded
+        assert_eq!(read(0).await, (3, vec![0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]));
+        assert_eq!(read(2).await, (2, vec![0, 0, 0, 1, 0, 0, 0, 2]));
+        waveform.store(&[1, 2]);
+        assert_eq!(read(0).await, (2, vec![0, 0, 0, 1, 0, 0, 0, 2]));
+        assert_eq!(read(3).await, (3, vec![0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0]));
+        waveform.store(&[1, 2, 3]);
+
+        // Dynamically sized subscriptions follow the length of the value
+        let subscribe = |subscription_id, data_count| messages::EventAdd {
+            data_type: long,
+            data_count,
+            server_id,
+            subscription_id,
+            mask: MonitorMask::default(),
+        };
+        client.write_all(&subscribe(1, 0).as_bytes()).await.unwrap();
+        let Message::EventAddResponse(initial) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected an initial subscription value");
+        };
+        assert_eq!(initial.data_count, 3);
+        waveform.store(&[4, 5]);
+        let Message::EventAddResponse(update) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a subscription update");
+        };
+        assert_eq!(update.data_count, 2);
+        assert_eq!(update.data, vec![0, 0, 0, 4, 0, 0, 0, 5]);
+
+        drop(client);
+        circuit.await.unwrap();
+    }
+
+    /// A provider that only answers asynchronously, and is slow to read values
+    #[derive(Clone, Default)]
+    struct SlowProvider;
+
+    impl Provider for SlowProvider {
+        fn provides(&self, _pv_name: &str) -> bool {
+            false
+        }
+        fn provides_async<'a>(&'a self, pv_name: &'a str) -> BoxFuture<'a, bool> {
+            Box::pin(async move {
+                // Looking for this one is as slow as reading
+                if pv_name == "SLOW:SEARCH" {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }
+                pv_name == "SLOW"
+            })
+        }
+
+        fn read_value_async<'a>(
+            &'a self,
+            _pv_name: &'a str,
+            _requested_type: Option<DbrType>,
+        ) -> BoxFuture<'a, Result<Dbr, ErrorCondition>> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                Ok(Dbr::Basic(DbrValue::Long(vec![1])))
+            })
+        }
+
+        // Only reads of the value itself are slow, so channels can connect
+        fn channel_metadata(&self, _pv_name: &str) -> Result<ChannelMetadata, ErrorCondition> {
+            Ok(ChannelMetadata::new(DbrBasicType::Long, 1))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_provider_read_times_out() {
+        let (mut client, circuit) = connect_circuit(
+            SlowProvider,
+            CircuitOptions {
+                request_timeout: Duration::from_millis(50),
+                ..Default::default()
+            },
+        )
+        .await;
+        let server_id = create_channel(&mut client, "SLOW").await;
+        let read = messages::ReadNotify {
+            data_type: DbrType::try_from(5).unwrap(),
+            data_count: 1,
+            server_id,
+            client_ioid: 1,
+        };
+        client.write_all(&read.as_bytes()).await.unwrap();
+        let Message::ECAError(error) = Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected the read to fail");
+        };
+        assert_eq!(error.condition as u32, ErrorCondition::Timeout as u32);
+        drop(client);
+        circuit.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_read_does_not_hold_up_subscriptions() {
+        let mut intercom = IntercomProvider::new();
+        let mut value = intercom.add_pv("FAST", 1i32).unwrap();
+        let provider = crate::providers::ProviderSet::new()
+            .with(SlowProvider)
+            .with(intercom);
+        let (mut client, _circuit) = connect_circuit(provider, CircuitOptions::default()).await;
+        let long = DbrType::try_from(5).unwrap();
+        let slow = create_channel(&mut client, "SLOW").await;
+        let fast = create_channel(&mut client, "FAST").await;
+        let subscribe = messages::EventAdd {
+            data_type: long,
+            data_count: 1,
+            server_id: fast,
+            subscription_id: 1,
+            mask: MonitorMask::default(),
+        };
+        client.write_all(&subscribe.as_bytes()).await.unwrap();
+        assert!(matches!(
+            Message::read_client_message(&mut client).await.unwrap(),
+            Message::EventAddResponse(_)
+        ));
+
+        // Updates still arrive while the provider is busy with a read
+        let read = messages::ReadNotify {
+            data_type: long,
+            data_count: 1,
+            server_id: slow,
+            client_ioid: 1,
+        };
+        client.write_all(&read.as_bytes()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        value.store(&2);
+        let update = tokio::time::timeout(
+            Duration::from_secs(1),
+            Message::read_client_message(&mut client),
+        )
+        .await
+        .expect("Subscription update was held up by the read");
+        let Message::EventAddResponse(update) = update.unwrap() else {
+            panic!("Expected a subscription update");
+        };
+        assert_eq!(update.data[..4], 2i32.to_be_bytes());
+    }
+
+    /// A provider that can be written to, but leaves access rights as the default
+    #[derive(Clone, Default)]
+    struct DefaultAccessProvider {
+        value: Arc<std::sync::Mutex<i32>>,
+    }
+
+    impl Provider for DefaultAccessProvider {
+        fn provides(&self, pv_name: &str) -> bool {
+            pv_name == "VALUE"
+        }
+        fn read_value(
+            &self,
+            _pv_name: &str,
+            _requested_type: Option<DbrType>,
+        ) -> Result<Dbr, ErrorCondition> {
+            Ok(Dbr::Basic(DbrValue::Long(vec![
+                *self.value.lock().unwrap(),
+            ])))
+        }
+        fn write_value(&mut self, _pv_name: &str, value: Dbr) -> Result<(), ErrorCondition> {
+            let DbrValue::Long(value) = value.value() else {
+                return Err(ErrorCondition::BadType);
+            };
+            *self.value.lock().unwrap() = value[0];
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_rights_are_enforced() {
+        let provider = DefaultAccessProvider::default();
+        let value = provider.value.clone();
+        let mut intercom = IntercomProvider::new();
+        let mut hidden = intercom.add_pv("HIDDEN", 1i32).unwrap();
+        hidden.set_access(messages::Access::None);
+        let provider = crate::providers::ProviderSet::new()
+            .with(provider)
+            .with(intercom);
+        let (mut client, _circuit) = connect_circuit(provider, CircuitOptions::default()).await;
+        let long = DbrType::try_from(5).unwrap();
+
+        // Clients are told they can only read, and writes are refused regardless
+        let request = messages::CreateChannel {
+            client_id: 1,
+            channel_name: "VALUE".to_string(),
+            ..Default::default()
+        };
+        client.write_all(&request.as_bytes()).await.unwrap();
+        let Message::AccessRights(rights) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected access rights for the new channel");
+        };
+        assert!(matches!(rights.access_rights, messages::Access::Read));
+        let Message::CreateChannelResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a CreateChannel response");
+        };
+        let write = messages::WriteNotify {
+            data_type: long,
+            data_count: 1,
+            server_id: response.server_id,
+            client_ioid: 1,
+            data: 5i32.to_be_bytes().to_vec(),
+        };
+        client.write_all(&write.as_bytes()).await.unwrap();
+        let Message::WriteNotifyResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a WriteNotify response");
+        };
+        assert_eq!(response.status_code, ErrorCondition::NoWtAccess.eca_code());
+        assert_eq!(*value.lock().unwrap(), 0);
+
+        // Reads are refused for PVs that the client can't read
+        let server_id = create_channel(&mut client, "HIDDEN").await;
+        let read = messages::ReadNotify {
+            data_type: long,
+            data_count: 1,
+            server_id,
+            client_ioid: 2,
+        };
+        client.write_all(&read.as_bytes()).await.unwrap();
+        let Message::ECAError(err) = Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected an error response to the read");
+        };
+        assert!(matches!(err.condition, ErrorCondition::NoRdAccess));
+    }
+
+    #[tokio::test]
+    async fn test_slow_put_callback_outlives_request_timeout() {
+        let mut intercom = IntercomProvider::new();
+        let mut setpoint = intercom.add_pv("SETPOINT", 0i32).unwrap();
+        intercom.add_pv("READBACK", 1i32).unwrap();
+        let arrived = Arc::new(tokio::sync::Notify::new());
+        let callback_arrived = arrived.clone();
+        setpoint.on_put_async(move |_| {
+            let arrived = callback_arrived.clone();
+            async move { arrived.notified().await }
+        });
+        let (mut client, _circuit) = connect_circuit(
+            intercom,
+            CircuitOptions {
+                request_timeout: Duration::from_millis(50),
+                ..Default::default()
+            },
+        )
+        .await;
+        let long = DbrType::try_from(5).unwrap();
+        let setpoint_id = create_channel(&mut client, "SETPOINT").await;
+        let readback_id = create_channel(&mut client, "READBACK").await;
+        let write = messages::WriteNotify {
+            data_type: long,
+            data_count: 1,
+            server_id: setpoint_id,
+            client_ioid: 1,
+            data: 5i32.to_be_bytes().to_vec(),
+        };
+        client.write_all(&write.as_bytes()).await.unwrap();
+
+        // Other requests are answered while the move carries on past the timeout
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let read = messages::ReadNotify {
+            data_type: long,
+            data_count: 1,
+            server_id: readback_id,
+            client_ioid: 2,
+        };
+        client.write_all(&read.as_bytes()).await.unwrap();
+        let Message::ReadNotifyResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a ReadNotify response before the write finished");
+        };
+        assert_eq!(response.client_ioid, 2);
+
+        // The write is only answered once the move has finished
+        arrived.notify_one();
+        let Message::WriteNotifyResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a WriteNotify response");
+        };
+        assert_eq!(response.client_ioid, 1);
+        assert_eq!(
+            response.status_code,
+            messages::ErrorCondition::Normal.eca_code()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_search_does_not_hold_up_others() {
+        let loopback = std::net::Ipv4Addr::LOCALHOST;
+        let mut intercom = IntercomProvider::new();
+        intercom.add_pv("FAST", 1i32).unwrap();
+        let provider = crate::providers::ProviderSet::new()
+            .with(SlowProvider)
+            .with(intercom);
+        let search_port = tokio::net::UdpSocket::bind((loopback, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let search_address = (loopback, search_port).into();
+        let server = crate::ServerBuilder::new(provider)
+            .search_port(search_port)
+            .connection_port(0)
+            .interface_addresses(vec![loopback])
+            .auto_beacon_addresses(false)
+            .beacon_addresses(Vec::new())
+            .start();
+        let client = tokio::net::UdpSocket::bind((loopback, 0)).await.unwrap();
+        // Make sure the server is listening first
+        let mut response = None;
+        for _ in 0..10 {
+            response = search_for(&client, search_address, "FAST").await;
+            if response.is_some() {
+                break;
+            }
+        }
+        assert!(response.is_some(), "No response to search");
+
+        // Searching for FAST is still answered while SLOW:SEARCH is being looked up
+        assert!(
+            search_for(&client, search_address, "SLOW:SEARCH")
+                .await
+                .is_none()
+        );
+        assert!(search_for(&client, search_address, "FAST").await.is_some());
+        server.stop().await.unwrap();
+    }
+
+    /// A provider that can only say what it has asynchronously
+    #[derive(Clone, Default)]
+    struct AsyncOnlyProvider {
+        value: Arc<std::sync::Mutex<i32>>,
+        events: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl Provider for AsyncOnlyProvider {
+        fn provides(&self, _pv_name: &str) -> bool {
+            false
+        }
+        fn provides_async<'a>(&'a self, pv_name: &'a str) -> BoxFuture<'a, bool> {
+            Box::pin(async move { pv_name == "VALUE" })
+        }
+        fn read_value_async<'a>(
+            &'a self,
+            _pv_name: &'a str,
+            _requested_type: Option<DbrType>,
+        ) -> BoxFuture<'a, Result<Dbr, ErrorCondition>> {
+            let value = *self.value.lock().unwrap();
+            Box::pin(async move { Ok(Dbr::Basic(DbrValue::Long(vec![value]))) })
+        }
+        fn write_value_async<'a>(
+            &'a mut self,
+            _pv_name: &'a str,
+            value: Dbr,
+        ) -> BoxFuture<'a, Result<(), ErrorCondition>> {
+            Box::pin(async move {
+                let DbrValue::Long(value) = value.value() else {
+                    return Err(ErrorCondition::BadType);
+                };
+                *self.value.lock().unwrap() = value[0];
+                Ok(())
+            })
+        }
+        fn get_access_right(
+            &self,
+            _pv_name: &str,
+            _client_user_name: Option<&str>,
+            _client_host_name: Option<&str>,
+        ) -> messages::Access {
+            messages::Access::ReadWrite
+        }
+        fn channel_created(&self, pv_name: &str, _client: &ClientInfo) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("created {pv_name}"));
+        }
+        fn channel_cleared(&self, pv_name: &str, _client: &ClientInfo) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("cleared {pv_name}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_only_provider_in_provider_set() {
+        let provider = AsyncOnlyProvider::default();
+        let events = provider.events.clone();
+        let set = crate::providers::ProviderSet::new().with_prefix("DEV:", provider.clone());
+        let (mut client, circuit) = connect_circuit(set, CircuitOptions::default()).await;
+
+        // The channel is described from an asynchronous read, with the right access
+        let request = messages::CreateChannel {
+            client_id: 1,
+            channel_name: "DEV:VALUE".to_string(),
+            ..Default::default()
+        };
+        client.write_all(&request.as_bytes()).await.unwrap();
+        let Message::AccessRights(access) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected access rights");
+        };
+        assert!(matches!(access.access_rights, messages::Access::ReadWrite));
+        let Message::CreateChannelResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected the channel to be created");
+        };
+        assert_eq!(response.data_type, DbrBasicType::Long);
+        assert_eq!(response.data_count, 1);
+        let server_id = response.server_id;
+
+        // So writing to it is allowed
+        let long = DbrType::try_from(5).unwrap();
+        let write = messages::WriteNotify {
+            data_type: long,
+            data_count: 1,
+            server_id,
+            client_ioid: 2,
+            data: vec![0, 0, 0, 7, 0, 0, 0, 0],
+        };
+        client.write_all(&write.as_bytes()).await.unwrap();
+        let Message::WriteNotifyResponse(response) =
+            Message::read_client_message(&mut client).await.unwrap()
+        else {
+            panic!("Expected a WriteNotify response");
+        };
+        assert_eq!(
+            response.status_code,
+            messages::ErrorCondition::Normal.eca_code()
+        );
+        assert_eq!(*provider.value.lock().unwrap(), 7);
+
+        // And the provider hears about the channel under its own name
+        drop(client);
+        circuit.await.unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["created VALUE".to_string(), "cleared VALUE".to_string()]
+        );
     }
 }
@@ -0,0 +1,304 @@
+//! Statistics about a running server, and PVs to publish them
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    dbr::{DbrValue, IntoDbrBasicType},
+    messages::Access,
+    proto::server::CircuitInfo,
+    providers::{
+        IntercomProvider,
+        intercom::{Intercom, StringIntercom},
+    },
+};
+
+/// The size of the client list PV, in bytes
+///
+/// This is fixed, so that channels to it never see the count change. Longer lists
+/// are cut short, always leaving a terminating null.
+pub(super) const CLIENT_LIST_LEN: usize = 4096;
+
+/// Requests that can be made of a running circuit from outside
+#[derive(Debug)]
+pub(super) enum CircuitCommand {
+    /// Close the connection to the client
+    Disconnect,
+    /// Tell the client that a channel has gone away, and forget about it
+    DisconnectChannel(u32),
+}
+
+struct CircuitEntry {
+    info: CircuitInfo,
+    commands: mpsc::Sender<CircuitCommand>,
+}
+
+/// Counters shared between all parts of a running server
+pub(super) struct ServerStats {
+    started: Instant,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    beacons_sent: AtomicU64,
+    circuits: Mutex<HashMap<u64, CircuitEntry>>,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        ServerStats {
+            started: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            messages_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            beacons_sent: AtomicU64::new(0),
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl ServerStats {
+    pub fn message_received(&self, size: usize) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(size as u64, Ordering::Relaxed);
+    }
+    /// Count `count` messages sent to a client in `size` bytes
+    pub fn messages_sent(&self, count: usize, size: usize) {
+        self.messages_out.fetch_add(count as u64, Ordering::Relaxed);
+        self.bytes_out.fetch_add(size as u64, Ordering::Relaxed);
+    }
+    pub fn beacon_sent(&self) {
+        self.beacons_sent.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Add a new circuit, along with a way to send it commands
+    pub fn register_circuit(&self, info: CircuitInfo, commands: mpsc::Sender<CircuitCommand>) {
+        self.circuits
+            .lock()
+            .unwrap()
+            .insert(info.id, CircuitEntry { info, commands });
+    }
+    /// Record the current state of a circuit
+    pub fn update_circuit(&self, info: CircuitInfo) {
+        if let Some(entry) = self.circuits.lock().unwrap().get_mut(&info.id) {
+            entry.info = info;
+        }
+    }
+    pub fn remove_circuit(&self, id: u64) {
+        self.circuits.lock().unwrap().remove(&id);
+    }
+    /// A snapshot of all open circuits, in the order that they were opened
+    pub fn circuits(&self) -> Vec<CircuitInfo> {
+        let mut circuits: Vec<_> = self
+            .circuits
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect();
+        circuits.sort_by_key(|c| c.id);
+        circuits
+    }
+    /// Send a command to a circuit, returning false if there is no such circuit
+    pub async fn send_command(&self, id: u64, command: CircuitCommand) -> bool {
+        let Some(commands) = self
+            .circuits
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|entry| entry.commands.clone())
+        else {
+            return false;
+        };
+        commands.send(command).await.is_ok()
+    }
+}
+
+/// The PVs that publish [`ServerStats`]
+pub(super) struct DiagnosticPVs {
+    provider: IntercomProvider,
+    client_count: Intercom<i32>,
+    clients: StringIntercom,
+    channel_count: Intercom<i32>,
+    subscription_count: Intercom<i32>,
+    bytes_in: Intercom<f64>,
+    bytes_out: Intercom<f64>,
+    messages_in: Intercom<f64>,
+    messages_out: Intercom<f64>,
+    beacon_count: Intercom<i32>,
+    uptime: Intercom<i32>,
+}
+
+impl DiagnosticPVs {
+    pub fn new(prefix: &str) -> Self {
+        // Clients can read the diagnostics, but only the server changes them
+        let mut provider = IntercomProvider::new();
+        let mut add = |name: &str| {
+            let mut pv = provider
+                .add_pv(&format!("{prefix}{name}"), 0)
+                .expect("Diagnostic PV names are unique");
+            pv.set_access(Access::Read);
+            pv
+        };
+        let client_count = add("CA_CLNT_CNT");
+        let channel_count = add("CA_CONN_CNT");
+        let subscription_count = add("CA_SUBS_CNT");
+        let beacon_count = add("BEACON_CNT");
+        let uptime = add("UPTIME");
+        let mut add = |name: &str| {
+            let mut pv = provider
+                .add_pv(&format!("{prefix}{name}"), 0.0)
+                .expect("Diagnostic PV names are unique");
+            pv.set_access(Access::Read);
+            pv
+        };
+        let bytes_in = add("CA_BYTES_IN");
+        let bytes_out = add("CA_BYTES_OUT");
+        let messages_in = add("CA_MSGS_IN");
+        let messages_out = add("CA_MSGS_OUT");
+        let mut clients = provider
+            .add_string_pv(&format!("{prefix}CA_CLIENTS"), "", Some(CLIENT_LIST_LEN))
+            .expect("Diagnostic PV names are unique");
+        clients.set_access(Access::Read);
+        DiagnosticPVs {
+            provider,
+            client_count,
+            clients,
+            channel_count,
+            subscription_count,
+            bytes_in,
+            bytes_out,
+            messages_in,
+            messages_out,
+            beacon_count,
+            uptime,
+        }
+    }
+
+    /// The provider that serves these PVs
+    pub fn provider(&self) -> IntercomProvider {
+        self.provider.clone()
+    }
+
+    /// Publish the current state of the server
+    ///
+    /// Only values that have changed are stored, so that subscribers only see
+    /// real updates.
+    pub fn update(&mut self, stats: &ServerStats) {
+        fn set<T>(intercom: &mut Intercom<T>, value: T)
+        where
+            T: IntoDbrBasicType + Clone + Default + PartialEq,
+            for<'a> Vec<T>: TryFrom<&'a DbrValue>,
+            DbrValue: From<Vec<T>>,
+        {
+            if intercom.load() != value {
+                intercom.store(&value);
+            }
+        }
+        let counter = |value: &AtomicU64| value.load(Ordering::Relaxed) as f64;
+
+        let circuits = stats.circuits();
+        set(&mut self.client_count, circuits.len() as i32);
+        set(
+            &mut self.channel_count,
+            circuits.iter().map(|c| c.channels.len()).sum::<usize>() as i32,
+        );
+        set(
+            &mut self.subscription_count,
+            circuits.iter().map(|c| c.subscriptions()).sum::<usize>() as i32,
+        );
+        set(&mut self.bytes_in, counter(&stats.bytes_in));
+        set(&mut self.bytes_out, counter(&stats.bytes_out));
+        set(&mut self.messages_in, counter(&stats.messages_in));
+        set(&mut self.messages_out, counter(&stats.messages_out));
+        set(
+            &mut self.beacon_count,
+            stats.beacons_sent.load(Ordering::Relaxed) as i32,
+        );
+        set(&mut self.uptime, stats.started.elapsed().as_secs() as i32);
+
+        let mut clients = circuits
+            .iter()
+            .map(|c| {
+                format!(
+                    "{}@{} ({}) channels={} subscriptions={}",
+                    c.user_name.as_deref().unwrap_or("?"),
+                    c.host_name.as_deref().unwrap_or("?"),
+                    c.address,
+                    c.channels.len(),
+                    c.subscriptions()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        clients.truncate(clients.floor_char_boundary(CLIENT_LIST_LEN - 1));
+        if self.clients.load() != clients {
+            self.clients.store(&clients);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{dbr::DbrValue, providers::Provider};
+
+    use super::{DiagnosticPVs, ServerStats};
+    use crate::proto::server::{ChannelInfo, CircuitInfo};
+
+    #[test]
+    fn test_diagnostics_publish_stats() {
+        let stats = ServerStats::default();
+        let mut diagnostics = DiagnosticPVs::new("TEST:");
+        let provider = diagnostics.provider();
+        let channel = |server_id, subscribed| ChannelInfo {
+            server_id,
+            name: "SOME:PV".to_string(),
+            subscribed,
+        };
+        let (commands, _) = tokio::sync::mpsc::channel(1);
+        stats.register_circuit(
+            CircuitInfo {
+                id: 3,
+                address: "127.0.0.1:4000".parse().unwrap(),
+                host_name: Some("somehost".to_string()),
+                user_name: Some("someone".to_string()),
+                channels: vec![channel(0, true), channel(1, false)],
+            },
+            commands,
+        );
+        stats.message_received(16);
+        stats.messages_sent(1, 24);
+        // e.g. a subscription update with a warning, written together
+        stats.messages_sent(2, 40);
+        diagnostics.update(&stats);
+
+        let read = |name: &str| provider.read_value(name, None).unwrap().value().clone();
+        assert_eq!(read("TEST:CA_CLNT_CNT"), DbrValue::Long(vec![1]));
+        assert_eq!(read("TEST:CA_CONN_CNT"), DbrValue::Long(vec![2]));
+        assert_eq!(read("TEST:CA_SUBS_CNT"), DbrValue::Long(vec![1]));
+        assert_eq!(read("TEST:CA_MSGS_OUT"), DbrValue::Double(vec![3.0]));
+        assert_eq!(read("TEST:CA_BYTES_OUT"), DbrValue::Double(vec![64.0]));
+        let DbrValue::Char(clients) = read("TEST:CA_CLIENTS") else {
+            panic!("Client list should be sent as a long string");
+        };
+        let clients = String::from_utf8(clients.iter().map(|c| *c as u8).collect()).unwrap();
+        assert!(clients.starts_with("someone@somehost (127.0.0.1:4000)"));
+
+        stats.remove_circuit(3);
+        diagnostics.update(&stats);
+        assert_eq!(read("TEST:CA_CLNT_CNT"), DbrValue::Long(vec![0]));
+
+        // Clients cannot change any of them
+        for name in ["TEST:CA_CLNT_CNT", "TEST:CA_BYTES_IN", "TEST:CA_CLIENTS"] {
+            assert!(!provider.get_access_right(name, None, None).can_write());
+        }
+    }
+}
@@ -2,9 +2,11 @@ use num::{FromPrimitive, traits::WrappingAdd};
 use socket2::{Domain, Protocol, Type};
 use std::{
     io::{self},
-    net::ToSocketAddrs,
+    net::{Ipv4Addr, SocketAddr, ToSocketAddrs},
+    str::FromStr,
 };
 use tokio::net::UdpSocket;
+use tracing::warn;
 
 pub fn new_reusable_udp_socket<T: ToSocketAddrs>(address: T) -> io::Result<UdpSocket> {
     let socket = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
@@ -21,3 +23,163 @@ pub fn wrapping_inplace_add<T: WrappingAdd + FromPrimitive + Copy>(value: &mut T
     *value = value.wrapping_add(&T::from_u8(1).unwrap());
     id
 }
+
+/// Reads EPICS settings by name, treating empty values as unset
+///
+/// This is usually the process environment, from [process_env], but can be any
+/// lookup from names to values, so that settings can be parsed without touching
+/// the environment.
+pub struct Env<F>(pub F);
+
+/// The settings in the environment of this process
+pub fn process_env() -> Env<fn(&str) -> Option<String>> {
+    Env(|name| std::env::var(name).ok())
+}
+
+impl<F: Fn(&str) -> Option<String>> Env<F> {
+    /// Read a setting, treating an empty value as unset
+    pub fn get(&self, name: &str) -> Option<String> {
+        (self.0)(name).filter(|v| !v.trim().is_empty())
+    }
+
+    /// Read a setting, falling back to a second one if not set
+    ///
+    /// EPICS has several server-specific variables (`EPICS_CAS_*`) that default to
+    /// the value of the general CA variable (`EPICS_CA_*`) if not set.
+    pub fn get_with_fallback(&self, name: &str, fallback: &str) -> Option<String> {
+        self.get(name).or_else(|| self.get(fallback))
+    }
+
+    /// Read and parse a setting
+    ///
+    /// Values that cannot be parsed are ignored with a warning, in the same way
+    /// that the EPICS C libraries fall back to their defaults.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Option<T> {
+        parse_env_value(name, self.get(name)?)
+    }
+
+    /// Read and parse a setting, falling back to a second one if not set
+    pub fn parse_with_fallback<T: FromStr>(&self, name: &str, fallback: &str) -> Option<T> {
+        parse_env_value(name, self.get_with_fallback(name, fallback)?)
+    }
+}
+
+fn parse_env_value<T: FromStr>(name: &str, value: String) -> Option<T> {
+    let parsed = value.trim().parse().ok();
+    if parsed.is_none() {
+        warn!("Ignoring invalid value '{value}' for {name}");
+    }
+    parsed
+}
+
+/// Interpret an EPICS YES/NO environment value
+pub fn parse_env_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_uppercase().as_str() {
+        "YES" | "Y" | "TRUE" | "1" => Some(true),
+        "NO" | "N" | "FALSE" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse an EPICS-style address list into socket addresses
+///
+/// This is a whitespace-separated list of `host[:port]` entries, as used in e.g.
+/// `EPICS_CA_ADDR_LIST`. Entries without a port use `default_port`. Entries that
+/// cannot be resolved to an IPv4 address are skipped with a warning.
+pub fn parse_address_list(list: &str, default_port: u16) -> Vec<SocketAddr> {
+    list.split_whitespace()
+        .filter_map(|entry| {
+            let resolved = if entry.contains(':') {
+                entry.to_socket_addrs()
+            } else {
+                (entry, default_port).to_socket_addrs()
+            };
+            let address = resolved.ok().and_then(|mut a| a.find(|a| a.is_ipv4()));
+            if address.is_none() {
+                warn!("Could not resolve address list entry '{entry}', ignoring");
+            }
+            address
+        })
+        .collect()
+}
+
+/// Parse an EPICS-style list of IPv4 addresses, ignoring any port numbers
+pub fn parse_ip_list(list: &str) -> Vec<Ipv4Addr> {
+    parse_address_list(list, 0)
+        .into_iter()
+        .filter_map(|address| match address {
+            SocketAddr::V4(address) => Some(*address.ip()),
+            SocketAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, SocketAddr},
+    };
+
+    use super::{Env, parse_address_list, parse_env_bool, parse_ip_list};
+
+    #[test]
+    fn test_parse_env_bool() {
+        for value in ["YES", "yes", " Y ", "true", "1"] {
+            assert_eq!(parse_env_bool(value), Some(true), "{value}");
+        }
+        for value in ["NO", "n", "False", "0"] {
+            assert_eq!(parse_env_bool(value), Some(false), "{value}");
+        }
+        for value in ["", "maybe", "2", "YESS"] {
+            assert_eq!(parse_env_bool(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn test_parse_address_list() {
+        let addresses = parse_address_list(
+            "127.0.0.1  10.0.0.1:5070\t1.2.3.4:notaport [::1]:5064",
+            5064,
+        );
+        assert_eq!(
+            addresses,
+            [
+                "127.0.0.1:5064".parse::<SocketAddr>().unwrap(),
+                "10.0.0.1:5070".parse().unwrap(),
+            ]
+        );
+        assert!(parse_address_list("   ", 5064).is_empty());
+        // Ports are dropped from plain IP lists
+        assert_eq!(
+            parse_ip_list("127.0.0.1:5064 10.0.0.1 1.2.3.4:notaport"),
+            [Ipv4Addr::LOCALHOST, Ipv4Addr::new(10, 0, 0, 1)]
+        );
+    }
+
+    #[test]
+    fn test_env_fallback() {
+        let vars = HashMap::from([
+            ("CAS_PORT", " "),
+            ("CA_PORT", "6064"),
+            ("CAS_PERIOD", "often"),
+            ("CA_PERIOD", "2"),
+        ]);
+        let env = Env(|name: &str| vars.get(name).map(|v| v.to_string()));
+        // Blank values count as unset
+        assert_eq!(
+            env.get_with_fallback("CAS_PORT", "CA_PORT").as_deref(),
+            Some("6064")
+        );
+        assert_eq!(
+            env.parse_with_fallback::<u16>("CAS_PORT", "CA_PORT"),
+            Some(6064)
+        );
+        // A set but invalid value is ignored, rather than using the fallback
+        assert_eq!(
+            env.parse_with_fallback::<f64>("CAS_PERIOD", "CA_PERIOD"),
+            None
+        );
+        assert_eq!(env.get_with_fallback("UNSET", "ALSO_UNSET"), None);
+    }
+}